@@ -0,0 +1,87 @@
+use std::sync::Arc;
+
+use crate::cache::Weigher;
+
+/// How `CacheMut::with_adaptive_capacity` sizes the cache as the working set changes. After every window of lookups,
+/// the capacity grows by a step while the hit rate is below one target and the cache is full, and shrinks by a step
+/// while the hit rate would stay above another without the step's worth of least recently used entries, always
+/// staying within `min..=max`. With `with_max_bytes`, it also shrinks whenever the cached values weigh too much,
+/// whatever the hit rate.
+pub struct AdaptiveCapacity<V> {
+    min: usize, max: usize, window: u64, grow_below: f64, shrink_above: f64, step: f64,
+    max_bytes: Option<(usize, Weigher<V>)>,
+}
+impl<V> Clone for AdaptiveCapacity<V> {
+    fn clone(&self) -> Self {
+        Self {
+            min: self.min, max: self.max, window: self.window, grow_below: self.grow_below,
+            shrink_above: self.shrink_above, step: self.step, max_bytes: self.max_bytes.clone(),
+        }
+    }
+}
+impl<V> std::fmt::Debug for AdaptiveCapacity<V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AdaptiveCapacity").field("min", &self.min).field("max", &self.max).field("window", &self.window)
+            .field("grow_below", &self.grow_below).field("shrink_above", &self.shrink_above).field("step", &self.step)
+            .field("max_bytes", &self.max_bytes.as_ref().map(|(bytes, _)| bytes)).finish()
+    }
+}
+impl<V> AdaptiveCapacity<V> {
+    /// Windows of 1000 lookups, growing by a quarter below 80% hits and shrinking by a quarter above 95%.
+    /// Panics unless `0 < min <= max`.
+    pub fn new(min: usize, max: usize) -> Self {
+        assert!(0 < min && min <= max, "adaptive capacity needs 0 < min <= max");
+        Self {min, max, window: 1000, grow_below: 0.8, shrink_above: 0.95, step: 0.25, max_bytes: None}
+    }
+    /// Adjusts the capacity every `lookups` lookups.
+    pub fn with_window(mut self, lookups: u64) -> Self {
+        assert!(lookups > 0, "the window must hold at least one lookup");
+        self.window = lookups;
+        self
+    }
+    /// Grows while fewer than `grow_below` of the lookups are hits, and shrinks while more than `shrink_above` would
+    /// be after shrinking.
+    pub fn with_targets(mut self, grow_below: f64, shrink_above: f64) -> Self {
+        assert!(grow_below <= shrink_above, "grow_below must not be above shrink_above");
+        (self.grow_below, self.shrink_above) = (grow_below, shrink_above);
+        self
+    }
+    /// Grows or shrinks by `fraction` of the capacity at a time, and by at least one entry.
+    pub fn with_step(mut self, fraction: f64) -> Self {
+        assert!(fraction > 0.0, "the step must be positive");
+        self.step = fraction;
+        self
+    }
+    /// Shrinks whenever the cached values weigh more than `max_bytes` by `weigh` (their serialized size, say), and
+    /// does not grow while over it. Every cached value is weighed once per window.
+    pub fn with_max_bytes(mut self, max_bytes: usize, weigh: impl Fn(&V) -> usize+Send+Sync+'static) -> Self {
+        self.max_bytes = Some((max_bytes, Arc::new(weigh)));
+        self
+    }
+    pub(crate) fn window(&self) -> u64 { self.window }
+    pub(crate) fn weigher(&self) -> Option<&Weigher<V>> { self.max_bytes.as_ref().map(|(_, weigh)| weigh) }
+    /// How many entries one step of `capacity` is.
+    pub(crate) fn step(&self, capacity: usize) -> usize { ((capacity as f64*self.step) as usize).max(1) }
+    /// The capacity to move to from `capacity` after a window of `lookups` with `hits`, `cold_hits` of them on the
+    /// step's worth of least recently used entries at the start of the window, where the cache holds `cached`
+    /// entries weighing `bytes` (`None` without `with_max_bytes`).
+    pub(crate) fn next(&self, capacity: usize, cached: usize, lookups: u64, hits: u64, cold_hits: u64, bytes: Option<usize>) -> usize {
+        let step = self.step(capacity);
+        let capacity = capacity.clamp(self.min, self.max);
+        let hit_rate = hits as f64/lookups as f64;
+        let hit_rate_after_shrink = (hits-cold_hits) as f64/lookups as f64;
+        let limit = self.max_bytes.as_ref().map(|(max_bytes, _)| *max_bytes);
+        let next = match (bytes, limit) {
+            // Shrinks in proportion to the excess, since a step may not be enough.
+            (Some(bytes), Some(limit)) if bytes > limit => {
+                let fits = (cached as f64*limit as f64/bytes as f64) as usize;
+                fits.min(capacity.saturating_sub(step))
+            },
+            _ if hit_rate_after_shrink > self.shrink_above => capacity.saturating_sub(step),
+            // Growing only helps when entries are being evicted.
+            _ if hit_rate < self.grow_below && cached >= capacity => capacity+step,
+            _ => capacity,
+        };
+        next.clamp(self.min, self.max)
+    }
+}
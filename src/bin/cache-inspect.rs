@@ -0,0 +1,238 @@
+//! Inspects and maintains a `FolderCache` folder without knowing its key and value types.
+//! Keys and values are decoded from their MessagePack form and printed as JSON.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use serde::{Serialize,Serializer,Deserialize,Deserializer};
+use serde::de::{Visitor,SeqAccess,MapAccess};
+use serde::ser::{SerializeSeq,SerializeMap};
+use cache::folder_compatible::{FolderCache,FolderCacheError,Layout};
+
+const USAGE: &str = "\
+usage: cache-inspect <folder> [--prefix <prefix>] [--extension <extension>] <command>
+
+commands:
+    keys          list every key
+    get <key>     print the value of a key, given as JSON (a number, string, bool or null);
+                  anything else is taken as a bare string
+    stats         show the size classes and their occupancy
+    verify        check every bucket file for damage
+    compact       reclaim unused space";
+
+/// Any MessagePack value. Floats are kept as their bits so that values can be used as keys.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Value {
+    Nil,
+    Bool(bool),
+    Int(i128),
+    F32(u32),
+    F64(u64),
+    Str(String),
+    Bin(Vec<u8>),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Value::Nil => s.serialize_unit(),
+            Value::Bool(b) => s.serialize_bool(*b),
+            Value::Int(i) => match i64::try_from(*i) {
+                Ok(i) => s.serialize_i64(i),
+                Err(_) => s.serialize_u64(*i as u64),
+            },
+            Value::F32(bits) => s.serialize_f32(f32::from_bits(*bits)),
+            Value::F64(bits) => s.serialize_f64(f64::from_bits(*bits)),
+            Value::Str(string) => s.serialize_str(string),
+            Value::Bin(bytes) => s.serialize_bytes(bytes),
+            Value::Array(items) => {
+                let mut seq = s.serialize_seq(Some(items.len()))?;
+                items.iter().try_for_each(|item| seq.serialize_element(item))?;
+                seq.end()
+            },
+            Value::Map(entries) => {
+                let mut map = s.serialize_map(Some(entries.len()))?;
+                entries.iter().try_for_each(|(k, v)| map.serialize_entry(k, v))?;
+                map.end()
+            },
+        }
+    }
+}
+
+struct ValueVisitor;
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {f.write_str("a MessagePack value")}
+    fn visit_unit<E>(self) -> Result<Value, E> {Ok(Value::Nil)}
+    fn visit_none<E>(self) -> Result<Value, E> {Ok(Value::Nil)}
+    fn visit_some<D: Deserializer<'de>>(self, d: D) -> Result<Value, D::Error> {Value::deserialize(d)}
+    fn visit_newtype_struct<D: Deserializer<'de>>(self, d: D) -> Result<Value, D::Error> {Value::deserialize(d)}
+    fn visit_bool<E>(self, b: bool) -> Result<Value, E> {Ok(Value::Bool(b))}
+    fn visit_i64<E>(self, i: i64) -> Result<Value, E> {Ok(Value::Int(i as i128))}
+    fn visit_u64<E>(self, i: u64) -> Result<Value, E> {Ok(Value::Int(i as i128))}
+    fn visit_f32<E>(self, f: f32) -> Result<Value, E> {Ok(Value::F32(f.to_bits()))}
+    fn visit_f64<E>(self, f: f64) -> Result<Value, E> {Ok(Value::F64(f.to_bits()))}
+    fn visit_str<E>(self, s: &str) -> Result<Value, E> {Ok(Value::Str(s.to_string()))}
+    fn visit_bytes<E>(self, b: &[u8]) -> Result<Value, E> {Ok(Value::Bin(b.to_vec()))}
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut items = Vec::new();
+        while let Some(item) = seq.next_element()? {
+            items.push(item);
+        }
+        Ok(Value::Array(items))
+    }
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let mut entries = Vec::new();
+        while let Some(entry) = map.next_entry()? {
+            entries.push(entry);
+        }
+        Ok(Value::Map(entries))
+    }
+}
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Value, D::Error> {d.deserialize_any(ValueVisitor)}
+}
+
+impl Value {
+    /// Reads a key from the command line.
+    fn parse(arg: &str) -> Value {
+        match arg {
+            "null" => Value::Nil,
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            _ => match arg.parse::<i128>() {
+                Ok(i) => Value::Int(i),
+                Err(_) => Value::Str(arg.strip_prefix('"').and_then(|s| s.strip_suffix('"')).unwrap_or(arg).to_string()),
+            },
+        }
+    }
+    /// JSON has no binary type or non-string map keys, so bytes become arrays of numbers and map keys are
+    /// written as the JSON of the key, quoted.
+    fn write_json(&self, out: &mut String) {
+        let float = |f: f64, out: &mut String| if f.is_finite() {out.push_str(&format!("{f:?}"))} else {out.push_str("null")};
+        match self {
+            Value::Nil => out.push_str("null"),
+            Value::Bool(b) => out.push_str(&b.to_string()),
+            Value::Int(i) => out.push_str(&i.to_string()),
+            Value::F32(bits) => float(f32::from_bits(*bits) as f64, out),
+            Value::F64(bits) => float(f64::from_bits(*bits), out),
+            Value::Str(s) => write_json_string(s, out),
+            Value::Bin(bytes) => Value::Array(bytes.iter().map(|b| Value::Int(*b as i128)).collect()).write_json(out),
+            Value::Array(items) => {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {out.push(',')}
+                    item.write_json(out);
+                }
+                out.push(']');
+            },
+            Value::Map(entries) => {
+                out.push('{');
+                for (i, (k, v)) in entries.iter().enumerate() {
+                    if i > 0 {out.push(',')}
+                    match k {
+                        Value::Str(s) => write_json_string(s, out),
+                        k => write_json_string(&k.to_json(), out),
+                    }
+                    out.push(':');
+                    v.write_json(out);
+                }
+                out.push('}');
+            },
+        }
+    }
+    fn to_json(&self) -> String {
+        let mut out = String::new();
+        self.write_json(&mut out);
+        out
+    }
+}
+
+fn write_json_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn run(folder: PathBuf, layout: Layout, command: &str, args: &[String]) -> Result<ExitCode, FolderCacheError> {
+    match (command, args) {
+        ("keys", []) => {
+            let cache = FolderCache::<Value>::read_only_with(folder, layout)?;
+            for key in cache.keys() {
+                println!("{}", key.to_json());
+            }
+        },
+        ("get", [key]) => {
+            let cache = FolderCache::<Value>::read_only_with(folder, layout)?;
+            println!("{}", cache.get::<Value>(&Value::parse(key))?.to_json());
+        },
+        ("stats", []) => {
+            let stats = FolderCache::<Value>::read_only_with(folder, layout)?.stats()?;
+            println!("{:>12} {:>14} {:>10} {:>10} {:>14}", "class", "file bytes", "reserved", "items", "wasted bytes");
+            for class in &stats.classes {
+                println!("{:>12} {:>14} {:>10} {:>10} {:>14}", class.size_per_item, class.file_bytes, class.reserved, class.items, class.wasted_bytes);
+            }
+            let total = &stats.total;
+            println!("{:>12} {:>14} {:>10} {:>10} {:>14}", "total", total.file_bytes, total.reserved, total.items, total.wasted_bytes);
+            println!("blob bytes: {}", stats.blob_bytes);
+        },
+        ("verify", []) => {
+            let report = FolderCache::<Value>::verify_with(&folder, &layout)?;
+            println!("{} files, {} records", report.files, report.records);
+            if report.pending_journal {
+                println!("a journal of interrupted writes is waiting to be replayed");
+            }
+            for (path, problem) in &report.problems {
+                println!("{}: {problem:?}", path.display());
+            }
+            if !report.is_ok() {
+                return Ok(ExitCode::FAILURE);
+            }
+        },
+        ("compact", []) => {
+            let freed = FolderCache::<Value>::continued_with(folder, layout)?.compact()?;
+            println!("freed {freed} bytes");
+        },
+        _ => {
+            eprintln!("{USAGE}");
+            return Ok(ExitCode::from(2));
+        },
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn main() -> ExitCode {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let mut layout = Layout::default();
+    while let Some(i) = args.iter().position(|a| a == "--prefix" || a == "--extension") {
+        let Some(value) = args.get(i+1).cloned() else {
+            eprintln!("{USAGE}");
+            return ExitCode::from(2);
+        };
+        layout = if args[i] == "--prefix" {layout.with_prefix(value)} else {layout.with_extension(value)};
+        args.drain(i..i+2);
+    }
+    if args.len() < 2 {
+        eprintln!("{USAGE}");
+        return ExitCode::from(2);
+    }
+    match run(PathBuf::from(&args[0]), layout, &args[1], &args[2..]) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        },
+    }
+}
@@ -0,0 +1,261 @@
+//! Serves a `FolderCache` folder over HTTP, so that several processes on a host can share one cache.
+//! Keys are unsigned 64-bit integers and values are arbitrary bytes:
+//! `GET /<key>`, `PUT /<key>` (the body is the value), `DELETE /<key>` and `GET /stats`.
+//! On SIGINT or SIGTERM it stops accepting connections, lets those in flight finish and commits the cache before
+//! exiting; killed any other way, it loses the changes since the last flush.
+
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::{Visitor, SeqAccess};
+use cache::CacheMut;
+use cache::folder_compatible::{FolderCache, FolderCacheError};
+use cache::maintenance::{Maintenance, MaintenancePolicy};
+
+const USAGE: &str = "\
+usage: cache-server <folder> [--listen <address>] [--capacity <entries>] [--flush <seconds>] [--connections <n>]
+
+Keeps up to <capacity> (default 1024) values in memory and writes changes to <folder> every <flush> (default 1)
+seconds, and when stopped by SIGINT or SIGTERM. Listens on <address> (default 127.0.0.1:7878; port 0 picks a free
+one, printed on startup) for up to <connections> (default 64) connections at a time, answering others with 503:
+    GET /<key>       the value of a key, or 404
+    PUT /<key>       sets the value of a key to the request body
+    DELETE /<key>    removes a key
+    GET /stats       counters of the in-memory layer and the folder";
+
+/// Request bodies larger than this are refused.
+const MAX_BODY: usize = 64 << 20;
+/// How long a connection may take to send its request, or to take the response, before it is dropped.
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the accepting thread checks for a shutdown while no connections come in.
+const POLL: Duration = Duration::from_millis(50);
+
+/// Set by SIGINT and SIGTERM.
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+#[cfg(unix)]
+extern "C" fn request_shutdown(_: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::Relaxed);
+}
+#[cfg(unix)]
+fn handle_signals() {
+    let handler = request_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+#[cfg(not(unix))]
+fn handle_signals() {}
+
+/// Counts a connection as open until dropped, even if serving it panics.
+struct Open(Arc<AtomicUsize>);
+impl Drop for Open {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A value, stored as MessagePack binary rather than an array of numbers.
+struct Blob(Vec<u8>);
+impl Serialize for Blob {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {s.serialize_bytes(&self.0)}
+}
+struct BlobVisitor;
+impl<'de> Visitor<'de> for BlobVisitor {
+    type Value = Blob;
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {f.write_str("bytes")}
+    fn visit_bytes<E>(self, b: &[u8]) -> Result<Blob, E> {Ok(Blob(b.to_vec()))}
+    fn visit_byte_buf<E>(self, b: Vec<u8>) -> Result<Blob, E> {Ok(Blob(b))}
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Blob, A::Error> {
+        let mut bytes = Vec::new();
+        while let Some(b) = seq.next_element()? {
+            bytes.push(b);
+        }
+        Ok(Blob(bytes))
+    }
+}
+impl<'de> Deserialize<'de> for Blob {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Blob, D::Error> {d.deserialize_byte_buf(BlobVisitor)}
+}
+
+/// `CacheMut::insert` and `remove` panic on borrowed entries, so requests take turns with the cache.
+type Shared = Arc<Mutex<CacheMut<u64, Blob, FolderCache<u64>>>>;
+
+struct Response {status: u16, body: Vec<u8>}
+impl Response {
+    fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {Self {status, body: body.into()}}
+    fn write_to(&self, stream: &mut TcpStream) -> std::io::Result<()> {
+        let reason = match self.status {
+            200 => "OK", 204 => "No Content", 400 => "Bad Request", 404 => "Not Found", 405 => "Method Not Allowed",
+            413 => "Payload Too Large", 503 => "Service Unavailable", _ => "Internal Server Error",
+        };
+        write!(stream, "HTTP/1.1 {} {reason}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", self.status, self.body.len())?;
+        stream.write_all(&self.body)?;
+        stream.flush()
+    }
+}
+
+fn handle(cache: &Shared, method: &str, path: &str, body: Vec<u8>) -> Result<Response, FolderCacheError> {
+    let mut cache = cache.lock().unwrap_or_else(PoisonError::into_inner);
+    if path == "/stats" {
+        if method != "GET" {
+            return Ok(Response::new(405, ""));
+        }
+        let stats = cache.stats();
+        let (disk_bytes, items) = cache.with_backend(|folder| (folder.disk_bytes(), folder.keys().count()))?;
+        let text = format!(
+            "active {}\ncached {}\nunwritten {}\nreleased {}\nitems {items}\ndisk_bytes {disk_bytes}\n",
+            stats.active, stats.cached, stats.unwritten, stats.released,
+        );
+        return Ok(Response::new(200, text));
+    }
+    let Some(k) = path.strip_prefix('/').and_then(|k| k.parse::<u64>().ok()) else {
+        return Ok(Response::new(400, "keys are unsigned 64-bit integers\n"));
+    };
+    match method {
+        "GET" if cache.contains(&k) => Ok(Response::new(200, cache.get(&k)?.0.clone())),
+        "GET" => Ok(Response::new(404, "")),
+        "PUT" => {
+            cache.insert(k, Blob(body))?;
+            Ok(Response::new(204, ""))
+        },
+        "DELETE" => {
+            cache.remove(&k)?;
+            Ok(Response::new(204, ""))
+        },
+        _ => Ok(Response::new(405, "")),
+    }
+}
+
+/// Reads one request from `stream` and answers it.
+fn serve(cache: &Shared, mut stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    // Closed without a request.
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(());
+    }
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next().map(str::to_string), parts.next().map(str::to_string)) else {
+        return Response::new(400, "").write_to(&mut stream);
+    };
+    let mut length = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') && name.trim().eq_ignore_ascii_case("content-length") {
+            match value.trim().parse() {
+                Ok(n) => length = n,
+                Err(_) => return Response::new(400, "").write_to(&mut stream),
+            }
+        }
+    }
+    if length > MAX_BODY {
+        return Response::new(413, "").write_to(&mut stream);
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    let response = handle(cache, &method, &path, body).unwrap_or_else(|e| Response::new(500, format!("{e}\n")));
+    response.write_to(&mut stream)
+}
+
+fn main() -> ExitCode {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let (mut listen, mut capacity, mut flush, mut connections) = ("127.0.0.1:7878".to_string(), 1024, 1, 64);
+    while let Some(i) = args.iter().position(|a| a.starts_with("--")) {
+        let value = args.get(i+1).cloned();
+        let ok = match (args[i].as_str(), value) {
+            ("--listen", Some(value)) => {listen = value; true},
+            ("--capacity", Some(value)) => value.parse().map(|n| capacity = n).is_ok() && capacity > 0,
+            ("--flush", Some(value)) => value.parse().map(|n| flush = n).is_ok() && flush > 0,
+            ("--connections", Some(value)) => value.parse().map(|n| connections = n).is_ok() && connections > 0,
+            _ => false,
+        };
+        if !ok {
+            eprintln!("{USAGE}");
+            return ExitCode::from(2);
+        }
+        args.drain(i..i+2);
+    }
+    let [folder] = &args[..] else {
+        eprintln!("{USAGE}");
+        return ExitCode::from(2);
+    };
+    let folder = match FolderCache::continued(PathBuf::from(folder)) {
+        Ok(folder) => folder,
+        Err(e) => {
+            eprintln!("error: {e}");
+            return ExitCode::FAILURE;
+        },
+    };
+    let listener = match TcpListener::bind(&listen) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("error: cannot listen on {listen}: {e}");
+            return ExitCode::FAILURE;
+        },
+    };
+    // Polled, so that the loop below notices a shutdown.
+    if let Err(e) = listener.set_nonblocking(true) {
+        eprintln!("error: {e}");
+        return ExitCode::FAILURE;
+    }
+    match listener.local_addr() {
+        Ok(address) => println!("listening on {address}"),
+        Err(e) => eprintln!("error: {e}"),
+    }
+    handle_signals();
+    let cache = CacheMut::new(folder, capacity);
+    let policy = MaintenancePolicy {flush_interval: Some(Duration::from_secs(flush)), ..MaintenancePolicy::default()};
+    let maintenance = Maintenance::new(policy).spawn(cache.clone(), |e| eprintln!("error: flush failed: {e}"));
+    let cache: Shared = Arc::new(Mutex::new(cache));
+    let open = Arc::new(AtomicUsize::new(0));
+    while !SHUTDOWN.load(Ordering::Relaxed) {
+        let mut stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(POLL);
+                continue;
+            },
+            Err(e) => {
+                eprintln!("error: {e}");
+                continue;
+            },
+        };
+        // Some platforms hand out accepted streams that are nonblocking like the listener.
+        let _ = stream.set_nonblocking(false);
+        if open.load(Ordering::Relaxed) >= connections {
+            let _ = stream.set_write_timeout(Some(IO_TIMEOUT));
+            let _ = Response::new(503, "").write_to(&mut stream);
+            continue;
+        }
+        open.fetch_add(1, Ordering::Relaxed);
+        let (cache, slot) = (cache.clone(), Open(open.clone()));
+        std::thread::spawn(move || {
+            let _slot = slot;
+            if let Err(e) = serve(&cache, stream) {
+                eprintln!("error: {e}");
+            }
+        });
+    }
+    while open.load(Ordering::Relaxed) > 0 {
+        std::thread::sleep(POLL);
+    }
+    drop(maintenance);
+    let mut cache = cache.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Err(e) = cache.commit() {
+        eprintln!("error: commit failed: {e}");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
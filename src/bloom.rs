@@ -0,0 +1,43 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+/// A set of keys that may answer "maybe" for a key it does not hold, but never "no" for one it does, in a fixed
+/// amount of memory. `CacheMut::with_bloom_filter` uses one to answer most misses without asking the backend.
+/// Keys can not be taken out again.
+#[derive(Clone, Debug)]
+pub struct BloomFilter {bits: Vec<u64>, hashes: u32}
+impl BloomFilter {
+    /// Sized to answer "maybe" for about `false_positive_rate` of absent keys while it holds up to `expected_keys`.
+    /// More keys than that raise the rate.
+    pub fn new(expected_keys: usize, false_positive_rate: f64) -> Self {
+        let n = expected_keys.max(1) as f64;
+        let rate = false_positive_rate.clamp(f64::MIN_POSITIVE, 0.5);
+        let bits = (-n*rate.ln()/(std::f64::consts::LN_2*std::f64::consts::LN_2)).ceil().max(64.0) as usize;
+        let hashes = ((bits as f64/n)*std::f64::consts::LN_2).round().clamp(1.0, 32.0) as u32;
+        Self {bits: vec![0; bits.div_ceil(64)], hashes}
+    }
+    /// A filter sized for `keys` and holding them, for backends that already have entries.
+    pub fn from_keys<'a, K: Hash+'a>(keys: impl ExactSizeIterator<Item = &'a K>, false_positive_rate: f64) -> Self {
+        let mut filter = Self::new(keys.len(), false_positive_rate);
+        keys.for_each(|k| filter.insert(k));
+        filter
+    }
+    /// The bits of `k`, by double hashing.
+    fn positions(&self, k: &impl Hash) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        k.hash(&mut hasher);
+        let hash = hasher.finish();
+        let (h1, h2) = (hash as u32 as u64, (hash >> 32)|1);
+        let bits = self.bits.len() as u64*64;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+    }
+    pub fn insert(&mut self, k: &impl Hash) {
+        for bit in self.positions(k).collect::<Vec<_>>() {
+            self.bits[bit/64] |= 1 << (bit%64);
+        }
+    }
+    /// `false` if `k` was never inserted; `true` if it was, or by chance.
+    pub fn might_contain(&self, k: &impl Hash) -> bool {
+        self.positions(k).all(|bit| self.bits[bit/64] & (1 << (bit%64)) != 0)
+    }
+    pub fn clear(&mut self) { self.bits.fill(0) }
+}
@@ -0,0 +1,134 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::{Appendable, CacheCompatible, CacheMutCompatible, IfChanged, Retrieval, Serialized, Token, Usage};
+use crate::clock::{Clock, SystemClock};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BreakerError<E> {
+    /// The backend failed too often, and is left alone until its cooldown is over.
+    Open,
+    Backend(E),
+}
+impl<E: std::fmt::Display> std::fmt::Display for BreakerError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Open => write!(f, "backend is failing; not asked until the cooldown is over"),
+            Self::Backend(e) => write!(f, "backend error: {e}"),
+        }
+    }
+}
+impl<E: std::fmt::Debug+std::fmt::Display> std::error::Error for BreakerError<E> {}
+
+type Counts<E> = Arc<dyn Fn(&E) -> bool+Send+Sync>;
+
+/// Wraps a backend that fails for a while at a time (a disk that was unmounted, a store across a network), so that
+/// after `failures` failures in a row it is left alone for `cooldown`: every call fails at once with
+/// `BreakerError::Open` and `contains` says no, so a `CacheMut` serves what it holds in memory (stale, past a TTL)
+/// and misses on the rest. Once the cooldown is over, the next call asks `CacheCompatible::health` first, and the
+/// breaker closes again if the backend answers, or waits for another cooldown if not.
+pub struct CircuitBreaker<CC, E> {
+    inner: CC, threshold: u32, cooldown: Duration, failures: u32, open_until: Option<Instant>, clock: Arc<dyn Clock>,
+    counts: Counts<E>,
+}
+impl<CC, E> CircuitBreaker<CC, E> {
+    /// Every error counts as a failure; see `counting`. Panics if `failures` is 0.
+    pub fn new(inner: CC, failures: u32, cooldown: Duration) -> Self {
+        assert!(failures > 0, "the breaker must allow at least one failure");
+        Self {
+            inner, threshold: failures, cooldown, failures: 0, open_until: None, clock: Arc::new(SystemClock),
+            counts: Arc::new(|_| true),
+        }
+    }
+    /// Only counts the errors for which `counts` returns true, so that a missing key, say, does not open the breaker.
+    pub fn counting(mut self, counts: impl Fn(&E) -> bool+Send+Sync+'static) -> Self {
+        self.counts = Arc::new(counts);
+        self
+    }
+    /// Times the cooldown by `clock` rather than the wall clock.
+    pub fn with_clock(mut self, clock: impl Clock+'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+    pub fn inner(&self) -> &CC {&self.inner}
+    /// Whether the backend is being left alone.
+    pub fn is_open(&self) -> bool { self.open_until.is_some() }
+    /// The failures in a row so far.
+    pub fn failures(&self) -> u32 { self.failures }
+    fn open(&mut self) {
+        self.open_until = Some(self.clock.now()+self.cooldown);
+    }
+    /// Fails while open, and probes the backend once the cooldown is over.
+    fn admit<K, V>(&mut self) -> Result<(), BreakerError<E>> where CC: CacheCompatible<K, V, Error = E> {
+        let Some(until) = self.open_until else {return Ok(())};
+        if self.clock.now() < until {
+            return Err(BreakerError::Open);
+        }
+        match self.inner.health() {
+            Ok(()) => {
+                (self.open_until, self.failures) = (None, 0);
+                Ok(())
+            },
+            Err(_) => {
+                self.open();
+                Err(BreakerError::Open)
+            },
+        }
+    }
+    fn record<T>(&mut self, result: Result<T, E>) -> Result<T, BreakerError<E>> {
+        match result {
+            Ok(t) => {
+                self.failures = 0;
+                Ok(t)
+            },
+            Err(e) => {
+                if (self.counts)(&e) {
+                    self.failures += 1;
+                    if self.failures >= self.threshold {
+                        self.open();
+                    }
+                }
+                Err(BreakerError::Backend(e))
+            },
+        }
+    }
+    fn call<K, V, T>(&mut self, f: impl FnOnce(&mut CC) -> Result<T, E>) -> Result<T, BreakerError<E>> where
+    CC: CacheCompatible<K, V, Error = E> {
+        self.admit::<K, V>()?;
+        let result = f(&mut self.inner);
+        self.record(result)
+    }
+}
+
+impl<K, V, CC> CacheCompatible<K, V> for CircuitBreaker<CC, CC::Error> where CC: CacheCompatible<K, V> {
+    type Error = BreakerError<CC::Error>;
+    const RETRIEVAL: Retrieval = CC::RETRIEVAL;
+
+    fn contains(&self, k: K) -> bool { !self.is_open() && self.inner.contains(k) }
+    fn get(&mut self, k: K) -> Result<V, Self::Error> { self.call(|inner| inner.get(k)) }
+    fn replace(&mut self, k: K, v: V) -> Result<(), Self::Error> { self.call(|inner| inner.replace(k, v)) }
+    fn get_versioned(&mut self, k: K) -> Result<(V, Option<Token>), Self::Error> { self.call(|inner| inner.get_versioned(k)) }
+    fn get_if_changed(&mut self, k: K, token: &Token) -> Result<IfChanged<V>, Self::Error> {
+        self.call(|inner| inner.get_if_changed(k, token))
+    }
+    fn health(&mut self) -> Result<(), Self::Error> { self.call(|inner| inner.health()) }
+}
+
+impl<K, V, CC> CacheMutCompatible<K, V> for CircuitBreaker<CC, CC::Error> where CC: CacheMutCompatible<K, V> {
+    fn insert(&mut self, k: K, v: V) -> Result<(), Self::Error> { self.call(|inner| inner.insert(k, v)) }
+    fn insert_shared(&mut self, k: K, v: &V) -> Result<bool, Self::Error> { self.call(|inner| inner.insert_shared(k, v)) }
+    const SERIALIZED: Option<Serialized<K, V, Self>> = match CC::SERIALIZED {
+        Some(_) => Some(Serialized {
+            serialize: |v| (CC::SERIALIZED.unwrap().serialize)(v).map_err(BreakerError::Backend),
+            insert: |breaker, k, vser| breaker.call(|inner| (CC::SERIALIZED.unwrap().insert)(inner, k, vser)),
+        }),
+        None => None,
+    };
+    fn append(&mut self, k: K, suffix: &V::Suffix) -> Result<(), Self::Error> where V: Appendable, K: Clone {
+        self.call(|inner| inner.append(k, suffix))
+    }
+    fn remove(&mut self, k: K) -> Result<(), Self::Error> { self.call(|inner| inner.remove(k)) }
+    fn remove_many(&mut self, keys: Vec<K>) -> Result<(), Self::Error> { self.call(|inner| inner.remove_many(keys)) }
+    fn commit(&mut self) -> Result<(), Self::Error> { self.call(|inner| inner.commit()) }
+    fn resource_usage(&self) -> Option<Usage> { self.inner.resource_usage() }
+}
@@ -0,0 +1,151 @@
+use std::sync::Arc;
+use std::sync::mpsc::{Sender, channel};
+use parking_lot::Mutex;
+
+/// A change made to the backend by one `CacheMut`, which others sharing the backend must not hide behind a copy of
+/// their own.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "folder", derive(serde::Serialize, serde::Deserialize))]
+pub enum Change<K> {
+    /// A new value was written to the backend.
+    Inserted(K),
+    Removed(K),
+}
+impl<K> Change<K> {
+    pub fn key(&self) -> &K {
+        match self {
+            Change::Inserted(k) | Change::Removed(k) => k,
+        }
+    }
+}
+
+/// Carries `Change`s between `CacheMut`s, usually in different processes, whose backends store into the same place
+/// (see `CacheMut::with_bus`).
+pub trait InvalidationBus<K>: Send+Sync {
+    /// Sends `change` to every subscriber but this one. Called with the cache's lock held, so it should not wait on
+    /// the other caches.
+    fn publish(&self, change: &Change<K>);
+    /// Has `receive` called with every change published by others from now on. It may be called from any thread, but
+    /// not from within `publish`.
+    fn subscribe(&self, receive: Box<dyn Fn(Change<K>)+Send+Sync>);
+}
+
+type Subscribers<K> = Arc<Mutex<Vec<(usize, Sender<Change<K>>)>>>;
+
+/// An `InvalidationBus` within one process, for caches in different parts of a program (and for tests). Each cache
+/// takes its own `handle`; changes are delivered on one thread per subscriber.
+pub struct LocalBus<K> {subscribers: Subscribers<K>, next: usize}
+impl<K> Default for LocalBus<K> {
+    fn default() -> Self { Self {subscribers: Arc::new(Mutex::new(Vec::new())), next: 0} }
+}
+impl<K> LocalBus<K> {
+    pub fn new() -> Self { Self::default() }
+    pub fn handle(&mut self) -> LocalBusHandle<K> {
+        self.next += 1;
+        LocalBusHandle {subscribers: self.subscribers.clone(), id: self.next}
+    }
+}
+pub struct LocalBusHandle<K> {subscribers: Subscribers<K>, id: usize}
+impl<K: Clone+Send+'static> InvalidationBus<K> for LocalBusHandle<K> {
+    fn publish(&self, change: &Change<K>) {
+        self.subscribers.lock().retain(|(id, sender)| *id == self.id || sender.send(change.clone()).is_ok());
+    }
+    fn subscribe(&self, receive: Box<dyn Fn(Change<K>)+Send+Sync>) {
+        let (sender, receiver) = channel();
+        self.subscribers.lock().push((self.id, sender));
+        std::thread::spawn(move || receiver.into_iter().for_each(receive));
+    }
+}
+
+/// An `InvalidationBus` over a Redis pub/sub channel, for caches in different processes or on different hosts.
+/// Changes are sent as MessagePack, together with an id of the sending bus so that it ignores its own. Publishing is
+/// done on a thread of its own, so a slow server does not hold up the cache.
+#[cfg(feature = "redis")]
+pub struct RedisBus<K> {address: String, channel: String, origin: u64, outbox: Mutex<Sender<Change<K>>>}
+#[cfg(feature = "redis")]
+impl<K: serde::Serialize+Send+'static> RedisBus<K> {
+    /// Connects to the Redis server at `address` (`host:port`) to publish on `channel`. Subscribing opens a second
+    /// connection, as Redis allows nothing else on a subscribed one.
+    pub fn connect(address: &str, channel: &str) -> std::io::Result<Self> {
+        use std::hash::{BuildHasher, RandomState};
+        let mut stream = std::net::TcpStream::connect(address)?;
+        let origin = RandomState::new().hash_one(std::process::id());
+        let (outbox, changes) = std::sync::mpsc::channel::<Change<K>>();
+        let publish_channel = channel.to_string();
+        std::thread::spawn(move || {
+            let mut reader = std::io::BufReader::new(stream.try_clone()?);
+            for change in changes {
+                let payload = rmp_serde::to_vec(&(origin, change)).map_err(std::io::Error::other)?;
+                resp::write_command(&mut stream, &[b"PUBLISH", publish_channel.as_bytes(), &payload])?;
+                resp::read(&mut reader)?;
+            }
+            Ok::<(), std::io::Error>(())
+        });
+        Ok(Self {address: address.to_string(), channel: channel.to_string(), origin, outbox: Mutex::new(outbox)})
+    }
+}
+#[cfg(feature = "redis")]
+impl<K> InvalidationBus<K> for RedisBus<K> where K: Clone+serde::Serialize+for <'a> serde::Deserialize<'a>+Send+'static {
+    fn publish(&self, change: &Change<K>) {
+        let _ = self.outbox.lock().send(change.clone());
+    }
+    /// Changes stop arriving if the connection is lost.
+    fn subscribe(&self, receive: Box<dyn Fn(Change<K>)+Send+Sync>) {
+        let (address, channel, origin) = (self.address.clone(), self.channel.clone(), self.origin);
+        std::thread::spawn(move || -> std::io::Result<()> {
+            let mut stream = std::net::TcpStream::connect(address)?;
+            resp::write_command(&mut stream, &[b"SUBSCRIBE", channel.as_bytes()])?;
+            let mut reader = std::io::BufReader::new(stream);
+            loop {
+                // Messages are `["message", channel, payload]`; anything else confirms the subscription.
+                let resp::Value::Array(parts) = resp::read(&mut reader)? else {continue};
+                if let [resp::Value::Bulk(kind), _, resp::Value::Bulk(payload)] = &parts[..] && kind == b"message"
+                    && let Ok((sender, change)) = rmp_serde::from_slice::<(u64, Change<K>)>(payload) && sender != origin {
+                    receive(change);
+                }
+            }
+        });
+    }
+}
+
+/// Just enough of the Redis protocol for `RedisBus`.
+#[cfg(feature = "redis")]
+mod resp {
+    use std::io::{self, BufRead, Write};
+
+    pub enum Value {Simple, Integer, Bulk(Vec<u8>), Nil, Array(Vec<Value>)}
+
+    pub fn write_command(out: &mut impl Write, args: &[&[u8]]) -> io::Result<()> {
+        let mut command = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            command.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            command.extend_from_slice(arg);
+            command.extend_from_slice(b"\r\n");
+        }
+        out.write_all(&command)
+    }
+    fn invalid(what: &str) -> io::Error { io::Error::new(io::ErrorKind::InvalidData, what) }
+    pub fn read(input: &mut impl BufRead) -> io::Result<Value> {
+        let mut line = Vec::new();
+        input.read_until(b'\n', &mut line)?;
+        let line = line.strip_suffix(b"\r\n").ok_or_else(|| invalid("truncated reply"))?;
+        let (&kind, rest) = line.split_first().ok_or_else(|| invalid("empty reply"))?;
+        let rest = String::from_utf8_lossy(rest);
+        let length = || rest.parse::<i64>().map_err(|_| invalid("bad length"));
+        match kind {
+            b'+' => Ok(Value::Simple),
+            b':' => Ok(Value::Integer),
+            b'-' => Err(io::Error::other(format!("redis: {rest}"))),
+            b'$' if length()? < 0 => Ok(Value::Nil),
+            b'$' => {
+                let mut bytes = vec![0; length()? as usize+2];
+                input.read_exact(&mut bytes)?;
+                bytes.truncate(bytes.len()-2);
+                Ok(Value::Bulk(bytes))
+            },
+            b'*' if length()? < 0 => Ok(Value::Nil),
+            b'*' => (0..length()?).map(|_| read(input)).collect::<io::Result<_>>().map(Value::Array),
+            _ => Err(invalid("unknown reply type")),
+        }
+    }
+}
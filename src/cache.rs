@@ -1,179 +1,2001 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{cell::RefCell, collections::{BTreeMap, HashMap, HashSet, VecDeque}, sync::{Arc, Weak}};
 use lru::LruCache;
-use parking_lot::{ArcRwLockReadGuard, ArcRwLockWriteGuard, Mutex, RawRwLock, RwLock};
-use std::ops::{Deref, DerefMut};
+use parking_lot::{Condvar, Mutex};
+use parking_lot::lock_api::{ArcRwLockReadGuard, ArcRwLockWriteGuard, RwLock};
+use crate::lock::RawRwLock;
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut, RangeBounds};
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use crate::adaptive::AdaptiveCapacity;
+use crate::bloom::BloomFilter;
+use crate::bus::{Change, InvalidationBus};
+use crate::clock::{Clock, SystemClock};
+use crate::sharded::ListKeys;
+use crate::trace::{Trace, TraceEvent};
 
-struct RefReturn<K, V, CC> where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V> {
+struct RefReturn<K, V, CC, L> where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V>, L: RawRwLock {
     k: K,
-    cache: Arc<Mutex<CacheMutBase<K, V, CC>>>
+    cache: Arc<Mutex<CacheMutBase<K, V, CC, L>>>,
+    since: Instant,
+    /// Whether this returns a `CMRefMut`, whose value may be left half-modified by a panic.
+    write: bool,
+    /// Whether the `CMRefMut` changed its value, by `DerefMut` or as told by `CMRefMut::modify_with`.
+    modified: bool,
 }
-impl<K, V, CC> Drop for RefReturn<K, V, CC> where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V> {
+impl<K, V, CC, L> Drop for RefReturn<K, V, CC, L> where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V>, L: RawRwLock {
     fn drop(&mut self) {
         let mut cache = self.cache.lock();
-        if !cache.active.get(&self.k).unwrap().1.is_locked() {
-            let _ = cache.deactivate(&self.k);
+        let held = cache.clock.now().saturating_duration_since(self.since);
+        cache.record_hold(held);
+        if self.write && std::thread::panicking() {
+            cache.poison(self.k);
+        } else {
+            let modified = self.write && self.modified;
+            if modified {
+                cache.mark_modified(self.k);
+            } else if self.write {
+                cache.keep_clean(&self.k);
+            }
+            if cache.active.get(&self.k).is_some_and(|(_, v)| !v.is_locked()) {
+                let _ = cache.deactivate(&self.k);
+            }
+            if modified {
+                let _ = cache.invalidate_dependents(&self.k);
+            }
+        }
+        if cache.closing {
+            cache.drained.notify_all();
         }
     }
 }
 
-
-pub struct CMRef<K, V, CC> where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V> {
-    item: ArcRwLockReadGuard<RawRwLock, V>,
-    _drop: RefReturn<K, V, CC>,
+/// Guards can be sent to other threads (and so released there) when `K: Send`, `V: Send+Sync` and `CC: Send`; the
+/// `send_guard` feature of `parking_lot` allows their locks to be released on another thread than the one that took
+/// them.
+pub struct CMRef<K, V, CC, L = parking_lot::RawRwLock> where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V>, L: RawRwLock {
+    item: ArcRwLockReadGuard<L, V>,
+    stale: bool,
+    _drop: RefReturn<K, V, CC, L>,
 }
-impl<'a, K, V, CC> Deref for CMRef<K, V, CC> where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V> {
+impl<K, V, CC, L> CMRef<K, V, CC, L> where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V>, L: RawRwLock {
+    /// Whether the value had outlived its TTL (see `CacheMut::with_ttl`) when it was read.
+    pub fn is_stale(&self) -> bool { self.stale }
+}
+impl<K, V, CC, L> Deref for CMRef<K, V, CC, L> where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V>, L: RawRwLock {
     type Target = V;
     fn deref(&self) -> &Self::Target { self.item.deref() }
 }
-pub struct CMRefMut<K, V, CC> where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V> {
-    item: ArcRwLockWriteGuard<RawRwLock, V>,
-    _drop: RefReturn<K, V, CC>,
+/// Shows the key and not the value, which may be large or not `Debug`.
+impl<K, V, CC, L> std::fmt::Debug for CMRef<K, V, CC, L> where
+K: Copy+Eq+std::hash::Hash+std::fmt::Debug, CC: CacheMutCompatible<K, V>, L: RawRwLock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CMRef").field("key", &self._drop.k).field("stale", &self.stale).finish_non_exhaustive()
+    }
+}
+/// `Send` under the same bounds as `CMRef`.
+pub struct CMRefMut<K, V, CC, L = parking_lot::RawRwLock> where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V>, L: RawRwLock {
+    item: ArcRwLockWriteGuard<L, V>,
+    _drop: RefReturn<K, V, CC, L>,
 }
-impl<'a, K, V, CC> Deref for CMRefMut<K, V, CC> where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V> {
+impl<K, V, CC, L> Deref for CMRefMut<K, V, CC, L> where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V>, L: RawRwLock {
     type Target = V;
     fn deref(&self) -> &Self::Target { self.item.deref() }
 }
-impl<'a, K, V, CC> DerefMut for CMRefMut<K, V, CC> where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V> {
-    fn deref_mut(&mut self) -> &mut Self::Target { self.item.deref_mut() }
+/// Counts as changing the value, which is then written back; see `CMRefMut::modify_with`.
+impl<K, V, CC, L> DerefMut for CMRefMut<K, V, CC, L> where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V>, L: RawRwLock {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self._drop.modified = true;
+        self.item.deref_mut()
+    }
+}
+impl<K, V, CC, L> CMRefMut<K, V, CC, L> where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V>, L: RawRwLock {
+    /// Runs `f` on the value, which returns whether it changed it. Unless it did (or the guard is dereferenced
+    /// mutably), releasing the guard leaves an unmodified entry unmodified: it is not written back to the backend,
+    /// nor are its dependents invalidated. Returns what `f` returned.
+    pub fn modify_with(&mut self, f: impl FnOnce(&mut V) -> bool) -> bool {
+        let changed = f(self.item.deref_mut());
+        self._drop.modified |= changed;
+        changed
+    }
+}
+impl<K, V, CC, L> std::fmt::Debug for CMRefMut<K, V, CC, L> where
+K: Copy+Eq+std::hash::Hash+std::fmt::Debug, CC: CacheMutCompatible<K, V>, L: RawRwLock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CMRefMut").field("key", &self._drop.k).finish_non_exhaustive()
+    }
+}
+
+/// How a backend hands values out through `CacheCompatible::get`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retrieval {
+    /// `get` returns a copy; the backend keeps its own. Unmodified values are simply dropped by the cache.
+    Copy,
+    /// `get` moves the value out of the backend. Every value is handed back through `replace` (or `insert`, if it
+    /// was modified) when the cache lets go of it, and errors from doing so are returned to the caller.
+    Take,
+}
+
+/// Why an entry left the cache, as reported to `CacheMut::subscribe`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EvictReason {
+    /// Pushed out of the in-memory layer to make room; the backend still has it.
+    Capacity,
+    /// Written back by `commit` or `flush`; the backend still has it.
+    Flushed,
+    /// Removed by `remove`, `remove_many` or `remove_range`.
+    Removed,
+    /// Removed by `invalidate_tag`, or as derived from a changed entry (see `CacheMut::add_dependency`).
+    Invalidated,
+    /// Dropped because a thread panicked while holding it mutably; see `PoisonPolicy`.
+    Poisoned,
+    /// Unused for longer than its time to idle (see `CacheMut::with_tti`); the backend still has it.
+    Idle,
+    /// Rejected by the validator of `CacheMut::with_validator` when read; the backend's value is loaded instead.
+    Rejected,
+}
+
+/// What happens to an entry whose `CMRefMut` was dropped by a panic, when its value may be half-modified. Either way
+/// the value in memory is discarded rather than written back.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "folder", derive(serde::Serialize, serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum PoisonPolicy {
+    /// The entry stays poisoned until it is inserted, removed or `CacheMut::clear_poison`ed: `try_get` and
+    /// `try_get_mut` fail with `LockError::Poisoned`, and `get` and `get_mut` panic.
+    #[default]
+    Fail,
+    /// The value is read from the backend again on the next access. `Retrieval::Take` backends no longer have it, so
+    /// for them the entry is lost.
+    Reload,
+}
+
+/// How a read through `CacheMut::get_with_hint` of a value that is not in memory yet affects what stays in memory.
+/// Values already in memory are read as by `get`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Hint {
+    /// As `get`: the value is kept in memory as the most recently used.
+    #[default]
+    Normal,
+    /// The value is kept in memory as the least recently used, so the next value loaded pushes it out rather than
+    /// a hot one. Meant for scans.
+    NoPromote,
+    /// The value is not kept in memory once released.
+    NoCache,
+}
+
+/// How readily an entry is evicted, set by `CacheMut::insert_with_priority` or `get_with_priority`. To make room,
+/// the cache evicts the lowest priority entry among its least recently used few (see
+/// `CacheMut::with_priority_window`), and the least recently used of those.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// How many of the least recently used entries `Priority` chooses among by default.
+const PRIORITY_WINDOW: usize = 16;
+
+/// Why `CacheMut::get_mut_many`, `try_get` or `try_get_mut` failed. Each variant but `Closing` names the key at fault.
+#[derive(Debug, PartialEq)]
+pub enum LockError<K, E> {
+    /// The key is already borrowed, or was asked for twice, so waiting for it could deadlock.
+    WouldDeadlock(K),
+    /// The entry was poisoned by a panic; see `PoisonPolicy`.
+    Poisoned(K),
+    /// The cache is being shut down by `CacheMut::shutdown`.
+    Closing,
+    /// The backend failed to load this key.
+    Backend(K, E),
+}
+
+/// Why `CacheMut::shutdown` did not return the backend. The cache is open again in the first two cases.
+pub enum ShutdownError<K, V, CC, L = parking_lot::RawRwLock> where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V>, L: RawRwLock {
+    /// These keys were still borrowed when the timeout ran out.
+    Held(CacheMut<K, V, CC, L>, Vec<K>),
+    /// The cache was committed, but other clones of the `CacheMut` are still alive.
+    Shared(CacheMut<K, V, CC, L>),
+    Backend(CC::Error),
 }
+impl<K, V, CC, L> std::fmt::Debug for ShutdownError<K, V, CC, L> where
+K: Copy+Eq+std::hash::Hash+std::fmt::Debug, CC: CacheMutCompatible<K, V>, L: RawRwLock, CC::Error: std::fmt::Debug {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Held(_, keys) => f.debug_tuple("Held").field(keys).finish(),
+            Self::Shared(cache) => f.debug_tuple("Shared").field(&cache.handle_count()).finish(),
+            Self::Backend(e) => f.debug_tuple("Backend").field(e).finish(),
+        }
+    }
+}
+type WriteGuard<L, V> = ArcRwLockWriteGuard<L, V>;
+type ManyResult<K, T, E> = Result<Vec<T>, LockError<K, E>>;
+type LockResult<K, T, E> = Result<T, LockError<K, E>>;
+type MaybeRef<K, V, CC, L> = Option<CMRef<K, V, CC, L>>;
+/// Tells whether a value held in memory is still current; see `CacheMut::with_validator`.
+type Validator<K, V> = Arc<dyn Fn(&K, &V) -> bool+Send+Sync>;
+/// An entry in memory: whether it was modified, and its value.
+type Item<L, V> = (bool, Arc<RwLock<L, V>>);
 
 pub trait CacheCompatible<K, V> {
     type Error;
+    /// Deliberately has no default, so that a backend has to state whether `get` removes the value.
+    const RETRIEVAL: Retrieval;
 
     fn contains(&self, k: K) -> bool;
     fn get(&mut self, k: K) -> Result<V, Self::Error>;
-    /// Called when get from cache is finished. This is only required if the backend removes the v to pass to the cache.
-    fn replace(&mut self, k: K, v: V);
+    /// Hands back an unmodified value obtained from `get`. Only called for `Retrieval::Take` backends.
+    fn replace(&mut self, k: K, v: V) -> Result<(), Self::Error>;
+    /// Like `get`, and also returns a token naming the version of the value (an HTTP ETag, say) for
+    /// `get_if_changed`. By default there is no token, and `CacheMut` never calls `get_if_changed`.
+    fn get_versioned(&mut self, k: K) -> Result<(V, Option<Token>), Self::Error> { self.get(k).map(|v| (v, None)) }
+    /// The value of `k` and its new token, unless its version is still `token`, so that backends across a network
+    /// need not send a value the cache already has. By default the value is always sent. Only called for
+    /// `Retrieval::Copy` backends.
+    fn get_if_changed(&mut self, k: K, _token: &Token) -> Result<IfChanged<V>, Self::Error> {
+        self.get_versioned(k).map(|(v, token)| IfChanged::Changed(v, token))
+    }
+    /// Checks that the backend can be reached (that its folder is still there, say), without reading any entry. A
+    /// `breaker::CircuitBreaker` asks this before trusting a failed backend again. By default always healthy.
+    fn health(&mut self) -> Result<(), Self::Error> { Ok(()) }
 }
 
+/// A version of a stored value, as given by `CacheCompatible::get_versioned`.
+pub type Token = Vec<u8>;
+
+/// The answer of `CacheCompatible::get_if_changed`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IfChanged<V> {
+    Changed(V, Option<Token>),
+    NotModified,
+}
+
+/// Values that can be extended in place, like `String` and `Vec<T>`.
+pub trait Appendable: Default {
+    type Suffix: ?Sized;
+    fn append(&mut self, suffix: &Self::Suffix);
+}
+impl Appendable for String {
+    type Suffix = str;
+    fn append(&mut self, suffix: &str) { self.push_str(suffix) }
+}
+impl<T: Clone> Appendable for Vec<T> {
+    type Suffix = [T];
+    fn append(&mut self, suffix: &[T]) { self.extend_from_slice(suffix) }
+}
+
+/// How a backend that stores values serialized takes them already serialized: `serialize` turns a value into the
+/// bytes `insert` stores. `serialize` may be called from several threads at once by `CacheMut::commit_parallel`.
+pub struct Serialized<K, V, CC: CacheCompatible<K, V>+?Sized> {
+    pub serialize: fn(&V) -> Result<Vec<u8>, CC::Error>,
+    pub insert: InsertSerialized<K, CC, CC::Error>,
+}
+type InsertSerialized<K, CC, E> = fn(&mut CC, K, Vec<u8>) -> Result<(), E>;
+
 pub trait CacheMutCompatible<K, V>: CacheCompatible<K, V> {
     fn insert(&mut self, k: K, v: V) -> Result<(), Self::Error>;
+    /// Stores a value by reference, and returns whether it was stored. The cache uses this for values it cannot hand
+    /// over because something else still shares it, and tries it first for modified ones, so that it still has the
+    /// value if writing fails. By default it is not stored, and the cache keeps a shared value in memory to write
+    /// back later; backends that serialize values anyway should override this.
+    fn insert_shared(&mut self, _k: K, _v: &V) -> Result<bool, Self::Error> { Ok(false) }
+    /// How to hand the backend serialized values, or `None` (the default) if it does not store them serialized.
+    const SERIALIZED: Option<Serialized<K, V, Self>> = None;
+    /// Appends to the stored value of `k`. By default the value is taken out with `get`, extended and inserted
+    /// again; backends that can extend a stored value directly should override this.
+    fn append(&mut self, k: K, suffix: &V::Suffix) -> Result<(), Self::Error> where V: Appendable, K: Clone {
+        let mut v = self.get(k.clone())?;
+        v.append(suffix);
+        self.insert(k, v)
+    }
     fn remove(&mut self, k: K) -> Result<(), Self::Error>;
+    /// Removes each of `keys`. By default this is one `remove` per key.
+    fn remove_many(&mut self, keys: Vec<K>) -> Result<(), Self::Error> {
+        keys.into_iter().try_for_each(|k| self.remove(k))
+    }
     /// Should ensure the cache resolves to a stable state. No active references will remain.
     /// For backends that do not have any notion of backing up, this would not be necessary.
     fn commit(&mut self) -> Result<(), Self::Error>;
+    /// What the backend takes on disk and how many entries it holds, for `CacheMut::usage`. `None` (the default) for
+    /// backends that do not know.
+    fn resource_usage(&self) -> Option<Usage> { None }
+}
+
+/// Resources a backend holds, from `CacheMutCompatible::resource_usage`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// What the backend's files take on disk.
+    pub disk_bytes: u64,
+    /// The part of `disk_bytes` given to live entries; the rest is reserved or dead space.
+    pub live_bytes: u64,
+    pub entries: u64,
 }
 
-pub struct CacheMutBase<K,V,CC> where
-CC: CacheMutCompatible<K, V>, K: Copy+Eq+std::hash::Hash {
-    compatible: CC, lru: LruCache<K, (bool, Arc<RwLock<V>>)>, active: HashMap<K, (bool, Arc<RwLock<V>>)>
-} impl<K,V,CC> CacheMutBase<K,V,CC> where 
-CC: CacheMutCompatible<K, V>, K: Copy+Eq+std::hash::Hash {
+/// Backends that keep their keys ordered, so that a range of them (such as every key of one tenant, e.g.
+/// `(tenant, 0)..(tenant+1, 0)`) can be removed in one call.
+pub trait CacheRangeCompatible<K, V>: CacheMutCompatible<K, V> {
+    fn remove_range(&mut self, range: impl RangeBounds<K>) -> Result<(), Self::Error>;
+}
+
+/// How many of the most recent guard hold times `CacheMut::stats` summarizes.
+const HOLD_SAMPLES: usize = 1024;
+
+/// A snapshot of a `CacheMut`'s in-memory layer, from `CacheMut::stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheMutStats {
+    /// Entries currently borrowed through a `CMRef` or `CMRefMut`.
+    pub active: usize,
+    /// Entries held in memory and not borrowed.
+    pub cached: usize,
+    /// Entries that left memory while their value was still shared, and wait to be written back.
+    pub unwritten: usize,
+    /// Entries of the three above that were modified (or borrowed mutably) and not written back yet.
+    pub dirty: usize,
+    /// Guards released since the cache was created.
+    pub released: u64,
+    /// How long guards were held, over the most recent 1024 released.
+    pub hold_p50: Duration, pub hold_p99: Duration, pub hold_max: Duration,
+    /// Lookups by `get` and `get_mut` served from memory, and those that went to the backend.
+    pub hits: u64, pub misses: u64,
+    /// The weight of the values in memory, with `CacheMut::with_weigher`.
+    pub weights: Option<WeightStats>,
+}
+
+/// How much the values a `CacheMut` holds in memory weigh, by `CacheMut::with_weigher`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WeightStats {
+    /// Of every value in memory, except those borrowed mutably at the time.
+    pub total: u64, pub heaviest: u64,
+    /// The same values by weight, in classes of powers of two, lightest first.
+    pub classes: Vec<WeightClass>,
+    /// Of the values pushed out of memory to make room since the cache was created; the difference between two
+    /// snapshots is what was evicted in between.
+    pub evicted: u64,
+}
+/// The values weighing more than half of `up_to` and at most `up_to`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WeightClass {
+    pub up_to: u64, pub values: usize, pub weight: u64,
+}
+
+/// Measures a value, in bytes or any other unit; see `CacheMut::with_weigher`.
+pub(crate) type Weigher<V> = Arc<dyn Fn(&V) -> usize+Send+Sync>;
+
+pub struct CacheMutBase<K, V, CC, L = parking_lot::RawRwLock> where
+CC: CacheMutCompatible<K, V>, L: RawRwLock, K: Copy+Eq+std::hash::Hash {
+    /// Only taken out by `CacheMut::shutdown`, which sets `closed` so that it is not used or dropped again.
+    compatible: ManuallyDrop<CC>, lru: LruCache<K, (bool, Arc<RwLock<L, V>>)>, active: HashMap<K, (bool, Arc<RwLock<L, V>>)>,
+    /// Keys by tag, and the tags of each key. Tags are kept in memory only.
+    tags: HashMap<String, HashSet<K>>, tags_of: HashMap<K, Vec<String>>,
+    /// Active entries invalidated while borrowed; they are dropped instead of written back once released.
+    stale: HashSet<K>,
+    events: Option<SyncSender<(K, EvictReason)>>,
+    /// When each key's value was last inserted or, failing that, first loaded from the backend.
+    ttl: Option<Duration>, fetched: HashMap<K, Instant>, clock: Arc<dyn Clock>,
+    /// How long released entries may go unused before `expire_idle` writes them back and drops them from memory,
+    /// overall and as set per key by `insert_with_tti`, and when each key was last used.
+    tti: Option<Duration>, idle_limits: HashMap<K, Duration>, used: HashMap<K, Instant>,
+    /// The thread of a running `RefreshAhead`, woken when a stale value is read with `get_stale_ok`, and again when
+    /// the value is released, since borrowed values are not refreshed. `wanted` holds the keys still to be released.
+    refresher: Option<std::thread::Thread>, wanted: HashSet<K>,
+    /// How long the last `HOLD_SAMPLES` guards were held, and how many have been released in all.
+    holds: VecDeque<Duration>, released: u64,
+    poison_policy: PoisonPolicy, poisoned: HashSet<K>,
+    /// Entries that left memory while their value was still shared, so could not be handed to the backend. They are
+    /// written back by the next `commit` or `flush` once the value is no longer shared, and taken back into memory
+    /// when accessed before that.
+    unwritten: HashMap<K, (bool, Arc<RwLock<L, V>>)>,
+    /// Hints of borrowed entries that were loaded by `get_with_hint`, applied when they are released.
+    hints: HashMap<K, Hint>,
+    /// Where changes written to the backend are announced (see `CacheMut::with_bus`), and the borrowed entries that
+    /// another cache changed meanwhile, which are dropped instead of written back once released.
+    bus: Option<Arc<dyn InvalidationBus<K>>>, outdated: HashSet<K>,
+    /// Set by `CacheMut::shutdown` while it waits on `drained` for the remaining guards.
+    closing: bool, closed: bool, drained: Arc<Condvar>,
+    /// Values serializing to more than this many bytes are written straight to the backend by `insert`.
+    max_value: Option<usize>,
+    /// Set for a capacity of 0, when entries are written back as soon as they are released instead of going into
+    /// `lru`, whose capacity can not be 0.
+    passthrough: bool,
+    /// The keys the backend may have, so that `contains` need not ask it about the others; see
+    /// `CacheMut::with_bloom_filter`.
+    bloom: Option<BloomFilter>,
+    /// Lookups served from memory and from the backend, and both counts as of when the current window of the adaptive
+    /// capacity began (see `CacheMut::with_adaptive_capacity`). `cold` holds the least recently used entries at the
+    /// start of the window, which a shrink would evict, and `cold_hits` counts the hits on them since.
+    hits: u64, misses: u64, adaptive: Option<AdaptiveCapacity<V>>, window_from: (u64, u64), cold: HashSet<K>, cold_hits: u64,
+    /// The most `set_capacity` and the adaptive capacity may set; see `CacheMut::limit_capacity`.
+    ceiling: Option<usize>,
+    /// Weighs values for `stats` (see `CacheMut::with_weigher`), and the weight of those evicted so far.
+    weigher: Option<Weigher<V>>, evicted_weight: u64,
+    /// How long after a key is modified its write-back waits (see `CacheMut::with_write_debounce`), and when keys
+    /// were last modified, kept for about that long.
+    debounce: Option<Duration>, dirtied: HashMap<K, Instant>,
+    /// The recent lookups recorded by `CacheMut::with_trace`, and when recording began.
+    trace: Option<(Trace, Instant)>,
+    /// Priorities other than `Priority::Normal`, and how many of the least recently used entries they choose among.
+    priorities: HashMap<K, Priority>, priority_window: usize,
+    /// Checked against released entries when they are read; see `CacheMut::with_validator`.
+    validator: Option<Validator<K, V>>,
+    /// The versions of the values loaded from the backend, for those backends that give them (see
+    /// `CacheCompatible::get_versioned`). Only used for entries in memory that were not modified.
+    tokens: HashMap<K, Token>,
+    /// Keys borrowed by a `CMRefMut` that were unmodified before, and stay so if it does not change them.
+    borrowed_clean: HashSet<K>,
+    /// The entries derived from each entry, and those each was derived from (see `CacheMut::add_dependency`). Kept
+    /// in memory only.
+    dependents: HashMap<K, HashSet<K>>, sources: HashMap<K, HashSet<K>>,
+    /// Keys by group, and the group of each key, for entries that leave memory together (see `CacheMut::set_group`).
+    /// Groups are kept in memory only.
+    groups: HashMap<u64, HashSet<K>>, group_of: HashMap<K, u64>,
+    /// How many `CacheMut::transaction`s are open, and while any is, when each key last changed, counting changes
+    /// as they come, or when every key did (see `version`).
+    transactions: usize, changes: u64, changed_at: HashMap<K, u64>, all_changed_at: u64,
+} impl<K, V, CC, L> CacheMutBase<K, V, CC, L> where 
+CC: CacheMutCompatible<K, V>, L: RawRwLock, K: Copy+Eq+std::hash::Hash {
     fn new(compatible: CC, capacity: usize) -> Self {
-        Self { compatible, lru: LruCache::new(std::num::NonZero::new(capacity).unwrap()), active: HashMap::new() }
+        Self {
+            compatible: ManuallyDrop::new(compatible), lru: LruCache::new(std::num::NonZero::new(capacity.max(1)).unwrap()), active: HashMap::new(),
+            tags: HashMap::new(), tags_of: HashMap::new(), stale: HashSet::new(), events: None,
+            ttl: None, fetched: HashMap::new(), clock: Arc::new(SystemClock),
+            tti: None, idle_limits: HashMap::new(), used: HashMap::new(), refresher: None, wanted: HashSet::new(),
+            holds: VecDeque::new(), released: 0, poison_policy: PoisonPolicy::Fail, poisoned: HashSet::new(), unwritten: HashMap::new(), hints: HashMap::new(),
+            bus: None, outdated: HashSet::new(), closing: false, closed: false, drained: Arc::new(Condvar::new()),
+            max_value: None, passthrough: capacity == 0, bloom: None, hits: 0, misses: 0, adaptive: None, window_from: (0, 0), cold: HashSet::new(), cold_hits: 0, ceiling: None, weigher: None, evicted_weight: 0, debounce: None, dirtied: HashMap::new(), trace: None,
+            priorities: HashMap::new(), priority_window: PRIORITY_WINDOW, groups: HashMap::new(), group_of: HashMap::new(),
+            dependents: HashMap::new(), sources: HashMap::new(), validator: None, tokens: HashMap::new(), borrowed_clean: HashSet::new(),
+            transactions: 0, changes: 0, changed_at: HashMap::new(), all_changed_at: 0,
+        }
+    }
+    fn record_hold(&mut self, held: Duration) {
+        if self.holds.len() == HOLD_SAMPLES {
+            self.holds.pop_front();
+        }
+        self.holds.push_back(held);
+        self.released += 1;
+    }
+    fn stats(&self) -> CacheMutStats {
+        let mut holds: Vec<Duration> = self.holds.iter().copied().collect();
+        holds.sort_unstable();
+        // Nearest rank.
+        let percentile = |p: usize| holds.get((holds.len()*p).div_ceil(100).saturating_sub(1)).copied().unwrap_or_default();
+        CacheMutStats {
+            active: self.active.len(), cached: self.lru.len(), unwritten: self.unwritten.len(),
+            dirty: self.active.values().chain(self.unwritten.values()).chain(self.lru.iter().map(|(_, item)| item))
+                .filter(|(changed, _)| *changed).count(),
+            released: self.released,
+            hold_p50: percentile(50), hold_p99: percentile(99), hold_max: holds.last().copied().unwrap_or_default(),
+            hits: self.hits, misses: self.misses, weights: self.weigher.as_ref().map(|weigh| self.weights(weigh)),
+        }
+    }
+    /// Weighs every value in memory that is not borrowed mutably.
+    fn weights(&self, weigh: &Weigher<V>) -> WeightStats {
+        let mut stats = WeightStats {evicted: self.evicted_weight, ..WeightStats::default()};
+        let mut classes: BTreeMap<u64, WeightClass> = BTreeMap::new();
+        let values = self.active.values().chain(self.unwritten.values()).chain(self.lru.iter().map(|(_, item)| item));
+        for weight in values.filter_map(|(_, v)| v.try_read().map(|v| weigh(&v) as u64)) {
+            stats.total += weight;
+            stats.heaviest = stats.heaviest.max(weight);
+            let up_to = weight.next_power_of_two();
+            let class = classes.entry(up_to).or_insert(WeightClass {up_to, ..WeightClass::default()});
+            class.values += 1;
+            class.weight += weight;
+        }
+        stats.classes = classes.into_values().collect();
+        stats
+    }
+    /// Keeps up to `capacity` released entries, writing back the least recently used ones over it.
+    fn set_capacity(&mut self, capacity: usize) -> Result<(), CC::Error> {
+        let capacity = capacity.min(self.ceiling.unwrap_or(usize::MAX));
+        while self.lru.len() > capacity {
+            let Some((k, (changed, v))) = self.lru.pop_lru() else {break};
+            self.evict(k, changed, v)?;
+        }
+        self.lru.resize(std::num::NonZero::new(capacity.max(1)).unwrap());
+        self.passthrough = capacity == 0;
+        Ok(())
+    }
+    /// Notes that a `CMRefMut` changed `k`.
+    fn mark_modified(&mut self, k: K) {
+        self.changed(&k);
+        self.borrowed_clean.remove(&k);
+        self.tokens.remove(&k);
+        self.mark_dirtied(k);
+    }
+    /// Notes that the value of `k` changed, for the open transactions to check the values they read against.
+    fn changed(&mut self, k: &K) {
+        if self.transactions > 0 {
+            self.changes += 1;
+            self.changed_at.insert(*k, self.changes);
+        }
+    }
+    /// Like `changed`, for every key at once.
+    fn changed_all(&mut self) {
+        if self.transactions > 0 {
+            self.changes += 1;
+            self.changed_at.clear();
+            self.all_changed_at = self.changes;
+        }
+    }
+    /// When `k` last changed since the oldest open transaction began, or 0.
+    fn version(&self, k: &K) -> u64 {
+        self.changed_at.get(k).copied().unwrap_or(0).max(self.all_changed_at)
+    }
+    fn end_transaction(&mut self) {
+        self.transactions -= 1;
+        if self.transactions == 0 {
+            self.changed_at.clear();
+            self.all_changed_at = 0;
+        }
+    }
+    /// A copy of the value of `k`, or `None` if there is none, leaving the entry released.
+    fn snapshot(&mut self, k: &K) -> Result<Option<V>, CC::Error> where V: Clone {
+        if !self.contains(k) {
+            return Ok(None);
+        }
+        let v = self.get(k)?.clone();
+        self.deactivate(k)?;
+        Ok(Some(v))
+    }
+    /// Marks `k` unmodified again after a `CMRefMut` that did not change it, if it was before.
+    fn keep_clean(&mut self, k: &K) {
+        if self.borrowed_clean.remove(k) && let Some((changed, _)) = self.active.get_mut(k) {
+            *changed = false;
+        }
+    }
+    /// Gets `k` from the backend, keeping its version if the backend gives one.
+    fn load(&mut self, k: &K) -> Result<V, CC::Error> {
+        let (v, token) = self.compatible.get_versioned(*k)?;
+        match token {
+            Some(token) => self.tokens.insert(*k, token),
+            None => self.tokens.remove(k),
+        };
+        Ok(v)
+    }
+    /// Asks the backend whether the released, unmodified copy of `k` is still current, by its version, and replaces
+    /// it if not; either way its TTL starts again. Returns whether it was replaced, or `None` if there was no such
+    /// copy with a version to ask about.
+    fn refetch(&mut self, k: &K) -> Result<Option<bool>, CC::Error> {
+        if CC::RETRIEVAL != Retrieval::Copy || self.lru.peek(k).is_none_or(|(changed, _)| *changed) {
+            return Ok(None);
+        }
+        let Some(token) = self.tokens.get(k) else {return Ok(None)};
+        let changed = match self.compatible.get_if_changed(*k, token)? {
+            IfChanged::NotModified => false,
+            IfChanged::Changed(v, token) => {
+                self.changed(k);
+                if let Some((_, value)) = self.lru.get_mut(k) {
+                    *value = Arc::new(RwLock::new(v));
+                }
+                match token {
+                    Some(token) => self.tokens.insert(*k, token),
+                    None => self.tokens.remove(k),
+                };
+                true
+            },
+        };
+        if self.ttl.is_some() {
+            self.fetched.insert(*k, self.clock.now());
+        }
+        Ok(Some(changed))
+    }
+    /// Drops the released copy of `k` if the validator rejects it, so that it is loaded from the backend instead.
+    /// Only unmodified copies from `Retrieval::Copy` backends are checked: the backend has nothing newer than a
+    /// modified copy, and a `Take` backend has no copy at all.
+    fn revalidate(&mut self, k: &K) {
+        let Some(valid) = &self.validator else {return};
+        if CC::RETRIEVAL == Retrieval::Copy
+            && self.lru.peek(k).is_some_and(|(changed, v)| !changed && v.try_read().is_some_and(|v| !valid(k, &v))) {
+            self.lru.pop(k);
+            self.fetched.remove(k);
+            self.changed(k);
+            self.notify(*k, EvictReason::Rejected);
+        }
+    }
+    fn count_lookup(&mut self, k: &K) {
+        let hit = self.active.contains_key(k) || self.lru.contains(k);
+        if hit {
+            self.hits += 1;
+            self.cold_hits += self.cold.remove(k) as u64;
+        } else {
+            self.misses += 1;
+        }
+        if let Some((trace, since)) = &mut self.trace {
+            trace.push(TraceEvent {key: crate::trace::key_hash(k), hit, at: self.clock.now().saturating_duration_since(*since)});
+        }
+    }
+    /// Resizes the cache once a window of lookups is complete; see `CacheMut::with_adaptive_capacity`.
+    fn adapt(&mut self) -> Result<(), CC::Error> {
+        let Some(adaptive) = &self.adaptive else {return Ok(())};
+        let (hits, misses) = (self.hits-self.window_from.0, self.misses-self.window_from.1);
+        if hits+misses < adaptive.window() {
+            return Ok(());
+        }
+        let bytes = adaptive.weigher().map(|weigh| self.lru.iter().filter_map(|(_, (_, v))| v.try_read().map(|v| weigh(&v))).sum());
+        let capacity = adaptive.next(self.cap(), self.lru.len(), hits+misses, hits, self.cold_hits, bytes);
+        let step = adaptive.step(capacity);
+        self.set_capacity(capacity)?;
+        self.window_from = (self.hits, self.misses);
+        self.cold = self.lru.iter().rev().take(step).map(|(k, _)| *k).collect();
+        self.cold_hits = 0;
+        Ok(())
+    }
+    fn poison(&mut self, k: K) {
+        self.changed(&k);
+        self.active.remove(&k);
+        self.ungroup(&k);
+        self.priorities.remove(&k);
+        self.hints.remove(&k);
+        self.stale.remove(&k);
+        self.outdated.remove(&k);
+        self.wanted.remove(&k);
+        self.fetched.remove(&k);
+        self.used.remove(&k);
+        if self.poison_policy == PoisonPolicy::Fail {
+            self.poisoned.insert(k);
+        }
+        self.notify(k, EvictReason::Poisoned);
+    }
+    fn purge_inactive_locks(&mut self) -> Result<Vec<K>, CC::Error> {
+        let keys: Vec<K> = self.active.iter().filter(|(_, (_, v))| !v.is_locked()).map(|(k, _)| *k).collect();
+        for k in &keys {
+            self.deactivate(k)?;
+        }
+        Ok(keys)
+    }
+    fn force_deactivate(&mut self, k: &K) -> Result<bool, CC::Error> {
+        let Some((_, v)) = self.active.get(k) else {return Ok(false)};
+        if v.is_locked_exclusive() {
+            self.active.remove(k);
+            self.hints.remove(k);
+            self.stale.remove(k);
+            self.outdated.remove(k);
+            self.wanted.remove(k);
+        } else {
+            self.deactivate(k)?;
+        }
+        Ok(true)
+    }
+    fn publish(&self, change: Change<K>) {
+        if let Some(bus) = &self.bus {
+            bus.publish(&change);
+        }
+    }
+    /// Drops the copy of `k` held in memory, if any, after another cache changed it in the backend.
+    fn forget(&mut self, k: &K) {
+        self.changed(k);
+        self.may_have(k);
+        self.fetched.remove(k);
+        self.used.remove(k);
+        let held = if self.active.contains_key(k) {
+            self.outdated.insert(*k)
+        } else {
+            self.lru.pop(k).is_some() | self.unwritten.remove(k).is_some()
+        };
+        if held {
+            self.notify(*k, EvictReason::Invalidated);
+        }
+    }
+    /// Notes that the backend may now have `k`.
+    fn may_have(&mut self, k: &K) {
+        if let Some(bloom) = &mut self.bloom {
+            bloom.insert(k);
+        }
+    }
+    /// Like `lru.push`, but when `lru` is full, pushes out the lowest priority entry among the least recently used
+    /// `priority_window` rather than the least recently used.
+    fn push_lru(&mut self, k: K, item: Item<L, V>) -> Option<(K, Item<L, V>)> {
+        if self.priorities.is_empty() || self.lru.len() < self.lru.cap().get() || self.lru.contains(&k) {
+            return self.lru.push(k, item);
+        }
+        let priority = |k: &K| self.priorities.get(k).copied().unwrap_or_default();
+        // `min_by_key` keeps the first of equals, which is the least recently used.
+        let victim = self.lru.iter().rev().take(self.priority_window).map(|(k, _)| *k).min_by_key(priority);
+        let out = victim.and_then(|victim| self.lru.pop_entry(&victim));
+        self.lru.push(k, item);
+        out
+    }
+    /// Writes back an entry pushed out of `lru` to make room, and the released entries of its group with it.
+    fn evict(&mut self, k: K, changed: bool, v: Arc<RwLock<L, V>>) -> Result<(), CC::Error> {
+        self.evict_one(k, changed, v)?;
+        let Some(members) = self.group_of.get(&k).and_then(|group| self.groups.get(group)) else {return Ok(())};
+        let members: Vec<K> = members.iter().filter(|member| self.lru.contains(member)).copied().collect();
+        for member in members {
+            if let Some((changed, v)) = self.lru.pop(&member) {
+                self.evict_one(member, changed, v)?;
+            }
+        }
+        Ok(())
+    }
+    fn evict_one(&mut self, k: K, changed: bool, v: Arc<RwLock<L, V>>) -> Result<(), CC::Error> {
+        self.priorities.remove(&k);
+        self.tokens.remove(&k);
+        if let Some(weigh) = &self.weigher {
+            self.evicted_weight += v.try_read().map_or(0, |v| weigh(&v)) as u64;
+        }
+        self.write_back_later(k, changed, v)?;
+        self.notify(k, EvictReason::Capacity);
+        Ok(())
+    }
+    fn set_group(&mut self, k: K, group: u64) {
+        self.ungroup(&k);
+        self.groups.entry(group).or_default().insert(k);
+        self.group_of.insert(k, group);
+    }
+    fn ungroup(&mut self, k: &K) {
+        let Some(group) = self.group_of.remove(k) else {return};
+        if let Some(keys) = self.groups.get_mut(&group) {
+            keys.remove(k);
+            if keys.is_empty() {
+                self.groups.remove(&group);
+            }
+        }
+    }
+    /// Makes the released entries of `k`'s group as recently used as `k`, so that the group ages together.
+    fn promote_group(&mut self, k: &K) {
+        let Some(members) = self.group_of.get(k).and_then(|group| self.groups.get(group)) else {return};
+        for member in members {
+            if member != k {
+                self.lru.promote(member);
+            }
+        }
+    }
+    /// Writes back and drops from memory the released entries of `group`, returning how many there were.
+    fn evict_group(&mut self, group: u64) -> Result<usize, CC::Error> {
+        let members: Vec<K> = self.groups.get(&group).into_iter().flatten().filter(|k| self.lru.contains(k)).copied().collect();
+        for k in &members {
+            if let Some((changed, v)) = self.lru.pop(k) {
+                self.evict_one(*k, changed, v)?;
+            }
+        }
+        Ok(members.len())
+    }
+    fn set_priority(&mut self, k: K, priority: Priority) {
+        if priority == Priority::Normal {
+            self.priorities.remove(&k);
+        } else {
+            self.priorities.insert(k, priority);
+        }
+    }
+    fn mark_dirtied(&mut self, k: K) {
+        if self.debounce.is_some() {
+            self.dirtied.insert(k, self.clock.now());
+        }
+    }
+    /// Whether a modified `k` was modified too recently to be written back yet; see `CacheMut::with_write_debounce`.
+    fn debounced(&self, k: &K, changed: bool) -> bool {
+        changed && self.debounce.is_some_and(|window| {
+            self.dirtied.get(k).is_some_and(|at| self.clock.now().saturating_duration_since(*at) < window)
+        })
+    }
+    /// Like `write_back`, but holds a debounced entry in `unwritten` until `write_due`, `commit` or `flush` after its
+    /// window, so that only its latest value is written.
+    fn write_back_later(&mut self, k: K, changed: bool, v: Arc<RwLock<L, V>>) -> Result<(), CC::Error> {
+        if self.debounced(&k, changed) {
+            self.unwritten.insert(k, (changed, v));
+            return Ok(());
+        }
+        self.write_back(k, changed, v)
+    }
+    /// Writes back the entries waiting in `unwritten` that are no longer debounced, and returns how many were written.
+    fn write_due(&mut self) -> Result<usize, CC::Error> {
+        if let Some(window) = self.debounce {
+            let now = self.clock.now();
+            self.dirtied.retain(|_, at| now.saturating_duration_since(*at) < window);
+        }
+        let keys: Vec<K> = self.unwritten.keys().filter(|k| !self.debounced(k, true)).copied().collect();
+        let mut written = 0;
+        for k in keys {
+            if let Some((changed, v)) = self.unwritten.remove(&k) {
+                self.write_back(k, changed, v)?;
+                written += 1;
+            }
+        }
+        Ok(written)
+    }
+    fn notify(&self, k: K, reason: EvictReason) {
+        if let Some(events) = &self.events {
+            let _ = events.try_send((k, reason));
+        }
     }
     fn insert(&mut self, k: K, v: V) -> Result<(), CC::Error> {
         if self.active.contains_key(&k) {
             panic!();
-        } else if let Some((changed, vv)) = self.lru.get_mut(&k) {
+        }
+        self.changed(&k);
+        self.tokens.remove(&k);
+        self.invalidate_dependents(&k)?;
+        self.may_have(&k);
+        if let Some(max) = self.max_value && let Some(serialized) = CC::SERIALIZED {
+            let vser = (serialized.serialize)(&v)?;
+            if vser.len() > max {
+                return self.bypass(k, |cc| (serialized.insert)(cc, k, vser));
+            }
+        }
+        self.poisoned.remove(&k);
+        self.unwritten.remove(&k);
+        if self.ttl.is_some() {
+            self.fetched.insert(k, self.clock.now());
+        }
+        self.idle_limits.remove(&k);
+        self.touch(k);
+        self.mark_dirtied(k);
+        if self.passthrough {
+            self.lru.pop(&k);
+            return self.write_back_later(k, true, Arc::new(RwLock::new(v)));
+        }
+        // Replaces any copy already in memory, so no older value is left to be written back over this one.
+        if let Some((k, (changed, v))) = self.push_lru(k, (true, Arc::new(RwLock::new(v)))) && !self.lru.contains(&k) {
+            self.evict(k, changed, v)?;
+        }
+        Ok(())
+    }
+    fn insert_bypass(&mut self, k: K, v: V) -> Result<(), CC::Error> {
+        self.bypass(k, |cc| cc.insert(k, v))
+    }
+    /// Has `write` store the new value of `k` in the backend, dropping any copy held in memory.
+    fn bypass(&mut self, k: K, write: impl FnOnce(&mut CC) -> Result<(), CC::Error>) -> Result<(), CC::Error> {
+        if self.active.contains_key(&k) {
+            panic!();
+        }
+        self.changed(&k);
+        self.poisoned.remove(&k);
+        self.unwritten.remove(&k);
+        self.lru.pop(&k);
+        self.may_have(&k);
+        write(&mut self.compatible)?;
+        self.publish(Change::Inserted(k));
+        if self.ttl.is_some() {
+            self.fetched.insert(k, self.clock.now());
+        }
+        Ok(())
+    }
+    /// Keys held in memory whose value is older than the TTL less `ahead`.
+    fn aging(&self, ahead: Duration) -> Vec<K> {
+        let Some(ttl) = self.ttl else {return Vec::new()};
+        let now = self.clock.now();
+        self.lru.iter().map(|(k, _)| k).chain(self.active.keys())
+            .filter(|k| self.fetched.get(k).is_some_and(|at| now.duration_since(*at)+ahead >= ttl)).copied().collect()
+    }
+    fn expired(&self, k: &K) -> bool {
+        self.ttl.is_some_and(|ttl| self.fetched.get(k).is_some_and(|at| self.clock.now().duration_since(*at) >= ttl))
+    }
+    /// Replaces the value of `k` with a freshly loaded one, unless it is borrowed.
+    fn refresh(&mut self, k: K, v: V) -> Result<bool, CC::Error> {
+        if self.active.contains_key(&k) {
+            return Ok(false);
+        }
+        self.insert(k, v)?;
+        Ok(true)
+    }
+    fn insert_with_tti(&mut self, k: K, v: V, tti: Duration) -> Result<(), CC::Error> {
+        self.insert(k, v)?;
+        self.idle_limits.insert(k, tti);
+        self.touch(k);
+        Ok(())
+    }
+    /// Notes that `k` was used just now, if it can expire by idling.
+    fn touch(&mut self, k: K) {
+        if self.tti.is_some() || self.idle_limits.contains_key(&k) {
+            self.used.insert(k, self.clock.now());
+        }
+    }
+    fn expire_idle(&mut self) -> Result<usize, CC::Error> {
+        if self.tti.is_none() && self.idle_limits.is_empty() {
+            return Ok(0);
+        }
+        let now = self.clock.now();
+        let idle: Vec<K> = self.lru.iter().map(|(k, _)| *k).filter(|k| {
+            let limit = self.idle_limits.get(k).copied().or(self.tti);
+            limit.is_some_and(|limit| self.used.get(k).is_some_and(|at| now.saturating_duration_since(*at) >= limit))
+        }).collect();
+        for k in &idle {
+            if let Some((changed, v)) = self.lru.pop(k) {
+                self.used.remove(k);
+                self.write_back(*k, changed, v)?;
+                self.notify(*k, EvictReason::Idle);
+            }
+        }
+        Ok(idle.len())
+    }
+    fn fetched(&mut self, k: K) {
+        if self.ttl.is_some() {
+            let now = self.clock.now();
+            self.fetched.entry(k).or_insert(now);
+        }
+    }
+    fn append(&mut self, k: &K, suffix: &V::Suffix) -> Result<(), CC::Error> where V: Appendable {
+        self.reclaim(k)?;
+        if self.active.contains_key(k) {
+            panic!();
+        }
+        self.changed(k);
+        if let Some((changed, v)) = self.lru.get_mut(k) {
             *changed = true;
-            *vv = Arc::new(RwLock::new(v));
+            v.write().append(suffix);
         } else {
-            self.compatible.insert(k, v)?;
+            self.may_have(k);
+            self.compatible.append(*k, suffix)?;
+            self.publish(Change::Inserted(*k));
+        }
+        Ok(())
+    }
+    fn insert_tagged(&mut self, k: K, v: V, tags: Vec<String>) -> Result<(), CC::Error> {
+        self.insert(k, v)?;
+        self.untag(&k);
+        for tag in &tags {
+            self.tags.entry(tag.clone()).or_default().insert(k);
+        }
+        self.tags_of.insert(k, tags);
+        Ok(())
+    }
+    fn untag(&mut self, k: &K) {
+        for tag in self.tags_of.remove(k).unwrap_or_default() {
+            if let Some(keys) = self.tags.get_mut(&tag) {
+                keys.remove(k);
+                if keys.is_empty() {
+                    self.tags.remove(&tag);
+                }
+            }
         }
+    }
+    fn invalidate_tag(&mut self, tag: &str) -> Result<usize, CC::Error> {
+        let keys = self.tags.get(tag).cloned().unwrap_or_default();
+        for k in &keys {
+            self.invalidate(k)?;
+            self.invalidate_dependents(k)?;
+        }
+        Ok(keys.len())
+    }
+    /// Removes `k` from memory and the backend; a borrowed entry is dropped rather than written back once released.
+    fn invalidate(&mut self, k: &K) -> Result<(), CC::Error> {
+        self.changed(k);
+        self.tokens.remove(k);
+        self.untag(k);
+        self.ungroup(k);
+        self.forget_sources(k);
+        self.fetched.remove(k);
+        self.used.remove(k);
+        self.idle_limits.remove(k);
+        if self.active.contains_key(k) {
+            self.stale.insert(*k);
+        } else {
+            self.lru.pop(k);
+            self.unwritten.remove(k);
+        }
+        self.compatible.remove(*k)?;
+        self.publish(Change::Removed(*k));
+        self.notify(*k, EvictReason::Invalidated);
         Ok(())
     }
+    fn add_dependency(&mut self, derived: K, source: K) {
+        self.dependents.entry(source).or_default().insert(derived);
+        self.sources.entry(derived).or_default().insert(source);
+    }
+    /// Drops the edges from the entries `k` was derived from.
+    fn forget_sources(&mut self, k: &K) {
+        for source in self.sources.remove(k).unwrap_or_default() {
+            if let Some(dependents) = self.dependents.get_mut(&source) {
+                dependents.remove(k);
+                if dependents.is_empty() {
+                    self.dependents.remove(&source);
+                }
+            }
+        }
+    }
+    /// Invalidates every entry derived from `k`, directly or through others, and returns how many there were.
+    fn invalidate_dependents(&mut self, k: &K) -> Result<usize, CC::Error> {
+        let mut seen = HashSet::from([*k]);
+        let mut pending: Vec<K> = self.dependents.remove(k).into_iter().flatten().collect();
+        let mut invalidated = 0;
+        while let Some(derived) = pending.pop() {
+            if !seen.insert(derived) {
+                continue;
+            }
+            pending.extend(self.dependents.remove(&derived).into_iter().flatten());
+            self.invalidate(&derived)?;
+            invalidated += 1;
+        }
+        Ok(invalidated)
+    }
+    /// Drops everything kept in memory about `k`, which is being removed.
+    fn discard(&mut self, k: &K) {
+        self.changed(k);
+        self.poisoned.remove(k);
+        self.unwritten.remove(k);
+        self.lru.pop(k);
+        self.untag(k);
+        self.ungroup(k);
+        self.fetched.remove(k);
+        self.used.remove(k);
+        self.idle_limits.remove(k);
+        self.priorities.remove(k);
+        self.tokens.remove(k);
+        self.forget_sources(k);
+    }
+    /// The keys anything is kept about in memory, borrowed entries aside.
+    fn known_keys(&self) -> HashSet<K> {
+        let mut keys: HashSet<K> = self.lru.iter().map(|(k, _)| *k).collect();
+        keys.extend(self.tags_of.keys().chain(self.fetched.keys()).chain(self.used.keys()).chain(self.idle_limits.keys())
+            .chain(&self.poisoned).chain(self.unwritten.keys()).chain(self.priorities.keys()).chain(self.group_of.keys())
+            .chain(self.sources.keys()).chain(self.dependents.keys()).chain(self.tokens.keys()));
+        keys
+    }
     fn remove(&mut self, k: &K) -> Result<(), CC::Error> {
-        if self.active.contains_key(&k) {
+        if self.active.contains_key(k) {
             panic!();
         }
-        self.lru.pop(k);
+        self.discard(k);
         self.compatible.remove(*k)?;
+        self.publish(Change::Removed(*k));
+        self.notify(*k, EvictReason::Removed);
+        self.invalidate_dependents(k)?;
         Ok(())
     }
+    fn remove_many(&mut self, keys: &[K]) -> Result<(), CC::Error> {
+        if keys.iter().any(|k| self.active.contains_key(k)) {
+            panic!();
+        }
+        for k in keys {
+            self.discard(k);
+        }
+        self.compatible.remove_many(keys.to_vec())?;
+        for k in keys {
+            self.publish(Change::Removed(*k));
+            self.notify(*k, EvictReason::Removed);
+        }
+        keys.iter().try_for_each(|k| self.invalidate_dependents(k).map(|_| ()))
+    }
+    fn remove_range(&mut self, range: impl RangeBounds<K>) -> Result<(), CC::Error> where CC: CacheRangeCompatible<K, V>, K: PartialOrd {
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        self.remove_matching(|k| range.contains(k), |cc| cc.remove_range(bounds))
+    }
+    fn clear_where(&mut self, matches: impl Fn(&K) -> bool) -> Result<(), CC::Error> where CC: ListKeys<K, V> {
+        self.remove_matching(&matches, |cc| cc.remove_where(&matches).map(|_| ()))
+    }
+    /// Removes every entry whose key `matches` from memory, and has `backend` remove them from the backend.
+    fn remove_matching(&mut self, matches: impl Fn(&K) -> bool, backend: impl FnOnce(&mut CC) -> Result<(), CC::Error>) -> Result<(), CC::Error> {
+        if self.active.keys().any(&matches) {
+            panic!();
+        }
+        self.changed_all();
+        let mut keys = self.known_keys();
+        keys.retain(&matches);
+        for k in &keys {
+            self.discard(k);
+        }
+        backend(&mut self.compatible)?;
+        for k in &keys {
+            self.publish(Change::Removed(*k));
+            self.notify(*k, EvictReason::Removed);
+        }
+        keys.iter().try_for_each(|k| self.invalidate_dependents(k).map(|_| ()))
+    }
     fn contains(&self, k: &K) -> bool {
-        self.compatible.contains(*k) || self.active.contains_key(k) || self.lru.contains(k)
+        self.contains_cached(k) || (!self.stale.contains(k) && self.bloom.as_ref().is_none_or(|bloom| bloom.might_contain(k))
+            && self.compatible.contains(*k))
+    }
+    fn contains_cached(&self, k: &K) -> bool {
+        !self.stale.contains(k) && (self.active.contains_key(k) || self.lru.contains(k) || self.unwritten.contains_key(k))
     }
-    fn get(&mut self, k: &K) -> Result<ArcRwLockReadGuard<RawRwLock, V>, CC::Error> {
+    fn get(&mut self, k: &K) -> Result<ArcRwLockReadGuard<L, V>, CC::Error> {
+        self.adapt()?;
+        self.reclaim(k)?;
+        self.revalidate(k);
+        // A backend that cannot be asked leaves the copy to be served stale.
+        if self.expired(k) {
+            let _ = self.refetch(k);
+        }
+        self.touch(*k);
+        self.count_lookup(k);
         if let Some((_, arc)) = self.active.get(k) {
-            return Ok(arc.read_arc());
+            Ok(arc.read_arc())
         } else if let Some(item) = self.lru.pop(k) {
             let arc = item.1.read_arc();
             self.active.insert(*k, item);
-            return Ok(arc);
+            Ok(arc)
         } else {
-            let v = self.compatible.get(*k)?;
+            let v = self.load(k)?;
+            self.fetched(*k);
             let arc = Arc::new(RwLock::new(v));
             let r = arc.read_arc();
             self.active.insert(*k, (false, arc));
-            return Ok(r);
+            Ok(r)
         }
     }
-    fn get_mut(&mut self, k: &K) -> Result<ArcRwLockWriteGuard<RawRwLock, V>, CC::Error> {
-        if let Some(_) = self.active.get(k) {
+    fn get_mut(&mut self, k: &K) -> Result<ArcRwLockWriteGuard<L, V>, CC::Error> {
+        self.adapt()?;
+        self.reclaim(k)?;
+        self.revalidate(k);
+        // A backend that cannot be asked leaves the copy to be served stale.
+        if self.expired(k) {
+            let _ = self.refetch(k);
+        }
+        self.touch(*k);
+        self.count_lookup(k);
+        if self.active.contains_key(k) {
             panic!();
-        } else if let Some((_, v)) = self.lru.pop(k) {
+        } else if let Some((changed, v)) = self.lru.pop(k) {
+            if changed {
+                self.borrowed_clean.remove(k);
+            } else {
+                self.borrowed_clean.insert(*k);
+            }
             let arc = v.write_arc();
             self.active.insert(*k, (true, v));
-            return Ok(arc);
+            Ok(arc)
         } else {
-            let v = self.compatible.get(*k)?;
+            let v = self.load(k)?;
+            self.fetched(*k);
+            self.borrowed_clean.insert(*k);
             let arc = Arc::new(RwLock::new(v));
             let r = arc.write_arc();
             self.active.insert(*k, (true, arc));
-            return Ok(r);
+            Ok(r)
+        }
+    }
+    fn get_mut_many(&mut self, keys: &[K]) -> ManyResult<K, WriteGuard<L, V>, CC::Error> {
+        let mut seen = HashSet::new();
+        if let Some(k) = keys.iter().find(|k| self.poisoned.contains(k)) {
+            return Err(LockError::Poisoned(*k));
+        }
+        if let Some(k) = keys.iter().find(|k| self.active.contains_key(k) || !seen.insert(**k)) {
+            return Err(LockError::WouldDeadlock(*k));
+        }
+        let mut guards = Vec::with_capacity(keys.len());
+        let mut changed = Vec::with_capacity(keys.len());
+        for k in keys {
+            self.reclaim(k).map_err(|e| LockError::Backend(*k, e))?;
+            changed.push(self.lru.peek(k).is_some_and(|(changed, _)| *changed));
+            match self.get_mut(k) {
+                Ok(guard) => guards.push(guard),
+                Err(e) => {
+                    drop(guards);
+                    // Puts back the entries taken so far, without marking them as modified.
+                    for (k, changed) in keys.iter().zip(changed) {
+                        if let Some(item) = self.active.get_mut(k) {
+                            item.0 = changed;
+                            self.deactivate(k).map_err(|e| LockError::Backend(*k, e))?;
+                        }
+                    }
+                    return Err(LockError::Backend(*k, e));
+                },
+            }
         }
+        Ok(guards)
     }
     fn commit(&mut self) -> Result<(), CC::Error> {
-        if self.active.len() > 0 {
+        if !self.active.is_empty() {
             panic!();
         }
+        self.retry_unwritten()?;
         while let Some((k, (changed, v))) = self.lru.pop_lru() {
-            let v = Arc::try_unwrap(v).unwrap_or_else(|_| unreachable!()).into_inner();
-            if changed {
-                self.compatible.insert(k, v)?;
-            } else {
-                self.compatible.replace(k, v);
-            }
+            self.write_back(k, changed, v)?;
+            self.notify(k, EvictReason::Flushed);
         }
         self.compatible.commit()?;
         Ok(())
     }
+    #[cfg(feature = "parallel")]
+    fn commit_parallel(&mut self) -> Result<(), CC::Error> where K: Sync, V: Send+Sync, CC::Error: Send, L: Send+Sync {
+        if !self.active.is_empty() {
+            panic!();
+        }
+        self.retry_unwritten()?;
+        let mut dirty = Vec::new();
+        while let Some((k, (changed, v))) = self.lru.pop_lru() {
+            if changed && Arc::strong_count(&v) == 1 {
+                dirty.push((k, v));
+                continue;
+            }
+            if let Err(e) = self.write_back(k, changed, v) {
+                self.keep_unwritten(dirty);
+                return Err(e);
+            }
+            self.notify(k, EvictReason::Flushed);
+        }
+        let threads = std::thread::available_parallelism().map_or(1, usize::from);
+        let chunk = dirty.len().div_ceil(threads).max(1);
+        let serialized: Vec<_> = std::thread::scope(|scope| {
+            let workers: Vec<_> = dirty.chunks(chunk)
+                .map(|part| scope.spawn(move || {
+                    part.iter().map(|(_, v)| CC::SERIALIZED.map(|serialized| (serialized.serialize)(&v.read()))).collect::<Vec<_>>()
+                }))
+                .collect();
+            workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect()
+        });
+        let mut dirty = dirty.into_iter().zip(serialized);
+        while let Some(((k, v), vser)) = dirty.next() {
+            let written = match (vser, CC::SERIALIZED) {
+                (Some(vser), Some(serialized)) => match vser.and_then(|vser| (serialized.insert)(&mut self.compatible, k, vser)) {
+                    Ok(()) => {
+                        self.publish(Change::Inserted(k));
+                        Ok(())
+                    },
+                    Err(e) => {
+                        self.unwritten.insert(k, (true, v));
+                        Err(e)
+                    },
+                },
+                _ => self.write_back(k, true, v),
+            };
+            if let Err(e) = written {
+                self.keep_unwritten(dirty.map(|(entry, _)| entry).collect());
+                return Err(e);
+            }
+            self.notify(k, EvictReason::Flushed);
+        }
+        self.compatible.commit()
+    }
+    /// Keeps modified entries taken out of the LRU list for writing in `unwritten` after a write failed, so that
+    /// they are still read from memory and written by the next commit.
+    #[cfg(feature = "parallel")]
+    fn keep_unwritten(&mut self, dirty: Vec<(K, Arc<RwLock<L, V>>)>) {
+        self.unwritten.extend(dirty.into_iter().map(|(k, v)| (k, (true, v))));
+    }
+    /// Like `commit`, but leaves active entries alone instead of panicking on them.
+    fn flush(&mut self) -> Result<(), CC::Error> {
+        self.write_due()?;
+        while let Some((k, (changed, v))) = self.lru.pop_lru() {
+            if self.debounced(&k, changed) {
+                self.unwritten.insert(k, (changed, v));
+                continue;
+            }
+            self.write_back(k, changed, v)?;
+            self.notify(k, EvictReason::Flushed);
+        }
+        self.compatible.commit()
+    }
+    /// Writes back the entries in memory that the backend does not have as they are, leaving the rest cached.
+    fn settle(&mut self) -> Result<(), CC::Error> {
+        self.retry_unwritten()?;
+        let keys: Vec<K> = self.lru.iter().filter(|(_, (changed, _))| *changed || CC::RETRIEVAL == Retrieval::Take).map(|(k, _)| *k).collect();
+        for k in keys {
+            if let Some((changed, v)) = self.lru.pop(&k) {
+                self.write_back(k, changed, v)?;
+                self.notify(k, EvictReason::Flushed);
+            }
+        }
+        Ok(())
+    }
     fn deactivate(&mut self, k: &K) -> Result<(), CC::Error> {
         let Some(item) = self.active.remove(k) else {return Ok(())};
-        let out = self.lru.push(*k, item);
+        let hint = self.hints.remove(k).unwrap_or_default();
+        if self.stale.remove(k) | self.outdated.remove(k) {
+            return Ok(());
+        }
+        self.touch(*k);
+        if self.wanted.remove(k) && let Some(refresher) = &self.refresher {
+            refresher.unpark();
+        }
+        if hint == Hint::NoCache || self.passthrough {
+            return self.write_back_later(*k, item.0, item.1);
+        }
+        let out = self.push_lru(*k, item);
+        self.promote_group(k);
+        if hint == Hint::NoPromote {
+            self.lru.demote(k);
+        }
         if let Some((k, (changed, v))) = out {
-            let v = Arc::try_unwrap(v).unwrap_or_else(|_| unreachable!()).into_inner();
-            if changed {
-                self.compatible.insert(k, v)?;
-            } else {
-                self.compatible.replace(k, v);
+            self.evict(k, changed, v)?;
+        }
+        Ok(())
+    }
+    /// Moves `k` back into memory if it is waiting to be written back.
+    fn reclaim(&mut self, k: &K) -> Result<(), CC::Error> {
+        let Some(item) = self.unwritten.remove(k) else {return Ok(())};
+        if let Some((k, (changed, v))) = self.push_lru(*k, item) {
+            self.evict(k, changed, v)?;
+        }
+        Ok(())
+    }
+    fn retry_unwritten(&mut self) -> Result<(), CC::Error> {
+        let keys: Vec<K> = self.unwritten.keys().copied().collect();
+        for k in keys {
+            if let Some((changed, v)) = self.unwritten.remove(&k) {
+                self.write_back(k, changed, v)?;
             }
         }
         Ok(())
     }
-    fn cap(&self) -> usize { self.lru.cap().into() }
-    fn active(&self, k: &K) -> bool { self.active.contains_key(&k) }
+    /// Hands `v` to the backend. A modified value the backend fails to take by reference is kept in `unwritten`,
+    /// so that reads still find it rather than the older value in the backend.
+    fn write_back(&mut self, k: K, changed: bool, v: Arc<RwLock<L, V>>) -> Result<(), CC::Error> {
+        if changed && Arc::strong_count(&v) == 1 {
+            let stored = self.compatible.insert_shared(k, &v.read());
+            match stored {
+                Ok(true) => {
+                    self.publish(Change::Inserted(k));
+                    return Ok(());
+                },
+                Ok(false) => {},
+                Err(e) => {
+                    self.unwritten.insert(k, (changed, v));
+                    return Err(e);
+                },
+            }
+            // Serialized, the value is kept until the backend has taken it.
+            let stored = CC::SERIALIZED
+                .map(|serialized| (serialized.serialize)(&v.read()).and_then(|vser| (serialized.insert)(&mut self.compatible, k, vser)));
+            match stored {
+                Some(Ok(())) => {
+                    self.publish(Change::Inserted(k));
+                    return Ok(());
+                },
+                Some(Err(e)) => {
+                    self.unwritten.insert(k, (changed, v));
+                    return Err(e);
+                },
+                None => {},
+            }
+        }
+        let v = match Arc::try_unwrap(v) {
+            Ok(v) => v.into_inner(),
+            Err(_) if !changed && CC::RETRIEVAL == Retrieval::Copy => return Ok(()),
+            Err(shared) => {
+                // A write lock here belongs to a leaked guard, so the value is read only if it is free.
+                let stored = match shared.try_read().map(|v| self.compatible.insert_shared(k, &v)) {
+                    Some(Ok(stored)) => stored,
+                    Some(Err(e)) => {
+                        self.unwritten.insert(k, (changed, shared));
+                        return Err(e);
+                    },
+                    None => false,
+                };
+                if stored && changed {
+                    self.publish(Change::Inserted(k));
+                }
+                if !stored {
+                    self.unwritten.insert(k, (changed, shared));
+                }
+                return Ok(());
+            },
+        };
+        if changed {
+            self.compatible.insert(k, v)?;
+            self.publish(Change::Inserted(k));
+            Ok(())
+        } else if CC::RETRIEVAL == Retrieval::Take {
+            self.compatible.replace(k, v)
+        } else {
+            Ok(())
+        }
+    }
+    fn cap(&self) -> usize { if self.passthrough {0} else {self.lru.cap().into()} }
+    fn active(&self, k: &K) -> bool { self.active.contains_key(k) }
     fn num_active(&self) -> usize { self.active.len() }
 }
-impl<K, V, CC> Drop for CacheMutBase<K, V, CC> where 
-K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K,V> {
+impl<K, V, CC, L> Drop for CacheMutBase<K, V, CC, L> where 
+K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K,V>, L: RawRwLock {
     fn drop(&mut self) {
-        let _ = self.commit();
+        if !self.closed {
+            let _ = self.commit();
+            // Safe, as `closed` is only set once `compatible` has been taken.
+            unsafe { ManuallyDrop::drop(&mut self.compatible) }
+        }
     }
 }
 
-#[derive(Clone)]
-pub struct CacheMut<K, V, CC>(Arc<Mutex<CacheMutBase<K, V, CC>>>) where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V>;
+/// A handle to a cache; clones share the same entries. Once a `CMRefMut` is dropped, a `get` through any clone sees
+/// its changes, whether the entry stayed in memory or was evicted and read back in between: a modified value is only
+/// let go of once the backend has it. If writing it back fails, it stays in memory until the next `commit` or
+/// `flush` writes it, for backends that take values by reference (see `CacheMutCompatible::insert_shared`); for
+/// others, it is lost with the error.
+pub struct CacheMut<K, V, CC, L = parking_lot::RawRwLock>(Arc<Mutex<CacheMutBase<K, V, CC, L>>>) where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V>, L: RawRwLock;
+/// Clones share the same cache.
+impl<K, V, CC, L> Clone for CacheMut<K, V, CC, L> where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V>, L: RawRwLock {
+    fn clone(&self) -> Self { Self(self.0.clone()) }
+}
+/// Shows the counts of `CacheMut::stats` rather than any keys or values. Like the standard library's `Mutex`, shows
+/// `locked` instead of waiting while the cache is in use, as it may be by the thread formatting it.
+impl<K, V, CC, L> std::fmt::Debug for CacheMut<K, V, CC, L> where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V>, L: RawRwLock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut s = f.debug_struct("CacheMut");
+        match self.0.try_lock() {
+            Some(base) => {
+                let stats = base.stats();
+                s.field("capacity", &base.cap()).field("cached", &stats.cached).field("active", &stats.active)
+                    .field("dirty", &stats.dirty).field("unwritten", &stats.unwritten)
+            },
+            None => s.field("locked", &true),
+        };
+        s.finish_non_exhaustive()
+    }
+}
+/// A handle to a `CacheMut` that does not keep it alive, so background tasks can hold one without preventing the
+/// owner from closing the cache.
+pub struct CacheMutWeak<K, V, CC, L = parking_lot::RawRwLock>(Weak<Mutex<CacheMutBase<K, V, CC, L>>>) where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V>, L: RawRwLock;
+impl<K, V, CC, L> Clone for CacheMutWeak<K, V, CC, L> where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V>, L: RawRwLock {
+    fn clone(&self) -> Self { Self(self.0.clone()) }
+}
+impl<K, V, CC, L> std::fmt::Debug for CacheMutWeak<K, V, CC, L> where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V>, L: RawRwLock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CacheMutWeak").field("open", &(self.0.strong_count() > 0)).finish()
+    }
+}
+impl<K, V, CC, L> CacheMutWeak<K, V, CC, L> where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V>, L: RawRwLock {
+    /// The cache, unless every `CacheMut` and guard sharing it has been dropped and it is closed.
+    pub fn upgrade(&self) -> Option<CacheMut<K, V, CC, L>> { self.0.upgrade().map(CacheMut) }
+}
 impl<K, V, CC> CacheMut<K, V, CC> where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V> {
-    pub fn new(compatible: CC, capacity: usize) -> Self {
+    /// Keeps up to `capacity` released entries in memory. With a capacity of 0, entries are only in memory while
+    /// borrowed: every access goes to the backend, which helps tell bugs of the cache from those of the backend.
+    pub fn new(compatible: CC, capacity: usize) -> Self { Self::new_with_lock(compatible, capacity) }
+}
+impl<K, V, CC, L> CacheMut<K, V, CC, L> where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V>, L: RawRwLock {
+    /// Like `new`, guarding each value with the lock `L` (see `lock`) rather than `parking_lot`'s:
+    /// `let cache: CacheMut<u32, String, HashMap<u32, String>, lock::NoLock> = CacheMut::new_with_lock(map, 16);`
+    pub fn new_with_lock(compatible: CC, capacity: usize) -> Self {
         Self(Arc::new(Mutex::new(CacheMutBase::new(compatible, capacity))))
     }
+    pub fn downgrade(&self) -> CacheMutWeak<K, V, CC, L> { CacheMutWeak(Arc::downgrade(&self.0)) }
+    /// How many clones of this `CacheMut` and guards from it are alive. The cache is committed and its backend
+    /// dropped when the last of them is.
+    pub fn handle_count(&self) -> usize { Arc::strong_count(&self.0) }
+    /// Gives values a time to live, counted from when they were inserted or, for values already in the backend, first
+    /// loaded. Expired values are still served; `refresh::RefreshAhead` replaces them before they expire.
+    pub fn with_ttl(self, ttl: Duration) -> Self {
+        self.0.lock().ttl = Some(ttl);
+        self
+    }
+    /// Has `insert` write values that serialize to more than `max_bytes` straight to the backend, as `insert_bypass`
+    /// does, rather than keep them in memory. Only affects backends that serialize values (see
+    /// `CacheMutCompatible::SERIALIZED`), and serializes every inserted value once more to measure it.
+    pub fn with_max_value_size(self, max_bytes: usize) -> Self {
+        self.0.lock().max_value = Some(max_bytes);
+        self
+    }
+    /// Gives entries a time to idle: once released entries have gone unused for `tti`, `expire_idle` writes them back
+    /// and drops them from memory. `insert_with_tti` sets it per key.
+    pub fn with_tti(self, tti: Duration) -> Self {
+        self.0.lock().tti = Some(tti);
+        self
+    }
+    /// Has `contains` (and so `get_if_present` and `Transaction::get`) answer for most keys the backend does not have
+    /// without asking it, for backends where that is slow. `filter` must already hold every key the backend has (see
+    /// `BloomFilter::from_keys`); keys written through this cache, or announced on its bus, are added as they go.
+    /// Removed keys stay in the filter, so size it for every key the backend will have held.
+    pub fn with_bloom_filter(self, filter: BloomFilter) -> Self {
+        self.0.lock().bloom = Some(filter);
+        self
+    }
+    /// Grows and shrinks the capacity with the hit rate of `get` and `get_mut`, as set by `adaptive`, for working sets
+    /// that change in size. It starts from the capacity given to `new`, and is brought within bounds after the first
+    /// window of lookups. `stats` shows the hits and misses.
+    pub fn with_adaptive_capacity(self, adaptive: AdaptiveCapacity<V>) -> Self {
+        {
+            let mut base = self.0.lock();
+            base.adaptive = Some(adaptive);
+            base.window_from = (base.hits, base.misses);
+        }
+        self
+    }
+    /// Has `stats` report what the values in memory weigh by `weigh` (their size in bytes, say): in total, in
+    /// classes by weight, and how much was evicted. `stats` then weighs every value in memory.
+    pub fn with_weigher(self, weigh: impl Fn(&V) -> usize+Send+Sync+'static) -> Self {
+        self.0.lock().weigher = Some(Arc::new(weigh));
+        self
+    }
+    /// Holds back writing a modified entry until `window` has passed since it was last modified, so that an entry
+    /// modified again and again is written once with its latest value rather than on every eviction, release (with
+    /// a capacity of 0) or `flush`. Held entries stay in memory, beyond the capacity, until `write_due` (which
+    /// `maintenance::Maintenance` runs on every tick) or `flush` after their window, or `commit`, writes them.
+    pub fn with_write_debounce(self, window: Duration) -> Self {
+        self.0.lock().debounce = Some(window);
+        self
+    }
+    /// Records the last `capacity` lookups by `get` and `get_mut` (a hash of the key, whether it was a hit, and when)
+    /// without changing what the cache does, for `trace::simulate` to predict the hit rate of other capacities.
+    pub fn with_trace(self, capacity: usize) -> Self {
+        {
+            let mut base = self.0.lock();
+            let now = base.clock.now();
+            base.trace = Some((Trace::new(capacity), now));
+        }
+        self
+    }
+    /// The lookups recorded since `with_trace`, oldest first.
+    pub fn trace(&self) -> Vec<TraceEvent> {
+        self.0.lock().trace.as_ref().map_or_else(Vec::new, |(trace, _)| trace.events.iter().copied().collect())
+    }
+    /// Has `Priority` choose what to evict among the `entries` least recently used entries (16 by default). The more
+    /// entries, the longer high priority entries outlast more recently used low priority ones.
+    pub fn with_priority_window(self, entries: usize) -> Self {
+        assert!(entries > 0, "the priority window must hold at least one entry");
+        self.0.lock().priority_window = entries;
+        self
+    }
+    /// Has `get` and `get_mut` check a value held in memory with `valid` (against its file's modification time,
+    /// say) before serving it. A rejected value is dropped and counted as a miss, and the backend's value is loaded
+    /// instead. Only values read from a `Retrieval::Copy` backend and not modified since are checked; borrowed
+    /// values are not checked either.
+    pub fn with_validator(self, valid: impl Fn(&K, &V) -> bool+Send+Sync+'static) -> Self {
+        self.0.lock().validator = Some(Arc::new(valid));
+        self
+    }
+    /// Takes the time for TTLs, times to idle and guard hold times from `clock` rather than the wall clock.
+    pub fn with_clock(self, clock: impl Clock+'static) -> Self {
+        self.0.lock().clock = Arc::new(clock);
+        self
+    }
+    /// How long ago the value of `k` was inserted or first loaded, if a TTL is set and the value has been seen.
+    pub fn age(&self, k: &K) -> Option<Duration> {
+        let base = self.0.lock();
+        base.fetched.get(k).map(|at| base.clock.now().duration_since(*at))
+    }
+    pub(crate) fn aging(&self, ahead: Duration) -> Vec<K> { self.0.lock().aging(ahead) }
+    /// Asks a backend that versions its values (see `CacheCompatible::get_versioned`) whether the copy of `k` held in
+    /// memory is still current, replacing it if not, and restarts its TTL. Returns whether it was replaced, or `None`
+    /// if `k` is borrowed, modified, not in memory or has no version. `get` and `get_mut` do this by themselves for
+    /// values past their TTL, and keep serving the copy the backend says is current.
+    pub fn refetch(&mut self, k: &K) -> Result<Option<bool>, CC::Error> { self.0.lock().refetch(k) }
+    /// Replaces the value of `k` with a freshly loaded one and restarts its TTL. Does nothing and returns `false` if
+    /// the entry is borrowed.
+    pub fn refresh(&mut self, k: K, v: V) -> Result<bool, CC::Error> { self.0.lock().refresh(k, v) }
+    /// Makes `v` the value of `k`, cached or not: it is kept in memory as the most recently used entry and written
+    /// to the backend when evicted, committed or flushed. Any older copy held by the cache is dropped, so it can not
+    /// be written back over `v` later. Panics if `k` is borrowed.
     pub fn insert(&mut self, k: K, v: V) -> Result<(), CC::Error> { self.0.lock().insert(k, v) }
+    /// Like `insert`, with a time to idle of `tti` for `k` instead of the one given to `with_tti`. It lasts until `k` is
+    /// inserted again or removed.
+    pub fn insert_with_tti(&mut self, k: K, v: V, tti: Duration) -> Result<(), CC::Error> { self.0.lock().insert_with_tti(k, v, tti) }
+    /// Writes back and drops from memory the released entries that have gone unused for longer than their time to
+    /// idle, and returns how many there were. `maintenance::Maintenance` calls this on every run.
+    pub fn expire_idle(&mut self) -> Result<usize, CC::Error> { self.0.lock().expire_idle() }
+    /// Writes back the modified entries held by `with_write_debounce` whose window has passed, and returns how many
+    /// there were.
+    pub fn write_due(&mut self) -> Result<usize, CC::Error> { self.0.lock().write_due() }
+    /// Writes `v` straight to the backend, dropping any copy of `k` held in memory instead of updating it, so that
+    /// values unlikely to be read soon do not take the place of cached ones.
+    pub fn insert_bypass(&mut self, k: K, v: V) -> Result<(), CC::Error> { self.0.lock().insert_bypass(k, v) }
+    /// Appends to the value of `k`: in memory if it is cached, otherwise through the backend, which may do so without
+    /// loading the value.
+    pub fn append(&mut self, k: &K, suffix: &V::Suffix) -> Result<(), CC::Error> where V: Appendable { self.0.lock().append(k, suffix) }
+    /// Like `insert`, and attaches `tags` to the entry in place of any it had, for `invalidate_tag`. Tags are not
+    /// persisted by the backend.
+    pub fn insert_tagged<T: Into<String>>(&mut self, k: K, v: V, tags: impl IntoIterator<Item = T>) -> Result<(), CC::Error> {
+        self.0.lock().insert_tagged(k, v, tags.into_iter().map(Into::into).collect())
+    }
+    /// Removes every entry tagged `tag` from the cache and the backend, and returns how many there were. Entries
+    /// that are currently borrowed stay readable through their guards, but are dropped rather than written back.
+    pub fn invalidate_tag(&mut self, tag: &str) -> Result<usize, CC::Error> { self.0.lock().invalidate_tag(tag) }
+    /// Like `insert`, and gives the entry `priority` until it is evicted or removed.
+    pub fn insert_with_priority(&mut self, k: K, v: V, priority: Priority) -> Result<(), CC::Error> {
+        let mut base = self.0.lock();
+        base.set_priority(k, priority);
+        base.insert(k, v)
+    }
+    /// Puts `k` in `group` (leaving any other), so that when one entry of the group is evicted to make room, the
+    /// others held in memory are written back and dropped with it, and using one keeps the others as recently used.
+    /// For entries that are only useful together. Borrowed entries stay until released. Groups are kept in memory
+    /// only, until the key is removed or `ungroup`ed.
+    pub fn set_group(&mut self, k: K, group: u64) { self.0.lock().set_group(k, group) }
+    pub fn ungroup(&mut self, k: &K) { self.0.lock().ungroup(k) }
+    /// The keys in `group`, in no particular order.
+    pub fn group(&self, group: u64) -> Vec<K> {
+        self.0.lock().groups.get(&group).map_or_else(Vec::new, |keys| keys.iter().copied().collect())
+    }
+    /// Writes back and drops from memory the entries of `group` that are not borrowed, and returns how many there
+    /// were. They stay in the group.
+    pub fn evict_group(&mut self, group: u64) -> Result<usize, CC::Error> { self.0.lock().evict_group(group) }
+    /// Declares that the value of `derived` was computed from that of `source`, so that inserting, modifying
+    /// (releasing a `CMRefMut`) or removing `source` removes `derived` from memory and the backend, and in turn
+    /// whatever was derived from it. A borrowed derived entry is dropped rather than written back once released. The
+    /// graph is kept in memory only; an invalidated entry loses its edges, so declare them again when re-deriving it.
+    pub fn add_dependency(&mut self, derived: K, source: K) { self.0.lock().add_dependency(derived, source) }
+    /// The entries declared as derived from `k`.
+    pub fn dependents(&self, k: &K) -> Vec<K> {
+        self.0.lock().dependents.get(k).map_or_else(Vec::new, |keys| keys.iter().copied().collect())
+    }
     pub fn remove(&mut self, k: &K) -> Result<(), CC::Error> { self.0.lock().remove(k) }
+    pub fn remove_many(&mut self, keys: &[K]) -> Result<(), CC::Error> { self.0.lock().remove_many(keys) }
+    /// Announces every change this cache makes to the backend on `bus`, and drops its in-memory copy of every key
+    /// that another cache on `bus` changes, for caches in several processes over the same storage. Any change to that
+    /// copy not yet written back is lost, and a borrowed copy is dropped once released. Only `Retrieval::Copy`
+    /// backends can be shared like this. Changes are announced once they reach the backend, so a value inserted here
+    /// is only seen elsewhere after it is evicted, flushed or committed.
+    pub fn with_bus(self, bus: impl InvalidationBus<K>+'static) -> Self where
+    K: Send+'static, V: Send+Sync+'static, CC: Send+'static, L: Send+Sync+'static {
+        let weak = self.downgrade();
+        bus.subscribe(Box::new(move |change| {
+            if let Some(cache) = weak.upgrade() {
+                cache.0.lock().forget(change.key());
+            }
+        }));
+        self.0.lock().bus = Some(Arc::new(bus));
+        self
+    }
+    /// Returns a channel of the entries that leave the cache from now on, replacing any earlier one. At most
+    /// `capacity` events are buffered; further events are dropped until the receiver catches up. Range removals only
+    /// report the keys that were held in memory or tagged.
+    pub fn subscribe(&self, capacity: usize) -> Receiver<(K, EvictReason)> {
+        let (sender, receiver) = std::sync::mpsc::sync_channel(capacity);
+        self.0.lock().events = Some(sender);
+        receiver
+    }
+    /// Removes every key in `range`, for backends that keep their keys ordered.
+    pub fn remove_range(&mut self, range: impl RangeBounds<K>) -> Result<(), CC::Error> where CC: CacheRangeCompatible<K, V>, K: PartialOrd {
+        self.0.lock().remove_range(range)
+    }
+    /// Removes every entry whose key `matches`, from memory and from the backend, which filters its own keys (see
+    /// `ListKeys::remove_where`) rather than having each removed on its own. Panics if a matching entry is borrowed.
+    pub fn clear_where(&mut self, matches: impl Fn(&K) -> bool) -> Result<(), CC::Error> where CC: ListKeys<K, V> {
+        self.0.lock().clear_where(matches)
+    }
+    /// Whether `k` has a value, in memory or in the backend. Neither loads the value nor counts as a use of it for
+    /// eviction.
     pub fn contains(&self, k: &K) -> bool { self.0.lock().contains(k) }
-    pub fn get(&self, k: &K) -> Result<CMRef<K, V, CC>, CC::Error> {
-        self.0.lock().get(k).map(|v|
-            CMRef { item: v, _drop: RefReturn { k: *k, cache: self.0.clone() } }
-        )
+    /// Like `contains`, only looking at the values held in memory, so the backend is not asked.
+    pub fn contains_cached(&self, k: &K) -> bool { self.0.lock().contains_cached(k) }
+    /// Values past their TTL are returned as they are; `CMRef::is_stale` tells them apart.
+    pub fn get(&self, k: &K) -> Result<CMRef<K, V, CC, L>, CC::Error> {
+        let mut base = self.0.lock();
+        if base.poisoned.contains(k) || base.closing {
+            panic!();
+        }
+        let item = base.get(k)?;
+        Ok(CMRef { item, stale: base.expired(k), _drop: RefReturn { k: *k, cache: self.0.clone(), since: base.clock.now(), write: false, modified: false } })
+    }
+    /// Like `get`, but `None` for a key that `contains` says has no value, so that misses are not errors.
+    pub fn get_if_present(&self, k: &K) -> Result<MaybeRef<K, V, CC, L>, CC::Error> {
+        if !self.contains(k) {
+            return Ok(None);
+        }
+        self.get(k).map(Some)
+    }
+    /// Like `get`, but panics with the key and the backend's error if it fails, for tests and prototypes.
+    pub fn get_expect(&self, k: &K) -> CMRef<K, V, CC, L> where K: std::fmt::Debug, CC::Error: std::fmt::Debug {
+        self.get(k).unwrap_or_else(|e| panic!("failed to get {k:?}: {e:?}"))
     }
-    pub fn get_mut(&self, k: &K) -> Result<CMRefMut<K, V, CC>, CC::Error> {
-        self.0.lock().get_mut(k).map(|v|
-            CMRefMut { item: v, _drop: RefReturn { k: *k, cache: self.0.clone() } }
+    /// Like `get`, and if the value has to be loaded from the backend, `hint` decides whether and how it is kept in
+    /// memory afterwards.
+    pub fn get_with_hint(&self, k: &K, hint: Hint) -> Result<CMRef<K, V, CC, L>, CC::Error> {
+        let mut base = self.0.lock();
+        if base.poisoned.contains(k) || base.closing {
+            panic!();
+        }
+        let loads = !base.active.contains_key(k) && !base.lru.contains(k) && !base.unwritten.contains_key(k);
+        let item = base.get(k)?;
+        if loads && hint != Hint::Normal {
+            base.hints.insert(*k, hint);
+        }
+        Ok(CMRef { item, stale: base.expired(k), _drop: RefReturn { k: *k, cache: self.0.clone(), since: base.clock.now(), write: false, modified: false } })
+    }
+    /// Like `get`, and gives the entry `priority` until it is evicted or removed.
+    pub fn get_with_priority(&self, k: &K, priority: Priority) -> Result<CMRef<K, V, CC, L>, CC::Error> {
+        let guard = self.get(k)?;
+        self.0.lock().set_priority(*k, priority);
+        Ok(guard)
+    }
+    /// A copy of the value of `k` that can be kept and sent to other threads. The entry is only borrowed while it is
+    /// copied, so it can be changed or evicted while the copy is alive.
+    pub fn get_arc(&self, k: &K) -> Result<Arc<V>, CC::Error> where V: Clone {
+        Ok(Arc::new(V::clone(&*self.get(k)?)))
+    }
+    /// Like `get`, but fails with `LockError::Poisoned` for a poisoned entry, and with `LockError::WouldDeadlock`
+    /// instead of waiting while the entry is borrowed mutably.
+    pub fn try_get(&self, k: &K) -> LockResult<K, CMRef<K, V, CC, L>, CC::Error> {
+        let mut base = self.0.lock();
+        if base.closing {
+            return Err(LockError::Closing);
+        }
+        if base.poisoned.contains(k) {
+            return Err(LockError::Poisoned(*k));
+        }
+        if base.active.get(k).is_some_and(|(_, v)| v.is_locked_exclusive()) {
+            return Err(LockError::WouldDeadlock(*k));
+        }
+        let item = base.get(k).map_err(|e| LockError::Backend(*k, e))?;
+        Ok(CMRef { item, stale: base.expired(k), _drop: RefReturn { k: *k, cache: self.0.clone(), since: base.clock.now(), write: false, modified: false } })
+    }
+    /// Like `get`, and if the value is stale, wakes the `RefreshAhead` task spawned for this cache (if any) to reload
+    /// it right away. The stale value is returned without waiting for the reload.
+    pub fn get_stale_ok(&self, k: &K) -> Result<CMRef<K, V, CC, L>, CC::Error> {
+        let r = self.get(k)?;
+        let mut base = self.0.lock();
+        if r.is_stale() && let Some(refresher) = &base.refresher {
+            refresher.unpark();
+            base.wanted.insert(*k);
+        }
+        Ok(r)
+    }
+    pub(crate) fn set_refresher(&self, refresher: Option<std::thread::Thread>) { self.0.lock().refresher = refresher; }
+    pub fn get_mut(&self, k: &K) -> Result<CMRefMut<K, V, CC, L>, CC::Error> {
+        let mut base = self.0.lock();
+        if base.poisoned.contains(k) || base.closing {
+            panic!();
+        }
+        base.get_mut(k).map(|v|
+            CMRefMut { item: v, _drop: RefReturn { k: *k, cache: self.0.clone(), since: base.clock.now(), write: true, modified: false } }
         )
     }
+    /// Like `get_mut`, but panics with the key and the backend's error if it fails, for tests and prototypes.
+    pub fn get_mut_expect(&self, k: &K) -> CMRefMut<K, V, CC, L> where K: std::fmt::Debug, CC::Error: std::fmt::Debug {
+        self.get_mut(k).unwrap_or_else(|e| panic!("failed to get {k:?} mutably: {e:?}"))
+    }
+    /// Like `get_mut`, but fails with `LockError::Poisoned` for a poisoned entry, and with `LockError::WouldDeadlock`
+    /// instead of panicking while the entry is borrowed.
+    pub fn try_get_mut(&self, k: &K) -> LockResult<K, CMRefMut<K, V, CC, L>, CC::Error> {
+        let mut guards = self.get_mut_many(std::slice::from_ref(k))?;
+        Ok(guards.pop().unwrap())
+    }
+    /// Like `insert`, but fails with `LockError::WouldDeadlock` instead of panicking while the entry is borrowed.
+    pub fn try_insert(&mut self, k: K, v: V) -> LockResult<K, (), CC::Error> {
+        let mut base = self.0.lock();
+        if base.active.contains_key(&k) {
+            return Err(LockError::WouldDeadlock(k));
+        }
+        base.insert(k, v).map_err(|e| LockError::Backend(k, e))
+    }
+    /// Like `remove`, but fails with `LockError::WouldDeadlock` instead of panicking while the entry is borrowed.
+    pub fn try_remove(&mut self, k: &K) -> LockResult<K, (), CC::Error> {
+        let mut base = self.0.lock();
+        if base.active.contains_key(k) {
+            return Err(LockError::WouldDeadlock(*k));
+        }
+        base.remove(k).map_err(|e| LockError::Backend(*k, e))
+    }
+    /// Sets what happens to entries whose `CMRefMut` is dropped by a panic. Defaults to `PoisonPolicy::Fail`.
+    pub fn with_poison_policy(self, policy: PoisonPolicy) -> Self {
+        self.0.lock().poison_policy = policy;
+        self
+    }
+    /// Moves entries that are still marked as borrowed, although no guard holds their lock, back to the in-memory
+    /// layer, and returns their keys. This only happens when a guard's drop was missed.
+    pub fn purge_inactive_locks(&mut self) -> Result<Vec<K>, CC::Error> { self.0.lock().purge_inactive_locks() }
+    /// Stops treating `k` as borrowed, for repairing entries whose guard was leaked (with `std::mem::forget`, for
+    /// example). If it is still borrowed mutably, the value in memory is discarded, so the next access reads it from
+    /// the backend; `Retrieval::Take` backends no longer have it. A `CMRefMut` that is in fact still alive keeps
+    /// working, but its changes are lost. Returns whether `k` was borrowed.
+    pub fn force_deactivate(&mut self, k: &K) -> Result<bool, CC::Error> { self.0.lock().force_deactivate(k) }
+    pub fn is_poisoned(&self, k: &K) -> bool { self.0.lock().poisoned.contains(k) }
+    /// Lets a poisoned entry be read from the backend again. Returns whether it was poisoned.
+    pub fn clear_poison(&self, k: &K) -> bool { self.0.lock().poisoned.remove(k) }
+    /// Borrows every one of `keys` mutably at once, returning the guards in the same order, or none of them. Fails
+    /// with `LockError::WouldDeadlock` instead of waiting if one of the keys is already borrowed or repeated, and with
+    /// `LockError::Poisoned` if one is poisoned. The keys
+    /// are all taken under the cache's lock, so callers updating several entries together need not agree on an order.
+    pub fn get_mut_many(&self, keys: &[K]) -> ManyResult<K, CMRefMut<K, V, CC, L>, CC::Error> {
+        let mut base = self.0.lock();
+        if base.closing {
+            return Err(LockError::Closing);
+        }
+        let guards = base.get_mut_many(keys)?;
+        let since = base.clock.now();
+        Ok(guards.into_iter().zip(keys).map(|(item, k)| CMRefMut {
+            item, _drop: RefReturn { k: *k, cache: self.0.clone(), since, write: true, modified: false },
+        }).collect())
+    }
+    /// Runs `f` with a `Transaction` whose changes are staged, and applies them all once `f` returns `Ok`. If `f`
+    /// returns an error or panics, nothing is changed. Panics without applying anything if one of the changed
+    /// entries is borrowed at that point. Fails with `Conflict`, applying nothing, if an entry `f` read was changed
+    /// by someone else in the meantime. A backend error while applying is returned once the entries changed so far
+    /// are set back to their earlier values, as far as the backend allows.
+    pub fn transaction<R, E>(&mut self, f: impl FnOnce(&mut Transaction<K, V, CC, L>) -> Result<R, E>) -> Result<R, E> where
+    V: Clone, E: From<CC::Error>+From<Conflict<K>> {
+        self.0.lock().transactions += 1;
+        // Declared before `base`, so that it is dropped, ending the transaction, once the lock is released.
+        let mut txn = Transaction {cache: self, staged: HashMap::new(), read: RefCell::new(HashMap::new())};
+        let r = f(&mut txn)?;
+        let staged: Vec<(K, Option<V>)> = std::mem::take(&mut txn.staged).into_iter().collect();
+        let read = txn.read.take();
+        let mut base = self.0.lock();
+        if staged.iter().any(|(k, _)| base.active.contains_key(k)) {
+            panic!();
+        }
+        if let Some((k, _)) = read.into_iter().find(|(k, version)| base.version(k) != *version) {
+            return Err(Conflict(k).into());
+        }
+        let mut prior = Vec::with_capacity(staged.len());
+        for (k, _) in &staged {
+            prior.push((*k, base.snapshot(k)?));
+        }
+        for (done, (k, v)) in staged.into_iter().enumerate() {
+            let applied = match v {
+                Some(v) => base.insert(k, v),
+                None => base.remove(&k),
+            };
+            if let Err(e) = applied {
+                // The failed step may have changed its entry partway, so it is set back too.
+                for (k, v) in prior.into_iter().take(done+1).rev() {
+                    let _ = match v {
+                        Some(v) => base.insert(k, v),
+                        None => base.remove(&k),
+                    };
+                }
+                return Err(e.into());
+            }
+        }
+        Ok(r)
+    }
     pub fn commit(&mut self) -> Result<(), CC::Error> { self.0.lock().commit() }
+    /// Runs `commit` on a new thread and returns its handle, so that a checkpoint or shutdown need not wait for it.
+    /// Other calls on the cache wait while it holds the cache's lock, and it panics if an entry is borrowed.
+    pub fn spawn_commit(&self) -> JoinHandle<Result<(), CC::Error>> where
+    K: Send+'static, V: Send+Sync+'static, CC: Send+'static, CC::Error: Send+'static, L: Send+Sync+'static {
+        let mut cache = self.clone();
+        std::thread::spawn(move || cache.commit())
+    }
+    /// Like `spawn_commit`, running `flush`, which leaves borrowed entries alone.
+    pub fn spawn_flush(&self) -> JoinHandle<Result<(), CC::Error>> where
+    K: Send+'static, V: Send+Sync+'static, CC: Send+'static, CC::Error: Send+'static, L: Send+Sync+'static {
+        let mut cache = self.clone();
+        std::thread::spawn(move || cache.flush())
+    }
+    /// Like `commit`, but first serializes the modified values on one thread per core, for backends that store
+    /// serialized values (see `CacheMutCompatible::SERIALIZED`). Others are committed as by `commit`.
+    #[cfg(feature = "parallel")]
+    pub fn commit_parallel(&mut self) -> Result<(), CC::Error> where K: Sync, V: Send+Sync, CC::Error: Send, L: Send+Sync {
+        self.0.lock().commit_parallel()
+    }
+    /// Writes back every entry that is not currently borrowed and commits the backend. Unlike `commit`, this
+    /// can be called while guards are alive.
+    pub fn flush(&mut self) -> Result<(), CC::Error> { self.0.lock().flush() }
+    /// Runs `f` on the backend under the cache's lock, for calling its own methods. Modified entries that are not
+    /// borrowed are written back first, so that the backend is up to date apart from borrowed ones; those of a
+    /// `Retrieval::Take` backend are written back whether modified or not.
+    pub fn with_backend<R>(&mut self, f: impl FnOnce(&mut CC) -> R) -> Result<R, CC::Error> {
+        let mut base = self.0.lock();
+        base.settle()?;
+        Ok(f(&mut base.compatible))
+    }
+    /// A page of the backend's keys (see `ListKeys::keys_paged`), for enumerating a huge backend bit by bit. Modified
+    /// entries are written back first, as by `with_backend`, so that the backend has every key.
+    pub fn keys_paged(&mut self, cursor: usize, limit: usize) -> Result<(Vec<K>, Option<usize>), CC::Error> where CC: ListKeys<K, V> {
+        self.with_backend(|cc| cc.keys_paged(cursor, limit))
+    }
+    pub(crate) fn maintain_backend<R>(&mut self, f: impl FnOnce(&mut CC) -> R) -> R { f(&mut self.0.lock().compatible) }
     pub fn cap(&self) -> usize { self.0.lock().cap() }
+    /// Keeps up to `capacity` released entries in memory from now on, writing back the least recently used ones
+    /// over it. An adaptive capacity (see `with_adaptive_capacity`) moves on from it after its next window.
+    pub fn set_capacity(&mut self, capacity: usize) -> Result<(), CC::Error> { self.0.lock().set_capacity(capacity) }
+    /// Keeps the capacity at or below `limit`, shrinking it now if needed, until called again with `None`; neither
+    /// `set_capacity` nor an adaptive capacity go past it meanwhile. `pressure::MemoryPressure` uses this.
+    pub fn limit_capacity(&mut self, limit: Option<usize>) -> Result<(), CC::Error> {
+        let mut base = self.0.lock();
+        base.ceiling = limit;
+        let capacity = base.cap();
+        base.set_capacity(capacity)
+    }
+    pub fn stats(&self) -> CacheMutStats { self.0.lock().stats() }
+    /// What the backend takes on disk and how many entries it holds, if it reports that (see
+    /// `CacheMutCompatible::resource_usage`). Modified entries still in memory are not counted.
+    pub fn usage(&self) -> Option<Usage> { self.0.lock().compatible.resource_usage() }
     pub fn active(&self, k: &K) -> bool { self.0.lock().active(k) }
     pub fn num_active(&self) -> usize { self.0.lock().num_active() }
+    /// Closes the cache and hands back its backend: stops lending out entries (`get` and `get_mut` panic, and the
+    /// `try_` variants fail with `LockError::Closing`), waits up to `timeout` for the guards still alive to be
+    /// dropped, and commits. Every other clone of this `CacheMut` must have been dropped by then.
+    pub fn shutdown(self, timeout: Duration) -> Result<CC, ShutdownError<K, V, CC, L>> {
+        let deadline = Instant::now()+timeout;
+        let mut base = self.0.lock();
+        base.closing = true;
+        let drained = base.drained.clone();
+        while !base.active.is_empty() && !drained.wait_until(&mut base, deadline).timed_out() {}
+        base.closing = false;
+        if !base.active.is_empty() {
+            let held = base.active.keys().copied().collect();
+            drop(base);
+            return Err(ShutdownError::Held(self, held));
+        }
+        base.commit().map_err(ShutdownError::Backend)?;
+        drop(base);
+        let mut base = match Arc::try_unwrap(self.0) {
+            Ok(base) => base.into_inner(),
+            Err(shared) => return Err(ShutdownError::Shared(Self(shared))),
+        };
+        base.closed = true;
+        // Safe, as `closed` keeps `compatible` from being dropped with the rest.
+        Ok(unsafe { ManuallyDrop::take(&mut base.compatible) })
+    }
+}
+
+/// Inserts every entry, as `insert`. Panics if one of the keys is borrowed or the backend fails.
+impl<K, V, CC, L> Extend<(K, V)> for CacheMut<K, V, CC, L> where
+K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V>, CC::Error: std::fmt::Debug, L: RawRwLock {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, entries: I) {
+        let mut base = self.0.lock();
+        for (k, v) in entries {
+            base.insert(k, v).expect("backend failed to take an entry");
+        }
+    }
+}
+/// Collects the entries into the `HashMap` backend of a cache that keeps up to 1024 of them in memory.
+impl<K, V> FromIterator<(K, V)> for CacheMut<K, V, HashMap<K, V>> where K: Copy+Eq+std::hash::Hash {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(entries: I) -> Self {
+        CacheMut::new(entries.into_iter().collect(), 1024)
+    }
+}
+/// Drains the cache: every entry is removed, as by `remove`, and yielded in no particular order. Other clones of the
+/// `CacheMut` are left empty too. Panics if an entry is borrowed or the backend fails.
+impl<K, V, CC, L> IntoIterator for CacheMut<K, V, CC, L> where
+K: Copy+Eq+std::hash::Hash, CC: ListKeys<K, V>, CC::Error: std::fmt::Debug, L: RawRwLock {
+    type Item = (K, V);
+    type IntoIter = std::vec::IntoIter<(K, V)>;
+    fn into_iter(self) -> Self::IntoIter {
+        let mut base = self.0.lock();
+        base.commit().expect("backend failed to commit");
+        let entries: Vec<(K, V)> = base.compatible.keys().into_iter().map(|k| {
+            let v = base.compatible.get(k).expect("backend failed to hand out a listed key");
+            base.remove(&k).expect("backend failed to remove a key");
+            (k, v)
+        }).collect();
+        entries.into_iter()
+    }
+}
+
+/// Why `CacheMut::transaction` applied nothing although its closure succeeded: the entry of this key, which the
+/// closure read, was changed by someone else in the meantime. Running the transaction again reads the new value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Conflict<K>(pub K);
+impl<K: std::fmt::Debug> std::fmt::Display for Conflict<K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} was changed during the transaction", self.0)
+    }
+}
+impl<K: std::fmt::Debug> std::error::Error for Conflict<K> {}
+
+/// Changes staged by `CacheMut::transaction`. Reads see the staged changes; values are copied out of the cache, so
+/// no entry stays borrowed.
+pub struct Transaction<'a, K, V, CC, L = parking_lot::RawRwLock> where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V>, L: RawRwLock {
+    cache: &'a CacheMut<K, V, CC, L>,
+    /// `None` for a staged removal.
+    staged: HashMap<K, Option<V>>,
+    /// The version of each entry read from the cache (see `CacheMutBase::version`), as of before it was read.
+    read: RefCell<HashMap<K, u64>>,
+}
+impl<K, V, CC, L> Drop for Transaction<'_, K, V, CC, L> where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V>, L: RawRwLock {
+    fn drop(&mut self) {
+        self.cache.0.lock().end_transaction();
+    }
+}
+impl<K, V, CC, L> std::fmt::Debug for Transaction<'_, K, V, CC, L> where K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V>, L: RawRwLock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Transaction").field("staged", &self.staged.len()).finish_non_exhaustive()
+    }
+}
+impl<K, V, CC, L> Transaction<'_, K, V, CC, L> where K: Copy+Eq+std::hash::Hash, V: Clone, CC: CacheMutCompatible<K, V>, L: RawRwLock {
+    /// Notes the version of `k` the first time it is read from the cache.
+    fn watch(&self, k: &K) {
+        if !self.read.borrow().contains_key(k) {
+            let version = self.cache.0.lock().version(k);
+            self.read.borrow_mut().insert(*k, version);
+        }
+    }
+    pub fn contains(&self, k: &K) -> bool {
+        match self.staged.get(k) {
+            Some(v) => v.is_some(),
+            None => {
+                self.watch(k);
+                self.cache.contains(k)
+            },
+        }
+    }
+    /// A copy of the value of `k`, or `None` if there is none.
+    pub fn get(&self, k: &K) -> Result<Option<V>, CC::Error> {
+        if let Some(v) = self.staged.get(k) {
+            return Ok(v.clone());
+        }
+        self.watch(k);
+        if !self.cache.contains(k) {
+            return Ok(None);
+        }
+        Ok(Some(self.cache.get(k)?.clone()))
+    }
+    /// The staged value of `k`, copied from the cache first if it has not been changed yet, or `None` if there is none.
+    pub fn get_mut(&mut self, k: &K) -> Result<Option<&mut V>, CC::Error> {
+        if !self.staged.contains_key(k) {
+            let v = self.get(k)?;
+            self.staged.insert(*k, v);
+        }
+        Ok(self.staged.get_mut(k).and_then(Option::as_mut))
+    }
+    pub fn insert(&mut self, k: K, v: V) {
+        self.staged.insert(k, Some(v));
+    }
+    pub fn remove(&mut self, k: &K) {
+        self.staged.insert(*k, None);
+    }
 }
@@ -0,0 +1,35 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use parking_lot::Mutex;
+
+/// Where the time-based features (TTLs, refresh-ahead, maintenance and guard hold times) get the current time from.
+pub trait Clock: Send+Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The wall clock, used unless another one is given.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> Instant { Instant::now() }
+}
+
+/// A clock that only moves when told to, for tests and simulations. Clones share the same time.
+#[derive(Clone, Debug)]
+pub struct MockClock {start: Instant, offset: Arc<Mutex<Duration>>}
+impl MockClock {
+    pub fn new() -> Self {
+        Self {start: Instant::now(), offset: Arc::new(Mutex::new(Duration::ZERO))}
+    }
+    pub fn advance(&self, by: Duration) {
+        *self.offset.lock() += by;
+    }
+    /// How far the clock has been advanced since it was created.
+    pub fn elapsed(&self) -> Duration { *self.offset.lock() }
+}
+impl Default for MockClock {
+    fn default() -> Self { Self::new() }
+}
+impl Clock for MockClock {
+    fn now(&self) -> Instant { self.start+*self.offset.lock() }
+}
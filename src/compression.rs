@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+
+/// The shortest back-reference worth encoding.
+const MIN_MATCH: usize = 4;
+
+/// Bytes that values to be compressed are likely to share, such as the field names of similar JSON documents.
+/// Back-references may point into it, as if every value were preceded by it.
+pub(crate) struct Dictionary {bytes: Vec<u8>, last: HashMap<[u8; MIN_MATCH], usize>}
+impl Dictionary {
+    pub(crate) fn new(bytes: Vec<u8>) -> Self {
+        let last = bytes.windows(MIN_MATCH).enumerate().map(|(i, key)| (key.try_into().unwrap(), i)).collect();
+        Self {bytes, last}
+    }
+    #[cfg(feature = "dictionary")]
+    pub(crate) fn bytes(&self) -> &[u8] {&self.bytes}
+    /// Builds a dictionary of up to `max_bytes` out of the 32-byte segments of `samples` whose 8-byte substrings
+    /// recur in the most other samples. Segments mostly covered by those already chosen are skipped, and the most useful segments go last,
+    /// where back-references to them are shortest.
+    #[cfg(feature = "dictionary")]
+    pub(crate) fn train(samples: &[Vec<u8>], max_bytes: usize) -> Self {
+        use std::collections::HashSet;
+
+        const GRAM: usize = 8;
+        const SEGMENT: usize = 32;
+        let mut spread: HashMap<&[u8], u64> = HashMap::new();
+        for sample in samples {
+            for gram in sample.windows(GRAM).collect::<HashSet<_>>() {
+                *spread.entry(gram).or_default() += 1;
+            }
+        }
+        let score = |segment: &[u8], covered: &HashSet<&[u8]>| -> u64 {
+            segment.windows(GRAM).filter(|gram| !covered.contains(gram)).map(|gram| spread[gram]-1).sum()
+        };
+        let mut segments: Vec<(u64, &[u8])> = samples.iter().flat_map(|sample| sample.chunks(SEGMENT))
+            .map(|segment| (score(segment, &HashSet::new()), segment)).filter(|(score, _)| *score > 0).collect();
+        segments.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+        let (mut covered, mut chosen, mut len) = (HashSet::new(), Vec::new(), 0);
+        for (full, segment) in segments {
+            if len+segment.len() > max_bytes {
+                continue;
+            }
+            // Skips segments that mostly repeat ones already chosen.
+            if score(segment, &covered)*2 > full {
+                covered.extend(segment.windows(GRAM));
+                chosen.push(segment);
+                len += segment.len();
+            }
+        }
+        chosen.reverse();
+        Self::new(chosen.concat())
+    }
+}
+
+pub(crate) fn compress(data: &[u8]) -> Vec<u8> {compress_with(None, data)}
+pub(crate) fn decompress(data: &[u8], max_len: usize) -> Option<Vec<u8>> {decompress_with(None, data, max_len)}
+
+/// Compresses `data` with a simple LZ77 scheme: runs of literals, each followed by a back-reference of at least
+/// `MIN_MATCH` bytes into the output so far (or the dictionary before it), all lengths and offsets as LEB128 varints.
+/// It is this crate's own format, not zstd's, and is only kept inside a folder or in memory.
+pub(crate) fn compress_with(dictionary: Option<&Dictionary>, data: &[u8]) -> Vec<u8> {
+    let prefix = dictionary.map_or(&[][..], |d| &d.bytes);
+    // Positions count from the start of the dictionary.
+    let at = |pos: usize| if pos < prefix.len() {&prefix[pos..]} else {&data[pos-prefix.len()..]};
+    let mut out = Vec::with_capacity(data.len()/2+8);
+    let mut last: HashMap<[u8; MIN_MATCH], usize> = HashMap::new();
+    let (mut i, mut literal_start) = (0, 0);
+    while i+MIN_MATCH <= data.len() {
+        let key: [u8; MIN_MATCH] = data[i..i+MIN_MATCH].try_into().unwrap();
+        let candidate = last.insert(key, prefix.len()+i).or_else(|| dictionary.and_then(|d| d.last.get(&key).copied()));
+        // A match starting in the dictionary may run on into the data.
+        let length = candidate.map_or(0, |pos| {
+            let source = at(pos).iter().chain(if pos < prefix.len() {data} else {&[]});
+            source.zip(&data[i..]).take_while(|(a, b)| a == b).count()
+        });
+        if length >= MIN_MATCH {
+            write_varint(&mut out, i-literal_start);
+            out.extend_from_slice(&data[literal_start..i]);
+            write_varint(&mut out, length);
+            write_varint(&mut out, prefix.len()+i-candidate.unwrap());
+            i += length;
+            literal_start = i;
+        } else {
+            i += 1;
+        }
+    }
+    write_varint(&mut out, data.len()-literal_start);
+    out.extend_from_slice(&data[literal_start..]);
+    write_varint(&mut out, 0);
+    out
+}
+/// Undoes `compress_with`, or returns `None` if `data` is corrupt or would decompress to more than `max_len` bytes,
+/// so that a corrupt length can not make it allocate without bound.
+pub(crate) fn decompress_with(dictionary: Option<&Dictionary>, mut data: &[u8], max_len: usize) -> Option<Vec<u8>> {
+    let mut out = dictionary.map_or_else(Vec::new, |d| d.bytes.clone());
+    let prefix = out.len();
+    let limit = prefix.checked_add(max_len)?;
+    out.reserve(data.len().saturating_mul(2).min(max_len));
+    loop {
+        let literals = read_varint(&mut data)?;
+        out.len().checked_add(literals).filter(|&len| len <= limit)?;
+        out.extend_from_slice(data.get(..literals)?);
+        data = &data[literals..];
+        let length = read_varint(&mut data)?;
+        if length == 0 {
+            return data.is_empty().then(|| out.split_off(prefix));
+        }
+        let offset = read_varint(&mut data)?;
+        let start = out.len().checked_sub(offset).filter(|_| offset > 0)?;
+        out.len().checked_add(length).filter(|&len| len <= limit)?;
+        // Byte by byte, since a match may overlap the bytes it produces.
+        for i in start..start+length {
+            out.push(out[i]);
+        }
+    }
+}
+fn write_varint(out: &mut Vec<u8>, mut n: usize) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+fn read_varint(data: &mut &[u8]) -> Option<usize> {
+    let mut n = 0usize;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = data.split_first()?;
+        *data = rest;
+        n |= ((byte & 0x7f) as usize).checked_shl(shift)?;
+        if byte < 0x80 {
+            return Some(n);
+        }
+    }
+    None
+}
@@ -0,0 +1,100 @@
+use std::path::PathBuf;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+
+use crate::{CacheMut, PoisonPolicy};
+use crate::folder_compatible::{Durability, FolderCache, FolderCacheError, SizeClasses};
+
+/// How `CacheConfig::folder` is opened.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OpenMode {
+    /// Keeps the entries already in the folder (`FolderCache::continued`).
+    #[default]
+    Continue,
+    /// Starts empty (`FolderCache::cleared`).
+    Clear,
+    /// Refuses writes (`FolderCache::read_only`).
+    ReadOnly,
+}
+
+/// A `CacheMut` over a `FolderCache`, described as data so that it can be kept in a configuration file. Every field
+/// but `folder` and `capacity` may be left out; durations are strings such as `"500ms"`, `"30s"`, `"5m"` or `"2h"`.
+/// In TOML:
+/// ```text
+/// folder = "/var/cache/thumbnails"
+/// capacity = 1024
+/// durability = "every_write"
+/// size_classes = { ladder = [64, 256, 1024] }
+/// ttl = "10m"
+/// ```
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheConfig {
+    pub folder: PathBuf,
+    /// How many released entries are kept in memory; see `CacheMut::new`.
+    pub capacity: usize,
+    #[serde(default)]
+    pub open: OpenMode,
+    /// When writes are flushed to disk.
+    #[serde(default)]
+    pub durability: Durability,
+    #[serde(default)]
+    pub size_classes: SizeClasses,
+    #[serde(default)]
+    pub poison_policy: PoisonPolicy,
+    #[serde(default, with = "optional_duration")]
+    pub ttl: Option<Duration>,
+    #[serde(default, with = "optional_duration")]
+    pub tti: Option<Duration>,
+    /// See `FolderCache::with_max_value_size`.
+    #[serde(default)]
+    pub max_value_size: Option<u64>,
+}
+
+impl<K, V> CacheMut<K, V, FolderCache<K>> where
+K: Copy+Eq+std::hash::Hash+Serialize+for <'a> Deserialize<'a>, V: Serialize+for <'a> Deserialize<'a> {
+    /// Opens the folder and builds the cache that `config` describes. Invalid size classes fail with an `IO` error
+    /// of kind `InvalidInput`.
+    pub fn from_config(config: &CacheConfig) -> Result<Self, FolderCacheError> {
+        if !config.size_classes.is_valid() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid size classes").into());
+        }
+        let folder = config.folder.clone();
+        let mut folder = match config.open {
+            OpenMode::Continue => FolderCache::continued(folder)?,
+            OpenMode::Clear => FolderCache::cleared(folder)?,
+            OpenMode::ReadOnly => FolderCache::read_only(folder)?,
+        }.with_durability(config.durability).with_size_classes(config.size_classes.clone());
+        if let Some(max) = config.max_value_size {
+            folder = folder.with_max_value_size(max);
+        }
+        let mut cache = CacheMut::new(folder, config.capacity).with_poison_policy(config.poison_policy);
+        if let Some(ttl) = config.ttl {
+            cache = cache.with_ttl(ttl);
+        }
+        if let Some(tti) = config.tti {
+            cache = cache.with_tti(tti);
+        }
+        Ok(cache)
+    }
+}
+
+/// Durations as the strings `maintenance::MaintenancePolicy` reads.
+mod optional_duration {
+    use std::time::Duration;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(d: &Option<Duration>, s: S) -> Result<S::Ok, S::Error> {
+        match d {
+            Some(d) if d.subsec_nanos() == 0 => s.serialize_some(&format!("{}s", d.as_secs())),
+            Some(d) => s.serialize_some(&format!("{}ms", d.as_millis())),
+            None => s.serialize_none(),
+        }
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+        let Some(text) = Option::<String>::deserialize(d)? else {return Ok(None)};
+        crate::maintenance::parse_duration(&text).map(Some)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid duration `{text}`")))
+    }
+}
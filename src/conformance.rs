@@ -0,0 +1,103 @@
+use std::fmt::Debug;
+
+use crate::{CacheMut, CacheMutCompatible, Retrieval};
+use crate::testing::ModelChecker;
+
+/// A test suite for backends, spelling out what `CacheMut` expects of a `CacheMutCompatible` implementation. Each
+/// check starts from a fresh, empty backend made by `backend`, and panics with a description of the first thing
+/// that does not behave as expected:
+/// ```ignore
+/// Conformance::new(|| MyBackend::default(), |i| i as u32, |i| format!("value {i}")).run();
+/// ```
+pub struct Conformance<K, V, CC>(ModelChecker<K, V, CC>);
+impl<K, V, CC> Conformance<K, V, CC> where
+K: Copy+Eq+std::hash::Hash+Debug, V: Clone+PartialEq+Debug, CC: CacheMutCompatible<K, V>, CC::Error: Debug {
+    /// `key` and `value` must give distinct keys and values for distinct numbers.
+    pub fn new(backend: impl FnMut() -> CC+'static, key: impl Fn(usize) -> K+'static, value: impl Fn(usize) -> V+'static) -> Self {
+        Self(ModelChecker::new(backend, key, value).with_keys(5))
+    }
+    /// Runs every check.
+    pub fn run(&mut self) {
+        self.round_trip();
+        self.missing_keys();
+        self.eviction_writes_back();
+        self.interleavings(200);
+    }
+    /// Values come back from `get` as they were inserted, `contains` follows inserts and removes, and a `Take`
+    /// backend gives its value up on `get` until it is handed back through `replace`.
+    pub fn round_trip(&mut self) {
+        let mut cc = (self.0.backend)();
+        let (k, v) = ((self.0.key)(0), (self.0.value)(0));
+        assert!(!cc.contains(k), "a fresh backend contains {k:?}");
+        cc.insert(k, v.clone()).expect("insert failed");
+        assert!(cc.contains(k), "{k:?} is missing after insert");
+        assert_eq!(cc.get(k).expect("get failed"), v);
+        match CC::RETRIEVAL {
+            Retrieval::Copy => assert!(cc.contains(k), "a Copy backend lost {k:?} on get"),
+            Retrieval::Take => {
+                assert!(!cc.contains(k), "a Take backend kept {k:?} on get");
+                cc.replace(k, v.clone()).expect("replace failed");
+                assert!(cc.contains(k), "{k:?} is missing after replace");
+            },
+        }
+        let newer = (self.0.value)(1);
+        cc.insert(k, newer.clone()).expect("insert over an existing value failed");
+        assert_eq!(cc.get(k).expect("get failed"), newer);
+        if CC::RETRIEVAL == Retrieval::Take {
+            cc.replace(k, newer).expect("replace failed");
+        }
+        cc.remove(k).expect("remove failed");
+        assert!(!cc.contains(k), "{k:?} is still there after remove");
+        cc.commit().expect("commit failed");
+    }
+    /// `get` of an absent key is an error, which `CacheMut::get` passes on without leaving anything borrowed, and
+    /// removing an absent key is not.
+    pub fn missing_keys(&mut self) {
+        let mut cc = (self.0.backend)();
+        let k = (self.0.key)(0);
+        assert!(cc.get(k).is_err(), "get of an absent key succeeded");
+        cc.remove(k).expect("remove of an absent key failed");
+        let mut cache = CacheMut::new(cc, 2);
+        assert!(cache.get(&k).is_err(), "CacheMut::get of an absent key succeeded");
+        assert!(cache.get_mut(&k).is_err(), "CacheMut::get_mut of an absent key succeeded");
+        assert_eq!(cache.num_active(), 0);
+        cache.insert(k, (self.0.value)(0)).expect("insert after a failed get failed");
+        assert_eq!(*cache.get(&k).expect("get failed"), (self.0.value)(0));
+        cache.commit().expect("commit failed");
+    }
+    /// A value modified through `CacheMut::get_mut` reaches the backend once it is pushed out of memory, and again on
+    /// `commit`.
+    pub fn eviction_writes_back(&mut self) {
+        let mut cache = CacheMut::new((self.0.backend)(), 1);
+        let (a, b) = ((self.0.key)(0), (self.0.key)(1));
+        cache.insert(a, (self.0.value)(0)).expect("insert failed");
+        cache.insert(b, (self.0.value)(1)).expect("insert failed");
+        *cache.get_mut(&a).expect("get_mut failed") = (self.0.value)(2);
+        drop(cache.get(&b).expect("get failed"));
+        assert_eq!(Self::stored(&mut cache, a), Some((self.0.value)(2)), "an evicted change was not written back");
+        *cache.get_mut(&b).expect("get_mut failed") = (self.0.value)(3);
+        cache.commit().expect("commit failed");
+        assert_eq!(Self::stored(&mut cache, b), Some((self.0.value)(3)), "a committed change was not written back");
+    }
+    /// Runs `steps` pseudo-random inserts, reads, writes, removes and commits through a `CacheMut` of capacity 2,
+    /// comparing them against a `HashMap` with a `testing::ModelChecker`. The sequence is the same on every run.
+    pub fn interleavings(&mut self, steps: usize) {
+        self.0.steps = steps;
+        if let Err(failure) = self.0.check(0) {
+            panic!("{failure}");
+        }
+    }
+    /// The backend's own value for `k`, read past the in-memory layer.
+    fn stored(cache: &mut CacheMut<K, V, CC>, k: K) -> Option<V> {
+        cache.maintain_backend(|cc| {
+            if !cc.contains(k) {
+                return None;
+            }
+            let v = cc.get(k).expect("get of a contained key failed");
+            if CC::RETRIEVAL == Retrieval::Take {
+                cc.replace(k, v.clone()).expect("replace failed");
+            }
+            Some(v)
+        })
+    }
+}
@@ -1,10 +1,18 @@
 use std::path::{Path,PathBuf};
 use std::fs::{OpenOptions,File};
 use serde::{Serialize,Deserialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap,BTreeSet,HashMap,HashSet};
+use std::ops::{Bound,Deref,DerefMut};
 use std::io::{Read,Write,Seek,SeekFrom};
 use std::ffi::OsStr;
 use std::mem::size_of;
+use std::borrow::Cow;
+use std::sync::{Arc,OnceLock};
+use std::sync::mpsc::{Receiver,Sender};
+use std::thread::JoinHandle;
+use parking_lot::{Condvar,Mutex};
+use lru::LruCache;
+use std::num::NonZeroUsize;
 use rmp_serde;
 use thiserror::Error;
 
@@ -17,15 +25,244 @@ pub enum FolderCacheError {
     #[error("I/O error: {0}")]
     IO(#[from] std::io::Error),
     #[error("Not present in cache")]
-    Nothing
+    Nothing,
+    #[error("folder is locked by another cache")]
+    AlreadyLocked,
+    #[error("cache was opened read-only")]
+    ReadOnly,
+    #[error("folder has interrupted writes; open it read-write once to recover them")]
+    NeedsRecovery,
+    #[error("bucket file was written by a newer format version ({0})")]
+    UnsupportedVersion(u32),
+    #[error("bucket file header is corrupt")]
+    BadHeader,
+    #[error("entry is at version {found}, expected {expected}")]
+    VersionMismatch {expected: u64, found: u64},
+    #[error("no index named {0}")]
+    NoIndex(String),
+    #[error("page layout mismatch: folder uses a stride of {found} bytes, expected {expected}")]
+    PageLayout {found: u64, expected: u64},
+    #[error("value is larger than the maximum of {0} bytes")]
+    TooLarge(u64),
+    #[error("compressed value is corrupt or its dictionary is missing")]
+    BadCompression,
+    #[error("manifest is corrupt")]
+    BadManifest,
+    #[error("no checkpoint named {0}")]
+    NoCheckpoint(String),
 }
 
-use crate::{CacheCompatible, CacheMutCompatible};
+use crate::{Appendable, CacheCompatible, CacheMutCompatible, CacheRangeCompatible, Retrieval, Serialized, Usage};
+use crate::compression::{Dictionary, compress_with, decompress_with};
+use crate::maintenance::Maintain;
+use crate::sharded::{ListKeys, page};
 
-const EXTENSION: &str = "cache";
+/// How a `FolderCache` names its files inside the folder. Bucket files are called `{prefix}{size}.{extension}`
+/// (`{prefix}{size}-{generation}.{extension}` once compaction has rewritten them), the manifest listing them
+/// `{prefix}manifest`, the journal `{prefix}journal.wal`, and with sharding enabled entries are spread by key hash
+/// over subdirectories named `{prefix}00`, `{prefix}01`, and so on. Caches sharing a folder must use different prefixes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Layout {prefix: String, extension: String, shards: u32}
+impl Default for Layout {
+    fn default() -> Self {
+        Self {prefix: String::new(), extension: "cache".to_string(), shards: 0}
+    }
+}
+impl Layout {
+    /// The prefix must not end in a digit, so that file names of different prefixes cannot be confused.
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        let prefix = prefix.into();
+        assert!(!prefix.ends_with(|c: char| c.is_ascii_digit()) && !prefix.contains(std::path::is_separator));
+        self.prefix = prefix;
+        self
+    }
+    pub fn with_extension(mut self, extension: impl Into<String>) -> Self {
+        let extension = extension.into();
+        assert!(!extension.is_empty() && !extension.contains('.') && !extension.contains(std::path::is_separator));
+        self.extension = extension;
+        self
+    }
+    /// Spreads entries over `shards` subdirectories (at most 256). 0 keeps everything in the folder itself.
+    /// Changing the shard count of an existing folder is safe: entries stay where they were written.
+    pub fn with_shards(mut self, shards: u32) -> Self {
+        assert!(shards <= 256);
+        self.shards = shards;
+        self
+    }
+    fn bucket_name(&self, size_per_item: u64, generation: u32) -> PathBuf {
+        match generation {
+            0 => PathBuf::from(format!("{}{}.{}", self.prefix, size_per_item, self.extension)),
+            _ => PathBuf::from(format!("{}{}-{}.{}", self.prefix, size_per_item, generation, self.extension)),
+        }
+    }
+    fn manifest_name(&self) -> String {format!("{}manifest", self.prefix)}
+    fn journal_name(&self) -> String {format!("{}{}", self.prefix, JOURNAL_NAME)}
+    fn blob_dir_name(&self) -> String {format!("{}blobs", self.prefix)}
+    fn index_dir_name(&self) -> String {format!("{}indexes", self.prefix)}
+    fn dictionary_dir_name(&self) -> String {format!("{}dictionaries", self.prefix)}
+    fn checkpoint_dir_name(&self) -> String {format!("{}checkpoints", self.prefix)}
+    fn pinned_name(&self) -> String {format!("{}pinned", self.prefix)}
+    /// The size class encoded in a bucket file name, or `None` if the path is not a bucket file.
+    fn size_class(&self, path: &Path) -> Option<u64> {
+        self.bucket_file(path).map(|(size_per_item, _)| size_per_item)
+    }
+    /// The size class and generation encoded in a bucket file name.
+    fn bucket_file(&self, path: &Path) -> Option<(u64, u32)> {
+        let rest = path.file_name()?.to_str()?.strip_prefix(self.prefix.as_str())?;
+        let (name, extension) = rest.split_once('.')?;
+        let (size, generation) = name.split_once('-').unwrap_or((name, "0"));
+        let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+        if extension == self.extension && digits(size) && digits(generation) {Some((size.parse().ok()?, generation.parse().ok()?))} else {None}
+    }
+    fn shard_name(&self, shard: u32) -> String {format!("{}{:02x}", self.prefix, shard)}
+    fn shard_number(&self, path: &Path) -> Option<u32> {
+        let rest = path.file_name()?.to_str()?.strip_prefix(self.prefix.as_str())?;
+        if rest.len() == 2 {u32::from_str_radix(rest, 16).ok()} else {None}
+    }
+    /// The folder itself followed by every shard directory, tagged with its shard number. With `create`, the
+    /// directories of all configured shards are created first.
+    fn bucket_dirs(&self, folder: &Path, create: bool) -> Result<Vec<(Option<u32>, PathBuf)>, FolderCacheError> {
+        if create {
+            for shard in 0..self.shards {
+                match std::fs::create_dir(folder.join(self.shard_name(shard))) {
+                    Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => return Err(e.into()),
+                    _ => {}
+                }
+            }
+        }
+        let mut dirs = vec![(None, folder.to_path_buf())];
+        for entry in folder.read_dir()? {
+            let entry = entry?;
+            if let Some(shard) = self.shard_number(&entry.path()).filter(|_| entry.file_type().is_ok_and(|t| t.is_dir())) {
+                dirs.push((Some(shard), entry.path()));
+            }
+        }
+        dirs.sort();
+        Ok(dirs)
+    }
+    /// The bucket files of `dir` that its manifest lists, with their size classes.
+    fn listed_buckets(&self, dir: &Path) -> Result<Vec<(PathBuf, u64)>, FolderCacheError> {
+        let manifest = Manifest::read(dir, self)?;
+        let mut buckets = Vec::new();
+        for file in dir.read_dir()? {
+            let path = file?.path();
+            if let Some((size_per_item, generation)) = self.bucket_file(&path)
+                && manifest.as_ref().is_none_or(|manifest| manifest.buckets.get(&size_per_item) == Some(&generation)) {
+                buckets.push((path, size_per_item));
+            }
+        }
+        Ok(buckets)
+    }
+    /// Removes every file this layout owns from `folder` and its shard directories. Each directory's manifest is
+    /// emptied first, so that a crash part way leaves files that the next open deletes rather than half a cache.
+    fn clear(&self, folder: &Path) -> Result<(), FolderCacheError> {
+        for (_, dir) in self.bucket_dirs(folder, false)? {
+            if dir.join(self.manifest_name()).exists() {
+                Manifest::default().write(&dir, self, true)?;
+            }
+            for file in dir.read_dir()? {
+                let path = file?.path();
+                let key_index = path.extension() == Some(OsStr::new("keys")) && self.size_class(&path.with_extension("")).is_some();
+                let named = [self.journal_name(), self.pinned_name()].iter().any(|name| path.file_name() == Some(OsStr::new(name)));
+                if self.size_class(&path).is_some() || key_index || named {
+                    std::fs::remove_file(&path)?;
+                }
+            }
+            for sub in [self.blob_dir_name(), self.index_dir_name(), self.dictionary_dir_name()] {
+                if dir.join(&sub).is_dir() {
+                    std::fs::remove_dir_all(dir.join(sub))?;
+                }
+            }
+            match std::fs::remove_file(dir.join(self.manifest_name())) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The bucket files of a directory that hold its records: the generation of each size class. It is replaced by
+/// renaming a new copy over it whenever files are created or compacted, and on open any bucket file it does not
+/// list is the leftover of an interrupted change and is deleted, so that the directory always holds the set of
+/// files from before a change or the one from after it. Directories written before manifests have none, and then
+/// every bucket file counts.
+#[derive(Default)]
+struct Manifest {buckets: BTreeMap<u64, u32>}
+impl Manifest {
+    const HEADER: &str = "FolderCache manifest 1";
+    fn read(dir: &Path, layout: &Layout) -> Result<Option<Self>, FolderCacheError> {
+        let text = match std::fs::read_to_string(dir.join(layout.manifest_name())) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut lines = text.lines();
+        if lines.next() != Some(Self::HEADER) {
+            return Err(FolderCacheError::BadManifest);
+        }
+        let buckets = lines.map(|line| {
+            let (size, generation) = line.split_once(' ')?;
+            Some((size.parse().ok()?, generation.parse().ok()?))
+        }).collect::<Option<_>>().ok_or(FolderCacheError::BadManifest)?;
+        Ok(Some(Self {buckets}))
+    }
+    fn of(files: &[CacheLevel1]) -> Self {
+        Self {buckets: files.iter().map(|f| (f.size_per_item, f.generation)).collect()}
+    }
+    /// With `sync`, the new manifest and the directory entry are flushed to disk before this returns.
+    fn write(&self, dir: &Path, layout: &Layout, sync: bool) -> Result<(), FolderCacheError> {
+        let mut text = format!("{}\n", Self::HEADER);
+        for (size_per_item, generation) in &self.buckets {
+            text += &format!("{size_per_item} {generation}\n");
+        }
+        let path = dir.join(layout.manifest_name());
+        let tmp = dir.join(format!("{}.tmp", layout.manifest_name()));
+        let mut file = File::create(&tmp)?;
+        file.write_all(text.as_bytes())?;
+        if sync {
+            file.sync_data()?;
+        }
+        std::fs::rename(&tmp, &path)?;
+        #[cfg(unix)]
+        if sync {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+    fn generation(&self, size_per_item: u64) -> u32 {
+        self.buckets.get(&size_per_item).copied().unwrap_or(0)
+    }
+}
 
-#[derive(Clone,Copy)]
-struct CacheLevel1 {num_items: u64, size_per_item: u64, reserved: u64}
+/// A serialized key and value.
+type RawRecord = (Vec<u8>, Vec<u8>);
+/// A serialized key and value with their timestamps.
+type StampedRecord = (Vec<u8>, Vec<u8>, Stamp);
+/// Nanoseconds since the Unix epoch, the unit of the timestamps in entry headers.
+fn unix_nanos() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64)
+}
+/// When a record's key was first inserted, when its value was last written, and its version: 1 when inserted and
+/// one more on every write since.
+#[derive(Clone, Copy)]
+struct Stamp {created: u64, written: u64, version: u64}
+impl Stamp {
+    fn now() -> Self {
+        let now = unix_nanos();
+        Self {created: now, written: now, version: 1}
+    }
+    /// The stamp of a record whose value is written again.
+    fn rewritten(self) -> Self {
+        Self {written: unix_nanos(), version: self.version+1, ..self}
+    }
+}
+/// `hints` mirrors the slots of the file and holds the affinity hint each record was placed with; they are kept in
+/// the key index, and unknown for a bucket opened without one.
+/// `pending` holds the last slots, appended but not yet written to the file; `num_items` already counts them, while
+/// the header on disk is only updated once they are written.
+#[derive(Clone)]
+struct CacheLevel1 {num_items: u64, size_per_item: u64, generation: u32, reserved: u64, hints: Vec<Option<u64>>, pending: Vec<u8>}
 impl Ord for CacheLevel1 {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {self.size_per_item.cmp(&other.size_per_item)}
 } impl PartialOrd for CacheLevel1 {
@@ -36,159 +273,775 @@ impl PartialEq for CacheLevel1 {
 }
 impl Eq for CacheLevel1 {}
 impl CacheLevel1 {
-    fn create_file(&mut self, folder: &Path) -> Result<PathBuf, FolderCacheError> {
-        let path = folder.join(self.file_name());
-        let mut file = OpenOptions::new().create(true).write(true).open(&path)?;
+    fn create_file(&mut self, path: &Path) -> Result<(), FolderCacheError> {
+        let file = OpenOptions::new().create(true).write(true).truncate(true).open(path)?;
         self.num_items = 0;
-        Level1Header(self.num_items).raw_write(&mut file)?;
+        self.hints.clear();
+        Level1Header(self.num_items).raw_write(&file)?;
         file.set_len(Level1Header::BYTES+self.reserved*self.size_per_item)?;
-        return Ok(path);
-    }
-    fn from_path(path: &Path) -> Result<Option<Self>, FolderCacheError> {
-        fn read_name(name: Option<&OsStr>) -> Option<u64> {
-            let mut name_itr = name?.to_str()?.split('.');
-            let ret = name_itr.next()?.parse().ok()?;
-            if name_itr.next()? == EXTENSION {Some(ret)} else {None}
-        }
-        if let Some(size_per_item) = read_name(path.file_name()) {
-            let length = path.metadata()?.len();
-            let mut filep = OpenOptions::new().read(true).open(path)?;
-            let Level1Header(num_items) = Level1Header::raw_read(&mut filep)?;
-            return Ok(Some(CacheLevel1 {size_per_item, num_items, reserved: (length-Level1Header::BYTES)/size_per_item}));
+        Ok(())
+    }
+    fn from_path(path: &Path, size_per_item: u64, generation: u32) -> Result<Self, FolderCacheError> {
+        let length = path.metadata()?.len();
+        let filep = OpenOptions::new().read(true).open(path)?;
+        let Level1Header(num_items) = Level1Header::raw_read(&filep)?;
+        let reserved = (length-Level1Header::BYTES)/size_per_item;
+        if num_items > reserved {
+            return Err(FolderCacheError::BadHeader);
+        }
+        Ok(CacheLevel1 {size_per_item, generation, num_items, reserved, hints: vec![None; num_items as usize], pending: Vec::new()})
+    }
+    fn rewrite_header(&self, filep: &File) -> Result<(), FolderCacheError> {
+        Level1Header(self.num_items).raw_write(filep)
+    }
+    fn len(&self) -> usize {self.num_items as usize}
+    fn slot(&self, i: u64) -> u64 {Level1Header::BYTES+i*self.size_per_item}
+    /// Number of items that are on disk, as opposed to pending.
+    fn written_items(&self) -> u64 {self.num_items-self.pending.len() as u64/self.size_per_item}
+    /// Reads from the file as it will be once the pending slots are written. Reads never span two slots.
+    fn read_at(&self, filep: &File, buf: &mut [u8], offset: u64) -> Result<(), FolderCacheError> {
+        let pending_start = self.slot(self.written_items());
+        if offset >= pending_start {
+            let at = (offset-pending_start) as usize;
+            buf.copy_from_slice(&self.pending[at..at+buf.len()]);
         } else {
-            return Ok(None);
+            read_exact_at(filep, buf, offset)?;
         }
+        Ok(())
     }
-    fn rewrite_header(&self, filep: &mut File) -> Result<(), FolderCacheError> {
-        filep.seek(SeekFrom::Start(0))?;
-        Level1Header(self.num_items).raw_write(filep)?;
+    fn entry_header(&self, filep: &File, i: u64) -> Result<Level1EntryHeader, FolderCacheError> {
+        let mut bytes = [0; Level1EntryHeader::BYTES as usize];
+        self.read_at(filep, &mut bytes, self.slot(i))?;
+        Ok(Level1EntryHeader::from_bytes(&bytes))
+    }
+    /// Writes the pending slots, then the header that covers them.
+    fn flush(&mut self, filep: &File) -> Result<(), FolderCacheError> {
+        if !self.pending.is_empty() {
+            write_all_at(filep, &self.pending, self.slot(self.written_items()))?;
+            self.pending.clear();
+            self.rewrite_header(filep)?;
+        }
         Ok(())
     }
-    fn len(&self) -> usize {self.num_items as usize}
-    fn read_k<K: for <'a> Deserialize<'a>>(&self, filep: &mut File, i: u64) -> Result<K, FolderCacheError> {
-        filep.seek(SeekFrom::Start(Level1Header::BYTES+i*self.size_per_item))?;
-        let Level1EntryHeader(k_size, v_size) = Level1EntryHeader::raw_read(filep)?;
-        filep.seek(SeekFrom::Start(Level1Header::BYTES+i*self.size_per_item+Level1EntryHeader::BYTES+v_size))?;
+    fn read_k<K: for <'a> Deserialize<'a>>(&self, filep: &File, i: u64) -> Result<K, FolderCacheError> {
+        Ok(rmp_serde::from_slice(&self.read_kser(filep, i)?)?)
+    }
+    fn read_kser(&self, filep: &File, i: u64) -> Result<Vec<u8>, FolderCacheError> {
+        let Level1EntryHeader(k_size, v_size, _) = self.entry_header(filep, i)?;
         let mut read = vec![0; k_size as usize];
-        filep.read(&mut read)?;
-        let k = rmp_serde::from_slice(&read[0..k_size as usize])?;
-        return Ok(k);
+        self.read_at(filep, &mut read, self.slot(i)+Level1EntryHeader::BYTES+v_size)?;
+        Ok(read)
     }
-    fn read_v<V: for <'a> Deserialize<'a>>(&self, filep: &mut File, i: u64) -> Result<V, FolderCacheError> {
-        filep.seek(SeekFrom::Start(Level1Header::BYTES+i*self.size_per_item))?;
-        let Level1EntryHeader(_, v_size) = Level1EntryHeader::raw_read(filep)?;
+    fn read_vser(&self, filep: &File, i: u64) -> Result<Vec<u8>, FolderCacheError> {
+        let Level1EntryHeader(_, v_size, _) = self.entry_header(filep, i)?;
         let mut read = vec![0; v_size as usize];
-        filep.read(&mut read)?;
-        let v = rmp_serde::from_slice(&read[0..v_size as usize])?;
-        return Ok(v);
+        self.read_at(filep, &mut read, self.slot(i)+Level1EntryHeader::BYTES)?;
+        Ok(read)
+    }
+    /// The blob a record's value was moved to, if any. Only reads the value if it is the size of a blob marker.
+    fn read_blob(&self, filep: &File, i: u64) -> Result<Option<BlobRef>, FolderCacheError> {
+        if self.entry_header(filep, i)?.1 != BLOB_MARKER_BYTES {
+            return Ok(None);
+        }
+        Ok(BlobRef::parse(&self.read_vser(filep, i)?))
+    }
+    /// Like `read_vser`, but borrows straight out of a mapping of the whole file. Only for slots that are written.
+    #[cfg(all(feature = "mmap", unix))]
+    fn mapped_vser<'a>(&self, mapped: &'a [u8], i: u64) -> Result<&'a [u8], FolderCacheError> {
+        let eof = || FolderCacheError::IO(std::io::ErrorKind::UnexpectedEof.into());
+        let slot = mapped.get(self.slot(i) as usize..self.slot(i+1) as usize).ok_or_else(eof)?;
+        let Level1EntryHeader(_, v_size, _) = Level1EntryHeader::from_bytes(slot);
+        let start = Level1EntryHeader::BYTES as usize;
+        slot.get(start..start.saturating_add(v_size as usize)).ok_or_else(eof)
     }
     #[allow(dead_code)]
-    fn read<K: for <'a> Deserialize<'a>, V: for <'a> Deserialize<'a>>(&self, filep: &mut File, i: u64) -> Result<(K,V), FolderCacheError> {
-        filep.seek(SeekFrom::Start(Level1Header::BYTES+i*self.size_per_item))?;
-        let Level1EntryHeader(k_size, v_size) = Level1EntryHeader::raw_read(filep)?;
+    fn read<K: for <'a> Deserialize<'a>, V: for <'a> Deserialize<'a>>(&self, filep: &File, i: u64) -> Result<(K,V), FolderCacheError> {
+        let Level1EntryHeader(k_size, v_size, _) = self.entry_header(filep, i)?;
         let mut read = vec![0; k_size as usize+v_size as usize];
-        filep.read(&mut read)?;
+        self.read_at(filep, &mut read, self.slot(i)+Level1EntryHeader::BYTES)?;
         let v = rmp_serde::from_slice(&read[0..v_size as usize])?;
         let k = rmp_serde::from_slice(&read[v_size as usize..k_size as usize+v_size as usize])?;
-        return Ok((k,v));
+        Ok((k,v))
     }
     /// Removes by swapping. If something was swapper, returns the K that was swapped into the position i.
-    fn swap_remove<K: for <'a> Deserialize<'a>>(&mut self, filep: &mut File, i: u64) -> Result<Option<K>, FolderCacheError> {
+    fn swap_remove<K: for <'a> Deserialize<'a>>(&mut self, filep: &File, journal: &mut Journal, i: u64) -> Result<Option<K>, FolderCacheError> {
+        self.flush(filep)?;
+        self.hints.swap_remove(i as usize);
         if i == self.num_items-1 {
             self.num_items -= 1;
             self.rewrite_header(filep)?;
-            return Ok(None);
+            Ok(None)
         } else {
             let mut read = vec![0; self.size_per_item as usize];
-            filep.seek(SeekFrom::Start(Level1Header::BYTES+self.size_per_item*(self.num_items-1)))?;
-            filep.read(&mut read)?;
-            let Level1EntryHeader(k_size, v_size) = Level1EntryHeader::from_bytes(&read[0..Level1EntryHeader::BYTES as usize]);
+            read_exact_at(filep, &mut read, self.slot(self.num_items-1))?;
+            let Level1EntryHeader(k_size, v_size, _) = Level1EntryHeader::from_bytes(&read[0..Level1EntryHeader::BYTES as usize]);
             let k = rmp_serde::from_slice(&read[Level1EntryHeader::BYTES as usize+v_size as usize..Level1EntryHeader::BYTES as usize+k_size as usize+v_size as usize])?;
-            filep.seek(SeekFrom::Start(Level1Header::BYTES+self.size_per_item*i))?;
-            filep.write(&read)?;
             self.num_items -= 1;
-            self.rewrite_header(filep)?;
-            return Ok(Some(k));
+            journal.apply(self.size_per_item, filep, &[
+                (self.slot(i), &read),
+                (0, &Level1Header(self.num_items).to_bytes()),
+            ])?;
+            Ok(Some(k))
         }
     }
+    fn read_raw(&self, filep: &File, i: u64) -> Result<StampedRecord, FolderCacheError> {
+        let Level1EntryHeader(k_size, v_size, stamp) = self.entry_header(filep, i)?;
+        let mut vser = vec![0; v_size as usize];
+        self.read_at(filep, &mut vser, self.slot(i)+Level1EntryHeader::BYTES)?;
+        let mut kser = vec![0; k_size as usize];
+        self.read_at(filep, &mut kser, self.slot(i)+Level1EntryHeader::BYTES+v_size)?;
+        Ok((kser, vser, stamp))
+    }
+    /// Drops the reserved tail so the file only holds the live items.
+    fn shrink(&mut self, filep: &File) -> Result<(), FolderCacheError> {
+        self.flush(filep)?;
+        self.reserved = self.num_items.max(1);
+        filep.set_len(self.slot(self.reserved))?;
+        Ok(())
+    }
     #[allow(dead_code)]
-    fn clear(&mut self, filep: &mut File) -> Result<(), FolderCacheError> {
+    fn clear(&mut self, filep: &File) -> Result<(), FolderCacheError> {
+        self.pending.clear();
         self.num_items = 0;
         self.rewrite_header(filep)?;
         Ok(())
     }
-    fn add(&mut self, filep: &mut File, kser: Vec<u8>, vser: Vec<u8>) -> Result<u64, FolderCacheError> {
+    /// Appends the record to the pending slots, which are written out in one go once `PENDING_BYTES` pile up.
+    fn add(&mut self, filep: &File, (kser, vser, stamp): StampedRecord, hint: Option<u64>) -> Result<u64, FolderCacheError> {
         if self.num_items >= self.reserved {
-            filep.set_len(Level1Header::BYTES+self.reserved*2*self.size_per_item)?;
-            self.reserved *= 2;
+            self.reserved = (self.reserved*2).max(1);
+            filep.set_len(self.slot(self.reserved))?;
         }
-        filep.seek(SeekFrom::Start(Level1Header::BYTES+self.num_items*self.size_per_item))?;
-        Level1EntryHeader(kser.len() as u64, vser.len() as u64).raw_write(filep)?;
-        filep.write(&vser)?;
-        filep.write(&kser)?;
+        let start = self.pending.len();
+        self.pending.extend_from_slice(&Level1EntryHeader(kser.len() as u64, vser.len() as u64, stamp).to_bytes());
+        self.pending.extend_from_slice(&vser);
+        self.pending.extend_from_slice(&kser);
+        self.pending.resize(start+self.size_per_item as usize, 0);
+        self.hints.push(hint);
         self.num_items += 1;
-        self.rewrite_header(filep)?;
-        return Ok(self.num_items-1);
+        if self.pending.len() >= PENDING_BYTES {
+            self.flush(filep)?;
+        }
+        Ok(self.num_items-1)
+    }
+    /// Appends several records at once: the file grows at most once, and the records and header are written
+    /// with one write each. Returns the index of the first record.
+    fn add_many(&mut self, filep: &File, records: Vec<RawRecord>, stamp: Stamp) -> Result<u64, FolderCacheError> {
+        let first = self.num_items;
+        let needed = self.num_items+records.len() as u64;
+        if needed > self.reserved {
+            self.reserved = self.reserved.max(1)*2;
+            while needed > self.reserved {
+                self.reserved *= 2;
+            }
+            filep.set_len(self.slot(self.reserved))?;
+        }
+        for (kser, vser) in records {
+            let start = self.pending.len();
+            self.pending.extend_from_slice(&Level1EntryHeader(kser.len() as u64, vser.len() as u64, stamp).to_bytes());
+            self.pending.extend_from_slice(&vser);
+            self.pending.extend_from_slice(&kser);
+            self.pending.resize(start+self.size_per_item as usize, 0);
+            self.hints.push(None);
+            self.num_items += 1;
+        }
+        self.flush(filep)?;
+        Ok(first)
+    }
+    /// Writes the record into the slot after the last record sharing its hint, moving the previous occupant of
+    /// that slot to the end. The copy of the displaced record, the new record and the item count are written as one
+    /// journaled batch, so a crash can not leave the displaced record in two slots. Returns the index used and, if
+    /// a record was displaced, its key and new index.
+    fn add_hinted<K: for <'a> Deserialize<'a>>(&mut self, filep: &File, journal: &mut Journal, (kser, vser, stamp): StampedRecord, hint: u64) -> Result<(u64, Option<(K, u64)>), FolderCacheError> {
+        let target = match self.hints.iter().rposition(|h| *h == Some(hint)) {
+            Some(last) if last as u64+1 < self.num_items => last as u64+1,
+            _ => return Ok((self.add(filep, (kser, vser, stamp), Some(hint))?, None)),
+        };
+        self.flush(filep)?;
+        let mut moved = vec![0; self.size_per_item as usize];
+        read_exact_at(filep, &mut moved, self.slot(target))?;
+        let Level1EntryHeader(k_size, v_size, _) = Level1EntryHeader::from_bytes(&moved[0..Level1EntryHeader::BYTES as usize]);
+        let key_at = Level1EntryHeader::BYTES as usize+v_size as usize;
+        let moved_k = rmp_serde::from_slice(moved.get(key_at..key_at+k_size as usize).ok_or(FolderCacheError::BadHeader)?)?;
+        if self.num_items >= self.reserved {
+            self.reserved = (self.reserved*2).max(1);
+            filep.set_len(self.slot(self.reserved))?;
+        }
+        let mut record = Level1EntryHeader(kser.len() as u64, vser.len() as u64, stamp).to_bytes().to_vec();
+        record.extend_from_slice(&vser);
+        record.extend_from_slice(&kser);
+        let moved_index = self.num_items;
+        journal.apply(self.size_per_item, filep, &[
+            (self.slot(moved_index), &moved),
+            (self.slot(target), &record),
+            (0, &Level1Header(moved_index+1).to_bytes()),
+        ])?;
+        self.num_items += 1;
+        self.hints.push(self.hints[target as usize]);
+        self.hints[target as usize] = Some(hint);
+        Ok((target, Some((moved_k, moved_index))))
+    }
+    fn overwrite(&mut self, filep: &File, journal: &mut Journal, i: u64, (kser, vser, stamp): StampedRecord) -> Result<(), FolderCacheError> {
+        self.flush(filep)?;
+        let mut record = Level1EntryHeader(kser.len() as u64, vser.len() as u64, stamp).to_bytes().to_vec();
+        record.extend_from_slice(&vser);
+        record.extend_from_slice(&kser);
+        journal.apply(self.size_per_item, filep, &[(self.slot(i), &record)])
+    }
+}
+
+/// A read-only shared mapping of a bucket file. Writes through the file handles stay visible through it, but
+/// the file must not shrink while it is mapped, so mappings are dropped before compaction.
+#[cfg(all(feature = "mmap", unix))]
+struct Mapping {ptr: *mut libc::c_void, len: usize}
+#[cfg(all(feature = "mmap", unix))]
+unsafe impl Send for Mapping {}
+#[cfg(all(feature = "mmap", unix))]
+unsafe impl Sync for Mapping {}
+#[cfg(all(feature = "mmap", unix))]
+impl Mapping {
+    fn new(file: &File) -> Result<Self, FolderCacheError> {
+        use std::os::fd::AsRawFd;
+        let len = file.metadata()?.len() as usize;
+        let ptr = unsafe {libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, file.as_raw_fd(), 0)};
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(Self {ptr, len})
+    }
+    fn bytes(&self) -> &[u8] {
+        unsafe {std::slice::from_raw_parts(self.ptr as *const u8, self.len)}
+    }
+}
+#[cfg(all(feature = "mmap", unix))]
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {libc::munmap(self.ptr, self.len);}
+    }
+}
+
+/// Appended records are held back until this many bytes are pending in a bucket file, or the cache commits.
+const PENDING_BYTES: usize = 64*1024;
+
+/// Positioned reads and writes leave the file cursor alone, so one handle can serve several threads at once.
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    #[cfg(unix)]
+    return std::os::unix::fs::FileExt::read_exact_at(file, buf, offset);
+    #[cfg(windows)]
+    {
+        let mut done = 0;
+        while done < buf.len() {
+            match std::os::windows::fs::FileExt::seek_read(file, &mut buf[done..], offset+done as u64)? {
+                0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                n => done += n,
+            }
+        }
+        Ok(())
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let mut file = file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buf)
+    }
+}
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> std::io::Result<()> {
+    #[cfg(unix)]
+    return std::os::unix::fs::FileExt::write_all_at(file, buf, offset);
+    #[cfg(windows)]
+    {
+        let mut done = 0;
+        while done < buf.len() {
+            match std::os::windows::fs::FileExt::seek_write(file, &buf[done..], offset+done as u64)? {
+                0 => return Err(std::io::ErrorKind::WriteZero.into()),
+                n => done += n,
+            }
+        }
+        Ok(())
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let mut file = file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(buf)
+    }
+}
+
+/// 64-bit FNV-1a, used to detect torn or corrupted writes.
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| (hash^*b as u64).wrapping_mul(0x100000001b3))
+}
+
+const JOURNAL_NAME: &str = "journal.wal";
+/// Bytes to write at an offset of a bucket file.
+type JournalWrite<'a> = (u64, &'a [u8]);
+/// Redo log for writes that overwrite live bytes in a bucket file (appends are already safe, since the item count
+/// is only bumped after the record is written). A batch of writes is logged with a checksum before it is applied,
+/// and the log is emptied afterwards, so a crash leaves either untouched bucket files and an incomplete log, or
+/// a complete log that `replay` re-applies on the next open.
+/// With `sync` set, the log is flushed to disk before the writes are applied and the writes before the log is
+/// emptied, which extends the guarantee from process crashes to power loss.
+struct Journal {path: PathBuf, file: Option<File>, sync: bool}
+impl Journal {
+    fn new(dir: &Path, layout: &Layout) -> Self {
+        Self {path: dir.join(layout.journal_name()), file: None, sync: false}
     }
-    fn overwrite(&mut self, filep: &mut File, i: u64, kser: Vec<u8>, vser: Vec<u8>) -> Result<(), FolderCacheError> {
-        filep.seek(SeekFrom::Start(Level1Header::BYTES+i*self.size_per_item))?;
-        Level1EntryHeader(kser.len() as u64, vser.len() as u64).raw_write(filep)?;
-        filep.write(&vser)?;
-        filep.write(&kser)?;
+    fn apply(&mut self, size_per_item: u64, filep: &File, writes: &[JournalWrite]) -> Result<(), FolderCacheError> {
+        let mut log = Vec::new();
+        log.extend_from_slice(&size_per_item.to_le_bytes());
+        log.extend_from_slice(&(writes.len() as u64).to_le_bytes());
+        for (offset, bytes) in writes {
+            log.extend_from_slice(&offset.to_le_bytes());
+            log.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+            log.extend_from_slice(bytes);
+        }
+        log.extend_from_slice(&checksum(&log).to_le_bytes());
+        let journal = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&self.path)?),
+        };
+        journal.seek(SeekFrom::Start(0))?;
+        journal.write_all(&log)?;
+        if self.sync {
+            journal.sync_data()?;
+        }
+        for (offset, bytes) in writes {
+            write_all_at(filep, bytes, *offset)?;
+        }
+        if self.sync {
+            filep.sync_data()?;
+        }
+        journal.set_len(0)?;
+        Ok(())
+    }
+    /// Re-applies a complete batch left behind by a crash and empties the log. A torn batch is discarded.
+    fn replay(dir: &Path, layout: &Layout, manifest: Option<&Manifest>) -> Result<(), FolderCacheError> {
+        let path = dir.join(layout.journal_name());
+        let Ok(log) = std::fs::read(&path) else {return Ok(())};
+        if let Some((size_per_item, writes)) = Self::decode(&log) {
+            let generation = manifest.map_or(0, |manifest| manifest.generation(size_per_item));
+            let filep = OpenOptions::new().write(true).open(dir.join(layout.bucket_name(size_per_item, generation)))?;
+            for (offset, bytes) in writes {
+                write_all_at(&filep, bytes, offset)?;
+            }
+        }
+        std::fs::remove_file(&path)?;
         Ok(())
     }
-    fn file_name(&self) -> PathBuf {
-        return PathBuf::from(format!("{}.{}", self.size_per_item, EXTENSION));
+    fn decode(log: &[u8]) -> Option<(u64, Vec<JournalWrite<'_>>)> {
+        let body_len = log.len().checked_sub(SZU64)?;
+        let body = &log[..body_len];
+        if checksum(body) != read_u64(&log[body_len..]) {
+            return None;
+        }
+        let mut at = 0;
+        let mut take = |len: usize| {
+            let bytes = body.get(at..at+len)?;
+            at += len;
+            Some(bytes)
+        };
+        let size_per_item = read_u64(take(SZU64)?);
+        let count = read_u64(take(SZU64)?);
+        let mut writes = Vec::new();
+        for _ in 0..count {
+            let offset = read_u64(take(SZU64)?);
+            let len = read_u64(take(SZU64)?) as usize;
+            writes.push((offset, take(len)?));
+        }
+        Some((size_per_item, writes))
     }
 }
 const SZU64: usize = size_of::<u64>();
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut a = [0; SZU64];
+    a.copy_from_slice(&bytes[0..SZU64]);
+    u64::from_le_bytes(a)
+}
+
+const MAGIC: [u8; 4] = *b"FCch";
+/// Version of the bucket file layout written by this build. Every layout change bumps it and appends the
+/// corresponding upgrade to `MIGRATIONS`.
+const FORMAT_VERSION: u32 = 4;
+/// Bytes at the start of a bucket file, before its first slot: the magic, format version and item count.
+pub const FILE_HEADER_BYTES: u64 = 2*SZU64 as u64;
+/// Bytes at the start of each slot, before the record's value and key: key size, value size, creation time, last
+/// write time and version, as little-endian u64s. A record needs a slot this much bigger than its value and key.
+pub const SLOT_HEADER_BYTES: u64 = 5*SZU64 as u64;
+/// Rewrites a bucket file of the given size class, returning any records that no longer fit in it.
+type Migration = fn(&Path, u64) -> Result<Vec<StampedRecord>, FolderCacheError>;
+/// `MIGRATIONS[v]` rewrites a bucket file from version `v` to version `v+1`.
+const MIGRATIONS: [Migration; FORMAT_VERSION as usize] = [migrate_v0, migrate_v1, migrate_v2, migrate_v3];
+
+/// The key index of the bucket at `path`: the affinity hints of the bucket's records, then their keys and the shared
+/// blobs their values were moved to, in slot order, so that opening the cache need not read the records. It is
+/// written when the cache is closed and deleted by the next write to the bucket.
+fn key_index_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".keys");
+    PathBuf::from(name)
+}
+/// Bumped whenever the key index format changes, so that indexes written before are ignored like stale ones.
+const KEY_INDEX_VERSION: u64 = 1;
+/// The key index format, and the item count, length and modification time of a bucket, which its key index records
+/// so that an index the bucket has moved on from is ignored.
+type IndexStamp = (u64, u64, u64, u64);
+fn key_index_stamp(bucket: &CacheLevel1, path: &Path) -> Result<IndexStamp, FolderCacheError> {
+    let meta = path.metadata()?;
+    let modified = meta.modified()?.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+    Ok((KEY_INDEX_VERSION, bucket.num_items, meta.len(), modified))
+}
+/// A key, and the shared blob its value was moved to, if any.
+type IndexedKey<K> = (K, Option<u64>);
+/// The keys and shared blobs in the key index of `bucket`, or `None` if it has none that matches it.
+fn read_key_index<K: for <'a> Deserialize<'a>>(bucket: &CacheLevel1, path: &Path) -> Result<Option<Vec<IndexedKey<K>>>, FolderCacheError> {
+    let bytes = match std::fs::read(key_index_path(path)) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let stamp = key_index_stamp(bucket, path)?;
+    let mut de = rmp_serde::Deserializer::new(&bytes[..]);
+    // A damaged index is ignored like a stale one, and the bucket read instead.
+    let mut decode = || -> Result<Option<Vec<IndexedKey<K>>>, rmp_serde::decode::Error> {
+        if IndexStamp::deserialize(&mut de)? != stamp {
+            return Ok(None);
+        }
+        serde::de::IgnoredAny::deserialize(&mut de)?;
+        (0..bucket.num_items).map(|_| Ok((K::deserialize(&mut de)?, Option::<u64>::deserialize(&mut de)?))).collect::<Result<_, _>>().map(Some)
+    };
+    Ok(decode().ok().flatten())
+}
+
+/// The affinity hints in the key index of `bucket`, by slot, or `None` if it has no index that matches it. Only the
+/// start of the index is read.
+fn read_key_index_hints(bucket: &CacheLevel1, path: &Path) -> Result<Option<Vec<Option<u64>>>, FolderCacheError> {
+    let file = match File::open(key_index_path(path)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let stamp = key_index_stamp(bucket, path)?;
+    let mut de = rmp_serde::Deserializer::new(std::io::BufReader::new(file));
+    let mut decode = || -> Result<Option<Vec<Option<u64>>>, rmp_serde::decode::Error> {
+        if IndexStamp::deserialize(&mut de)? != stamp {
+            return Ok(None);
+        }
+        let hints = Vec::<Option<u64>>::deserialize(&mut de)?;
+        Ok((hints.len() as u64 == bucket.num_items).then_some(hints))
+    };
+    Ok(decode().ok().flatten())
+}
+
+/// A shard, its buckets' paths, size classes and generations, and where to send the references to shared blobs counted in them.
+type LoadLater = (usize, Vec<(PathBuf, u64, u32)>, Sender<HashMap<u64, u64>>);
+/// Reads the key of every record in `bucket` into `map`, and counts its references to shared blobs.
+fn load_bucket<K: Eq+std::hash::Hash+for <'a> Deserialize<'a>>(bucket: &CacheLevel1, path: &Path, shard: usize, map: &mut HashMap<K, Ref>,
+shared: &mut HashMap<u64, u64>) -> Result<(), FolderCacheError> {
+    if let Some(keys) = read_key_index::<K>(bucket, path)? {
+        for (i, (k, blob)) in keys.into_iter().enumerate() {
+            map.insert(k, Ref {shard, file: bucket.size_per_item, index: i as u64});
+            if let Some(hash) = blob {
+                *shared.entry(hash).or_default() += 1;
+            }
+        }
+        return Ok(());
+    }
+    let filep = OpenOptions::new().read(true).open(path)?;
+    for i in 0..bucket.len() as u64 {
+        let k = bucket.read_k(&filep, i)?;
+        map.insert(k, Ref {shard, file: bucket.size_per_item, index: i});
+        if let Some(BlobRef::Shared(hash)) = bucket.read_blob(&filep, i)? {
+            *shared.entry(hash).or_default() += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Files from before the version header carry no magic and are treated as version 0.
+fn file_version(path: &Path) -> Result<u32, FolderCacheError> {
+    let mut bytes = [0; SZU64];
+    File::open(path)?.read_exact(&mut bytes)?;
+    if bytes[0..4] == MAGIC {
+        Ok(u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]))
+    } else {
+        Ok(0)
+    }
+}
+/// Brings a bucket file up to `FORMAT_VERSION`. Records that outgrew their slot on the way are removed from the file
+/// and returned, to be added again in a larger size class.
+fn migrate(path: &Path, size_per_item: u64) -> Result<Vec<StampedRecord>, FolderCacheError> {
+    let version = file_version(path)?;
+    if version == 0 {
+        // Without a magic number, only the item count can tell a legacy file from a corrupt one.
+        let mut count = [0; SZU64];
+        File::open(path)?.read_exact(&mut count)?;
+        if u64::from_le_bytes(count).saturating_mul(size_per_item) > path.metadata()?.len()-SZU64 as u64 {
+            return Err(FolderCacheError::BadHeader);
+        }
+    }
+    if version > FORMAT_VERSION {
+        return Err(FolderCacheError::UnsupportedVersion(version));
+    }
+    let mut spilled = Vec::new();
+    for step in &MIGRATIONS[version as usize..] {
+        spilled.extend(step(path, size_per_item)?);
+    }
+    Ok(spilled)
+}
+/// Writes `bytes` next to `path` and renames it over the original, so an interrupted migration leaves the old file.
+fn replace_file(path: &Path, bytes: &[u8]) -> Result<(), FolderCacheError> {
+    let tmp = path.with_extension("migrating");
+    std::fs::write(&tmp, bytes)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+/// The serialized keys pinned in the folder of `root`, its first directory.
+fn read_pins(root: &CacheLevel2) -> Result<HashSet<Vec<u8>>, FolderCacheError> {
+    match std::fs::read(root.dir.join(root.layout.pinned_name())) {
+        Ok(bytes) => Ok(rmp_serde::from_slice(&bytes)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashSet::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+/// Gives `from` the second name `to`: a hard link on Unix, where `FolderCache` can tell that a bucket file has other
+/// names and copies it before writing to it, and a copy elsewhere or where the file system can not link.
+fn share_file(from: &Path, to: &Path) -> Result<(), FolderCacheError> {
+    #[cfg(unix)]
+    if std::fs::hard_link(from, to).is_ok() {
+        return Ok(());
+    }
+    std::fs::copy(from, to)?;
+    Ok(())
+}
+/// `share_file` for every file under `from`, recreating its directories under `to`.
+fn share_tree(from: &Path, to: &Path) -> Result<(), FolderCacheError> {
+    std::fs::create_dir_all(to)?;
+    for entry in from.read_dir()? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            share_tree(&entry.path(), &to.join(entry.file_name()))?;
+        } else {
+            share_file(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    }
+    Ok(())
+}
+/// Whether the file at `path` has another name, such as in a checkpoint.
+fn is_shared(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        path.metadata().is_ok_and(|m| m.nlink() > 1)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        false
+    }
+}
+/// Version 0 files start directly with the 8-byte item count.
+fn migrate_v0(path: &Path, _: u64) -> Result<Vec<StampedRecord>, FolderCacheError> {
+    let old = std::fs::read(path)?;
+    let mut new = Level1Header::versioned_bytes(1, read_u64(&old)).to_vec();
+    new.extend_from_slice(&old[SZU64..]);
+    replace_file(path, &new)?;
+    Ok(Vec::new())
+}
+/// Version 1 entry headers hold only the key and value sizes. The write time is taken from the file's modification
+/// time.
+fn migrate_v1(path: &Path, size_per_item: u64) -> Result<Vec<StampedRecord>, FolderCacheError> {
+    let written = path.metadata()?.modified().ok().and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok()).map_or(0, |d| d.as_nanos() as u64);
+    widen_entries(path, size_per_item, 2, 2*SZU64, |old| {
+        let mut new = old.to_vec();
+        new.extend_from_slice(&written.to_le_bytes());
+        (new, Stamp {created: written, written, version: 1})
+    })
+}
+/// Version 2 entry headers have no creation time; the last write stands in for it.
+fn migrate_v2(path: &Path, size_per_item: u64) -> Result<Vec<StampedRecord>, FolderCacheError> {
+    widen_entries(path, size_per_item, 3, 3*SZU64, |old| {
+        let written = read_u64(&old[2*SZU64..]);
+        let mut new = old[..2*SZU64].to_vec();
+        new.extend_from_slice(&written.to_le_bytes());
+        new.extend_from_slice(&written.to_le_bytes());
+        (new, Stamp {created: written, written, version: 1})
+    })
+}
+/// Version 3 entry headers have no version; every record starts at 1.
+fn migrate_v3(path: &Path, size_per_item: u64) -> Result<Vec<StampedRecord>, FolderCacheError> {
+    widen_entries(path, size_per_item, 4, 4*SZU64, |old| {
+        let mut new = old.to_vec();
+        new.extend_from_slice(&1u64.to_le_bytes());
+        (new, Stamp {created: read_u64(&old[2*SZU64..]), written: read_u64(&old[3*SZU64..]), version: 1})
+    })
+}
+/// Rewrites every slot of a bucket file with a longer entry header, built from the old one by `widen` along with the
+/// record's stamp. The old header starts with the key and value sizes. Records that no longer fit their slot
+/// are dropped from the file and returned.
+fn widen_entries(path: &Path, size_per_item: u64, version: u32, old_header: usize, widen: impl Fn(&[u8]) -> (Vec<u8>, Stamp)) -> Result<Vec<StampedRecord>, FolderCacheError> {
+    let old = std::fs::read(path)?;
+    let slots = old.get(Level1Header::BYTES as usize..).unwrap_or_default();
+    let count = (read_u64(&old[SZU64..]) as usize).min(slots.len()/size_per_item as usize);
+    let mut body = Vec::with_capacity(slots.len());
+    let mut spilled = Vec::new();
+    for slot in slots.chunks_exact(size_per_item as usize).take(count) {
+        let (k_size, v_size) = (read_u64(slot) as usize, read_u64(&slot[SZU64..]) as usize);
+        let record = slot.get(old_header..old_header+v_size+k_size).ok_or(FolderCacheError::BadHeader)?;
+        let (header, stamp) = widen(&slot[..old_header]);
+        if header.len()+record.len() > size_per_item as usize {
+            spilled.push((record[v_size..].to_vec(), record[..v_size].to_vec(), stamp));
+            continue;
+        }
+        let start = body.len();
+        body.extend_from_slice(&header);
+        body.extend_from_slice(record);
+        body.resize(start+size_per_item as usize, 0);
+    }
+    let kept = body.len() as u64/size_per_item;
+    body.resize(slots.len().max(body.len()), 0);
+    let mut new = Level1Header::versioned_bytes(version, kept).to_vec();
+    new.extend_from_slice(&body);
+    replace_file(path, &new)?;
+    Ok(spilled)
+}
+
+/// Bucket file header: magic, format version and item count.
 struct Level1Header(u64);
-struct Level1EntryHeader(u64,u64);
+/// Entry header: key size, value size and the record's stamp.
+struct Level1EntryHeader(u64,u64,Stamp);
 impl Level1Header {
-    const BYTES: u64 = size_of::<u64>() as u64;
-    fn raw_write(&self, file: &mut File) -> Result<(), FolderCacheError> {
-        let bytes = self.to_bytes();
-        file.write(&bytes)?;
+    const BYTES: u64 = FILE_HEADER_BYTES;
+    fn raw_write(&self, file: &File) -> Result<(), FolderCacheError> {
+        write_all_at(file, &self.to_bytes(), 0)?;
         Ok(())
     }
-    fn raw_read(file: &mut File) -> Result<Self, FolderCacheError> {
+    fn raw_read(file: &File) -> Result<Self, FolderCacheError> {
         let mut bytes = [0; Self::BYTES as usize];
-        file.read(&mut bytes)?;
-        Ok(Self::from_bytes(&bytes))
+        read_exact_at(file, &mut bytes, 0)?;
+        Self::from_bytes(&bytes)
     }
 
-    fn to_bytes(&self) -> [u8; Self::BYTES as usize] {self.0.to_le_bytes()}
-    fn from_bytes(bytes: &[u8]) -> Self {
-        let mut a = [0; SZU64];
-        a.copy_from_slice(&bytes[0..SZU64]);
-        Self(u64::from_le_bytes(a))
+    fn versioned_bytes(version: u32, num_items: u64) -> [u8; Self::BYTES as usize] {
+        let mut x = [0; Self::BYTES as usize];
+        x[0..4].copy_from_slice(&MAGIC);
+        x[4..8].copy_from_slice(&version.to_le_bytes());
+        x[SZU64..SZU64*2].copy_from_slice(&num_items.to_le_bytes());
+        x
+    }
+    fn to_bytes(&self) -> [u8; Self::BYTES as usize] {Self::versioned_bytes(FORMAT_VERSION, self.0)}
+    fn from_bytes(bytes: &[u8]) -> Result<Self, FolderCacheError> {
+        if bytes[0..4] != MAGIC {
+            return Err(FolderCacheError::BadHeader);
+        }
+        let version = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        if version != FORMAT_VERSION {
+            return Err(FolderCacheError::UnsupportedVersion(version));
+        }
+        Ok(Self(read_u64(&bytes[SZU64..])))
     }
 }
 impl Level1EntryHeader {
-    const BYTES: u64 = size_of::<u64>() as u64*2;
-    fn raw_write(&self, file: &mut File) -> Result<(), FolderCacheError> {
-        let bytes = self.to_bytes();
-        file.write(&bytes)?;
-        Ok(())
-    }
-    fn raw_read(file: &mut File) -> Result<Self, FolderCacheError> {
-        let mut bytes = [0; Self::BYTES as usize];
-        file.read(&mut bytes)?;
-        Ok(Self::from_bytes(&bytes))
-    }
+    const BYTES: u64 = SLOT_HEADER_BYTES;
 
     fn to_bytes(&self) -> [u8; Self::BYTES as usize] {
-        let mut x: [u8; 16] = [0;Self::BYTES as usize];
+        let mut x = [0; Self::BYTES as usize];
         x[0..SZU64].copy_from_slice(&self.0.to_le_bytes());
         x[SZU64..SZU64*2].copy_from_slice(&self.1.to_le_bytes());
-        return x;
+        x[SZU64*2..SZU64*3].copy_from_slice(&self.2.created.to_le_bytes());
+        x[SZU64*3..SZU64*4].copy_from_slice(&self.2.written.to_le_bytes());
+        x[SZU64*4..SZU64*5].copy_from_slice(&self.2.version.to_le_bytes());
+        x
     }
     fn from_bytes(bytes: &[u8]) -> Self {
-        let mut a = [0; SZU64];
-        let mut b = [0; SZU64];
-        a.copy_from_slice(&bytes[0..SZU64]);
-        b.copy_from_slice(&bytes[SZU64..SZU64*2]);
-        Self(u64::from_le_bytes(a), u64::from_le_bytes(b))
+        let stamp = Stamp {created: read_u64(&bytes[SZU64*2..]), written: read_u64(&bytes[SZU64*3..]), version: read_u64(&bytes[SZU64*4..])};
+        Self(read_u64(bytes), read_u64(&bytes[SZU64..]), stamp)
+    }
+}
+
+/// MessagePack extension types marking a value that lives in a blob file. The record then holds a `fixext 8` of
+/// one of these types with the blob's id or content hash, which no ordinary value serializes to.
+const BLOB_EXT: u8 = 0x42;
+const SHARED_EXT: u8 = 0x43;
+const BLOB_MARKER_BYTES: u64 = 10;
+/// MessagePack extension type of a value compressed with a dictionary (see `FolderCache::train_dictionary`). The
+/// record then holds an `ext 32` of this type with the dictionary's id and the compressed bytes.
+const COMPRESSED_EXT: u8 = 0x44;
+/// Bigger values are stored uncompressed, so that decompressing a corrupt record allocates at most this much.
+const MAX_COMPRESSED_VALUE: usize = 64 << 20;
+/// The dictionary id and compressed bytes of a compressed value.
+fn compressed_value(vser: &[u8]) -> Option<(u32, &[u8])> {
+    match vser {
+        [0xc9, a, b, c, d, COMPRESSED_EXT, rest @ ..] if u32::from_be_bytes([*a, *b, *c, *d]) as usize == rest.len() && rest.len() >= 4 =>
+            Some((u32::from_le_bytes(rest[..4].try_into().unwrap()), &rest[4..])),
+        _ => None,
+    }
+}
+
+/// A value moved out of its record: into a blob of its own, or into a content-addressed blob shared by every record
+/// holding the same value.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum BlobRef {Owned(u64), Shared(u64)}
+impl BlobRef {
+    fn parse(vser: &[u8]) -> Option<Self> {
+        match vser {
+            [0xd7, BLOB_EXT, id @ ..] if id.len() == SZU64 => Some(Self::Owned(read_u64(id))),
+            [0xd7, SHARED_EXT, hash @ ..] if hash.len() == SZU64 => Some(Self::Shared(read_u64(hash))),
+            _ => None,
+        }
+    }
+    fn marker(self) -> Vec<u8> {
+        let (ext, id) = match self {
+            Self::Owned(id) => (BLOB_EXT, id),
+            Self::Shared(hash) => (SHARED_EXT, hash),
+        };
+        let mut marker = vec![0xd7, ext];
+        marker.extend_from_slice(&id.to_le_bytes());
+        marker
+    }
+    fn name(self) -> String {
+        match self {
+            Self::Owned(id) => format!("{id}.blob"),
+            Self::Shared(hash) => format!("{hash:016x}.shared"),
+        }
+    }
+}
+/// Where the bytes of a binary value are: copied out of its record, or in a blob file from the given offset on.
+enum Binary {Inline(Vec<u8>), Blob(File, u64)}
+/// Where the bytes of a MessagePack `bin` value start, and how many there are.
+fn bin_header(vser: &[u8]) -> Result<(usize, u64), FolderCacheError> {
+    match vser {
+        [0xc4, len, ..] => Ok((2, *len as u64)),
+        [0xc5, a, b, ..] => Ok((3, u16::from_be_bytes([*a, *b]) as u64)),
+        [0xc6, a, b, c, d, ..] => Ok((5, u32::from_be_bytes([*a, *b, *c, *d]) as u64)),
+        _ => Err(rmp_serde::decode::Error::Uncategorized("value is not binary".to_string()).into()),
+    }
+}
+/// The MessagePack types `FolderCache::append` can extend.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Sequence {Str, Bin, Array}
+/// The type of a MessagePack string, binary or array value, where its elements start and how many there are.
+fn sequence_header(vser: &[u8]) -> Result<(Sequence, usize, u64), FolderCacheError> {
+    match vser {
+        [b @ 0xa0..=0xbf, ..] => Ok((Sequence::Str, 1, (b&0x1f) as u64)),
+        [0xd9, len, ..] => Ok((Sequence::Str, 2, *len as u64)),
+        [0xda, a, b, ..] => Ok((Sequence::Str, 3, u16::from_be_bytes([*a, *b]) as u64)),
+        [0xdb, a, b, c, d, ..] => Ok((Sequence::Str, 5, u32::from_be_bytes([*a, *b, *c, *d]) as u64)),
+        [b @ 0x90..=0x9f, ..] => Ok((Sequence::Array, 1, (b&0x0f) as u64)),
+        [0xdc, a, b, ..] => Ok((Sequence::Array, 3, u16::from_be_bytes([*a, *b]) as u64)),
+        [0xdd, a, b, c, d, ..] => Ok((Sequence::Array, 5, u32::from_be_bytes([*a, *b, *c, *d]) as u64)),
+        _ => bin_header(vser).map(|(start, len)| (Sequence::Bin, start, len))
+            .map_err(|_| rmp_serde::decode::Error::Uncategorized("value is not a string, binary or array".to_string()).into()),
+    }
+}
+/// The shortest header of a MessagePack value of the given type and length.
+fn sequence_bytes(kind: Sequence, len: u64) -> Vec<u8> {
+    match (kind, len) {
+        (Sequence::Str, 0..32) => vec![0xa0|len as u8],
+        (Sequence::Array, 0..16) => vec![0x90|len as u8],
+        (Sequence::Str, 0..256) => vec![0xd9, len as u8],
+        (Sequence::Bin, 0..256) => vec![0xc4, len as u8],
+        (_, 0..65536) => [[[0xda, 0xc5, 0xdc][kind as usize]].as_slice(), &(len as u16).to_be_bytes()].concat(),
+        _ => [[[0xdb, 0xc6, 0xdd][kind as usize]].as_slice(), &(len as u32).to_be_bytes()].concat(),
     }
 }
 
@@ -198,204 +1051,2347 @@ fn foremost_bit(x: u64) -> u32 {
             return i;
         }
     }
-    return 64;
+    64
 }
+/// `shard` is the position of the record's directory in `FolderCache::shards`.
 #[derive(Eq,PartialEq,Clone,Copy,Hash)]
-struct Ref {file: u64, index: u64}
-struct CacheLevel2 {files: Vec<CacheLevel1>, open: Option<(u64, File)>}
+struct Ref {shard: usize, file: u64, index: u64}
+/// Size classes holding fewer items than this are folded into the next larger class on compaction.
+const MERGE_BELOW: u64 = 4;
+/// When `File::sync_data` is called on bucket files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Durability {
+    /// Leave flushing to the OS. Data survives a process crash, but not necessarily a power failure.
+    Never,
+    /// Flush every file written since the last commit when the cache commits.
+    #[default]
+    OnCommit,
+    /// Flush after every write, before the call returns.
+    EveryWrite,
+}
+
+/// The slot sizes records are grouped into. Each size class gets its own bucket file, and a record takes up a
+/// whole slot of the smallest class it fits in (after a `SLOT_HEADER_BYTES` header), so finer classes waste less
+/// space at the cost of more files. Files of any size already in a folder keep working after the classes change.
+#[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SizeClasses {
+    /// Powers of two, more than twice the record size.
+    #[default]
+    PowerOfTwo,
+    /// An explicit, strictly increasing list of slot sizes. Records larger than the last class are rounded up to a
+    /// multiple of it.
+    Ladder(Vec<u64>),
+    /// Classes starting at 32 bytes, each the previous one times the factor (above 1), rounded up to 8 bytes.
+    Growth(f64),
+}
+impl SizeClasses {
+    /// Whether `FolderCache::with_size_classes` accepts these.
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Self::PowerOfTwo => true,
+            Self::Ladder(ladder) => !ladder.is_empty() && ladder[0] > Level1EntryHeader::BYTES && ladder.is_sorted_by(|a, b| a < b),
+            Self::Growth(factor) => *factor > 1.0,
+        }
+    }
+    fn fit(&self, kser: &[u8], vser: &[u8]) -> u64 {
+        let full_len = kser.len() as u64+vser.len() as u64+Level1EntryHeader::BYTES;
+        match self {
+            Self::PowerOfTwo => 1<<(foremost_bit(full_len) as u64+1),
+            Self::Ladder(ladder) => {
+                let last = *ladder.last().unwrap();
+                ladder.iter().copied().find(|class| *class >= full_len).unwrap_or(full_len.next_multiple_of(last))
+            },
+            Self::Growth(factor) => {
+                let mut class = 32;
+                while class < full_len {
+                    class = ((class as f64*factor).ceil() as u64).next_multiple_of(8).max(class+8);
+                }
+                class
+            },
+        }
+    }
+}
+
+/// Bucket files each directory keeps open unless `FolderCache::with_open_files` says otherwise.
+const DEFAULT_OPEN_FILES: NonZeroUsize = NonZeroUsize::new(8).unwrap();
+/// The bucket files of one directory: the folder itself or one of its shards. `open` is an LRU pool of file handles
+/// keyed by size class.
+struct CacheLevel2 {
+    dir: PathBuf, layout: Layout, shard: usize, files: Vec<CacheLevel1>, open: Mutex<LruCache<u64, Arc<File>>>, journal: Journal,
+    durability: Durability, dirty: HashSet<u64>, dir_dirty: bool, read_only: bool, classes: SizeClasses,
+    overflow: Option<u64>, next_blob: u64, blob_bytes: u64, dedup: Option<u64>, shared: HashMap<u64, u64>,
+    /// The references to shared blobs counted by `FolderCache::continued_lazy`'s thread, still to be added to `shared`.
+    loading_shared: Mutex<Option<Receiver<HashMap<u64, u64>>>>,
+    /// The compression dictionaries of the folder by id, and the one new values are compressed with.
+    dictionaries: HashMap<u32, Arc<Dictionary>>, dictionary: Option<u32>,
+    /// Size classes whose key index has been deleted since the cache was opened, so that later writes need not.
+    unindexed: HashSet<u64>,
+    /// Size classes whose bucket file is shared with a checkpoint, so that it is copied before it is written to.
+    shared_files: HashSet<u64>,
+    #[cfg(all(feature = "mmap", unix))]
+    maps: Mutex<HashMap<u64, Arc<Mapping>>>,
+}
 impl CacheLevel2 {
-    fn new(folder: &Path) -> Result<Self, FolderCacheError> {
+    /// In read-only mode nothing is recovered or migrated; folders that need it are refused instead.
+    /// Bucket files the manifest does not list are deleted, or in read-only mode skipped.
+    fn new(dir: PathBuf, layout: Layout, shard: usize, read_only: bool) -> Result<Self, FolderCacheError> {
+        let manifest = Manifest::read(&dir, &layout)?;
+        if !read_only {
+            Journal::replay(&dir, &layout, manifest.as_ref())?;
+        } else if dir.join(layout.journal_name()).metadata().is_ok_and(|m| m.len() > 0) {
+            return Err(FolderCacheError::NeedsRecovery);
+        }
         let mut files = Vec::new();
-        for file in folder.read_dir()? {
-            if let Some(cachefile) = CacheLevel1::from_path(&file?.path())? {
-                files.push(cachefile);
+        let mut spilled = Vec::new();
+        let mut stray = false;
+        for file in dir.read_dir()? {
+            let path = file?.path();
+            if let Some((size_per_item, generation)) = layout.bucket_file(&path) {
+                if manifest.as_ref().is_some_and(|manifest| manifest.buckets.get(&size_per_item) != Some(&generation)) {
+                    if !read_only {
+                        std::fs::remove_file(&path)?;
+                        let _ = std::fs::remove_file(key_index_path(&path));
+                        stray = true;
+                    }
+                    continue;
+                }
+                if !read_only {
+                    spilled.extend(migrate(&path, size_per_item)?);
+                }
+                let mut bucket = CacheLevel1::from_path(&path, size_per_item, generation)?;
+                if let Some(hints) = read_key_index_hints(&bucket, &path)? {
+                    bucket.hints = hints;
+                }
+                files.push(bucket);
             }
         }
         files.sort();
-        Ok(Self {files, open: None})
+        let shared_files = files.iter().filter(|f| is_shared(&dir.join(layout.bucket_name(f.size_per_item, f.generation))))
+            .map(|f| f.size_per_item).collect();
+        let (mut next_blob, mut blob_bytes) = (0, 0);
+        if let Ok(blobs) = dir.join(layout.blob_dir_name()).read_dir() {
+            for blob in blobs {
+                let blob = blob?;
+                blob_bytes += blob.metadata()?.len();
+                if let Some(id) = blob.file_name().to_str().and_then(|n| n.strip_suffix(".blob")).and_then(|id| id.parse::<u64>().ok()) {
+                    next_blob = next_blob.max(id+1);
+                }
+            }
+        }
+        let journal = Journal::new(&dir, &layout);
+        let open = Mutex::new(LruCache::new(DEFAULT_OPEN_FILES));
+        let mut lvl2 = Self {
+            dir, layout, shard, files, open, journal, durability: Durability::default(), dirty: HashSet::new(), dir_dirty: false, read_only,
+            classes: SizeClasses::default(), overflow: None, next_blob, blob_bytes, dedup: None, shared: HashMap::new(), loading_shared: Mutex::new(None), unindexed: HashSet::new(), shared_files,
+            dictionaries: HashMap::new(), dictionary: None,
+            #[cfg(all(feature = "mmap", unix))]
+            maps: Mutex::new(HashMap::new()),
+        };
+        if !read_only && (stray || manifest.map_or(!lvl2.files.is_empty(), |manifest| manifest.buckets.len() != lvl2.files.len())) {
+            lvl2.dir_dirty |= stray;
+            lvl2.save_manifest()?;
+        }
+        for record in spilled {
+            lvl2.add_raw(record)?;
+        }
+        lvl2.flush()?;
+        Ok(lvl2)
     }
-    fn new_file(&mut self, folder: &Path, size_per_item: u64) -> Result<(), FolderCacheError> {
-        let mut lvl1 = CacheLevel1 {num_items: 0, size_per_item, reserved: 4};
-        if let Err(insertion_point) = self.files.binary_search(&lvl1) {
-            lvl1.create_file(folder)?;
-            self.files.insert(insertion_point, lvl1);
+    fn bucket_path(&self, size_per_item: u64) -> PathBuf {
+        let generation = self.find(size_per_item).map_or(0, |i| self.files[i].generation);
+        self.dir.join(self.layout.bucket_name(size_per_item, generation))
+    }
+    /// Replaces the manifest with one listing the current bucket files.
+    fn save_manifest(&self) -> Result<(), FolderCacheError> {
+        Manifest::of(&self.files).write(&self.dir, &self.layout, self.durability != Durability::Never)
+    }
+    fn blob_path(&self, blob: BlobRef) -> PathBuf {
+        self.dir.join(self.layout.blob_dir_name()).join(blob.name())
+    }
+    /// Moves a value into a blob file if it is large enough to be deduplicated or to overflow, and returns what to
+    /// store in the record in its place. Blobs are synced right away (unless durability is `Never`), since the marker
+    /// can reach the disk before the next commit.
+    fn store_value(&mut self, vser: Vec<u8>) -> Result<Vec<u8>, FolderCacheError> {
+        if BlobRef::parse(&vser).is_some() {
+            return Ok(vser);
         }
-        Ok(())
+        if self.dedup.is_some_and(|min_bytes| vser.len() as u64 >= min_bytes) {
+            return self.store_shared(vser);
+        }
+        if self.overflow.is_none_or(|threshold| vser.len() as u64 <= threshold) {
+            return Ok(self.compress(vser));
+        }
+        self.store_owned(vser)
     }
-    fn switch_open(&mut self, folder: &Path, size_per_item: u64) -> Result<usize, FolderCacheError> {
-        if let Some((old_size, _)) = &mut self.open {
-            if size_per_item == *old_size {return Ok(self.files.binary_search(&CacheLevel1 {reserved: 0, num_items: 0, size_per_item}).unwrap())}
-            self.open = None;
+    /// Compresses a value that stays in its record with the current dictionary, if that makes it smaller.
+    fn compress(&self, vser: Vec<u8>) -> Vec<u8> {
+        let Some(id) = self.dictionary.filter(|_| vser.len() <= MAX_COMPRESSED_VALUE) else {return vser};
+        let packed = compress_with(Some(&self.dictionaries[&id]), &vser);
+        if packed.len()+10 >= vser.len() {
+            return vser;
         }
-        match self.files.binary_search(&CacheLevel1 {num_items: 0, size_per_item, reserved: 0}) {
-            Ok(i) => {
-                let file = OpenOptions::new()
-                .read(true).write(true).open(
-                    folder.join(self.files[i].file_name())
-                )?;
-                self.open = Some((size_per_item, file));
-                return Ok(i);
-            },
-            Err(i) => {
-                self.new_file(folder, size_per_item)?;
-                let file = OpenOptions::new()
-                .read(true).write(true).open(
-                    folder.join(self.files[i].file_name())
-                )?;
-                self.open = Some((size_per_item, file));
-                return Ok(i);
-            }
+        let mut out = vec![0xc9];
+        out.extend_from_slice(&(packed.len() as u32+4).to_be_bytes());
+        out.push(COMPRESSED_EXT);
+        out.extend_from_slice(&id.to_le_bytes());
+        out.extend_from_slice(&packed);
+        out
+    }
+    /// The serialized value held in a record, decompressed if it was compressed.
+    fn inflate<'a>(&self, vser: &'a [u8]) -> Result<Cow<'a, [u8]>, FolderCacheError> {
+        let Some((id, packed)) = compressed_value(vser) else {return Ok(Cow::Borrowed(vser))};
+        let dictionary = self.dictionaries.get(&id).ok_or(FolderCacheError::BadCompression)?;
+        Ok(Cow::Owned(decompress_with(Some(dictionary), packed, MAX_COMPRESSED_VALUE).ok_or(FolderCacheError::BadCompression)?))
+    }
+    fn store_owned(&mut self, vser: Vec<u8>) -> Result<Vec<u8>, FolderCacheError> {
+        let (id, mut blob) = self.create_blob()?;
+        blob.write_all(&vser)?;
+        if self.durability != Durability::Never {
+            blob.sync_data()?;
         }
+        self.blob_bytes += vser.len() as u64;
+        Ok(BlobRef::Owned(id).marker())
     }
-    fn load_to_hashmap<K: Eq+std::hash::Hash+for <'a> Deserialize<'a>>(&mut self, folder: &Path, map: &mut HashMap<K, Ref>) -> Result<(), FolderCacheError> {
-        if let Some(_) = &mut self.open {
-            self.open = None;
+    /// Stores the value in the blob named by its hash, or just counts another reference if that blob already holds
+    /// it. On a hash collision the value gets a blob of its own instead.
+    fn store_shared(&mut self, vser: Vec<u8>) -> Result<Vec<u8>, FolderCacheError> {
+        let hash = checksum(&vser);
+        let path = self.blob_path(BlobRef::Shared(hash));
+        match std::fs::read(&path) {
+            Ok(existing) if existing == vser => {},
+            Ok(_) => return self.store_owned(vser),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.blob_dir()?;
+                let mut blob = OpenOptions::new().write(true).create_new(true).open(&path)?;
+                blob.write_all(&vser)?;
+                if self.durability != Durability::Never {
+                    blob.sync_data()?;
+                }
+                self.blob_bytes += vser.len() as u64;
+            },
+            Err(e) => return Err(e.into()),
         }
-        for filen in 0..self.files.len() {
-            let mut filep = OpenOptions::new().read(true).open(folder.join(self.files[filen].file_name()))?;
-            for i in 0..self.files[filen].len() as u64 {
-                let k = self.files[filen].read_k(&mut filep, i)?;
-                map.insert(k, Ref {file: self.files[filen].size_per_item, index: i});
+        self.load_shared()?;
+        *self.shared.entry(hash).or_default() += 1;
+        Ok(BlobRef::Shared(hash).marker())
+    }
+    /// Streams `reader` into a new blob as a MessagePack binary value and returns the marker and the byte count.
+    /// The length in the binary header is filled in once the reader is exhausted. Fails if it yields more than `max`
+    /// bytes.
+    fn store_stream(&mut self, reader: &mut impl Read, max: Option<u64>) -> Result<(Vec<u8>, u64), FolderCacheError> {
+        let (id, mut blob) = self.create_blob()?;
+        let mut stream = || -> Result<u32, FolderCacheError> {
+            blob.write_all(&[0xc6, 0, 0, 0, 0])?;
+            let len = std::io::copy(&mut reader.take(max.map_or(u64::MAX, |max| max+1)), &mut blob)?;
+            if let Some(max) = max && len > max {
+                return Err(FolderCacheError::TooLarge(max));
+            }
+            let len = u32::try_from(len).map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "value exceeds 4 GiB"))?;
+            write_all_at(&blob, &len.to_be_bytes(), 1)?;
+            if self.durability != Durability::Never {
+                blob.sync_data()?;
             }
+            Ok(len)
+        };
+        match stream() {
+            Ok(len) => {
+                self.blob_bytes += 5+len as u64;
+                Ok((BlobRef::Owned(id).marker(), len as u64))
+            },
+            Err(e) => {
+                let _ = std::fs::remove_file(self.blob_path(BlobRef::Owned(id)));
+                Err(e)
+            },
         }
-        Ok(())
     }
-    #[allow(dead_code)]
-    fn get_v_against_k<K: for <'a> Deserialize<'a>+Eq,V: for <'a> Deserialize<'a>>(&mut self, folder: &Path, k: &K, refs: &[Ref]) -> Result<Option<V>, FolderCacheError> {
-        for Ref {file, index} in refs {
-            let i = self.switch_open(folder, *file)?;
-            let (_, open) = self.open.as_mut().unwrap();
-
-            let (test_k,v) = self.files[i].read::<K,V>(open, *index)?;
-            if k == &test_k {
-                return Ok(Some(v));
-            }
+    fn blob_dir(&mut self) -> Result<(), FolderCacheError> {
+        match std::fs::create_dir(self.dir.join(self.layout.blob_dir_name())) {
+            Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => return Err(e.into()),
+            Ok(()) => self.dir_dirty = true,
+            _ => {}
         }
-        return Ok(None);
+        Ok(())
     }
-    fn get_v<V: for <'a> Deserialize<'a>>(&mut self, folder: &Path, Ref {file, index}: Ref) -> Result<V, FolderCacheError> {
-        let i = self.switch_open(folder, file)?;
-        let (_, open) = self.open.as_mut().unwrap();
-
-        return self.files[i].read_v::<V>(open, index);
+    fn create_blob(&mut self) -> Result<(u64, File), FolderCacheError> {
+        let id = self.next_blob;
+        self.blob_dir()?;
+        let blob = OpenOptions::new().read(true).write(true).create_new(true).open(self.blob_path(BlobRef::Owned(id)))?;
+        self.next_blob += 1;
+        Ok((id, blob))
     }
-    #[allow(dead_code)]
-    fn get<K: for <'a> Deserialize<'a>,V: for <'a> Deserialize<'a>>(&mut self, folder: &Path, Ref {file, index}: Ref) -> Result<(K,V), FolderCacheError> {
-        let i = self.switch_open(folder, file)?;
-        let (_, open) = self.open.as_mut().unwrap();
-
-        return self.files[i].read::<K,V>(open, index);
+    fn load_value<V: for <'a> Deserialize<'a>>(&self, vser: &[u8]) -> Result<V, FolderCacheError> {
+        match BlobRef::parse(vser) {
+            Some(blob) => Ok(rmp_serde::from_slice(&std::fs::read(self.blob_path(blob))?)?),
+            None => Ok(rmp_serde::from_slice(&self.inflate(vser)?)?),
+        }
     }
-    /// Returns the reference that was put IN PLACE of the old reference.
-    fn remove<K: for <'a> Deserialize<'a>>(&mut self, folder: &Path, Ref {file, index}: Ref) -> Result<Option<K>, FolderCacheError> {
-        let i = self.switch_open(folder, file)?;
-        let (_, open) = self.open.as_mut().unwrap();
-        return Ok(self.files[i].swap_remove(open, index)?);
+    /// The serialized value of a record, read from its blob if it has one.
+    fn value_bytes(&self, Ref {file, index, ..}: Ref) -> Result<Vec<u8>, FolderCacheError> {
+        let i = self.find(file).unwrap();
+        let vser = self.files[i].read_vser(&*self.handle(file)?, index)?;
+        match BlobRef::parse(&vser) {
+            Some(blob) => Ok(std::fs::read(self.blob_path(blob))?),
+            None => Ok(self.inflate(&vser)?.into_owned()),
+        }
     }
-    fn add<K: Serialize, V: Serialize>(&mut self, folder: &Path, k: &K, v: &V) -> Result<Ref, FolderCacheError> {
-        let kser = rmp_serde::encode::to_vec(k)?;
-        let vser = rmp_serde::encode::to_vec(v)?;
-        let full_len = kser.len() as u64+vser.len() as u64+Level1EntryHeader::BYTES;
-        let file = 1<<(foremost_bit(full_len) as u64+1);
-        let i = self.switch_open(folder, file)?;
-        let (_, open) = self.open.as_mut().unwrap();
-        let index = self.files[i].add(open, kser, vser)?;
-        Ok(Ref { file, index })
-    }
-    fn overwrite<K: Serialize+for<'a> Deserialize<'a>, V: Serialize>(&mut self, folder: &Path, old_ref: Ref, k: &K, v: &V) -> Result<Option<(Option<K>, Ref)>, FolderCacheError> {
-        let kser = rmp_serde::encode::to_vec(k)?;
-        let vser = rmp_serde::encode::to_vec(v)?;
-        let full_len = kser.len() as u64+vser.len() as u64+Level1EntryHeader::BYTES;
-        let file = 1<<(foremost_bit(full_len) as u64+1);
-        if file == old_ref.file {
-            let i = self.switch_open(folder, file)?;
-            let (_, open) = self.open.as_mut().unwrap();
-            self.files[i].overwrite(open, old_ref.index, kser, vser)?;
-            return Ok(None);
-        } else {
-            let i = self.switch_open(folder, old_ref.file)?;
-            let (_, open) = self.open.as_mut().unwrap();
-            let replace_ref = self.files[i].swap_remove(open, old_ref.index)?;
-            let i = self.switch_open(folder, file)?;
-            let (_, open) = self.open.as_mut().unwrap();
-            let new_ref = Ref {file, index: self.files[i].add(open, kser, vser)?};
-            return Ok(Some((replace_ref, new_ref)));
+    /// Locates the bytes of a MessagePack binary value, without reading them if they are in a blob.
+    fn binary(&self, Ref {file, index, ..}: Ref) -> Result<(Binary, u64), FolderCacheError> {
+        let eof = || FolderCacheError::IO(std::io::ErrorKind::UnexpectedEof.into());
+        let i = self.find(file).unwrap();
+        let vser = self.files[i].read_vser(&*self.handle(file)?, index)?;
+        let Some(blob) = BlobRef::parse(&vser) else {
+            let vser = self.inflate(&vser)?;
+            let (start, len) = bin_header(&vser)?;
+            let bytes = vser.get(start..start+len as usize).ok_or_else(eof)?.to_vec();
+            return Ok((Binary::Inline(bytes), len));
+        };
+        let blob = File::open(self.blob_path(blob))?;
+        let mut head = Vec::new();
+        (&blob).take(5).read_to_end(&mut head)?;
+        let (start, len) = bin_header(&head)?;
+        if blob.metadata()?.len() < start as u64+len {
+            return Err(eof());
         }
+        Ok((Binary::Blob(blob, start as u64), len))
     }
-}
-
-pub fn clear_cache(folder: &Path) -> Result<(), FolderCacheError> {
-    for file in folder.read_dir()? {
-        let path = file?.path();
-        if let Some(_) = CacheLevel1::from_path(&path)? {
-            std::fs::remove_file(&path)?;
+    /// Copies a binary value into `out` without decoding it, straight from the blob file if it has one.
+    fn copy_bytes(&self, refv: Ref, out: &mut impl Write) -> Result<u64, FolderCacheError> {
+        let (binary, len) = self.binary(refv)?;
+        match binary {
+            Binary::Inline(bytes) => out.write_all(&bytes)?,
+            Binary::Blob(mut blob, start) => {
+                blob.seek(SeekFrom::Start(start))?;
+                std::io::copy(&mut blob.take(len), out)?;
+            },
         }
+        Ok(len)
     }
-    Ok(())
-}
+    /// Reads up to `len` bytes of a binary value from `offset` on, with a positioned read if it is in a blob.
+    fn read_range(&self, refv: Ref, offset: u64, len: u64) -> Result<Vec<u8>, FolderCacheError> {
+        let (binary, total) = self.binary(refv)?;
+        let start = offset.min(total);
+        let end = offset.saturating_add(len).min(total);
+        match binary {
+            Binary::Inline(bytes) => Ok(bytes[start as usize..end as usize].to_vec()),
+            Binary::Blob(blob, at) => {
+                let mut buf = vec![0; (end-start) as usize];
+                read_exact_at(&blob, &mut buf, at+start)?;
+                Ok(buf)
+            },
+        }
+    }
+    /// Waits for the references to shared blobs counted in the background, if the keys are still being loaded.
+    fn load_shared(&mut self) -> Result<(), FolderCacheError> {
+        let Some(loading) = self.loading_shared.get_mut().take() else {return Ok(())};
+        let counted = loading.recv().map_err(|_| std::io::Error::other("failed to load the keys"))?;
+        for (hash, count) in counted {
+            *self.shared.entry(hash).or_default() += count;
+        }
+        Ok(())
+    }
+    /// Called when a record no longer refers to `blob`. Deletes the blob unless other records share it.
+    fn drop_blob(&mut self, blob: Option<BlobRef>) -> Result<(), FolderCacheError> {
+        self.load_shared()?;
+        if let Some(BlobRef::Shared(hash)) = blob && let Some(count) = self.shared.get_mut(&hash) {
+            *count -= 1;
+            if *count > 0 {
+                return Ok(());
+            }
+            self.shared.remove(&hash);
+        }
+        if let Some(blob) = blob {
+            let path = self.blob_path(blob);
+            self.blob_bytes = self.blob_bytes.saturating_sub(path.metadata()?.len());
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+    /// When the record was last written, and the bytes it takes up on disk: its slot plus its blob, or its share of
+    /// the blob if several records hold the same value.
+    fn footprint(&self, Ref {file, index, ..}: Ref) -> Result<(u64, u64), FolderCacheError> {
+        let i = self.find(file).unwrap();
+        let filep = self.handle(file)?;
+        let Level1EntryHeader(_, _, stamp) = self.files[i].entry_header(&filep, index)?;
+        let blob = match self.files[i].read_blob(&filep, index)? {
+            Some(BlobRef::Shared(hash)) => self.blob_path(BlobRef::Shared(hash)).metadata()?.len()/self.shared.get(&hash).copied().unwrap_or(1).max(1),
+            Some(blob) => self.blob_path(blob).metadata()?.len(),
+            None => 0,
+        };
+        Ok((stamp.written, file+blob))
+    }
+    fn hint(&self, Ref {file, index, ..}: Ref) -> Option<u64> {
+        self.files[self.find(file).ok()?].hints.get(index as usize).copied().flatten()
+    }
+    fn stamp(&self, Ref {file, index, ..}: Ref) -> Result<Stamp, FolderCacheError> {
+        let i = self.find(file).unwrap();
+        Ok(self.files[i].entry_header(&*self.handle(file)?, index)?.2)
+    }
+    fn get_k<K: for <'a> Deserialize<'a>>(&self, Ref {file, index, ..}: Ref) -> Result<K, FolderCacheError> {
+        let i = self.find(file).unwrap();
+        self.files[i].read_k(&*self.handle(file)?, index)
+    }
+    fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+        self.journal.sync = durability == Durability::EveryWrite;
+    }
+    /// Called after writing to the open file of a size class.
+    fn written(&mut self, size_per_item: u64) -> Result<(), FolderCacheError> {
+        match self.durability {
+            Durability::Never => {},
+            Durability::OnCommit => {self.dirty.insert(size_per_item);},
+            Durability::EveryWrite => {
+                let file = self.handle(size_per_item)?;
+                let i = self.find(size_per_item).unwrap();
+                self.files[i].flush(&file)?;
+                file.sync_data()?;
+            },
+        }
+        Ok(())
+    }
+    /// Writes out the pending appends of every bucket file.
+    fn flush(&mut self) -> Result<(), FolderCacheError> {
+        for i in 0..self.files.len() {
+            if !self.files[i].pending.is_empty() {
+                let file = self.handle(self.files[i].size_per_item)?;
+                self.files[i].flush(&file)?;
+            }
+        }
+        Ok(())
+    }
+    /// Flushes everything written since the last sync, including directory entries of created or deleted files.
+    fn sync(&mut self) -> Result<(), FolderCacheError> {
+        self.flush()?;
+        for size_per_item in std::mem::take(&mut self.dirty) {
+            if self.find(size_per_item).is_ok() {
+                File::open(self.bucket_path(size_per_item))?.sync_data()?;
+            }
+        }
+        #[cfg(unix)]
+        if std::mem::take(&mut self.dir_dirty) {
+            File::open(&self.dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+    /// Copies the bucket files into `dest` after flushing them. Blob files are never modified once written, so they
+    /// are hard-linked where possible; bucket files are updated in place and always copied.
+    fn copy_into(&mut self, dest: &Path) -> Result<(), FolderCacheError> {
+        self.flush()?;
+        match std::fs::create_dir(dest) {
+            Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => return Err(e.into()),
+            _ => {}
+        }
+        for lvl1 in &self.files {
+            std::fs::copy(self.bucket_path(lvl1.size_per_item), dest.join(self.layout.bucket_name(lvl1.size_per_item, 0)))?;
+        }
+        Manifest {buckets: self.files.iter().map(|f| (f.size_per_item, 0)).collect()}.write(dest, &self.layout, false)?;
+        if let Ok(blobs) = self.dir.join(self.layout.blob_dir_name()).read_dir() {
+            let dest = dest.join(self.layout.blob_dir_name());
+            std::fs::create_dir(&dest)?;
+            for blob in blobs {
+                let blob = blob?;
+                if std::fs::hard_link(blob.path(), dest.join(blob.file_name())).is_err() {
+                    std::fs::copy(blob.path(), dest.join(blob.file_name()))?;
+                }
+            }
+        }
+        Ok(())
+    }
+    fn new_file(&mut self, size_per_item: u64) -> Result<(), FolderCacheError> {
+        let mut lvl1 = CacheLevel1 {num_items: 0, size_per_item, generation: 0, reserved: 4, hints: Vec::new(), pending: Vec::new()};
+        if let Err(insertion_point) = self.find(size_per_item) {
+            let path = self.bucket_path(size_per_item);
+            lvl1.create_file(&path)?;
+            if self.durability != Durability::Never {
+                File::open(&path)?.sync_data()?;
+            }
+            self.files.insert(insertion_point, lvl1);
+            self.save_manifest()?;
+        }
+        Ok(())
+    }
+    fn find(&self, size_per_item: u64) -> Result<usize, usize> {
+        self.files.binary_search_by_key(&size_per_item, |f| f.size_per_item)
+    }
+    /// The handle of an existing bucket file, from the pool if it is still open.
+    fn handle(&self, size_per_item: u64) -> Result<Arc<File>, FolderCacheError> {
+        let mut open = self.open.lock();
+        if let Some(file) = open.get(&size_per_item) {
+            return Ok(file.clone());
+        }
+        let file = Arc::new(OpenOptions::new().read(true).write(!self.read_only).open(self.bucket_path(size_per_item))?);
+        open.put(size_per_item, file.clone());
+        Ok(file)
+    }
+    /// A mapping of a bucket file that covers at least `len` bytes, remapping if the file has grown since.
+    #[cfg(all(feature = "mmap", unix))]
+    fn mapping(&self, size_per_item: u64, len: u64) -> Result<Arc<Mapping>, FolderCacheError> {
+        let mut maps = self.maps.lock();
+        if let Some(mapping) = maps.get(&size_per_item).filter(|m| m.len as u64 >= len) {
+            return Ok(mapping.clone());
+        }
+        let mapping = Arc::new(Mapping::new(&*self.handle(size_per_item)?)?);
+        maps.insert(size_per_item, mapping.clone());
+        Ok(mapping)
+    }
+    /// Like `handle`, but creates the file if the size class does not exist yet. Called before writing to it.
+    fn switch_open(&mut self, size_per_item: u64) -> Result<(usize, Arc<File>), FolderCacheError> {
+        self.unindex(size_per_item)?;
+        self.unshare(size_per_item)?;
+        let i = match self.find(size_per_item) {
+            Ok(i) => i,
+            Err(_) if self.read_only => return Err(FolderCacheError::ReadOnly),
+            Err(i) => {
+                self.new_file(size_per_item)?;
+                i
+            }
+        };
+        Ok((i, self.handle(size_per_item)?))
+    }
+    /// Deletes the key index of a size class before its bucket is written to.
+    fn unindex(&mut self, size_per_item: u64) -> Result<(), FolderCacheError> {
+        if self.unindexed.insert(size_per_item) {
+            match std::fs::remove_file(key_index_path(&self.bucket_path(size_per_item))) {
+                Ok(()) => self.dir_dirty = true,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+                Err(e) => {
+                    self.unindexed.remove(&size_per_item);
+                    return Err(e.into());
+                },
+            }
+        }
+        Ok(())
+    }
+    /// Before a bucket file shared with a checkpoint is written to, moves the bucket to a copy of its own, a new
+    /// generation of the file, leaving the old one to the checkpoint.
+    fn unshare(&mut self, size_per_item: u64) -> Result<(), FolderCacheError> {
+        let Ok(i) = self.find(size_per_item) else {return Ok(())};
+        if !self.shared_files.contains(&size_per_item) {
+            return Ok(());
+        }
+        let old = self.bucket_path(size_per_item);
+        self.open.get_mut().pop(&size_per_item);
+        #[cfg(all(feature = "mmap", unix))]
+        self.maps.get_mut().remove(&size_per_item);
+        self.files[i].generation += 1;
+        let new = self.bucket_path(size_per_item);
+        std::fs::copy(&old, &new)?;
+        if self.durability != Durability::Never {
+            File::open(&new)?.sync_data()?;
+        }
+        self.save_manifest()?;
+        std::fs::remove_file(&old)?;
+        self.shared_files.remove(&size_per_item);
+        Ok(())
+    }
+    /// Shares the bucket and blob files of this directory into `dest`, with a manifest, after flushing them.
+    fn checkpoint_into(&mut self, dest: &Path) -> Result<(), FolderCacheError> {
+        self.flush()?;
+        std::fs::create_dir_all(dest)?;
+        for lvl1 in &self.files {
+            let name = self.layout.bucket_name(lvl1.size_per_item, lvl1.generation);
+            share_file(&self.dir.join(&name), &dest.join(name))?;
+        }
+        Manifest::of(&self.files).write(dest, &self.layout, false)?;
+        let blobs = self.dir.join(self.layout.blob_dir_name());
+        if blobs.is_dir() {
+            share_tree(&blobs, &dest.join(self.layout.blob_dir_name()))?;
+        }
+        self.shared_files = self.files.iter().map(|f| f.size_per_item).filter(|size| is_shared(&self.bucket_path(*size))).collect();
+        Ok(())
+    }
+    /// Takes the settings of `other`, for a directory reopened in its place.
+    fn configure_like(&mut self, other: &CacheLevel2) {
+        self.set_durability(other.durability);
+        self.classes = other.classes.clone();
+        self.overflow = other.overflow;
+        self.dedup = other.dedup;
+        self.open.get_mut().resize(other.open.lock().cap());
+    }
+    /// Writes the key index of every bucket that has none that matches it. Reads the key of every record in those.
+    fn save_key_indexes(&mut self) -> Result<(), FolderCacheError> {
+        self.flush()?;
+        for bucket in &self.files {
+            let path = self.bucket_path(bucket.size_per_item);
+            let stamp = key_index_stamp(bucket, &path)?;
+            let current = std::fs::read(key_index_path(&path)).ok()
+                .and_then(|bytes| rmp_serde::from_read::<_, IndexStamp>(&bytes[..]).ok());
+            if current == Some(stamp) {
+                continue;
+            }
+            let filep = OpenOptions::new().read(true).open(&path)?;
+            let mut bytes = rmp_serde::encode::to_vec(&stamp)?;
+            bytes.extend(rmp_serde::encode::to_vec(&bucket.hints)?);
+            for i in 0..bucket.num_items {
+                bytes.extend(bucket.read_kser(&filep, i)?);
+                let blob = match bucket.read_blob(&filep, i)? {
+                    Some(BlobRef::Shared(hash)) => Some(hash),
+                    _ => None,
+                };
+                bytes.extend(rmp_serde::encode::to_vec(&blob)?);
+            }
+            replace_file(&key_index_path(&path), &bytes)?;
+        }
+        Ok(())
+    }
+    fn load_to_hashmap<K: Eq+std::hash::Hash+for <'a> Deserialize<'a>>(&mut self, map: &mut HashMap<K, Ref>) -> Result<(), FolderCacheError> {
+        self.open.get_mut().clear();
+        for bucket in &self.files {
+            load_bucket(bucket, &self.bucket_path(bucket.size_per_item), self.shard, map, &mut self.shared)?;
+        }
+        Ok(())
+    }
+    /// Has `FolderCache::continued_lazy`'s thread count the references to shared blobs, and returns the paths and
+    /// size classes of the buckets for it to read.
+    fn load_later(&mut self) -> LoadLater {
+        let (counted, loading) = std::sync::mpsc::channel();
+        *self.loading_shared.get_mut() = Some(loading);
+        (self.shard, self.files.iter().map(|bucket| (self.bucket_path(bucket.size_per_item), bucket.size_per_item, bucket.generation)).collect(), counted)
+    }
+    fn file_bytes(&self) -> u64 {
+        self.files.iter().map(|f| Level1Header::BYTES+f.reserved*f.size_per_item).sum()
+    }
+    fn live_bytes(&self) -> u64 {
+        self.files.iter().map(|f| Level1Header::BYTES+f.num_items*f.size_per_item).sum()
+    }
+    /// Moves underfilled size classes up into the next larger class, deletes empty files and
+    /// truncates the reserved tail of the rest. Returns the number of bytes freed.
+    fn compact<K: Eq+std::hash::Hash+for <'a> Deserialize<'a>>(&mut self, map: &mut HashMap<K, Ref>) -> Result<u64, FolderCacheError> {
+        self.release_files()?;
+        let before = self.file_bytes();
+        let mut i = 0;
+        while i < self.files.len() {
+            if !self.compact_bucket(i, map)? {
+                i += 1;
+            }
+        }
+        if self.durability != Durability::Never {
+            self.sync()?;
+        }
+        Ok(before-self.file_bytes())
+    }
+    /// Compacts only bucket `i`, as one step of `compact`. Returns the number of bytes freed.
+    fn compact_one<K: Eq+std::hash::Hash+for <'a> Deserialize<'a>>(&mut self, i: usize, map: &mut HashMap<K, Ref>) -> Result<u64, FolderCacheError> {
+        self.release_files()?;
+        let before = self.file_bytes();
+        self.compact_bucket(i, map)?;
+        if self.durability != Durability::Never {
+            self.sync()?;
+        }
+        Ok(before-self.file_bytes())
+    }
+    /// Flushes pending appends and closes every handle and mapping, before bucket files are shrunk or deleted.
+    fn release_files(&mut self) -> Result<(), FolderCacheError> {
+        self.flush()?;
+        self.open.get_mut().clear();
+        #[cfg(all(feature = "mmap", unix))]
+        self.maps.get_mut().clear();
+        Ok(())
+    }
+    /// Whether bucket `i` is too sparse to keep its own size class, so that compacting moves its records up into the
+    /// next one.
+    fn folds(&self, i: usize) -> bool {
+        let from = &self.files[i];
+        from.num_items < MERGE_BELOW && (from.num_items == 0 || i+1 < self.files.len())
+    }
+    /// The bucket with the largest fraction of dead space, and that fraction. A bucket that compacting would fold into
+    /// the next size class counts as all dead.
+    fn most_wasted(&self) -> Option<(usize, f64)> {
+        (0..self.files.len()).map(|i| {
+            let f = &self.files[i];
+            let waste = if self.folds(i) {1.0} else if f.reserved == 0 {0.0} else {(f.reserved-f.num_items) as f64/f.reserved as f64};
+            (i, waste)
+        }).max_by(|(_, a), (_, b)| a.total_cmp(b))
+    }
+    /// Folds bucket `i` into the next size class, or truncates its reserved tail. Returns whether it was folded away.
+    /// Expects `release_files` first.
+    fn compact_bucket<K: Eq+std::hash::Hash+for <'a> Deserialize<'a>>(&mut self, i: usize, map: &mut HashMap<K, Ref>) -> Result<bool, FolderCacheError> {
+        let from = self.files[i].clone();
+        let from_path = self.bucket_path(from.size_per_item);
+        self.unindex(from.size_per_item)?;
+        if self.folds(i) {
+            self.shared_files.remove(&from.size_per_item);
+            let mut obsolete = vec![from_path.clone()];
+            if from.num_items > 0 {
+                // The records move into a new generation of the next bucket file, which replaces the old one in the
+                // same manifest change that drops this one.
+                let into = self.files[i+1].size_per_item;
+                self.unindex(into)?;
+                let into_path = self.bucket_path(into);
+                self.files[i+1].generation += 1;
+                self.shared_files.remove(&into);
+                std::fs::copy(&into_path, self.bucket_path(into))?;
+                obsolete.push(into_path);
+                let src = OpenOptions::new().read(true).open(&from_path)?;
+                let dst = OpenOptions::new().read(true).write(true).open(self.bucket_path(into))?;
+                let mut moved = Vec::new();
+                for index in 0..from.num_items {
+                    let record = from.read_raw(&src, index)?;
+                    let k = rmp_serde::from_slice(&record.0)?;
+                    let new_index = self.files[i+1].add(&dst, record, from.hints[index as usize])?;
+                    moved.push((k, Ref {shard: self.shard, file: into, index: new_index}));
+                }
+                self.files[i+1].flush(&dst)?;
+                if self.durability != Durability::Never {
+                    dst.sync_data()?;
+                }
+                map.extend(moved);
+            }
+            self.files.remove(i);
+            self.save_manifest()?;
+            for path in obsolete {
+                std::fs::remove_file(path)?;
+            }
+            self.dir_dirty = true;
+            Ok(true)
+        } else {
+            self.unshare(from.size_per_item)?;
+            let filep = OpenOptions::new().write(true).open(self.bucket_path(from.size_per_item))?;
+            self.files[i].shrink(&filep)?;
+            if self.durability != Durability::Never {
+                self.dirty.insert(from.size_per_item);
+            }
+            Ok(false)
+        }
+    }
+    #[allow(dead_code)]
+    fn get_v_against_k<K: for <'a> Deserialize<'a>+Eq,V: for <'a> Deserialize<'a>>(&self, k: &K, refs: &[Ref]) -> Result<Option<V>, FolderCacheError> {
+        for Ref {file, index, ..} in refs {
+            let i = self.find(*file).unwrap();
+            let (test_k,v) = self.files[i].read::<K,V>(&*self.handle(*file)?, *index)?;
+            if k == &test_k {
+                return Ok(Some(v));
+            }
+        }
+        Ok(None)
+    }
+    fn get_v<V: for <'a> Deserialize<'a>>(&self, Ref {file, index, ..}: Ref) -> Result<V, FolderCacheError> {
+        let i = self.find(file).unwrap();
+        #[cfg(all(feature = "mmap", unix))]
+        if index < self.files[i].written_items() {
+            let mapping = self.mapping(file, self.files[i].slot(index+1))?;
+            return self.load_value(self.files[i].mapped_vser(mapping.bytes(), index)?);
+        }
+        self.load_value(&self.files[i].read_vser(&*self.handle(file)?, index)?)
+    }
+    fn get<K: for <'a> Deserialize<'a>,V: for <'a> Deserialize<'a>>(&self, refv: Ref) -> Result<(K,V), FolderCacheError> {
+        Ok((self.get_k(refv)?, self.get_v(refv)?))
+    }
+    /// Adds the occupancy of each bucket file to `classes`, keyed by size class.
+    fn stats(&self, classes: &mut BTreeMap<u64, ClassStats>) -> Result<(), FolderCacheError> {
+        for lvl1 in &self.files {
+            let filep = self.handle(lvl1.size_per_item)?;
+            let class = classes.entry(lvl1.size_per_item).or_insert(ClassStats {size_per_item: lvl1.size_per_item, ..ClassStats::default()});
+            class.file_bytes += Level1Header::BYTES+lvl1.reserved*lvl1.size_per_item;
+            class.reserved += lvl1.reserved;
+            class.items += lvl1.num_items;
+            class.wasted_bytes += (lvl1.reserved-lvl1.num_items)*lvl1.size_per_item;
+            for i in 0..lvl1.num_items {
+                let Level1EntryHeader(k_size, v_size, _) = lvl1.entry_header(&filep, i)?;
+                class.wasted_bytes += lvl1.size_per_item.saturating_sub(Level1EntryHeader::BYTES+k_size+v_size);
+            }
+        }
+        Ok(())
+    }
+    /// Every record of this directory, in on-disk order.
+    fn refs(&self) -> impl Iterator<Item = Ref> + '_ {
+        self.files.iter().flat_map(|f| (0..f.num_items).map(|index| Ref {shard: self.shard, file: f.size_per_item, index}))
+    }
+    /// Returns the reference that was put IN PLACE of the old reference.
+    fn remove<K: for <'a> Deserialize<'a>>(&mut self, Ref {file, index, ..}: Ref) -> Result<Option<K>, FolderCacheError> {
+        let (i, open) = self.switch_open(file)?;
+        let blob = self.files[i].read_blob(&open, index)?;
+        let moved = self.files[i].swap_remove(&open, &mut self.journal, index)?;
+        self.written(file)?;
+        self.drop_blob(blob)?;
+        Ok(moved)
+    }
+    fn add_raw(&mut self, (kser, vser, stamp): StampedRecord) -> Result<Ref, FolderCacheError> {
+        let vser = self.store_value(vser)?;
+        let file = self.classes.fit(&kser, &vser);
+        let (i, open) = self.switch_open(file)?;
+        let index = self.files[i].add(&open, (kser, vser, stamp), None)?;
+        self.written(file)?;
+        Ok(Ref { shard: self.shard, file, index })
+    }
+    /// Appends records that all belong to the size class `file`.
+    fn add_many(&mut self, file: u64, records: Vec<RawRecord>) -> Result<Vec<Ref>, FolderCacheError> {
+        let count = records.len() as u64;
+        let (i, open) = self.switch_open(file)?;
+        let first = self.files[i].add_many(&open, records, Stamp::now())?;
+        self.written(file)?;
+        Ok((first..first+count).map(|index| Ref {shard: self.shard, file, index}).collect())
+    }
+    /// Like `add`, but places the record next to others sharing `hint`. Also returns the key that had to be moved
+    /// out of the way, if any, with its new location.
+    fn add_hinted<K: for <'a> Deserialize<'a>>(&mut self, kser: Vec<u8>, vser: Vec<u8>, hint: u64, stamp: Stamp) -> Result<(Ref, Option<(K, Ref)>), FolderCacheError> {
+        let vser = self.store_value(vser)?;
+        let file = self.classes.fit(&kser, &vser);
+        let (i, open) = self.switch_open(file)?;
+        let (index, moved) = self.files[i].add_hinted(&open, &mut self.journal, (kser, vser, stamp), hint)?;
+        self.written(file)?;
+        let shard = self.shard;
+        Ok((Ref { shard, file, index }, moved.map(|(k, index)| (k, Ref { shard, file, index }))))
+    }
+    fn overwrite<K: for<'a> Deserialize<'a>>(&mut self, old_ref: Ref, kser: Vec<u8>, vser: Vec<u8>) -> Result<Option<(Option<K>, Ref)>, FolderCacheError> {
+        let vser = self.store_value(vser)?;
+        let file = self.classes.fit(&kser, &vser);
+        let (i, open) = self.switch_open(old_ref.file)?;
+        let old_blob = self.files[i].read_blob(&open, old_ref.index)?;
+        let stamp = self.files[i].entry_header(&open, old_ref.index)?.2.rewritten();
+        if file == old_ref.file {
+            self.files[i].overwrite(&open, &mut self.journal, old_ref.index, (kser, vser, stamp))?;
+            self.written(file)?;
+            self.drop_blob(old_blob)?;
+            Ok(None)
+        } else {
+            let hint = self.files[i].hints[old_ref.index as usize];
+            let replace_ref = self.files[i].swap_remove(&open, &mut self.journal, old_ref.index)?;
+            self.written(old_ref.file)?;
+            let (i, open) = self.switch_open(file)?;
+            let new_ref = Ref {shard: self.shard, file, index: self.files[i].add(&open, (kser, vser, stamp), hint)?};
+            // The old copy is already gone, so the new one must not wait for the next commit.
+            self.files[i].flush(&open)?;
+            self.written(file)?;
+            self.drop_blob(old_blob)?;
+            Ok(Some((replace_ref, new_ref)))
+        }
+    }
+}
+
+impl Drop for CacheLevel2 {
+    fn drop(&mut self) {
+        let _ = self.flush();
+        if !self.read_only {
+            let _ = self.save_key_indexes();
+        }
+    }
+}
+
+/// Removes the files of a default-layout cache from `folder`.
+pub fn clear_cache(folder: &Path) -> Result<(), FolderCacheError> {
+    Layout::default().clear(folder)
+}
+
+/// Advisory lock on the cache folder, held for as long as the cache is open. With the default (empty) prefix, the
+/// directory itself is locked rather than a lock file, so that no extra file appears in the folder; prefixed caches
+/// lock a `{prefix}lock` file instead so they can share the folder. Only enforced on Unix.
+/// `tables` holds the names of the `CacheFolder` tables currently open under this lock.
+struct FolderLock {_dir: File, tables: Mutex<HashSet<String>>}
+impl FolderLock {
+    fn acquire(folder: &Path, layout: &Layout, shared: bool) -> Result<Self, FolderCacheError> {
+        let dir = if layout.prefix.is_empty() {
+            File::open(folder)?
+        } else {
+            OpenOptions::new().read(true).write(true).create(true).truncate(false).open(folder.join(format!("{}lock", layout.prefix)))?
+        };
+        #[cfg(unix)]
+        {
+            use std::os::fd::AsRawFd;
+            let op = if shared {libc::LOCK_SH} else {libc::LOCK_EX};
+            if unsafe {libc::flock(dir.as_raw_fd(), op|libc::LOCK_NB)} != 0 {
+                let err = std::io::Error::last_os_error();
+                return Err(if err.kind() == std::io::ErrorKind::WouldBlock {FolderCacheError::AlreadyLocked} else {err.into()});
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = shared;
+        Ok(Self {_dir: dir, tables: Mutex::new(HashSet::new())})
+    }
+}
+/// A cache's share of a `FolderLock`, which may be held together with the other tables of a `CacheFolder`.
+struct LockRef {lock: Arc<FolderLock>, table: Option<String>}
+impl LockRef {
+    fn acquire(folder: &Path, layout: &Layout, shared: bool) -> Result<Self, FolderCacheError> {
+        Ok(Self {lock: Arc::new(FolderLock::acquire(folder, layout, shared)?), table: None})
+    }
+}
+impl Drop for LockRef {
+    fn drop(&mut self) {
+        if let Some(table) = &self.table {
+            self.lock.tables.lock().remove(table);
+        }
+    }
+}
+
+/// A folder holding any number of independent `FolderCache` tables, all opened under one lock. The files of table
+/// `name` carry the prefix `{name}.`, so each table has its own key space.
+pub struct CacheFolder {folder: PathBuf, lock: Arc<FolderLock>}
+impl CacheFolder {
+    /// Locks `folder` for its tables. Fails with `AlreadyLocked` if a cache already has the folder open.
+    pub fn open(folder: PathBuf) -> Result<Self, FolderCacheError> {
+        let lock = Arc::new(FolderLock::acquire(&folder, &Layout::default(), false)?);
+        Ok(Self {folder, lock})
+    }
+    /// Opens table `name`, keeping the entries already in it. Fails with `AlreadyLocked` if the table is open.
+    pub fn table<K>(&self, name: &str) -> Result<FolderCache<K>, FolderCacheError> where
+    K: Eq+std::hash::Hash+Serialize+for <'a> Deserialize<'a> {
+        self.table_with(name, Layout::default())
+    }
+    /// Like `table`, with the extension and sharding of `layout`. Its prefix is replaced by the table's.
+    pub fn table_with<K>(&self, name: &str, layout: Layout) -> Result<FolderCache<K>, FolderCacheError> where
+    K: Eq+std::hash::Hash+Serialize+for <'a> Deserialize<'a> {
+        assert!(!name.is_empty() && !name.contains('.') && !name.contains(std::path::is_separator));
+        if !self.lock.tables.lock().insert(name.to_string()) {
+            return Err(FolderCacheError::AlreadyLocked);
+        }
+        let lock = LockRef {lock: self.lock.clone(), table: Some(name.to_string())};
+        FolderCache::open(self.folder.clone(), layout.with_prefix(format!("{name}.")), lock, false, false)
+    }
+    /// Names of the tables that have files in the folder.
+    pub fn table_names(&self) -> Result<Vec<String>, FolderCacheError> {
+        let mut names = Vec::new();
+        for entry in self.folder.read_dir()? {
+            let entry = entry?;
+            let Some((table, rest)) = entry.file_name().to_str().and_then(|n| n.split_once('.')).map(|(t, r)| (t.to_string(), r.to_string())) else {continue};
+            let bucket = rest.split_once('.').is_some_and(|(size, _)| !size.is_empty() && size.bytes().all(|b| b.is_ascii_digit()));
+            let shard = rest.len() == 2 && u32::from_str_radix(&rest, 16).is_ok() && entry.file_type()?.is_dir();
+            if bucket || shard {
+                names.push(table);
+            }
+        }
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+}
+
+/// Something `FolderCache::verify` found wrong with a bucket file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// The file is too short to hold a header.
+    Truncated,
+    BadHeader,
+    /// Written by an older build; `continued()` will migrate it.
+    Outdated(u32),
+    UnsupportedVersion(u32),
+    /// The data region is not a whole number of slots.
+    Misaligned,
+    /// The header claims more items than the file has room for.
+    CountExceedsReserved {num_items: u64, reserved: u64},
+    /// The record's sizes run past the end of its slot.
+    RecordOutOfBounds(u64),
+    UndecodableKey(u64),
+    UndecodableValue(u64),
+    /// The slot holds a key already seen in another slot.
+    DuplicateKey(u64),
+    /// The record's value was moved to a blob file that no longer exists.
+    MissingBlob(u64),
+}
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub files: usize,
+    pub records: u64,
+    /// Whether a journal of interrupted writes is waiting to be replayed by the next open.
+    pub pending_journal: bool,
+    pub problems: Vec<(PathBuf, Problem)>,
+}
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.iter().all(|(_, problem)| matches!(problem, Problem::Outdated(_)))
+    }
+}
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    pub salvaged: u64,
+    pub dropped: u64,
+}
+
+/// Reads a bucket file without trusting it. Returns file-level problems and, unless the header is unusable,
+/// each slot that the header covers as either its record or the reason it is unreadable.
+/// With `every_slot`, a missing or corrupt header is not fatal and all slots in the file are returned.
+#[allow(clippy::type_complexity)]
+fn scan_bucket(path: &Path, size_per_item: u64, every_slot: bool) -> Result<(Vec<Problem>, Vec<Result<StampedRecord, Problem>>), FolderCacheError> {
+    let bytes = std::fs::read(path)?;
+    let mut problems = Vec::new();
+    let body_len = bytes.len().saturating_sub(Level1Header::BYTES as usize) as u64;
+    let reserved = body_len/size_per_item;
+    if bytes.len() < Level1Header::BYTES as usize {
+        problems.push(Problem::Truncated);
+    } else if !body_len.is_multiple_of(size_per_item) {
+        problems.push(Problem::Misaligned);
+    }
+    let num_items = match bytes.get(..Level1Header::BYTES as usize).map(Level1Header::from_bytes) {
+        Some(Ok(Level1Header(num_items))) if num_items > reserved => {
+            problems.push(Problem::CountExceedsReserved {num_items, reserved});
+            Some(reserved)
+        },
+        Some(Ok(Level1Header(num_items))) => Some(num_items),
+        Some(Err(FolderCacheError::UnsupportedVersion(v))) if v < FORMAT_VERSION => {problems.push(Problem::Outdated(v)); None},
+        Some(Err(FolderCacheError::UnsupportedVersion(v))) => {problems.push(Problem::UnsupportedVersion(v)); None},
+        Some(Err(_)) if read_u64(&bytes).saturating_mul(size_per_item) <= bytes.len() as u64-SZU64 as u64 => {problems.push(Problem::Outdated(0)); None},
+        Some(Err(_)) => {problems.push(Problem::BadHeader); None},
+        None => None,
+    };
+    let Some(count) = num_items.or(every_slot.then_some(reserved)) else {return Ok((problems, Vec::new()))};
+    let slots = (0..count).map(|i| {
+        let start = (Level1Header::BYTES+i*size_per_item) as usize;
+        let slot = &bytes[start..start+size_per_item as usize];
+        let Level1EntryHeader(k_size, v_size, stamp) = Level1EntryHeader::from_bytes(slot);
+        let end = Level1EntryHeader::BYTES.checked_add(v_size).and_then(|x| x.checked_add(k_size));
+        if end.is_none_or(|end| end > size_per_item) {
+            return Err(Problem::RecordOutOfBounds(i));
+        }
+        let v_start = Level1EntryHeader::BYTES as usize;
+        let k_start = v_start+v_size as usize;
+        let vser = &slot[v_start..k_start];
+        if rmp_serde::from_slice::<serde::de::IgnoredAny>(vser).is_err() {
+            return Err(Problem::UndecodableValue(i));
+        }
+        Ok((slot[k_start..k_start+k_size as usize].to_vec(), vser.to_vec(), stamp))
+    }).collect();
+    Ok((problems, slots))
+}
+
+/// Occupancy of one size class, summed over shards, as reported by `FolderCache::stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClassStats {
+    pub size_per_item: u64,
+    /// Header plus reserved slots; this is what the files take on disk.
+    pub file_bytes: u64,
+    pub reserved: u64,
+    pub items: u64,
+    /// Bytes of the slots not holding records: empty reserved slots and the padding behind each record.
+    pub wasted_bytes: u64,
+}
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// By increasing size class.
+    pub classes: Vec<ClassStats>,
+    /// Sums over all classes, with a `size_per_item` of 0.
+    pub total: ClassStats,
+    /// Bytes in blob files (see `FolderCache::with_overflow_threshold`).
+    pub blob_bytes: u64,
+}
+
+/// Maps a serialized value to the serialized attribute it is indexed by.
+type Extractor = Box<dyn Fn(&[u8]) -> Result<Vec<u8>, FolderCacheError>+Send+Sync>;
+/// An index of a `FolderCache` by some attribute of its values. Keys and attributes are kept serialized.
+struct SecondaryIndex {
+    extract: Extractor,
+    keys: HashMap<Vec<u8>, HashSet<Vec<u8>>>, attributes: HashMap<Vec<u8>, Vec<u8>>,
+}
+impl SecondaryIndex {
+    fn insert(&mut self, kser: Vec<u8>, attribute: Vec<u8>) {
+        self.remove(&kser);
+        self.keys.entry(attribute.clone()).or_default().insert(kser.clone());
+        self.attributes.insert(kser, attribute);
+    }
+    fn remove(&mut self, kser: &[u8]) {
+        let Some(attribute) = self.attributes.remove(kser) else {return};
+        if let Some(keys) = self.keys.get_mut(&attribute) {
+            keys.remove(kser);
+            if keys.is_empty() {
+                self.keys.remove(&attribute);
+            }
+        }
+    }
+}
+
+/// Timestamps of an entry, as returned by `FolderCache::metadata`. Entries migrated from files that predate them
+/// take the bucket file's modification time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EntryMetadata {
+    pub created: std::time::SystemTime,
+    /// The last time the value was written. Moves during compaction do not count.
+    pub modified: std::time::SystemTime,
+    /// 1 when inserted, and one more on every write since.
+    pub version: u64,
+}
+
+/// The keys of a `FolderCache` in order, kept by `FolderCache::with_ordered_keys`. A trait object, so that only
+/// caches that keep it need `K: Ord`.
+trait OrderedKeys<K>: Send+Sync {
+    fn insert(&mut self, k: &K);
+    fn remove(&mut self, k: &K);
+    fn clear(&mut self);
+    fn range<'a>(&'a self, start: Bound<&K>, end: Bound<&K>) -> Box<dyn Iterator<Item = &'a K>+'a>;
+}
+impl<K: Ord+Clone+Send+Sync> OrderedKeys<K> for BTreeSet<K> {
+    fn insert(&mut self, k: &K) { BTreeSet::insert(self, k.clone()); }
+    fn remove(&mut self, k: &K) { BTreeSet::remove(self, k); }
+    fn clear(&mut self) { BTreeSet::clear(self) }
+    fn range<'a>(&'a self, start: Bound<&K>, end: Bound<&K>) -> Box<dyn Iterator<Item = &'a K>+'a> {
+        Box::new(BTreeSet::range(self, (start, end)))
+    }
+}
+
+/// The in-memory index of a `FolderCache`'s keys. Opened by `FolderCache::continued_lazy`, it is read on a background
+/// thread, and the first use waits for it.
+struct KeyMap<K> {map: OnceLock<HashMap<K, Ref>>, loading: Mutex<Option<Loading<K>>>}
+type Loading<K> = JoinHandle<Result<HashMap<K, Ref>, FolderCacheError>>;
+impl<K> KeyMap<K> {
+    fn loaded(map: HashMap<K, Ref>) -> Self {
+        Self {map: OnceLock::from(map), loading: Mutex::new(None)}
+    }
+    fn wait(&self) -> Result<&HashMap<K, Ref>, FolderCacheError> {
+        if let Some(map) = self.map.get() {
+            return Ok(map);
+        }
+        let mut loading = self.loading.lock();
+        if let Some(map) = self.map.get() {
+            return Ok(map);
+        }
+        let thread = loading.take().ok_or_else(|| std::io::Error::other("failed to load the keys"))?;
+        let map = thread.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))?;
+        Ok(self.map.get_or_init(|| map))
+    }
+}
+impl<K> Deref for KeyMap<K> {
+    type Target = HashMap<K, Ref>;
+    fn deref(&self) -> &Self::Target {
+        self.wait().unwrap_or_else(|e| panic!("failed to load the keys of a FolderCache: {e}"))
+    }
+}
+impl<K> DerefMut for KeyMap<K> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        let _ = self.deref();
+        self.map.get_mut().unwrap()
+    }
+}
 
 pub struct FolderCache<K: std::hash::Hash+Eq+Serialize+for <'a> Deserialize<'a>>
-{lvl2: CacheLevel2, map: HashMap<K, Ref>, folder: PathBuf}
+{shards: Vec<CacheLevel2>, targets: Vec<usize>, map: KeyMap<K>, ordered: Option<Box<dyn OrderedKeys<K>>>, quota: Option<u64>, max_value: Option<u64>, evicted: Vec<K>, indexes: HashMap<String, SecondaryIndex>,
+/// The serialized keys of the pinned entries (see `pin`).
+pinned: HashSet<Vec<u8>>, _lock: LockRef}
 impl<K> FolderCache<K> where
 K: Eq+std::hash::Hash+Serialize+for <'a> Deserialize<'a> {
+    fn open(folder: PathBuf, layout: Layout, _lock: LockRef, read_only: bool, clear: bool) -> Result<Self, FolderCacheError> {
+        let (mut shards, targets) = Self::open_shards(&folder, &layout, read_only, clear)?;
+        let mut map = HashMap::new();
+        for lvl2 in &mut shards {
+            lvl2.load_to_hashmap(&mut map)?;
+        }
+        Self::from_parts(shards, targets, KeyMap::loaded(map), _lock)
+    }
+    fn open_shards(folder: &Path, layout: &Layout, read_only: bool, clear: bool) -> Result<(Vec<CacheLevel2>, Vec<usize>), FolderCacheError> {
+        if clear {
+            layout.clear(folder)?;
+        }
+        let dirs = layout.bucket_dirs(folder, !read_only)?;
+        let targets = (0..layout.shards).map(|shard| dirs.iter().position(|(n, _)| *n == Some(shard)))
+            .collect::<Option<Vec<usize>>>().filter(|targets| !targets.is_empty()).unwrap_or(vec![0]);
+        let mut shards = dirs.into_iter().enumerate().map(|(i, (_, dir))| CacheLevel2::new(dir, layout.clone(), i, read_only))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut dictionaries = HashMap::new();
+        if let Ok(files) = folder.join(layout.dictionary_dir_name()).read_dir() {
+            for file in files {
+                let path = file?.path();
+                if let Some(id) = path.file_name().and_then(OsStr::to_str).and_then(|n| n.strip_suffix(".dict")).and_then(|id| id.parse::<u32>().ok()) {
+                    dictionaries.insert(id, Arc::new(Dictionary::new(std::fs::read(&path)?)));
+                }
+            }
+        }
+        let current = dictionaries.keys().max().copied();
+        for lvl2 in &mut shards {
+            lvl2.dictionaries = dictionaries.clone();
+            lvl2.dictionary = current;
+        }
+        Ok((shards, targets))
+    }
+    fn from_parts(shards: Vec<CacheLevel2>, targets: Vec<usize>, map: KeyMap<K>, _lock: LockRef) -> Result<Self, FolderCacheError> {
+        let pinned = read_pins(&shards[0])?;
+        Ok(Self {shards, targets, map, ordered: None, quota: None, max_value: None, evicted: Vec::new(), indexes: HashMap::new(), pinned, _lock})
+    }
+    /// Opens `folder`, deleting any cache files already in it. Fails with `AlreadyLocked` if another cache has the
+    /// folder open.
     pub fn cleared(folder: PathBuf) -> Result<Self, FolderCacheError> {
-        clear_cache(&folder)?;
-        let lvl2 = CacheLevel2::new(&folder)?;
-        let map = HashMap::new();
-        Ok(Self {folder, lvl2, map})
+        Self::cleared_with(folder, Layout::default())
+    }
+    pub fn cleared_with(folder: PathBuf, layout: Layout) -> Result<Self, FolderCacheError> {
+        let lock = LockRef::acquire(&folder, &layout, false)?;
+        Self::open(folder, layout, lock, false, true)
     }
+    /// Opens `folder`, keeping the entries already in it. Fails with `AlreadyLocked` if another cache has the
+    /// folder open.
     pub fn continued(folder: PathBuf) -> Result<Self, FolderCacheError> {
-        let mut lvl2 = CacheLevel2::new(&folder)?;
-        let mut map = HashMap::new();
-        lvl2.load_to_hashmap(&folder, &mut map)?;
-        Ok(Self {folder, lvl2, map})
+        Self::continued_with(folder, Layout::default())
+    }
+    pub fn continued_with(folder: PathBuf, layout: Layout) -> Result<Self, FolderCacheError> {
+        let lock = LockRef::acquire(&folder, &layout, false)?;
+        Self::open(folder, layout, lock, false, false)
+    }
+    /// Like `continued`, but returns without reading the keys, which a background thread loads instead, so that
+    /// opening a large cache does not take time in proportion to its entries. The first use of the keys waits for
+    /// them. If they fail to load, that use panics; call `wait_loaded` first to get the error instead.
+    pub fn continued_lazy(folder: PathBuf) -> Result<Self, FolderCacheError> where K: Send+'static {
+        Self::continued_lazy_with(folder, Layout::default())
+    }
+    pub fn continued_lazy_with(folder: PathBuf, layout: Layout) -> Result<Self, FolderCacheError> where K: Send+'static {
+        let lock = LockRef::acquire(&folder, &layout, false)?;
+        let (mut shards, targets) = Self::open_shards(&folder, &layout, false, false)?;
+        let buckets: Vec<_> = shards.iter_mut().map(CacheLevel2::load_later).collect();
+        let thread = std::thread::spawn(move || {
+            let mut map = HashMap::new();
+            for (shard, paths, counted) in buckets {
+                let mut shared = HashMap::new();
+                for (path, size_per_item, generation) in paths {
+                    let bucket = CacheLevel1::from_path(&path, size_per_item, generation)?;
+                    load_bucket(&bucket, &path, shard, &mut map, &mut shared)?;
+                }
+                let _ = counted.send(shared);
+            }
+            Ok(map)
+        });
+        let map = KeyMap {map: OnceLock::new(), loading: Mutex::new(Some(thread))};
+        Self::from_parts(shards, targets, map, lock)
+    }
+    /// Waits until the keys are loaded, after `continued_lazy`, and returns why they could not be if so.
+    pub fn wait_loaded(&self) -> Result<(), FolderCacheError> {
+        self.map.wait().map(|_| ())
+    }
+    /// Opens `folder` without ever writing to it: files are opened read-only and `insert`, `remove` and `compact`
+    /// fail with `ReadOnly`. Any number of read-only caches can share a folder, but not with a writable one.
+    pub fn read_only(folder: PathBuf) -> Result<Self, FolderCacheError> {
+        Self::read_only_with(folder, Layout::default())
+    }
+    pub fn read_only_with(folder: PathBuf, layout: Layout) -> Result<Self, FolderCacheError> {
+        let lock = LockRef::acquire(&folder, &layout, true)?;
+        Self::open(folder, layout, lock, true, false)
+    }
+    pub fn is_read_only(&self) -> bool {self.shards[0].read_only}
+    fn check_writable(&self) -> Result<(), FolderCacheError> {
+        if self.is_read_only() {Err(FolderCacheError::ReadOnly)} else {Ok(())}
+    }
+    /// The shard a new key is written to.
+    fn target(&self, k: &K) -> Result<usize, FolderCacheError> {
+        if self.targets.len() == 1 {
+            return Ok(self.targets[0]);
+        }
+        let hash = checksum(&rmp_serde::encode::to_vec(k)?);
+        Ok(self.targets[(hash%self.targets.len() as u64) as usize])
+    }
+    /// Checks every bucket file in `folder` for structural damage without modifying anything.
+    pub fn verify(folder: &Path) -> Result<VerifyReport, FolderCacheError> {
+        Self::verify_with(folder, &Layout::default())
+    }
+    pub fn verify_with(folder: &Path, layout: &Layout) -> Result<VerifyReport, FolderCacheError> {
+        let mut report = VerifyReport::default();
+        let mut seen = HashSet::new();
+        for (_, dir) in layout.bucket_dirs(folder, false)? {
+            report.pending_journal |= dir.join(layout.journal_name()).metadata().is_ok_and(|m| m.len() > 0);
+            for (path, size_per_item) in layout.listed_buckets(&dir)? {
+                report.files += 1;
+                let (problems, slots) = scan_bucket(&path, size_per_item, false)?;
+                report.problems.extend(problems.into_iter().map(|problem| (path.clone(), problem)));
+                let blobs = dir.join(layout.blob_dir_name());
+                for (i, slot) in slots.into_iter().enumerate() {
+                    let checked = slot.and_then(|(kser, vser, _)| match BlobRef::parse(&vser) {
+                        Some(blob) if !blobs.join(blob.name()).is_file() => Err(Problem::MissingBlob(i as u64)),
+                        _ => Ok(kser),
+                    })
+                        .and_then(|kser| rmp_serde::from_slice::<K>(&kser).map_err(|_| Problem::UndecodableKey(i as u64)))
+                        .and_then(|k| if seen.insert(k) {Ok(())} else {Err(Problem::DuplicateKey(i as u64))});
+                    match checked {
+                        Ok(()) => report.records += 1,
+                        Err(problem) => report.problems.push((path.clone(), problem)),
+                    }
+                }
+            }
+        }
+        Ok(report)
+    }
+    /// Rebuilds the bucket files of `folder` from every record that can still be read, dropping the rest. Files
+    /// with an unreadable header have all their slots tried, which can bring back records that had been removed.
+    pub fn repair(folder: &Path) -> Result<RepairReport, FolderCacheError> {
+        Self::repair_with(folder, &Layout::default())
+    }
+    pub fn repair_with(folder: &Path, layout: &Layout) -> Result<RepairReport, FolderCacheError> {
+        let _lock = FolderLock::acquire(folder, layout, false)?;
+        let mut report = RepairReport::default();
+        let mut seen = HashSet::new();
+        for (_, dir) in layout.bucket_dirs(folder, false)? {
+            Journal::replay(&dir, layout, Manifest::read(&dir, layout)?.as_ref())?;
+            let staging = dir.join(format!("{}repair.tmp", layout.prefix));
+            if staging.exists() {
+                std::fs::remove_dir_all(&staging)?;
+            }
+            std::fs::create_dir(&staging)?;
+            let mut fresh = CacheLevel2::new(staging.clone(), layout.clone(), 0, false)?;
+            for (path, size_per_item) in layout.listed_buckets(&dir)? {
+                // If the file cannot be migrated, the scan below still salvages what it can.
+                let spilled = migrate(&path, size_per_item).unwrap_or_default();
+                let (_, slots) = scan_bucket(&path, size_per_item, true)?;
+                for slot in slots.into_iter().chain(spilled.into_iter().map(Ok)) {
+                    let salvage = slot.ok().and_then(|record| {
+                        let k = rmp_serde::from_slice::<K>(&record.0).ok().filter(|k| !seen.contains(k))?;
+                        Some((k, record))
+                    });
+                    match salvage {
+                        Some((k, record)) => {
+                            seen.insert(k);
+                            fresh.add_raw(record)?;
+                            report.salvaged += 1;
+                        },
+                        None => report.dropped += 1,
+                    }
+                }
+            }
+            fresh.flush()?;
+            fresh.open.get_mut().clear();
+            for file in dir.read_dir()? {
+                let path = file?.path();
+                if layout.size_class(&path).is_some() {
+                    std::fs::remove_file(path)?;
+                }
+            }
+            for file in staging.read_dir()? {
+                let path = file?.path();
+                if layout.size_class(&path).is_some() || path.file_name() == Some(OsStr::new(&layout.manifest_name())) {
+                    std::fs::rename(&path, dir.join(path.file_name().unwrap()))?;
+                }
+            }
+            std::fs::remove_dir_all(&staging)?;
+        }
+        Ok(report)
     }
     pub fn insert<V: Serialize>(&mut self, k: K, v: &V) -> Result<(), FolderCacheError> {
+        self.write(k, Some(rmp_serde::encode::to_vec(v)?))
+    }
+    /// Inserts a serialized value, or removes the key for `None`.
+    fn write(&mut self, k: K, vser: Option<Vec<u8>>) -> Result<(), FolderCacheError> {
+        let Some(vser) = vser else {return self.remove(&k)};
+        self.check_writable()?;
+        self.check_size(vser.len())?;
+        self.insert_raw(k, vser)?;
+        self.enforce_quota()
+    }
+    fn check_size(&self, len: usize) -> Result<(), FolderCacheError> {
+        match self.max_value {
+            Some(max) if len as u64 > max => Err(FolderCacheError::TooLarge(max)),
+            _ => Ok(()),
+        }
+    }
+    fn insert_raw(&mut self, k: K, vser: Vec<u8>) -> Result<(), FolderCacheError> {
+        let kser = rmp_serde::encode::to_vec(&k)?;
+        let attributes = match BlobRef::parse(&vser) {
+            None if !self.indexes.is_empty() => Some((kser.clone(), self.attributes(&vser)?)),
+            _ => None,
+        };
         if let Some(old_ref) = self.map.get(&k) {
-            let old_ref = old_ref.clone();
-            if let Some((replace_k, new_ref)) = self.lvl2.overwrite(&self.folder, old_ref, &k, v)? {
+            let old_ref = *old_ref;
+            if let Some((replace_k, new_ref)) = self.shards[old_ref.shard].overwrite(old_ref, kser, vser)? {
                 self.map.insert(k, new_ref);
                 if let Some(moved_k) = replace_k {
                     self.map.insert(moved_k, old_ref);
                 }
             }
         } else {
-            let refv = self.lvl2.add(&self.folder, &k, v)?;
+            let shard = self.target(&k)?;
+            let refv = self.shards[shard].add_raw((kser, vser, Stamp::now()))?;
+            if let Some(ordered) = &mut self.ordered {
+                ordered.insert(&k);
+            }
             self.map.insert(k, refv);
         }
+        if let Some((kser, attributes)) = attributes {
+            self.index(&kser, attributes);
+        }
         Ok(())
     }
+    /// Stores everything `reader` yields as the value of `k`, streaming it into a blob file instead of holding it
+    /// in memory. The value is MessagePack binary, so `get` can decode it into byte-buffer types (such as
+    /// `serde_bytes::ByteBuf`, but not `Vec<u8>`), and `read_to` streams it back out. Returns the number of bytes.
+    pub fn insert_from(&mut self, k: K, mut reader: impl Read) -> Result<u64, FolderCacheError> {
+        self.check_writable()?;
+        let shard = match self.map.get(&k) {
+            Some(old_ref) => old_ref.shard,
+            None => self.target(&k)?,
+        };
+        let (marker, len) = self.shards[shard].store_stream(&mut reader, self.max_value)?;
+        let attributes = if self.indexes.is_empty() {None} else {
+            let vser = std::fs::read(self.shards[shard].blob_path(BlobRef::parse(&marker).unwrap()))?;
+            Some((rmp_serde::encode::to_vec(&k)?, self.attributes(&vser)?))
+        };
+        self.insert_raw(k, marker)?;
+        if let Some((kser, attributes)) = attributes {
+            self.index(&kser, attributes);
+        }
+        self.enforce_quota()?;
+        Ok(len)
+    }
+    /// Reads up to `len` bytes of a binary value (see `insert_from`) starting at byte `offset`. For values in a blob
+    /// file only the requested window is read, so a small slice of a huge value is cheap. The result is shorter than
+    /// `len` if the value ends first.
+    pub fn read_range(&self, k: &K, offset: u64, len: u64) -> Result<Vec<u8>, FolderCacheError> {
+        let refv = self.map.get(k).ok_or(FolderCacheError::Nothing)?;
+        self.shards[refv.shard].read_range(*refv, offset, len)
+    }
+    /// Writes the bytes of a binary value (see `insert_from`) to `out` without materializing them. Returns the number
+    /// of bytes written; values that are not binary fail with `Decode`.
+    pub fn read_to(&self, k: &K, mut out: impl Write) -> Result<u64, FolderCacheError> {
+        let refv = self.map.get(k).ok_or(FolderCacheError::Nothing)?;
+        self.shards[refv.shard].copy_bytes(*refv, &mut out)
+    }
+    /// Inserts many entries at once. New keys are grouped by size class and each group is appended contiguously
+    /// with a single write, which is far faster for bulk loading than repeated `insert`. Keys already present are
+    /// overwritten one by one; if a key repeats within `entries`, the last value wins.
+    pub fn insert_many<V: Serialize>(&mut self, entries: impl IntoIterator<Item = (K, V)>) -> Result<(), FolderCacheError> {
+        self.check_writable()?;
+        let mut new = HashMap::new();
+        let mut attributes = Vec::new();
+        for (k, v) in entries {
+            if self.map.contains_key(&k) {
+                self.insert(k, &v)?;
+            } else {
+                let records = (rmp_serde::encode::to_vec(&k)?, rmp_serde::encode::to_vec(&v)?);
+                self.check_size(records.1.len())?;
+                if !self.indexes.is_empty() {
+                    attributes.push((records.0.clone(), self.attributes(&records.1)?));
+                }
+                new.insert(k, records);
+            }
+        }
+        let mut groups: BTreeMap<(usize, u64), (Vec<K>, Vec<RawRecord>)> = BTreeMap::new();
+        for (k, (kser, vser)) in new {
+            let shard = self.target(&k)?;
+            let vser = self.shards[shard].store_value(vser)?;
+            let file = self.shards[shard].classes.fit(&kser, &vser);
+            let (keys, records) = groups.entry((shard, file)).or_default();
+            keys.push(k);
+            records.push((kser, vser));
+        }
+        for ((shard, file), (keys, records)) in groups {
+            let refs = self.shards[shard].add_many(file, records)?;
+            if let Some(ordered) = &mut self.ordered {
+                keys.iter().for_each(|k| ordered.insert(k));
+            }
+            self.map.extend(keys.into_iter().zip(refs));
+        }
+        // A key given twice is filed under its last value.
+        for (kser, attributes) in attributes {
+            self.index(&kser, attributes);
+        }
+        self.enforce_quota()
+    }
+    /// Appends the elements of `tail`, a string, byte string or sequence, to the value of `k`, which must be of the
+    /// same kind. The stored value is extended without being decoded, and its record is rewritten in place as long as
+    /// it still fits its slot.
+    pub fn append<V: Serialize>(&mut self, k: K, tail: &V) -> Result<(), FolderCacheError> {
+        self.check_writable()?;
+        let refv = *self.map.get(&k).ok_or(FolderCacheError::Nothing)?;
+        let old = self.shards[refv.shard].value_bytes(refv)?;
+        let tail = rmp_serde::encode::to_vec(tail)?;
+        let ((kind, start, len), (tail_kind, tail_start, tail_len)) = (sequence_header(&old)?, sequence_header(&tail)?);
+        if kind != tail_kind {
+            return Err(rmp_serde::decode::Error::Uncategorized("appended value is of another type".to_string()).into());
+        }
+        let mut vser = sequence_bytes(kind, len+tail_len);
+        vser.extend_from_slice(&old[start..]);
+        vser.extend_from_slice(&tail[tail_start..]);
+        self.check_size(vser.len())?;
+        self.insert_raw(k, vser)?;
+        self.enforce_quota()
+    }
+    /// Inserts the value next to other records given the same `hint` (e.g. a group ID), so that reading the group
+    /// back with `get_many` touches one contiguous region per size class. Hints are kept in the key indexes written
+    /// when the cache is closed; after a crash, the records placed before keep their places, but later ones are not
+    /// placed next to them.
+    pub fn insert_with_hint<V: Serialize>(&mut self, k: K, v: &V, hint: u64) -> Result<(), FolderCacheError> {
+        self.check_writable()?;
+        let (kser, vser) = (rmp_serde::encode::to_vec(&k)?, rmp_serde::encode::to_vec(v)?);
+        self.check_size(vser.len())?;
+        let attributes = if self.indexes.is_empty() {None} else {Some(self.attributes(&vser)?)};
+        let mut old_ref = self.map.get(&k).copied();
+        let (shard, stamp) = match old_ref {
+            Some(old_ref) => (old_ref.shard, self.shards[old_ref.shard].stamp(old_ref)?.rewritten()),
+            None => (self.target(&k)?, Stamp::now()),
+        };
+        // The new record is written before the old one is let go, so a failed write leaves the old value in place.
+        let (mut refv, moved) = self.shards[shard].add_hinted(kser.clone(), vser, hint, stamp)?;
+        if let Some((moved_k, moved_ref)) = moved {
+            if moved_k == k {
+                old_ref = Some(moved_ref);
+            } else {
+                self.map.insert(moved_k, moved_ref);
+            }
+        }
+        if let Some(old_ref) = old_ref {
+            // The record moved into the freed slot is either the new one or another key's.
+            match self.shards[shard].remove::<K>(old_ref)? {
+                Some(other_k) if other_k == k => refv = old_ref,
+                Some(other_k) => {self.map.insert(other_k, old_ref);},
+                None => {},
+            }
+            self.shards[shard].flush()?;
+        }
+        if let Some(ordered) = &mut self.ordered {
+            ordered.insert(&k);
+        }
+        self.map.insert(k, refv);
+        if let Some(attributes) = attributes {
+            self.index(&kser, attributes);
+        }
+        self.enforce_quota()
+    }
+    /// Chooses when written data is flushed to disk. Defaults to `Durability::OnCommit`.
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        for lvl2 in &mut self.shards {
+            lvl2.set_durability(durability);
+        }
+        self
+    }
+    /// Stores values that serialize to more than `threshold` bytes in blob files of their own, in a `{prefix}blobs`
+    /// subdirectory, so that a few huge values do not need a size class of their own. Off by default.
+    pub fn with_overflow_threshold(mut self, threshold: u64) -> Self {
+        for lvl2 in &mut self.shards {
+            lvl2.overflow = Some(threshold);
+        }
+        self
+    }
+    /// Stores each distinct value of at least `min_bytes` (serialized) only once: such values go to a blob file named
+    /// by a hash of their contents in the `{prefix}blobs` subdirectory, and every record holding the same value refers
+    /// to it. Blobs are reference-counted in memory and deleted once no record uses them. Hash collisions are caught
+    /// by comparing contents. Off by default; values streamed with `insert_from` are never shared.
+    pub fn with_dedup(mut self, min_bytes: u64) -> Self {
+        for lvl2 in &mut self.shards {
+            lvl2.dedup = Some(min_bytes);
+        }
+        self
+    }
+    /// Trains a compression dictionary on up to `samples` of the stored values, spread over the cache, and compresses
+    /// the values written from then on with it. Many small values that share their structure, such as similar JSON
+    /// documents, compress far better against a common dictionary than each on its own. The dictionary, of up to
+    /// `max_bytes`, is saved in a `{prefix}dictionaries` subdirectory and used again whenever the folder is opened;
+    /// values already stored stay as they are until rewritten. Returns the size of the dictionary, or 0 if the samples
+    /// had too little in common to train one.
+    ///
+    /// Requires the `dictionary` feature. The codec is a simple LZ77 scheme of this crate's own, not zstd, and its
+    /// format may change once zstd can be used instead; folders it wrote stay readable by builds without the feature.
+    #[cfg(feature = "dictionary")]
+    pub fn train_dictionary(&mut self, samples: usize, max_bytes: usize) -> Result<usize, FolderCacheError> {
+        self.check_writable()?;
+        let refs: Vec<Ref> = self.map.values().copied().collect();
+        let step = (refs.len()/samples.max(1)).max(1);
+        let samples = refs.iter().step_by(step).take(samples)
+            .map(|refv| self.shards[refv.shard].value_bytes(*refv)).collect::<Result<Vec<_>, _>>()?;
+        let dictionary = Dictionary::train(&samples, max_bytes);
+        if dictionary.bytes().is_empty() {
+            return Ok(0);
+        }
+        let dir = self.shards[0].dir.join(self.shards[0].layout.dictionary_dir_name());
+        std::fs::create_dir_all(&dir)?;
+        let id = self.shards[0].dictionaries.keys().max().map_or(0, |id| id+1);
+        let path = dir.join(format!("{id}.dict"));
+        replace_file(&path, dictionary.bytes())?;
+        // Records compressed with it must not reach the disk before it.
+        if self.shards[0].durability != Durability::Never {
+            File::open(&path)?.sync_data()?;
+            self.shards[0].dir_dirty = true;
+        }
+        let dictionary = Arc::new(dictionary);
+        for lvl2 in &mut self.shards {
+            lvl2.dictionaries.insert(id, dictionary.clone());
+            lvl2.dictionary = Some(id);
+        }
+        Ok(dictionary.bytes().len())
+    }
+    /// Chooses the slot sizes new records are stored in. Defaults to `SizeClasses::PowerOfTwo`.
+    pub fn with_size_classes(mut self, classes: SizeClasses) -> Self {
+        assert!(classes.is_valid());
+        for lvl2 in &mut self.shards {
+            lvl2.classes = classes.clone();
+        }
+        self
+    }
+    /// Sets how many bucket files are kept open per directory. Workloads that interleave many size classes
+    /// avoid reopening files with a larger pool. Defaults to 8.
+    pub fn with_open_files(mut self, open_files: NonZeroUsize) -> Self {
+        for lvl2 in &mut self.shards {
+            lvl2.open.get_mut().resize(open_files);
+        }
+        self
+    }
+    /// Writes a consistent copy of the cache to `dest`, replacing any cache files of the same layout already there.
+    /// The copy is taken under the cache's lock after flushing, and `dest` is locked while it is written.
+    pub fn snapshot(&mut self, dest: &Path) -> Result<(), FolderCacheError> {
+        let layout = self.shards[0].layout.clone();
+        std::fs::create_dir_all(dest)?;
+        let _lock = LockRef::acquire(dest, &layout, false)?;
+        layout.clear(dest)?;
+        self.copy_into(dest)
+    }
+    fn copy_into(&mut self, dest: &Path) -> Result<(), FolderCacheError> {
+        let root = self.shards[0].dir.clone();
+        for lvl2 in &mut self.shards {
+            let relative = lvl2.dir.strip_prefix(&root).unwrap_or(Path::new("")).to_path_buf();
+            lvl2.copy_into(&dest.join(relative))?;
+        }
+        Ok(())
+    }
+    /// Restores the snapshot at `snapshot` into `folder`, replacing the cache there, and opens it.
+    pub fn from_snapshot(snapshot: &Path, folder: PathBuf) -> Result<Self, FolderCacheError> {
+        Self::from_snapshot_with(snapshot, folder, Layout::default())
+    }
+    pub fn from_snapshot_with(snapshot: &Path, folder: PathBuf, layout: Layout) -> Result<Self, FolderCacheError> {
+        std::fs::create_dir_all(&folder)?;
+        let lock = LockRef::acquire(&folder, &layout, false)?;
+        layout.clear(&folder)?;
+        Self::read_only_with(snapshot.to_path_buf(), layout.clone())?.copy_into(&folder)?;
+        Self::open(folder, layout, lock, false, false)
+    }
+    fn checkpoint_path(&self, name: &str) -> PathBuf {
+        assert!(!name.is_empty() && !name.contains('.') && !name.contains(std::path::is_separator));
+        self.shards[0].dir.join(self.shards[0].layout.checkpoint_dir_name()).join(name)
+    }
+    /// Saves the current contents of the cache as a checkpoint called `name`, replacing any checkpoint of that name,
+    /// for `rollback_to`. Files are hard-linked rather than copied where possible, so a checkpoint costs little until
+    /// the cache diverges from it: the first write to a bucket file after a checkpoint copies that file. Checkpoints
+    /// live in a `{prefix}checkpoints` subdirectory and are kept by `cleared` and `clear_cache`.
+    pub fn checkpoint(&mut self, name: &str) -> Result<(), FolderCacheError> {
+        self.check_writable()?;
+        let dest = self.checkpoint_path(name);
+        // Built under another name first, so that a checkpoint is never seen half written.
+        let partial = dest.with_extension("partial");
+        if partial.exists() {
+            std::fs::remove_dir_all(&partial)?;
+        }
+        let root = self.shards[0].dir.clone();
+        for lvl2 in &mut self.shards {
+            let relative = lvl2.dir.strip_prefix(&root).unwrap_or(Path::new("")).to_path_buf();
+            lvl2.checkpoint_into(&partial.join(relative))?;
+        }
+        let dictionaries = self.shards[0].layout.dictionary_dir_name();
+        if root.join(&dictionaries).is_dir() {
+            share_tree(&root.join(&dictionaries), &partial.join(&dictionaries))?;
+        }
+        let pinned = self.shards[0].layout.pinned_name();
+        if root.join(&pinned).is_file() {
+            share_file(&root.join(&pinned), &partial.join(&pinned))?;
+        }
+        if dest.exists() {
+            std::fs::remove_dir_all(&dest)?;
+        }
+        std::fs::rename(&partial, &dest)?;
+        Ok(())
+    }
+    /// Replaces the contents of the cache with those of the checkpoint `name`, which is kept. Settings made with
+    /// `with_*` carry over, and indexes are rebuilt. On an error part way, the cache turns read-only and should be
+    /// opened again.
+    pub fn rollback_to(&mut self, name: &str) -> Result<(), FolderCacheError> {
+        self.check_writable()?;
+        let source = self.checkpoint_path(name);
+        if !source.is_dir() {
+            return Err(FolderCacheError::NoCheckpoint(name.to_string()));
+        }
+        self.wait_loaded()?;
+        let (root, layout) = (self.shards[0].dir.clone(), self.shards[0].layout.clone());
+        for lvl2 in &mut self.shards {
+            lvl2.release_files()?;
+            // Nothing of the old files is to be saved when they are dropped.
+            lvl2.read_only = true;
+        }
+        layout.clear(&root)?;
+        share_tree(&source, &root)?;
+        let (mut shards, targets) = Self::open_shards(&root, &layout, false, false)?;
+        let mut map = HashMap::new();
+        for (lvl2, old) in shards.iter_mut().zip(&self.shards) {
+            lvl2.configure_like(old);
+            lvl2.load_to_hashmap(&mut map)?;
+        }
+        self.shards = shards;
+        self.targets = targets;
+        self.map = KeyMap::loaded(map);
+        self.pinned = read_pins(&self.shards[0])?;
+        self.evicted.clear();
+        if let Some(ordered) = &mut self.ordered {
+            ordered.clear();
+            self.map.keys().for_each(|k| ordered.insert(k));
+        }
+        for index in self.indexes.values_mut() {
+            index.keys.clear();
+            index.attributes.clear();
+        }
+        if !self.indexes.is_empty() {
+            let entries = self.map.iter().map(|(k, refv)| Ok((rmp_serde::encode::to_vec(k)?, *refv))).collect::<Result<Vec<_>, FolderCacheError>>()?;
+            for (kser, refv) in entries {
+                let vser = self.shards[refv.shard].value_bytes(refv)?;
+                let attributes = self.attributes(&vser)?;
+                self.index(&kser, attributes);
+            }
+        }
+        Ok(())
+    }
+    /// Protects the entry of `k` from quota eviction (`with_quota`) and `clear_unpinned`, across reopens, for entries
+    /// that are expensive to recompute. Only `remove` and its variants take a pinned entry out, which also unpins it.
+    /// Returns `false`, pinning nothing, if there is no such entry.
+    pub fn pin(&mut self, k: &K) -> Result<bool, FolderCacheError> {
+        self.check_writable()?;
+        if !self.map.contains_key(k) {
+            return Ok(false);
+        }
+        if self.pinned.insert(rmp_serde::encode::to_vec(k)?) {
+            self.save_pins()?;
+        }
+        Ok(true)
+    }
+    /// Returns whether `k` was pinned.
+    pub fn unpin(&mut self, k: &K) -> Result<bool, FolderCacheError> {
+        self.check_writable()?;
+        let unpinned = self.pinned.remove(&rmp_serde::encode::to_vec(k)?);
+        if unpinned {
+            self.save_pins()?;
+        }
+        Ok(unpinned)
+    }
+    pub fn is_pinned(&self, k: &K) -> bool {
+        !self.pinned.is_empty() && rmp_serde::encode::to_vec(k).is_ok_and(|kser| self.pinned.contains(&kser))
+    }
+    /// The pinned keys, in no particular order.
+    pub fn pinned(&self) -> Result<Vec<K>, FolderCacheError> {
+        self.pinned.iter().map(|kser| Ok(rmp_serde::from_slice(kser)?)).collect()
+    }
+    /// Removes every entry that is not pinned, returning how many.
+    pub fn clear_unpinned(&mut self) -> Result<usize, FolderCacheError> where K: Clone {
+        self.check_writable()?;
+        let keys: Vec<K> = self.map.keys().filter(|k| !self.is_pinned(k)).cloned().collect();
+        for k in &keys {
+            self.remove(k)?;
+        }
+        Ok(keys.len())
+    }
+    fn save_pins(&mut self) -> Result<(), FolderCacheError> {
+        let path = self.shards[0].dir.join(self.shards[0].layout.pinned_name());
+        replace_file(&path, &rmp_serde::encode::to_vec(&self.pinned)?)?;
+        if self.shards[0].durability != Durability::Never {
+            File::open(&path)?.sync_data()?;
+            self.shards[0].dir_dirty = true;
+        }
+        Ok(())
+    }
+    /// The names of the checkpoints, in order.
+    pub fn checkpoints(&self) -> Result<Vec<String>, FolderCacheError> {
+        let dir = self.shards[0].dir.join(self.shards[0].layout.checkpoint_dir_name());
+        let Ok(entries) = dir.read_dir() else {return Ok(Vec::new())};
+        let mut names = Vec::new();
+        for entry in entries {
+            if let Some(name) = entry?.file_name().to_str().filter(|name| !name.contains('.')) {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+    /// Deletes the checkpoint `name`, returning whether there was one.
+    pub fn remove_checkpoint(&mut self, name: &str) -> Result<bool, FolderCacheError> {
+        let path = self.checkpoint_path(name);
+        if !path.is_dir() {
+            return Ok(false);
+        }
+        std::fs::remove_dir_all(path)?;
+        Ok(true)
+    }
+    /// Flushes outstanding writes to disk according to the durability setting.
+    /// Also saves the indexes (see `add_index`).
+    pub fn commit(&mut self) -> Result<(), FolderCacheError> {
+        self.shards.iter_mut().try_for_each(CacheLevel2::sync)?;
+        self.save_indexes()
+    }
+    fn index_path(&self, name: &str) -> PathBuf {
+        self.shards[0].dir.join(self.shards[0].layout.index_dir_name()).join(name)
+    }
+    /// Maintains an index of the entries by `extract(&value)` under `name`, for `keys_by` and `remove_by`. Every value
+    /// in the cache must decode as `V`. The index is saved on `commit` and picked up by the next `add_index` of the
+    /// same name, which only reads the values written since; without a saved index, every value is read once.
+    pub fn add_index<V, I>(&mut self, name: &str, extract: impl Fn(&V) -> I+Send+Sync+'static) -> Result<(), FolderCacheError> where
+    V: for <'a> Deserialize<'a>, I: Serialize {
+        assert!(!name.is_empty() && !name.contains('.') && !name.contains(std::path::is_separator));
+        let mut index = SecondaryIndex {
+            extract: Box::new(move |vser| Ok(rmp_serde::encode::to_vec(&extract(&rmp_serde::from_slice(vser)?))?)),
+            keys: HashMap::new(), attributes: HashMap::new(),
+        };
+        let saved: HashMap<Vec<u8>, (u64, u64, Vec<u8>)> = match std::fs::read(self.index_path(name)) {
+            Ok(bytes) => rmp_serde::from_slice::<Vec<(Vec<u8>, u64, u64, Vec<u8>)>>(&bytes).unwrap_or_default()
+                .into_iter().map(|(kser, created, version, attribute)| (kser, (created, version, attribute))).collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        for (k, refv) in self.map.iter() {
+            let kser = rmp_serde::encode::to_vec(k)?;
+            let stamp = self.shards[refv.shard].stamp(*refv)?;
+            let attribute = match saved.get(&kser) {
+                Some((created, version, attribute)) if (*created, *version) == (stamp.created, stamp.version) => attribute.clone(),
+                _ => (index.extract)(&self.shards[refv.shard].value_bytes(*refv)?)?,
+            };
+            index.insert(kser, attribute);
+        }
+        self.indexes.insert(name.to_string(), index);
+        Ok(())
+    }
+    /// The attributes of a value in each index, for `index`. Fails if any extractor does.
+    fn attributes(&self, vser: &[u8]) -> Result<Vec<Vec<u8>>, FolderCacheError> {
+        self.indexes.values().map(|index| (index.extract)(vser)).collect()
+    }
+    /// Updates the indexes for a new value of a key, once it is written.
+    fn index(&mut self, kser: &[u8], attributes: Vec<Vec<u8>>) {
+        for (index, attribute) in self.indexes.values_mut().zip(attributes) {
+            index.insert(kser.to_vec(), attribute);
+        }
+    }
+    /// Writes each index with the stamps of its entries, so that `add_index` can tell which are still current.
+    fn save_indexes(&self) -> Result<(), FolderCacheError> {
+        if self.indexes.is_empty() || self.is_read_only() {
+            return Ok(());
+        }
+        std::fs::create_dir_all(self.index_path(""))?;
+        for (name, index) in &self.indexes {
+            let mut entries = Vec::with_capacity(index.attributes.len());
+            for (kser, attribute) in &index.attributes {
+                let Some(&refv) = self.map.get(&rmp_serde::from_slice::<K>(kser)?) else {continue};
+                let stamp = self.shards[refv.shard].stamp(refv)?;
+                entries.push((kser, stamp.created, stamp.version, attribute));
+            }
+            replace_file(&self.index_path(name), &rmp_serde::encode::to_vec(&entries)?)?;
+        }
+        Ok(())
+    }
+    /// The keys whose value has `attribute` in index `name`.
+    pub fn keys_by<I: Serialize>(&self, name: &str, attribute: &I) -> Result<Vec<K>, FolderCacheError> {
+        let index = self.indexes.get(name).ok_or_else(|| FolderCacheError::NoIndex(name.to_string()))?;
+        let Some(keys) = index.keys.get(&rmp_serde::encode::to_vec(attribute)?) else {return Ok(Vec::new())};
+        keys.iter().map(|kser| Ok(rmp_serde::from_slice(kser)?)).collect()
+    }
+    /// Removes every key in `range` and returns how many there were. Without `with_ordered_keys`, this scans them all.
+    pub fn remove_range(&mut self, range: impl std::ops::RangeBounds<K>) -> Result<usize, FolderCacheError> where K: Ord+Clone {
+        let keys = match &self.ordered {
+            Some(ordered) => ordered.range(range.start_bound(), range.end_bound()).cloned().collect(),
+            None => self.map.keys().filter(|k| range.contains(k)).cloned().collect(),
+        };
+        self.remove_keys(keys)
+    }
+    /// Keeps the keys in order as well, in memory, so that `scan_range` and `scan_prefix` find their keys without
+    /// looking at the others.
+    pub fn with_ordered_keys(mut self) -> Self where K: Ord+Clone+Send+Sync+'static {
+        self.ordered = Some(Box::new(self.map.keys().cloned().collect::<BTreeSet<K>>()));
+        self
+    }
+    /// The keys in `range`, in order. Without `with_ordered_keys`, this scans and sorts them all.
+    pub fn scan_range(&self, range: impl std::ops::RangeBounds<K>) -> Vec<K> where K: Ord+Clone {
+        match &self.ordered {
+            Some(ordered) => ordered.range(range.start_bound(), range.end_bound()).cloned().collect(),
+            None => self.sorted_matching(|k| range.contains(k)),
+        }
+    }
+    /// The keys starting with `prefix`, in order, such as all children of a path. Without `with_ordered_keys`, this
+    /// scans and sorts them all.
+    pub fn scan_prefix(&self, prefix: &str) -> Vec<K> where K: AsRef<str>+Ord+Clone+for <'a> From<&'a str> {
+        match &self.ordered {
+            Some(ordered) => {
+                let start = K::from(prefix);
+                ordered.range(Bound::Included(&start), Bound::Unbounded)
+                    .take_while(|k| k.as_ref().starts_with(prefix)).cloned().collect()
+            },
+            None => self.sorted_matching(|k| k.as_ref().starts_with(prefix)),
+        }
+    }
+    fn sorted_matching(&self, matches: impl Fn(&K) -> bool) -> Vec<K> where K: Ord+Clone {
+        let mut keys: Vec<K> = self.map.keys().filter(|k| matches(k)).cloned().collect();
+        keys.sort();
+        keys
+    }
+    /// The entries of `scan_range`.
+    pub fn scan_range_entries<V: for <'a> Deserialize<'a>>(&self, range: impl std::ops::RangeBounds<K>) -> Result<Vec<(K, V)>, FolderCacheError> where K: Ord+Clone {
+        self.scan_range(range).into_iter().map(|k| Ok((k.clone(), self.get(&k)?))).collect()
+    }
+    /// The entries of `scan_prefix`.
+    pub fn scan_prefix_entries<V: for <'a> Deserialize<'a>>(&self, prefix: &str) -> Result<Vec<(K, V)>, FolderCacheError> where
+    K: AsRef<str>+Ord+Clone+for <'a> From<&'a str> {
+        self.scan_prefix(prefix).into_iter().map(|k| Ok((k.clone(), self.get(&k)?))).collect()
+    }
+    /// Removes every key starting with `prefix` and returns how many there were, like `remove_range`.
+    pub fn remove_prefix(&mut self, prefix: &str) -> Result<usize, FolderCacheError> where K: AsRef<str>+Clone {
+        self.remove_matching(|k| k.as_ref().starts_with(prefix))
+    }
+    /// Removes every entry whose key `matches`, in one pass over the in-memory index, returning how many.
+    pub fn clear_where(&mut self, matches: impl Fn(&K) -> bool) -> Result<usize, FolderCacheError> where K: Clone {
+        self.remove_matching(matches)
+    }
+    fn remove_matching(&mut self, matches: impl Fn(&K) -> bool) -> Result<usize, FolderCacheError> where K: Clone {
+        let keys = self.map.keys().filter(|k| matches(k)).cloned().collect();
+        self.remove_keys(keys)
+    }
+    fn remove_keys(&mut self, keys: Vec<K>) -> Result<usize, FolderCacheError> {
+        self.check_writable()?;
+        for k in &keys {
+            self.remove(k)?;
+        }
+        Ok(keys.len())
+    }
+    /// Removes every entry whose value has `attribute` in index `name`, and returns how many there were.
+    pub fn remove_by<I: Serialize>(&mut self, name: &str, attribute: &I) -> Result<usize, FolderCacheError> {
+        let keys = self.keys_by(name, attribute)?;
+        for k in &keys {
+            self.remove(k)?;
+        }
+        Ok(keys.len())
+    }
     pub fn contains(&self, k: &K) -> bool {self.map.contains_key(k)}
-    pub fn get<V: for <'a> Deserialize<'a>>(&mut self, k: &K) -> Result<V, FolderCacheError> {
+    /// Reports file sizes, slot usage and wasted space per size class. Reads every entry header, so it costs about as
+    /// much as a scan of the keys.
+    pub fn stats(&self) -> Result<CacheStats, FolderCacheError> {
+        let mut classes = BTreeMap::new();
+        for lvl2 in &self.shards {
+            lvl2.stats(&mut classes)?;
+        }
+        let classes: Vec<ClassStats> = classes.into_values().collect();
+        let mut total = ClassStats::default();
+        for class in &classes {
+            total.file_bytes += class.file_bytes;
+            total.reserved += class.reserved;
+            total.items += class.items;
+            total.wasted_bytes += class.wasted_bytes;
+        }
+        Ok(CacheStats {classes, total, blob_bytes: self.shards.iter().map(|lvl2| lvl2.blob_bytes).sum()})
+    }
+    /// What the bucket files and blobs take on disk, by their metadata, and how much of it live entries hold.
+    /// Unlike `stats`, this reads no entries.
+    pub fn usage(&self) -> Result<Usage, FolderCacheError> {
+        let mut usage = Usage {entries: self.map.len() as u64, ..Usage::default()};
+        for lvl2 in &self.shards {
+            for bucket in &lvl2.files {
+                usage.disk_bytes += lvl2.bucket_path(bucket.size_per_item).metadata()?.len();
+            }
+            usage.disk_bytes += lvl2.blob_bytes;
+            usage.live_bytes += lvl2.live_bytes()+lvl2.blob_bytes;
+        }
+        Ok(usage)
+    }
+    /// Checks that the shard folders are still there, and writable unless the cache was opened read-only.
+    pub fn health(&self) -> Result<(), FolderCacheError> {
+        for lvl2 in &self.shards {
+            let metadata = lvl2.dir.metadata()?;
+            if !lvl2.read_only && metadata.permissions().readonly() {
+                return Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied).into());
+            }
+        }
+        Ok(())
+    }
+    /// The keys of all entries, from the in-memory index.
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.map.keys()
+    }
+    /// Reads every entry back, one record at a time in on-disk order, so a large cache can be exported or
+    /// analyzed without holding all of its values in memory.
+    pub fn iter<V: for <'a> Deserialize<'a>>(&self) -> impl Iterator<Item = Result<(K, V), FolderCacheError>> + '_ {
+        self.shards.iter().flat_map(|lvl2| lvl2.refs().map(|refv| lvl2.get(refv)))
+    }
+    /// Like `iter`, without decoding the keys.
+    pub fn values<V: for <'a> Deserialize<'a>>(&self) -> impl Iterator<Item = Result<V, FolderCacheError>> + '_ {
+        self.shards.iter().flat_map(|lvl2| lvl2.refs().map(|refv| lvl2.get_v(refv)))
+    }
+    /// Reads several values at once, visiting them in on-disk order. Missing keys yield `None`.
+    pub fn get_many<V: for <'a> Deserialize<'a>>(&self, ks: &[K]) -> Result<Vec<Option<V>>, FolderCacheError> {
+        let mut order: Vec<(Ref, usize)> = ks.iter().enumerate()
+            .filter_map(|(i, k)| self.map.get(k).map(|refv| (*refv, i))).collect();
+        order.sort_by_key(|(refv, _)| (refv.shard, refv.file, refv.index));
+        let mut out: Vec<Option<V>> = ks.iter().map(|_| None).collect();
+        for (refv, i) in order {
+            out[i] = Some(self.shards[refv.shard].get_v(refv)?);
+        }
+        Ok(out)
+    }
+    /// Refuses values that serialize to more than `max_bytes` with `FolderCacheError::TooLarge`, before anything is
+    /// written. `with_overflow_threshold` instead moves large values out of the bucket files.
+    pub fn with_max_value_size(mut self, max_bytes: u64) -> Self {
+        self.max_value = Some(max_bytes);
+        self
+    }
+    /// Caps the space the cache takes on disk (bucket files, reserved space included, and blobs). Once an insert
+    /// pushes it over `max_bytes`, the least recently written entries are evicted until the cache is a tenth below
+    /// the quota, and the files are compacted. Evicted keys are collected for `take_evicted`.
+    pub fn with_quota(mut self, max_bytes: u64) -> Self {
+        self.quota = Some(max_bytes);
+        self
+    }
+    /// The affinity hint `k` was inserted with by `insert_with_hint`, if it is known.
+    pub fn hint(&self, k: &K) -> Option<u64> {
+        let refv = self.map.get(k)?;
+        self.shards[refv.shard].hint(*refv)
+    }
+    /// When `k` was first inserted and when its value was last written.
+    pub fn metadata(&self, k: &K) -> Result<EntryMetadata, FolderCacheError> {
+        let refv = self.map.get(k).ok_or(FolderCacheError::Nothing)?;
+        let stamp = self.shards[refv.shard].stamp(*refv)?;
+        let at = |nanos| std::time::UNIX_EPOCH+std::time::Duration::from_nanos(nanos);
+        Ok(EntryMetadata {created: at(stamp.created), modified: at(stamp.written), version: stamp.version})
+    }
+    /// The value of `k` along with its version (see `insert_if_version`).
+    pub fn get_versioned<V: for <'a> Deserialize<'a>>(&self, k: &K) -> Result<(V, u64), FolderCacheError> {
+        let refv = self.map.get(k).ok_or(FolderCacheError::Nothing)?;
+        Ok((self.shards[refv.shard].get_v(*refv)?, self.shards[refv.shard].stamp(*refv)?.version))
+    }
+    /// Inserts `v` only if `k` is still at `expected_version`, where 0 means that `k` must not be present, and
+    /// returns the new version. Fails with `VersionMismatch` otherwise. Versions count the writes of an entry since
+    /// it was inserted, so a removed and reinserted entry starts over at 1.
+    pub fn insert_if_version<V: Serialize>(&mut self, k: K, v: &V, expected_version: u64) -> Result<u64, FolderCacheError> {
+        let found = match self.map.get(&k) {
+            Some(refv) => self.shards[refv.shard].stamp(*refv)?.version,
+            None => 0,
+        };
+        if found != expected_version {
+            return Err(FolderCacheError::VersionMismatch {expected: expected_version, found});
+        }
+        self.insert(k, v)?;
+        Ok(found+1)
+    }
+    /// The keys evicted to stay under the quota since the last call.
+    pub fn take_evicted(&mut self) -> Vec<K> {
+        std::mem::take(&mut self.evicted)
+    }
+    /// Bytes the cache takes on disk, counting reserved space in bucket files and blob files.
+    pub fn disk_bytes(&self) -> u64 {
+        self.shards.iter().map(|lvl2| lvl2.file_bytes()+lvl2.blob_bytes).sum()
+    }
+    fn enforce_quota(&mut self) -> Result<(), FolderCacheError> {
+        let Some(quota) = self.quota.filter(|quota| self.disk_bytes() > *quota) else {return Ok(())};
+        let mut entries = Vec::with_capacity(self.map.len());
+        for (k, refv) in self.map.iter() {
+            let (written, bytes) = self.shards[refv.shard].footprint(*refv)?;
+            entries.push((written, bytes, *refv, self.is_pinned(k)));
+        }
+        entries.sort_unstable_by_key(|(written, _, _, _)| *written);
+        let mut live: u64 = entries.iter().map(|(_, bytes, _, _)| bytes).sum();
+        let mut victims = Vec::new();
+        for (_, bytes, refv, pinned) in entries {
+            if live <= quota-quota/10 {
+                break;
+            }
+            if pinned {
+                continue;
+            }
+            live -= bytes;
+            victims.push(self.shards[refv.shard].get_k::<K>(refv)?);
+        }
+        for k in victims {
+            self.remove(&k)?;
+            self.evicted.push(k);
+        }
+        self.compact()?;
+        Ok(())
+    }
+    /// Rewrites the bucket files to drop unused reserved space and fold sparse size classes together.
+    /// Returns the number of bytes reclaimed on disk.
+    pub fn compact(&mut self) -> Result<u64, FolderCacheError> {
+        self.check_writable()?;
+        let mut freed = 0;
+        for lvl2 in &mut self.shards {
+            freed += lvl2.compact(&mut self.map)?;
+        }
+        Ok(freed)
+    }
+    /// Compacts just the bucket file with the largest fraction of dead space, if that fraction is over `above_waste`
+    /// (a bucket too sparse to keep its own size class counts as all dead), and returns the bytes freed, or `None` if
+    /// no bucket was over. Each step touches at most two files, so it holds the cache for much less time than
+    /// `compact`; see `maintenance::MaintenancePolicy::compact_step_above_waste`.
+    pub fn compact_step(&mut self, above_waste: f64) -> Result<Option<u64>, FolderCacheError> {
+        self.check_writable()?;
+        let worst = self.shards.iter().enumerate()
+            .filter_map(|(shard, lvl2)| lvl2.most_wasted().map(|(i, waste)| (shard, i, waste)))
+            .max_by(|(_, _, a), (_, _, b)| a.total_cmp(b));
+        match worst {
+            Some((shard, i, waste)) if waste > above_waste => self.shards[shard].compact_one(i, &mut self.map).map(Some),
+            _ => Ok(None),
+        }
+    }
+    /// Only needs `&self`: reads use positioned I/O on shared handles, so several threads can read at once.
+    pub fn get<V: for <'a> Deserialize<'a>>(&self, k: &K) -> Result<V, FolderCacheError> {
         if let Some(refv) = self.map.get(k) {
-            return Ok(self.lvl2.get_v(&self.folder, *refv)?);
+            return self.shards[refv.shard].get_v(*refv);
         }
-        return Err(FolderCacheError::Nothing);
+        Err(FolderCacheError::Nothing)
     }
     pub fn remove(&mut self, k: &K) -> Result<(), FolderCacheError> {
-        if let Some(old_ref) = self.map.remove(&k) {
-            if let Some(other_k) = self.lvl2.remove(&self.folder, old_ref)? {
-                self.map.insert(other_k, old_ref);
+        self.check_writable()?;
+        if !self.indexes.is_empty() {
+            let kser = rmp_serde::encode::to_vec(k)?;
+            self.indexes.values_mut().for_each(|index| index.remove(&kser));
+        }
+        if let Some(ordered) = &mut self.ordered {
+            ordered.remove(k);
+        }
+        if !self.pinned.is_empty() && self.pinned.remove(&rmp_serde::encode::to_vec(k)?) {
+            self.save_pins()?;
+        }
+        if let Some(old_ref) = self.map.remove(k) && let Some(other_k) = self.shards[old_ref.shard].remove(old_ref)? {
+            self.map.insert(other_k, old_ref);
+        }
+        Ok(())
+    }
+    /// Hands the cache to a background thread that performs all of its writes. See `BackgroundFolderCache`.
+    pub fn in_background(self) -> BackgroundFolderCache<K> where K: Send+'static {
+        let shared = Arc::new(Background {
+            cache: Mutex::new(self), queue: Mutex::new(WriteQueue {pending: HashMap::new(), closed: false}), error: Mutex::new(None),
+            wake: Condvar::new(), drained: Condvar::new(),
+        });
+        let background = shared.clone();
+        let thread = std::thread::spawn(move || background.run());
+        BackgroundFolderCache {shared, thread: Some(thread)}
+    }
+}
+
+/// Writes waiting for the background thread, latest per key: a serialized value, or `None` for a removal.
+struct WriteQueue<K> {pending: HashMap<K, Option<Vec<u8>>>, closed: bool}
+/// The queue is always locked before the cache. The writer takes the cache lock before letting go of the queue, so
+/// a reader that missed a key in the queue cannot see the cache until that key is written. `error` holds the first
+/// failed write since the last commit and is locked last.
+struct Background<K: std::hash::Hash+Eq+Serialize+for <'a> Deserialize<'a>> {
+    cache: Mutex<FolderCache<K>>, queue: Mutex<WriteQueue<K>>, error: Mutex<Option<FolderCacheError>>, wake: Condvar, drained: Condvar,
+}
+impl<K> Background<K> where
+K: Eq+std::hash::Hash+Serialize+for <'a> Deserialize<'a> {
+    fn run(&self) {
+        loop {
+            let mut queue = self.queue.lock();
+            while queue.pending.is_empty() && !queue.closed {
+                self.wake.wait(&mut queue);
+            }
+            if queue.pending.is_empty() {
+                return;
+            }
+            let mut cache = self.cache.lock();
+            let batch = std::mem::take(&mut queue.pending);
+            self.drained.notify_all();
+            drop(queue);
+            for (k, vser) in batch {
+                if let Err(e) = cache.write(k, vser) {
+                    self.error.lock().get_or_insert(e);
+                }
             }
         }
+    }
+}
+
+/// A `FolderCache` whose writes are queued and performed by a dedicated thread, so that `insert` and `remove` (and
+/// the write-backs a `CacheMut` does on eviction) only pay for serializing the value. Reads see queued writes.
+/// Errors of queued writes are returned by the next `commit`, which waits for the queue to drain first.
+/// Dropping it writes out the queue and stops the thread.
+pub struct BackgroundFolderCache<K: std::hash::Hash+Eq+Serialize+for <'a> Deserialize<'a>> {
+    shared: Arc<Background<K>>, thread: Option<std::thread::JoinHandle<()>>,
+}
+impl<K> BackgroundFolderCache<K> where
+K: Eq+std::hash::Hash+Serialize+for <'a> Deserialize<'a> {
+    fn queue(&mut self, k: K, vser: Option<Vec<u8>>) {
+        self.shared.queue.lock().pending.insert(k, vser);
+        self.shared.wake.notify_one();
+    }
+    pub fn insert<V: Serialize>(&mut self, k: K, v: &V) -> Result<(), FolderCacheError> {
+        let vser = rmp_serde::encode::to_vec(v)?;
+        self.queue(k, Some(vser));
+        Ok(())
+    }
+    pub fn remove(&mut self, k: &K) -> Result<(), FolderCacheError> where K: Clone {
+        self.queue(k.clone(), None);
         Ok(())
     }
+    pub fn contains(&self, k: &K) -> bool {
+        let queue = self.shared.queue.lock();
+        if let Some(vser) = queue.pending.get(k) {
+            return vser.is_some();
+        }
+        let cache = self.shared.cache.lock();
+        drop(queue);
+        cache.contains(k)
+    }
+    pub fn get<V: for <'a> Deserialize<'a>>(&self, k: &K) -> Result<V, FolderCacheError> {
+        let queue = self.shared.queue.lock();
+        if let Some(vser) = queue.pending.get(k) {
+            return Ok(rmp_serde::from_slice(vser.as_ref().ok_or(FolderCacheError::Nothing)?)?);
+        }
+        let cache = self.shared.cache.lock();
+        drop(queue);
+        cache.get(k)
+    }
+    /// Waits for the queued writes, then commits the cache. Returns the first error of a queued write since the
+    /// last commit, if any.
+    pub fn commit(&mut self) -> Result<(), FolderCacheError> {
+        let mut queue = self.shared.queue.lock();
+        while !queue.pending.is_empty() {
+            self.shared.drained.wait(&mut queue);
+        }
+        let mut cache = self.shared.cache.lock();
+        drop(queue);
+        if let Some(e) = self.shared.error.lock().take() {
+            return Err(e);
+        }
+        cache.commit()
+    }
+}
+impl<K> Drop for BackgroundFolderCache<K> where
+K: Eq+std::hash::Hash+Serialize+for <'a> Deserialize<'a> {
+    fn drop(&mut self) {
+        self.shared.queue.lock().closed = true;
+        self.shared.wake.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl<K, V> CacheCompatible<K, V> for BackgroundFolderCache<K> where
+K: Eq+std::hash::Hash+Clone+Serialize+for <'a> Deserialize<'a>, V: Serialize+for <'a> Deserialize<'a> {
+    type Error = FolderCacheError;
+    const RETRIEVAL: Retrieval = Retrieval::Copy;
+
+    fn contains(&self, k: K) -> bool { BackgroundFolderCache::<K>::contains(self, &k) }
+    fn get(&mut self, k: K) -> Result<V, Self::Error> { BackgroundFolderCache::<K>::get(self, &k) }
+
+    fn replace(&mut self, _: K, _: V) -> Result<(), Self::Error> { Ok(()) }
+
+    fn health(&mut self) -> Result<(), Self::Error> { self.shared.cache.lock().health() }
+}
+impl<K, V> CacheMutCompatible<K, V> for BackgroundFolderCache<K> where
+K: Eq+std::hash::Hash+Clone+Serialize+for <'a> Deserialize<'a>, V: Serialize+for <'a> Deserialize<'a> {
+    fn insert(&mut self, k: K, v: V) -> Result<(), Self::Error> { BackgroundFolderCache::<K>::insert(self, k, &v) }
+
+    fn insert_shared(&mut self, k: K, v: &V) -> Result<bool, Self::Error> { BackgroundFolderCache::<K>::insert(self, k, v).map(|_| true) }
+
+    const SERIALIZED: Option<Serialized<K, V, Self>> = Some(Serialized {
+        serialize: |v| Ok(rmp_serde::encode::to_vec(v)?),
+        insert: |background, k, vser| {
+            background.queue(k, Some(vser));
+            Ok(())
+        },
+    });
+
+    fn remove(&mut self, k: K) -> Result<(), Self::Error> { BackgroundFolderCache::<K>::remove(self, &k) }
+
+    fn commit(&mut self) -> Result<(), Self::Error> { BackgroundFolderCache::<K>::commit(self) }
+}
+
+impl<K, V> CacheRangeCompatible<K, V> for FolderCache<K> where
+K: Ord+Clone+std::hash::Hash+Serialize+for <'a> Deserialize<'a>, V: Serialize+for <'a> Deserialize<'a> {
+    fn remove_range(&mut self, range: impl std::ops::RangeBounds<K>) -> Result<(), Self::Error> {
+        FolderCache::<K>::remove_range(self, range).map(|_| ())
+    }
 }
 
 impl<K, V> CacheCompatible<K, V> for FolderCache<K> where
 K: std::hash::Hash+Eq+Serialize+for <'a> Deserialize<'a>, V: Serialize+for <'a> Deserialize<'a> {
     type Error = FolderCacheError;
+    const RETRIEVAL: Retrieval = Retrieval::Copy;
 
     fn contains(&self, k: K) -> bool { self.contains(&k) }
     fn get(&mut self, k: K) -> Result<V, Self::Error> { FolderCache::<K>::get(self, &k) }
 
-    fn replace(&mut self, _: K, _: V) {}
+    fn replace(&mut self, _: K, _: V) -> Result<(), Self::Error> { Ok(()) }
+
+    fn health(&mut self) -> Result<(), Self::Error> { FolderCache::<K>::health(self) }
 }
 impl<K, V> CacheMutCompatible<K, V> for FolderCache<K> where
 K: std::hash::Hash+Eq+Serialize+for <'a> Deserialize<'a>, V: Serialize+for <'a> Deserialize<'a> {
     fn insert(&mut self, k: K, v: V) -> Result<(), Self::Error> { FolderCache::<K>::insert(self, k, &v) }
 
+    fn insert_shared(&mut self, k: K, v: &V) -> Result<bool, Self::Error> { FolderCache::<K>::insert(self, k, v).map(|_| true) }
+
+    const SERIALIZED: Option<Serialized<K, V, Self>> = Some(Serialized {
+        serialize: |v| Ok(rmp_serde::encode::to_vec(v)?),
+        insert: |folder, k, vser| folder.write(k, Some(vser)),
+    });
+
+    fn append(&mut self, k: K, suffix: &V::Suffix) -> Result<(), Self::Error> where V: Appendable, K: Clone {
+        let mut tail = V::default();
+        tail.append(suffix);
+        FolderCache::<K>::append(self, k, &tail)
+    }
+
     fn remove(&mut self, k: K) -> Result<(), Self::Error> { FolderCache::<K>::remove(self, &k) }
 
-    fn commit(&mut self) -> Result<(), Self::Error> { Ok(()) }
+    fn commit(&mut self) -> Result<(), Self::Error> { FolderCache::<K>::commit(self) }
+
+    fn resource_usage(&self) -> Option<Usage> { self.usage().ok() }
+}
+impl<K, V> Maintain<K, V> for FolderCache<K> where
+K: std::hash::Hash+Eq+Serialize+for <'a> Deserialize<'a>, V: Serialize+for <'a> Deserialize<'a> {
+    fn wasted_fraction(&self) -> f64 {
+        let total: u64 = self.shards.iter().map(CacheLevel2::file_bytes).sum();
+        let live: u64 = self.shards.iter().map(CacheLevel2::live_bytes).sum();
+        if total == 0 {0.0} else {1.0-live as f64/total as f64}
+    }
+    fn compact(&mut self) -> Result<u64, Self::Error> { FolderCache::<K>::compact(self) }
+    fn compact_step(&mut self, above_waste: f64) -> Result<Option<u64>, Self::Error> { FolderCache::<K>::compact_step(self, above_waste) }
+}
+impl<K, V> ListKeys<K, V> for FolderCache<K> where
+K: std::hash::Hash+Eq+Clone+Serialize+for <'a> Deserialize<'a>, V: Serialize+for <'a> Deserialize<'a> {
+    fn keys(&self) -> Vec<K> { FolderCache::<K>::keys(self).cloned().collect() }
+    /// Pages through the in-memory index, without copying the keys of other pages.
+    fn keys_paged(&self, cursor: usize, limit: usize) -> (Vec<K>, Option<usize>) { page(FolderCache::<K>::keys(self).cloned(), cursor, limit) }
+    fn remove_where(&mut self, matches: &dyn Fn(&K) -> bool) -> Result<usize, Self::Error> { self.clear_where(matches) }
+}
+
+/// 128-bit FNV-1a, used to key records by their key's hash.
+fn checksum128(bytes: &[u8]) -> u128 {
+    bytes.iter().fold(0x6c62272e07bb014262b821756295c58d, |hash, b| (hash^*b as u128).wrapping_mul(0x0000000001000000000000000000013b))
+}
+
+/// A `FolderCache` that stores a 128-bit hash of each serialized key in place of the key, which keeps records small
+/// and the index quick to load when keys are long. Keys cannot be listed back from the folder.
+/// With `verify`, the key is also kept next to the value and compared on every read, so that a hash collision reads
+/// as a missing entry rather than another key's value; this gives up the space savings but keeps the fast index load.
+/// A folder must always be opened with the same `verify` setting.
+pub struct HashedFolderCache<K> {inner: FolderCache<u128>, verify: bool, _key: std::marker::PhantomData<fn(&K)>}
+impl<K> HashedFolderCache<K> where
+K: Eq+Serialize+for <'a> Deserialize<'a> {
+    /// Wraps a cache opened with any of the `FolderCache` constructors and options.
+    pub fn new(inner: FolderCache<u128>, verify: bool) -> Self {
+        Self {inner, verify, _key: std::marker::PhantomData}
+    }
+    pub fn inner(&self) -> &FolderCache<u128> {&self.inner}
+    pub fn inner_mut(&mut self) -> &mut FolderCache<u128> {&mut self.inner}
+    fn hash(k: &K) -> Result<u128, FolderCacheError> {
+        Ok(checksum128(&rmp_serde::encode::to_vec(k)?))
+    }
+    pub fn insert<V: Serialize>(&mut self, k: &K, v: &V) -> Result<(), FolderCacheError> {
+        let hash = Self::hash(k)?;
+        if self.verify {
+            self.inner.insert(hash, &(k, v))
+        } else {
+            self.inner.insert(hash, v)
+        }
+    }
+    pub fn contains(&self, k: &K) -> bool {
+        Self::hash(k).is_ok_and(|hash| self.inner.contains(&hash))
+    }
+    /// Fails with `Nothing` if the key is absent, or with `verify`, if the record belongs to a colliding key.
+    pub fn get<V: for <'a> Deserialize<'a>>(&self, k: &K) -> Result<V, FolderCacheError> {
+        let hash = Self::hash(k)?;
+        if !self.verify {
+            return self.inner.get(&hash);
+        }
+        let (stored, v): (K, V) = self.inner.get(&hash)?;
+        if &stored == k {Ok(v)} else {Err(FolderCacheError::Nothing)}
+    }
+    pub fn remove(&mut self, k: &K) -> Result<(), FolderCacheError> {
+        let hash = Self::hash(k)?;
+        if self.verify && self.inner.contains(&hash) && self.inner.get::<(K, serde::de::IgnoredAny)>(&hash)?.0 != *k {
+            return Ok(());
+        }
+        self.inner.remove(&hash)
+    }
+    pub fn commit(&mut self) -> Result<(), FolderCacheError> {self.inner.commit()}
+}
+
+impl<K, V> CacheCompatible<K, V> for HashedFolderCache<K> where
+K: Eq+Serialize+for <'a> Deserialize<'a>, V: Serialize+for <'a> Deserialize<'a> {
+    type Error = FolderCacheError;
+    const RETRIEVAL: Retrieval = Retrieval::Copy;
+
+    fn contains(&self, k: K) -> bool { self.contains(&k) }
+    fn get(&mut self, k: K) -> Result<V, Self::Error> { HashedFolderCache::<K>::get(self, &k) }
+
+    fn replace(&mut self, _: K, _: V) -> Result<(), Self::Error> { Ok(()) }
+
+    fn health(&mut self) -> Result<(), Self::Error> { self.inner.health() }
+}
+impl<K, V> CacheMutCompatible<K, V> for HashedFolderCache<K> where
+K: Eq+Serialize+for <'a> Deserialize<'a>, V: Serialize+for <'a> Deserialize<'a> {
+    fn insert(&mut self, k: K, v: V) -> Result<(), Self::Error> { HashedFolderCache::<K>::insert(self, &k, &v) }
+
+    fn insert_shared(&mut self, k: K, v: &V) -> Result<bool, Self::Error> { HashedFolderCache::<K>::insert(self, &k, v).map(|_| true) }
+
+    fn remove(&mut self, k: K) -> Result<(), Self::Error> { HashedFolderCache::<K>::remove(self, &k) }
+
+    fn commit(&mut self) -> Result<(), Self::Error> { HashedFolderCache::<K>::commit(self) }
+
+    fn resource_usage(&self) -> Option<Usage> { self.inner.usage().ok() }
 }
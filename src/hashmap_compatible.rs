@@ -1,11 +1,14 @@
-use std::collections::HashMap;
-use crate::{CacheCompatible, CacheMutCompatible};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::RangeBounds;
+use crate::sharded::{ListKeys, page};
+use crate::{CacheCompatible, CacheMutCompatible, CacheRangeCompatible, Retrieval};
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct NotInMap;
 
 impl<K, V> CacheCompatible<K, V> for HashMap<K, V> where K: Eq+std::hash::Hash, {
     type Error = NotInMap;
+    const RETRIEVAL: Retrieval = Retrieval::Take;
 
     fn contains(&self, k: K) -> bool {
         self.contains_key(&k)
@@ -18,8 +21,9 @@ impl<K, V> CacheCompatible<K, V> for HashMap<K, V> where K: Eq+std::hash::Hash,
         }
     }
 
-    fn replace(&mut self, k: K, v: V) {
+    fn replace(&mut self, k: K, v: V) -> Result<(), Self::Error> {
         self.insert(k, v);
+        Ok(())
     }
 }
 
@@ -36,3 +40,62 @@ impl<K, V> CacheMutCompatible<K, V> for HashMap<K, V> where K: Eq+std::hash::Has
 
     fn commit(&mut self) -> Result<(), Self::Error> { Ok(()) }
 }
+
+impl<K, V> CacheCompatible<K, V> for BTreeMap<K, V> where K: Ord {
+    type Error = NotInMap;
+    const RETRIEVAL: Retrieval = Retrieval::Take;
+
+    fn contains(&self, k: K) -> bool {
+        self.contains_key(&k)
+    }
+
+    fn get(&mut self, k: K) -> Result<V, Self::Error> {
+        BTreeMap::<K,V>::remove(self, &k).ok_or(NotInMap)
+    }
+
+    fn replace(&mut self, k: K, v: V) -> Result<(), Self::Error> {
+        self.insert(k, v);
+        Ok(())
+    }
+}
+
+impl<K, V> CacheMutCompatible<K, V> for BTreeMap<K, V> where K: Ord {
+    fn insert(&mut self, k: K, v: V) -> Result<(), Self::Error> {
+        BTreeMap::<K,V>::insert(self, k, v);
+        Ok(())
+    }
+
+    fn remove(&mut self, k: K) -> Result<(), Self::Error> {
+        BTreeMap::<K,V>::remove(self, &k);
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), Self::Error> { Ok(()) }
+}
+
+impl<K, V> CacheRangeCompatible<K, V> for BTreeMap<K, V> where K: Ord {
+    fn remove_range(&mut self, range: impl RangeBounds<K>) -> Result<(), Self::Error> {
+        self.retain(|k, _| !range.contains(k));
+        Ok(())
+    }
+}
+
+impl<K, V> ListKeys<K, V> for HashMap<K, V> where K: Eq+std::hash::Hash+Clone {
+    fn keys(&self) -> Vec<K> { HashMap::<K,V>::keys(self).cloned().collect() }
+    fn keys_paged(&self, cursor: usize, limit: usize) -> (Vec<K>, Option<usize>) { page(HashMap::<K,V>::keys(self).cloned(), cursor, limit) }
+    fn remove_where(&mut self, matches: &dyn Fn(&K) -> bool) -> Result<usize, Self::Error> {
+        let before = self.len();
+        self.retain(|k, _| !matches(k));
+        Ok(before-self.len())
+    }
+}
+
+impl<K, V> ListKeys<K, V> for BTreeMap<K, V> where K: Ord+Clone {
+    fn keys(&self) -> Vec<K> { BTreeMap::<K,V>::keys(self).cloned().collect() }
+    fn keys_paged(&self, cursor: usize, limit: usize) -> (Vec<K>, Option<usize>) { page(BTreeMap::<K,V>::keys(self).cloned(), cursor, limit) }
+    fn remove_where(&mut self, matches: &dyn Fn(&K) -> bool) -> Result<usize, Self::Error> {
+        let before = self.len();
+        self.retain(|k, _| !matches(k));
+        Ok(before-self.len())
+    }
+}
@@ -0,0 +1,119 @@
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+
+use crate::{Appendable, CacheCompatible, CacheMutCompatible, IfChanged, Retrieval, Serialized, Token, Usage};
+
+/// Reads from a primary backend (a store across a network, say), and if it has not answered within a deadline, from
+/// a fallback holding the same values too (a replica, or a slower tier), returning whichever answers first. A read
+/// the primary answers in time, even with an error, is never sent to the fallback. Writes only go to the primary;
+/// the fallback is kept up to date by whatever else writes to it.
+///
+/// Each read runs on a thread of its own so that it can be waited on with a deadline; a read the fallback won keeps
+/// running, and holds up the next calls to the primary until it is done (which are then hedged in turn). Only
+/// `Retrieval::Copy` backends are hedged; with a `Take` primary or fallback, every read goes to the primary.
+pub struct Hedged<P, F> {
+    primary: Arc<Mutex<P>>, fallback: Arc<Mutex<F>>, deadline: Duration,
+    reads: u64, hedged: u64, fallback_won: u64,
+}
+impl<P, F> Hedged<P, F> {
+    pub fn new(primary: P, fallback: F, deadline: Duration) -> Self {
+        Self {
+            primary: Arc::new(Mutex::new(primary)), fallback: Arc::new(Mutex::new(fallback)), deadline,
+            reads: 0, hedged: 0, fallback_won: 0,
+        }
+    }
+    /// How long a read waits for the primary before asking the fallback too.
+    pub fn deadline(&self) -> Duration { self.deadline }
+    pub fn set_deadline(&mut self, deadline: Duration) { self.deadline = deadline }
+    /// Reads so far.
+    pub fn reads(&self) -> u64 { self.reads }
+    /// Reads also sent to the fallback, as the primary was late.
+    pub fn hedged(&self) -> u64 { self.hedged }
+    /// Hedged reads the fallback answered first.
+    pub fn fallback_won(&self) -> u64 { self.fallback_won }
+    /// Runs `primary`, and `fallback` as well if `primary` is not done within the deadline.
+    fn hedge<T, E>(&mut self, primary: impl FnOnce(&mut P) -> Result<T, E>+Send+'static,
+    fallback: impl FnOnce(&mut F) -> Result<T, E>+Send+'static) -> Result<T, E> where
+    P: Send+'static, F: Send+'static, T: Send+'static, E: Send+'static {
+        self.reads += 1;
+        let (send, answers) = mpsc::channel();
+        let (backend, answer) = (self.primary.clone(), send.clone());
+        std::thread::spawn(move || { let _ = answer.send((false, primary(&mut backend.lock()))); });
+        if let Ok((_, result)) = answers.recv_timeout(self.deadline) {
+            return result;
+        }
+        self.hedged += 1;
+        let backend = self.fallback.clone();
+        std::thread::spawn(move || { let _ = send.send((true, fallback(&mut backend.lock()))); });
+        // Both senders are gone once both reads are done, ending the loop.
+        let mut failed = None;
+        while let Ok((from_fallback, result)) = answers.recv() {
+            match result {
+                Ok(t) => {
+                    self.fallback_won += from_fallback as u64;
+                    return Ok(t);
+                },
+                Err(e) => failed = Some(e),
+            }
+        }
+        Err(failed.expect("both hedged reads panicked"))
+    }
+}
+
+impl<K, V, P, F> CacheCompatible<K, V> for Hedged<P, F> where
+K: Clone+Send+'static, V: Send+'static, P: CacheCompatible<K, V>+Send+'static, P::Error: Send+'static,
+F: CacheCompatible<K, V, Error = P::Error>+Send+'static {
+    type Error = P::Error;
+    const RETRIEVAL: Retrieval = P::RETRIEVAL;
+
+    fn contains(&self, k: K) -> bool { self.primary.lock().contains(k) }
+    fn get(&mut self, k: K) -> Result<V, Self::Error> {
+        if P::RETRIEVAL == Retrieval::Take || F::RETRIEVAL == Retrieval::Take {
+            return self.primary.lock().get(k);
+        }
+        let other = k.clone();
+        self.hedge(move |primary| primary.get(k), move |fallback| fallback.get(other))
+    }
+    fn replace(&mut self, k: K, v: V) -> Result<(), Self::Error> { self.primary.lock().replace(k, v) }
+    /// Hedged like `get`, so the fallback's tokens should name the same versions as the primary's.
+    fn get_versioned(&mut self, k: K) -> Result<(V, Option<Token>), Self::Error> {
+        if P::RETRIEVAL == Retrieval::Take || F::RETRIEVAL == Retrieval::Take {
+            return self.primary.lock().get_versioned(k);
+        }
+        let other = k.clone();
+        self.hedge(move |primary| primary.get_versioned(k), move |fallback| fallback.get_versioned(other))
+    }
+    fn get_if_changed(&mut self, k: K, token: &Token) -> Result<IfChanged<V>, Self::Error> {
+        if F::RETRIEVAL == Retrieval::Take {
+            return self.primary.lock().get_if_changed(k, token);
+        }
+        let (other, token, other_token) = (k.clone(), token.clone(), token.clone());
+        self.hedge(move |primary| primary.get_if_changed(k, &token),
+            move |fallback| fallback.get_if_changed(other, &other_token))
+    }
+    fn health(&mut self) -> Result<(), Self::Error> { self.primary.lock().health() }
+}
+
+impl<K, V, P, F> CacheMutCompatible<K, V> for Hedged<P, F> where
+K: Clone+Send+'static, V: Send+'static, P: CacheMutCompatible<K, V>+Send+'static, P::Error: Send+'static,
+F: CacheCompatible<K, V, Error = P::Error>+Send+'static {
+    fn insert(&mut self, k: K, v: V) -> Result<(), Self::Error> { self.primary.lock().insert(k, v) }
+    fn insert_shared(&mut self, k: K, v: &V) -> Result<bool, Self::Error> { self.primary.lock().insert_shared(k, v) }
+    const SERIALIZED: Option<Serialized<K, V, Self>> = match P::SERIALIZED {
+        Some(serialized) => Some(Serialized {
+            serialize: serialized.serialize,
+            insert: |hedged, k, vser| (P::SERIALIZED.unwrap().insert)(&mut hedged.primary.lock(), k, vser),
+        }),
+        None => None,
+    };
+    fn append(&mut self, k: K, suffix: &V::Suffix) -> Result<(), Self::Error> where V: Appendable, K: Clone {
+        self.primary.lock().append(k, suffix)
+    }
+    fn remove(&mut self, k: K) -> Result<(), Self::Error> { self.primary.lock().remove(k) }
+    fn remove_many(&mut self, keys: Vec<K>) -> Result<(), Self::Error> { self.primary.lock().remove_many(keys) }
+    fn commit(&mut self) -> Result<(), Self::Error> { self.primary.lock().commit() }
+    fn resource_usage(&self) -> Option<Usage> { self.primary.lock().resource_usage() }
+}
@@ -0,0 +1,174 @@
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use serde::{Deserialize, Serialize};
+
+use crate::{CacheMut, CacheMutCompatible, LockError};
+
+/// Messages larger than this are refused, so a corrupt length can not make either side allocate without bound.
+const MAX_MESSAGE: usize = 256 << 20;
+
+/// What a client asks of the cache. On the wire, each message is a 4-byte big-endian length followed by that many
+/// bytes of MessagePack: a request is a one-entry map such as `{"get": key}` or `{"insert": [key, value]}`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Request<K, V> {
+    Get(K),
+    Contains(K),
+    Insert(K, V),
+    Remove(K),
+    Flush,
+}
+
+/// The answer to a `Request`: `{"value": value-or-nil}` to `get`, `{"contains": bool}` to `contains`, `"done"` to the
+/// others, or `{"error": message}` if the backend failed or the entry is borrowed in the serving process.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Response<V> {
+    Value(Option<V>),
+    Contains(bool),
+    Done,
+    Error(String),
+}
+
+fn write_message(stream: &mut UnixStream, message: &impl Serialize) -> io::Result<()> {
+    let bytes = rmp_serde::to_vec(message).map_err(io::Error::other)?;
+    let length = u32::try_from(bytes.len()).ok().filter(|n| *n as usize <= MAX_MESSAGE)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "message too large"))?;
+    stream.write_all(&length.to_be_bytes())?;
+    stream.write_all(&bytes)
+}
+/// Reads one message, or `None` if the other side closed the connection first.
+fn read_message<T: for <'a> Deserialize<'a>>(stream: &mut UnixStream) -> io::Result<Option<T>> {
+    let mut length = [0; 4];
+    match stream.read_exact(&mut length) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_MESSAGE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "message too large"));
+    }
+    let mut bytes = vec![0; length];
+    stream.read_exact(&mut bytes)?;
+    rmp_serde::from_slice(&bytes).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Serves `cache` on a Unix socket at `path`, so that other processes (a sidecar written in another language, for
+/// example) can use it through the protocol of `Request`. Each connection gets a thread, and requests take turns
+/// with the cache. The serving process may go on using clones of `cache`: a request for an entry it has borrowed
+/// (mutably, for `get`) is answered with `Response::Error` rather than waiting for it. Fails if `path` already
+/// exists.
+pub fn serve<K, V, CC>(cache: CacheMut<K, V, CC>, path: impl Into<PathBuf>) -> io::Result<IpcHandle> where
+K: Copy+Eq+std::hash::Hash+Serialize+for <'a> Deserialize<'a>+Send+'static,
+V: Clone+Serialize+for <'a> Deserialize<'a>+Send+Sync+'static,
+CC: CacheMutCompatible<K, V>+Send+'static, CC::Error: std::fmt::Debug {
+    let path = path.into();
+    let listener = UnixListener::bind(&path)?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread_stop = stop.clone();
+    let cache = Arc::new(Mutex::new(cache));
+    let thread = std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            if thread_stop.load(Ordering::Relaxed) {
+                break;
+            }
+            let Ok(stream) = stream else {continue};
+            let cache = cache.clone();
+            std::thread::spawn(move || connection(&cache, stream));
+        }
+    });
+    Ok(IpcHandle {path, stop, thread: Some(thread)})
+}
+
+/// Answers requests on `stream` until it is closed or sends something that is not a request.
+fn connection<K, V, CC>(cache: &Mutex<CacheMut<K, V, CC>>, mut stream: UnixStream) where
+K: Copy+Eq+std::hash::Hash+for <'a> Deserialize<'a>, V: Clone+Serialize+for <'a> Deserialize<'a>,
+CC: CacheMutCompatible<K, V>, CC::Error: std::fmt::Debug {
+    while let Ok(Some(request)) = read_message::<Request<K, V>>(&mut stream) {
+        let response = {
+            let mut cache = cache.lock().unwrap_or_else(PoisonError::into_inner);
+            let result = match request {
+                Request::Get(k) if cache.contains(&k) =>
+                    cache.try_get(&k).map(|v| Response::Value(Some(V::clone(&v)))).map_err(describe),
+                Request::Get(_) => Ok(Response::Value(None)),
+                Request::Contains(k) => Ok(Response::Contains(cache.contains(&k))),
+                Request::Insert(k, v) => cache.try_insert(k, v).map(|_| Response::Done).map_err(describe),
+                Request::Remove(k) => cache.try_remove(&k).map(|_| Response::Done).map_err(describe),
+                Request::Flush => cache.flush().map(|_| Response::Done).map_err(|e| format!("{e:?}")),
+            };
+            result.unwrap_or_else(Response::Error)
+        };
+        if write_message(&mut stream, &response).is_err() {
+            break;
+        }
+    }
+}
+
+/// The message of a `Response::Error`.
+fn describe<K, E: std::fmt::Debug>(e: LockError<K, E>) -> String {
+    match e {
+        LockError::WouldDeadlock(_) => "entry is borrowed by the serving process".into(),
+        LockError::Poisoned(_) => "entry is poisoned".into(),
+        LockError::Closing => "cache is shutting down".into(),
+        LockError::Backend(_, e) => format!("{e:?}"),
+    }
+}
+
+/// Stops accepting connections and removes the socket when dropped. Connections already open are served until
+/// their client closes them.
+pub struct IpcHandle {path: PathBuf, stop: Arc<AtomicBool>, thread: Option<JoinHandle<()>>}
+impl IpcHandle {
+    pub fn path(&self) -> &Path {&self.path}
+}
+impl Drop for IpcHandle {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // Wakes the accepting thread, which then sees `stop`.
+        let _ = UnixStream::connect(&self.path);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// A connection to a cache served by `serve`.
+pub struct IpcClient(UnixStream);
+impl IpcClient {
+    pub fn connect(path: impl AsRef<Path>) -> io::Result<Self> {
+        UnixStream::connect(path).map(Self)
+    }
+    /// Sends `request` and waits for its response.
+    pub fn request<K: Serialize, V: Serialize+for <'a> Deserialize<'a>>(&mut self, request: &Request<K, V>) -> io::Result<Response<V>> {
+        write_message(&mut self.0, request)?;
+        read_message(&mut self.0)?.ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "the server closed the connection"))
+    }
+    pub fn get<K: Serialize, V: Serialize+for <'a> Deserialize<'a>>(&mut self, k: K) -> io::Result<Option<V>> {
+        match self.request(&Request::Get(k))? {
+            Response::Value(v) => Ok(v),
+            response => Err(unexpected(response)),
+        }
+    }
+    pub fn insert<K: Serialize, V: Serialize+for <'a> Deserialize<'a>>(&mut self, k: K, v: V) -> io::Result<()> {
+        match self.request(&Request::Insert(k, v))? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+    pub fn remove<K: Serialize, V: Serialize+for <'a> Deserialize<'a>>(&mut self, k: K) -> io::Result<()> {
+        match self.request::<K, V>(&Request::Remove(k))? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+}
+fn unexpected<V>(response: Response<V>) -> io::Error {
+    match response {
+        Response::Error(message) => io::Error::other(message),
+        _ => io::Error::new(io::ErrorKind::InvalidData, "unexpected response"),
+    }
+}
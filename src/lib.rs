@@ -1,7 +1,35 @@
+pub mod adaptive;
+pub mod bloom;
+pub mod breaker;
+pub mod bus;
 mod cache;
+pub mod clock;
+#[cfg(feature = "folder")]
+mod compression;
+#[cfg(feature = "folder")]
+pub mod config;
+#[cfg(feature = "conformance")]
+pub mod conformance;
+#[cfg(feature = "folder")]
 pub mod folder_compatible;
 pub mod hashmap_compatible;
-pub use cache::{CMRef, CMRefMut, CacheMut, CacheCompatible, CacheMutCompatible};
+pub mod hedged;
+#[cfg(all(unix, feature = "ipc"))]
+pub mod ipc;
+pub mod lock;
+pub mod maintenance;
+#[cfg(feature = "folder")]
+pub mod paged_compatible;
+#[cfg(feature = "pressure")]
+pub mod pressure;
+pub mod refresh;
+pub mod sharded;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "folder")]
+pub mod tiered;
+pub mod trace;
+pub use cache::{Appendable, CMRef, CMRefMut, CacheMut, CacheMutStats, CacheMutWeak, CacheCompatible, CacheMutCompatible, CacheRangeCompatible, Conflict, EvictReason, Hint, IfChanged, LockError, PoisonPolicy, Priority, Retrieval, Serialized, ShutdownError, Token, Transaction, Usage, WeightClass, WeightStats};
 
 #[cfg(test)]
 mod tests {
@@ -15,7 +43,7 @@ mod tests {
         for i in 0..10 {
             map.insert(i, i.to_string());
         }
-        let mut cache = CacheMut::new(map, 4);
+        let cache = CacheMut::new(map, 4);
         let get = cache.get(&3).unwrap();
         assert_eq!(*get, "3");
     }
@@ -26,7 +54,7 @@ mod tests {
         for i in 0..10 {
             map.insert(i, i.to_string());
         }
-        let mut cache = CacheMut::new(map, 4);
+        let cache = CacheMut::new(map, 4);
         
         {
             let mut get_mut = cache.get_mut(&3).unwrap();
@@ -44,7 +72,7 @@ mod tests {
             map.insert(i, i.to_string());
         }
 
-        let mut cache = CacheMut::new(map, 4);
+        let cache = CacheMut::new(map, 4);
 
         // Get two mutable references from the cache concurrently.
         let mut get_mut1 = cache.get_mut(&2).unwrap();
@@ -73,7 +101,7 @@ mod tests {
             map.insert(i, i.to_string());
         }
 
-        let mut cache = CacheMut::new(map, 4);
+        let cache = CacheMut::new(map, 4);
 
         // Get an immutable reference
         {
@@ -107,7 +135,7 @@ mod tests {
             map.insert(i, i.to_string());
         }
 
-        let mut cache = CacheMut::new(map, 4);
+        let cache = CacheMut::new(map, 4);
 
         // First immutable get
         let get1 = cache.get(&3).unwrap();
@@ -139,7 +167,7 @@ mod tests {
         }
 
         // Cache capacity = 4
-        let mut cache = CacheMut::new(map, 4);
+        let cache = CacheMut::new(map, 4);
 
         // Modify one value
         {
@@ -167,7 +195,7 @@ mod tests {
             map.insert(i, i.to_string());
         }
 
-        let mut cache = CacheMut::new(map, 4);
+        let cache = CacheMut::new(map, 4);
 
         let result = cache.get(&99);
 
@@ -226,347 +254,3665 @@ mod tests {
         let result = cache.get(&2);
         assert!(match result {Ok(_) => false, Err(NotInMap) => true});
     }
-}
-
-#[cfg(test)]
-mod folder_tests {
-    use crate::CacheMut;
-    use crate::folder_compatible::FolderCache;
-    use tempdir::TempDir;
 
     #[test]
-    fn insert() {
-        let tempdir = TempDir::new("test").unwrap();
-        let folder = FolderCache::continued(tempdir.into_path()).unwrap();
-        let mut cache = CacheMut::new(folder, 2);
-        for i in 0..10 {
-            cache.insert(i, i.to_string()).unwrap();
+    fn take_backend_replace_error_is_reported() {
+        use crate::{CacheCompatible, CacheMutCompatible, Retrieval};
+
+        /// Hands values out by removal and refuses to take them back.
+        struct Stubborn(HashMap<i32, String>);
+        impl CacheCompatible<i32, String> for Stubborn {
+            type Error = &'static str;
+            const RETRIEVAL: Retrieval = Retrieval::Take;
+            fn contains(&self, k: i32) -> bool { self.0.contains_key(&k) }
+            fn get(&mut self, k: i32) -> Result<String, Self::Error> { self.0.remove(&k).ok_or("missing") }
+            fn replace(&mut self, _: i32, _: String) -> Result<(), Self::Error> { Err("replace refused") }
         }
-        assert_eq!(*cache.get(&1).unwrap(), "1");
-        assert_eq!(*cache.get(&2).unwrap(), "2");
-        assert_eq!(*cache.get(&3).unwrap(), "3");
-        assert_eq!(*cache.get(&4).unwrap(), "4");
-        assert_eq!(*cache.get(&9).unwrap(), "9");
-        assert_eq!(*cache.get(&1).unwrap(), "1");
+        impl CacheMutCompatible<i32, String> for Stubborn {
+            fn insert(&mut self, k: i32, v: String) -> Result<(), Self::Error> { self.0.insert(k, v); Ok(()) }
+            fn remove(&mut self, k: i32) -> Result<(), Self::Error> { self.0.remove(&k); Ok(()) }
+            fn commit(&mut self) -> Result<(), Self::Error> { Ok(()) }
+        }
+
+        let mut cache = CacheMut::new(Stubborn(HashMap::from([(1, "one".to_string())])), 4);
+        assert_eq!(*cache.get(&1).unwrap(), "one");
+        assert_eq!(cache.commit(), Err("replace refused"));
     }
 
     #[test]
-    fn persist_across_drop() {
-        // Create a temporary folder for persistence
-        let tempdir = TempDir::new("test_persist").unwrap();
-        let folder_path = tempdir.path().to_path_buf();
-
-        {
-            // Create a cache and insert a few values
-            let folder = FolderCache::continued(folder_path.clone()).unwrap();
-            let mut cache = CacheMut::new(folder, 2);
-            cache.insert(42, "meaning".to_string()).unwrap();
-            cache.insert(99, "bottles".to_string()).unwrap();
+    fn invalidate_tag_removes_tagged_entries() {
+        let map: HashMap<i32, String> = (0..10).map(|i| (i, i.to_string())).collect();
+        let mut cache = CacheMut::new(map, 4);
+        cache.insert_tagged(20, "twenty".to_string(), ["even", "big"]).unwrap();
+        cache.insert_tagged(21, "twenty-one".to_string(), ["big"]).unwrap();
+        // Pull 20 into the LRU layer, and keep 21 borrowed while its tag is invalidated.
+        drop(cache.get(&20).unwrap());
+        let borrowed = cache.get_mut(&21).unwrap();
+        assert_eq!(cache.invalidate_tag("big").unwrap(), 2);
+        assert!(!cache.contains(&20) && !cache.contains(&21));
+        assert_eq!(*borrowed, "twenty-one");
+        drop(borrowed);
+        cache.commit().unwrap();
+        assert!(cache.get(&21).is_err());
+        assert_eq!(cache.invalidate_tag("even").unwrap(), 0);
+        assert!(cache.contains(&3));
+    }
 
-            // Ensure value is get()table before drop
-            assert_eq!(*cache.get(&42).unwrap(), "meaning");
-        } // <- cache and folder dropped here
+    #[test]
+    fn remove_many_and_ranges() {
+        use std::collections::BTreeMap;
 
-        // Continue the cache from the same folder path
-        {
-            let folder = FolderCache::continued(folder_path.clone()).unwrap();
-            let mut cache: CacheMut<i32, String, FolderCache<i32>> = CacheMut::new(folder, 2);
+        let map: BTreeMap<(u32, u32), String> = (0..3).flat_map(|t| (0..5).map(move |i| ((t, i), format!("{t}/{i}")))).collect();
+        let mut cache = CacheMut::new(map, 4);
+        drop(cache.get(&(1, 2)).unwrap());
+        cache.insert((1, 9), "new".to_string()).unwrap();
+        cache.remove_many(&[(0, 0), (0, 1)]).unwrap();
+        // Every key of tenant 1, whether in the LRU layer or the backend.
+        cache.remove_range((1, 0)..(2, 0)).unwrap();
+        assert!(!cache.contains(&(0, 0)) && cache.contains(&(0, 2)));
+        assert!((0..10).all(|i| !cache.contains(&(1, i))));
+        assert!(cache.contains(&(2, 4)));
+        cache.commit().unwrap();
+        assert!(cache.get(&(1, 2)).is_err());
+    }
 
-            // Ensure persisted value is still available
-            assert_eq!(*cache.get(&42).unwrap(), "meaning");
-            assert_eq!(*cache.get(&99).unwrap(), "bottles");
+    #[test]
+    fn eviction_events() {
+        use crate::EvictReason;
+
+        let map: HashMap<i32, String> = (0..10).map(|i| (i, i.to_string())).collect();
+        let mut cache = CacheMut::new(map, 2);
+        let events = cache.subscribe(4);
+        for i in 0..3 {
+            drop(cache.get(&i).unwrap());
         }
+        cache.remove(&5).unwrap();
+        // Inserted values are held in memory too.
+        cache.insert_tagged(20, "twenty".to_string(), ["t"]).unwrap();
+        cache.invalidate_tag("t").unwrap();
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), [(0, EvictReason::Capacity), (5, EvictReason::Removed), (1, EvictReason::Capacity), (20, EvictReason::Invalidated)]);
+        cache.commit().unwrap();
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), [(2, EvictReason::Flushed)]);
+        // Events beyond the capacity are dropped rather than blocking the cache.
+        cache.remove_many(&[3, 6, 7, 8, 9]).unwrap();
+        assert_eq!(events.try_iter().count(), 4);
     }
 
-
     #[test]
-    fn cleared_removes_old_data() {
-        // Create a temporary directory and persist some data
-        let tempdir = TempDir::new("test_cleared").unwrap();
-        let folder_path = tempdir.path().to_path_buf();
+    fn refresh_ahead_replaces_aging_values() {
+        use std::time::Duration;
+        use crate::refresh::RefreshAhead;
+
+        let map: HashMap<i32, String> = (0..4).map(|i| (i, format!("v{i}"))).collect();
+        let mut cache = CacheMut::new(map, 4).with_ttl(Duration::from_millis(50));
+        drop(cache.get(&0).unwrap());
+        drop(cache.get(&1).unwrap());
+        let refresher = RefreshAhead::new(Duration::from_millis(20), |k: &i32| (*k != 1).then(|| format!("fresh{k}")));
+        // Nothing is close to expiring yet.
+        assert_eq!(refresher.run_due(&mut cache).unwrap(), 0);
+        std::thread::sleep(Duration::from_millis(40));
+        // 1 is due as well, but its loader fails, so the old value stays.
+        assert_eq!(refresher.run_due(&mut cache).unwrap(), 1);
+        assert_eq!(*cache.get(&0).unwrap(), "fresh0");
+        assert_eq!(*cache.get(&1).unwrap(), "v1");
+        assert!(cache.age(&0).unwrap() < Duration::from_millis(40));
+        // Values only in the backend are not refreshed.
+        assert!(cache.age(&2).is_none());
+
+        let handle = RefreshAhead::new(Duration::from_millis(45), |k: &i32| Some(format!("background{k}")))
+            .with_tick(Duration::from_millis(5)).spawn(cache.clone(), |_| {});
+        std::thread::sleep(Duration::from_millis(100));
+        handle.stop();
+        assert_eq!(*cache.get(&0).unwrap(), "background0");
+    }
 
+    #[test]
+    fn stale_values_are_served_while_reloading() {
+        use std::time::Duration;
+        use crate::refresh::RefreshAhead;
+
+        let map: HashMap<i32, String> = HashMap::from([(0, "old".to_string())]);
+        let cache = CacheMut::new(map, 4).with_ttl(Duration::from_millis(20));
+        assert!(!cache.get(&0).unwrap().is_stale());
+        // The refresher only wakes up on its own once an hour, and reloads nothing that is not yet stale.
+        let _handle = RefreshAhead::new(Duration::ZERO, |_: &i32| Some("new".to_string()))
+            .with_tick(Duration::from_secs(3600)).spawn(cache.clone(), |_| {});
+        std::thread::sleep(Duration::from_millis(30));
         {
-            let folder = FolderCache::continued(folder_path.clone()).unwrap();
-            let mut cache = CacheMut::new(folder, 2);
-            cache.insert(1, "one".to_string()).unwrap();
-            cache.insert(2, "two".to_string()).unwrap();
-            assert_eq!(*cache.get(&1).unwrap(), "one");
+            let stale = cache.get_stale_ok(&0).unwrap();
+            assert!(stale.is_stale());
+            assert_eq!(*stale, "old");
+        }
+        for _ in 0..100 {
+            if *cache.get(&0).unwrap() == "new" {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(5));
         }
+        let fresh = cache.get(&0).unwrap();
+        assert_eq!(*fresh, "new");
+        assert!(!fresh.is_stale());
+    }
 
-        // Check that files exist before clearing
-        let entries_before: Vec<_> = std::fs::read_dir(&folder_path)
-            .unwrap()
-            .collect();
-        assert!(!entries_before.is_empty(), "Folder should not be empty before clearing");
+    #[test]
+    fn mock_clock_drives_ttls() {
+        use std::time::Duration;
+        use crate::clock::MockClock;
+        use crate::refresh::RefreshAhead;
+
+        let clock = MockClock::new();
+        let map: HashMap<i32, String> = HashMap::from([(0, "old".to_string())]);
+        let mut cache = CacheMut::new(map, 4).with_ttl(Duration::from_secs(60)).with_clock(clock.clone());
+        assert!(!cache.get(&0).unwrap().is_stale());
+        let refresher = RefreshAhead::new(Duration::from_secs(10), |_: &i32| Some("new".to_string()));
+        clock.advance(Duration::from_secs(49));
+        assert_eq!(cache.age(&0), Some(Duration::from_secs(49)));
+        assert_eq!(refresher.run_due(&mut cache).unwrap(), 0);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(refresher.run_due(&mut cache).unwrap(), 1);
+        assert_eq!(cache.age(&0), Some(Duration::ZERO));
+        clock.advance(Duration::from_secs(60));
+        let stale = cache.get(&0).unwrap();
+        assert!(stale.is_stale());
+        assert_eq!(*stale, "new");
+    }
 
-        // Recreate using cleared() — should delete old data
+    #[test]
+    fn get_mut_many_takes_all_keys_or_none() {
+        use crate::LockError;
+        use crate::hashmap_compatible::NotInMap;
+
+        let map: HashMap<&str, i32> = HashMap::from([("a", 100), ("b", 20), ("c", 0)]);
+        let cache = CacheMut::new(map, 4);
         {
-            let folder = FolderCache::cleared(folder_path.clone()).unwrap();
+            let mut accounts = cache.get_mut_many(&["a", "b"]).unwrap();
+            *accounts[0] -= 30;
+            *accounts[1] += 30;
+            assert_eq!(cache.num_active(), 2);
+        }
+        assert_eq!((*cache.get(&"a").unwrap(), *cache.get(&"b").unwrap()), (70, 50));
+
+        assert_eq!(cache.get_mut_many(&["a", "a"]).err(), Some(LockError::WouldDeadlock("a")));
+        let held = cache.get(&"b").unwrap();
+        assert_eq!(cache.get_mut_many(&["a", "b"]).err(), Some(LockError::WouldDeadlock("b")));
+        drop(held);
+        // A key the backend does not have releases the ones taken before it.
+        assert_eq!(cache.get_mut_many(&["c", "a", "missing"]).err(), Some(LockError::Backend("missing", NotInMap)));
+        assert_eq!(cache.num_active(), 0);
+        assert_eq!((*cache.get(&"a").unwrap(), *cache.get(&"c").unwrap()), (70, 0));
+    }
 
-            // Folder should now be empty
-            let entries_after: Vec<_> = std::fs::read_dir(&folder_path)
-                .unwrap()
-                .collect();
-            assert!(entries_after.is_empty(), "Folder should be empty after FolderCache::cleared");
+    #[test]
+    fn transactions_apply_all_or_nothing() {
+        use crate::Conflict;
+        use crate::hashmap_compatible::NotInMap;
+
+        #[derive(Debug, PartialEq)]
+        enum TransferError {Missing, Insufficient, Conflict}
+        impl From<NotInMap> for TransferError {
+            fn from(_: NotInMap) -> Self { Self::Missing }
+        }
+        impl From<Conflict<&'static str>> for TransferError {
+            fn from(_: Conflict<&'static str>) -> Self { Self::Conflict }
+        }
+        let transfer = |cache: &mut CacheMut<&'static str, i32, HashMap<&'static str, i32>>, from, to, amount| {
+            cache.transaction(|txn| {
+                *txn.get_mut(&to)?.ok_or(TransferError::Missing)? += amount;
+                let balance = txn.get_mut(&from)?.ok_or(TransferError::Missing)?;
+                *balance -= amount;
+                if *balance < 0 {
+                    return Err(TransferError::Insufficient);
+                }
+                Ok(*balance)
+            })
+        };
+        let map: HashMap<&str, i32> = HashMap::from([("a", 100), ("b", 20)]);
+        let mut cache = CacheMut::new(map, 1);
+        assert_eq!(transfer(&mut cache, "a", "b", 30), Ok(70));
+        assert_eq!(transfer(&mut cache, "b", "a", 80), Err(TransferError::Insufficient));
+        assert_eq!(transfer(&mut cache, "a", "missing", 1), Err(TransferError::Missing));
+        assert_eq!((*cache.get(&"a").unwrap(), *cache.get(&"b").unwrap()), (70, 50));
+
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _: Result<(), TransferError> = cache.transaction(|txn| {
+                txn.insert("a", 0);
+                panic!("aborted");
+            });
+        }));
+        assert!(panicked.is_err());
+        cache.transaction(|txn| {
+            assert!(txn.contains(&"b"));
+            txn.remove(&"b");
+            txn.insert("c", 5);
+            assert_eq!(txn.get(&"b")?, None);
+            Ok::<_, TransferError>(())
+        }).unwrap();
+        assert_eq!(*cache.get(&"a").unwrap(), 70);
+        assert!(!cache.contains(&"b"));
+        assert_eq!(*cache.get(&"c").unwrap(), 5);
+    }
 
-            let mut cache: CacheMut<i32, String, FolderCache<i32>> = CacheMut::new(folder, 2);
+    #[test]
+    fn transactions_fail_if_what_they_read_changed() {
+        use crate::Conflict;
 
-            // Old values should not exist
-            assert!(cache.get(&1).is_err(), "Old value 1 should not persist after clear");
-            assert!(cache.get(&2).is_err(), "Old value 2 should not persist after clear");
+        #[derive(Debug, PartialEq)]
+        enum Failed {Missing, Conflict(&'static str)}
+        impl From<NotInMap> for Failed {
+            fn from(_: NotInMap) -> Self { Self::Missing }
         }
+        impl From<Conflict<&'static str>> for Failed {
+            fn from(conflict: Conflict<&'static str>) -> Self { Self::Conflict(conflict.0) }
+        }
+        let mut cache = CacheMut::new(HashMap::from([("a", 1), ("b", 2)]), 4);
+        let mut other = cache.clone();
+        let copied = cache.transaction(|txn| {
+            let a = txn.get(&"a")?.ok_or(Failed::Missing)?;
+            other.insert("a", 10).unwrap();
+            txn.insert("b", a);
+            Ok(())
+        });
+        assert_eq!(copied, Err(Failed::Conflict("a")));
+        assert_eq!((*cache.get(&"a").unwrap(), *cache.get(&"b").unwrap()), (10, 2));
+        // Changes to entries it did not read do not stop it.
+        cache.transaction(|txn| {
+            let a = txn.get(&"a")?.ok_or(Failed::Missing)?;
+            other.insert("c", 3).unwrap();
+            txn.insert("b", a);
+            Ok::<_, Failed>(())
+        }).unwrap();
+        assert_eq!(*cache.get(&"b").unwrap(), 10);
     }
 
     #[test]
-    fn mutation_persists_after_eviction() {
-        // Create persistent folder
-        let tempdir = TempDir::new("test_mut_persist").unwrap();
-        let folder_path = tempdir.path().to_path_buf();
+    fn failed_transactions_restore_what_they_applied() {
+        use crate::{CacheCompatible, CacheMutCompatible, Conflict, Retrieval};
+
+        /// Refuses to remove 0.
+        struct Refusing(HashMap<i32, String>);
+        impl CacheCompatible<i32, String> for Refusing {
+            type Error = &'static str;
+            const RETRIEVAL: Retrieval = Retrieval::Copy;
+            fn contains(&self, k: i32) -> bool { self.0.contains_key(&k) }
+            fn get(&mut self, k: i32) -> Result<String, Self::Error> { self.0.get(&k).cloned().ok_or("missing") }
+            fn replace(&mut self, _: i32, _: String) -> Result<(), Self::Error> { Ok(()) }
+        }
+        impl CacheMutCompatible<i32, String> for Refusing {
+            fn insert(&mut self, k: i32, v: String) -> Result<(), Self::Error> { self.insert_shared(k, &v).map(|_| ()) }
+            fn insert_shared(&mut self, k: i32, v: &String) -> Result<bool, Self::Error> {
+                self.0.insert(k, v.clone());
+                Ok(true)
+            }
+            fn remove(&mut self, k: i32) -> Result<(), Self::Error> {
+                if k == 0 {
+                    return Err("remove refused");
+                }
+                self.0.remove(&k);
+                Ok(())
+            }
+            fn commit(&mut self) -> Result<(), Self::Error> { Ok(()) }
+        }
+        #[derive(Debug, PartialEq)]
+        enum Failed {Backend(&'static str), Conflict}
+        impl From<&'static str> for Failed {
+            fn from(e: &'static str) -> Self { Self::Backend(e) }
+        }
+        impl From<Conflict<i32>> for Failed {
+            fn from(_: Conflict<i32>) -> Self { Self::Conflict }
+        }
 
-        // Create a cache with small capacity so eviction happens quickly
-        let folder = FolderCache::continued(folder_path.clone()).unwrap();
-        let mut cache = CacheMut::new(folder, 2);
+        let map = HashMap::from([(0, "zero".to_string()), (1, "one".to_string()), (2, "two".to_string())]);
+        let mut cache = CacheMut::new(Refusing(map), 4);
+        let applied = cache.transaction(|txn| {
+            txn.get_mut(&1)?.unwrap().push_str(" changed");
+            txn.insert(3, "three".to_string());
+            txn.remove(&2);
+            txn.remove(&0);
+            Ok(())
+        });
+        assert_eq!(applied, Err(Failed::Backend("remove refused")));
+        assert_eq!(*cache.get(&0).unwrap(), "zero");
+        assert_eq!(*cache.get(&1).unwrap(), "one");
+        assert_eq!(*cache.get(&2).unwrap(), "two");
+        assert!(!cache.contains(&3));
+        cache.commit().unwrap();
+        assert_eq!(cache.with_backend(|refusing| refusing.0.len()).unwrap(), 3);
+        assert_eq!(cache.with_backend(|refusing| refusing.0[&1].clone()).unwrap(), "one");
+    }
 
-        // Insert several items
-        for i in 0..5 {
-            cache.insert(i, format!("value_{i}")).unwrap();
+    #[test]
+    fn stats_report_guard_hold_times() {
+        use std::time::Duration;
+        use crate::clock::MockClock;
+
+        let clock = MockClock::new();
+        let map: HashMap<i32, i32> = (0..200).map(|i| (i, i)).collect();
+        let cache = CacheMut::new(map, 4).with_clock(clock.clone());
+        for i in 0..100 {
+            let r = cache.get(&i).unwrap();
+            clock.advance(Duration::from_millis(if i == 0 {500} else {1}));
+            drop(r);
         }
+        let long = cache.get_mut(&100).unwrap();
+        clock.advance(Duration::from_secs(2));
+        let stats = cache.stats();
+        assert_eq!((stats.active, stats.cached, stats.released), (1, 4, 100));
+        assert_eq!((stats.hold_p50, stats.hold_p99, stats.hold_max), (Duration::from_millis(1), Duration::from_millis(1), Duration::from_millis(500)));
+        drop(long);
+        let stats = cache.stats();
+        assert_eq!((stats.hold_p99, stats.hold_max), (Duration::from_millis(500), Duration::from_secs(2)));
+    }
 
-        // Mutate a value via get_mut
+    #[test]
+    fn panics_poison_mutably_borrowed_entries() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        use crate::{EvictReason, LockError, PoisonPolicy};
+        use crate::hashmap_compatible::NotInMap;
+
+        let map: HashMap<i32, Vec<i32>> = HashMap::from([(0, vec![1, 2]), (1, vec![3])]);
+        let mut cache = CacheMut::new(map, 4);
+        let events = cache.subscribe(4);
+        let panicked = catch_unwind(AssertUnwindSafe(|| {
+            let _reader = cache.get(&1).unwrap();
+            let mut v = cache.get_mut(&0).unwrap();
+            v.clear();
+            panic!("half-way through");
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), [(0, EvictReason::Poisoned)]);
+        assert_eq!(cache.num_active(), 0);
+        // Only the entry held mutably is poisoned.
+        assert_eq!(*cache.try_get(&1).unwrap(), [3]);
+        assert!(cache.is_poisoned(&0));
+        assert_eq!(cache.try_get(&0).err(), Some(LockError::Poisoned(0)));
+        assert_eq!(cache.try_get_mut(&0).err(), Some(LockError::Poisoned(0)));
+        assert!(catch_unwind(AssertUnwindSafe(|| {cache.get(&0).ok();})).is_err());
+        cache.insert(0, vec![4]).unwrap();
+        assert_eq!(*cache.try_get(&0).unwrap(), [4]);
         {
-            let mut v = cache.get_mut(&1).unwrap();
-            *v = "modified".to_string();
+            let _held = cache.get_mut(&0).unwrap();
+            assert_eq!(cache.try_get(&0).err(), Some(LockError::WouldDeadlock(0)));
         }
 
-        // Cause eviction: hold more than capacity (2) active entries
-        let _a = cache.get(&2).unwrap();
-        let _b = cache.get(&3).unwrap();
-        let _c = cache.get(&4).unwrap();
-        // The mutated value (key 1) should be evicted here
-
-        // Drop active references to allow reloading
-        drop(_a);
-        drop(_b);
-        drop(_c);
+        // With `Reload`, the next access goes to the backend, which for a `HashMap` no longer has the value.
+        let mut cache = cache.with_poison_policy(PoisonPolicy::Reload);
+        let _ = catch_unwind(AssertUnwindSafe(|| {
+            let _v = cache.get_mut(&1).unwrap();
+            panic!();
+        }));
+        assert!(!cache.is_poisoned(&1));
+        assert_eq!(cache.try_get(&1).err(), Some(LockError::Backend(1, NotInMap)));
+        cache.insert(1, vec![5]).unwrap();
+        assert_eq!(*cache.get(&1).unwrap(), [5]);
+    }
 
-        // Fetch the value again (forcing reload from folder)
-        let v = cache.get(&1).unwrap();
+    #[test]
+    fn shared_values_are_written_back_later() {
+        let map: HashMap<i32, String> = HashMap::from([(0, "a".to_string()), (1, "b".to_string())]);
+        let mut cache = CacheMut::new(map, 1);
+        *cache.get_mut(&0).unwrap() = "changed".to_string();
+        // A leaked read guard keeps sharing the value after the entry is forced out of the borrowed set.
+        std::mem::forget(cache.get(&0).unwrap());
+        assert!(cache.force_deactivate(&0).unwrap());
+        drop(cache.get(&1).unwrap());
+        // A `HashMap` needs the value itself, so it is kept until it is no longer shared.
+        assert_eq!(cache.stats().unwritten, 1);
+        assert!(cache.contains(&0));
+        cache.commit().unwrap();
+        assert_eq!(cache.stats().unwritten, 1);
+        assert_eq!(*cache.get(&0).unwrap(), "changed");
+        assert_eq!(cache.stats().unwritten, 0);
+        // The leaked guard still holds the lock, so the entry stays borrowed until it is forced out again.
+        assert!(cache.active(&0));
+        assert!(cache.force_deactivate(&0).unwrap());
+        cache.remove(&0).unwrap();
+        assert!(!cache.contains(&0));
+    }
 
-        // The mutation should have persisted through eviction
-        assert_eq!(*v, "modified");
+    #[test]
+    fn get_arc_outlives_the_entry() {
+        let map: HashMap<i32, Vec<u8>> = HashMap::from([(0, vec![1, 2, 3]), (1, vec![4])]);
+        let mut cache = CacheMut::new(map, 1);
+        let arc = cache.get_arc(&0).unwrap();
+        assert_eq!(cache.num_active(), 0);
+        *cache.get_mut(&0).unwrap() = vec![9];
+        drop(cache.get(&1).unwrap());
+        cache.remove(&0).unwrap();
+        let sum = std::thread::spawn(move || arc.iter().map(|x| *x as u32).sum::<u32>()).join().unwrap();
+        assert_eq!(sum, 6);
     }
 
     #[test]
-    fn commit_persists_non_evicted_mutations() {
-        // Create a temporary persistent folder
-        let tempdir = TempDir::new("test_commit_persist").unwrap();
-        let folder_path = tempdir.path().to_path_buf();
+    fn guards_move_between_threads() {
+        use crate::{CMRef, CMRefMut};
+
+        fn assert_send<T: Send>() {}
+        assert_send::<CMRef<u64, String, HashMap<u64, String>>>();
+        assert_send::<CMRefMut<u64, String, HashMap<u64, String>>>();
+
+        let map: HashMap<i32, i32> = (0..8).map(|i| (i, i)).collect();
+        let cache = CacheMut::new(map, 2);
+        let workers: Vec<_> = (0..4).map(|i| {
+            let (from, mut to) = (cache.get(&i).unwrap(), cache.get_mut(&(i+4)).unwrap());
+            std::thread::spawn(move || *to += *from * 10)
+        }).collect();
+        workers.into_iter().for_each(|w| w.join().unwrap());
+        assert_eq!(cache.num_active(), 0);
+        assert_eq!((4..8).map(|i| *cache.get(&i).unwrap()).collect::<Vec<_>>(), [4, 15, 26, 37]);
+    }
 
-        // Create a cache with capacity large enough to hold all entries
-        let folder = FolderCache::continued(folder_path.clone()).unwrap();
-        let mut cache = CacheMut::new(folder, 10);
+    #[test]
+    fn hinted_reads_do_not_push_out_hot_entries() {
+        use crate::Hint;
 
-        // Insert a few values
-        for i in 0..5 {
-            cache.insert(i, format!("value_{i}")).unwrap();
+        let map: HashMap<i32, i32> = (0..100).map(|i| (i, i)).collect();
+        let cache = CacheMut::new(map, 3);
+        for i in 0..3 {
+            drop(cache.get(&i).unwrap());
         }
+        let events = cache.subscribe(100);
+        // A scan keeps at most one of its values in memory, in place of the least recently used hot entry.
+        let sum: i32 = (10..100).map(|i| *cache.get_with_hint(&i, Hint::NoPromote).unwrap()).sum();
+        assert_eq!(sum, (10..100).sum());
+        assert_eq!(events.try_iter().next(), Some((0, crate::EvictReason::Capacity)));
+        assert_eq!(cache.stats().cached, 3);
+        drop(cache.get(&1).unwrap());
+        drop(cache.get(&2).unwrap());
+        assert!(events.try_iter().all(|(k, _)| k >= 10));
+        // Uncached reads leave memory as it was.
+        assert_eq!(*cache.get_with_hint(&50, Hint::NoCache).unwrap(), 50);
+        assert_eq!(cache.stats().cached, 3);
+        assert!(events.try_iter().next().is_none());
+        assert!(cache.contains(&50));
+    }
 
-        // Mutate several values through get_mut()
-        {
-            *cache.get_mut(&0).unwrap() = "zero".to_string();
-            *cache.get_mut(&1).unwrap() = "one".to_string();
-            *cache.get_mut(&2).unwrap() = "two".to_string();
+    #[test]
+    fn insert_bypass_leaves_memory_alone() {
+        let map: HashMap<i32, String> = (0..3).map(|i| (i, i.to_string())).collect();
+        let mut cache = CacheMut::new(map, 2);
+        drop(cache.get(&0).unwrap());
+        drop(cache.get(&1).unwrap());
+        let events = cache.subscribe(10);
+        for i in 10..20 {
+            cache.insert_bypass(i, i.to_string()).unwrap();
         }
-
-        // No eviction occurs because capacity is large enough
-        // Force all in-memory changes to persist
+        assert!(events.try_iter().next().is_none());
+        assert_eq!(cache.stats().cached, 2);
+        // A cached copy of the key is dropped, so it cannot overwrite the new value later.
+        *cache.get_mut(&1).unwrap() = "changed".to_string();
+        cache.insert_bypass(1, "bypassed".to_string()).unwrap();
+        assert_eq!(cache.stats().cached, 1);
         cache.commit().unwrap();
+        assert_eq!(*cache.get(&1).unwrap(), "bypassed");
+        assert_eq!(*cache.get(&15).unwrap(), "15");
+    }
 
-        // Drop the cache entirely to ensure reload from disk
-        drop(cache);
+    #[test]
+    fn other_lock_types() {
+        use crate::lock::{NoLock, StdRwLock};
+        let mut cache: CacheMut<u32, String, HashMap<u32, String>, NoLock> = CacheMut::new_with_lock(HashMap::new(), 2);
+        cache.insert(1, "one".to_string()).unwrap();
+        let (a, b) = (cache.get(&1).unwrap(), cache.get(&1).unwrap());
+        assert_eq!((a.as_str(), b.as_str()), ("one", "one"));
+        drop((a, b));
+        cache.get_mut(&1).unwrap().push('!');
+        assert_eq!(*cache.get(&1).unwrap(), "one!");
+
+        let mut cache: CacheMut<u32, String, HashMap<u32, String>, StdRwLock> = CacheMut::new_with_lock(HashMap::new(), 2);
+        cache.insert(1, "one".to_string()).unwrap();
+        let mut guard = cache.get_mut(&1).unwrap();
+        let writer = std::thread::spawn(move || guard.push('!'));
+        writer.join().unwrap();
+        assert_eq!(*cache.get(&1).unwrap(), "one!");
+        cache.commit().unwrap();
+    }
 
-        // Reload from the same folder and verify persistence
-        let folder = FolderCache::continued(folder_path.clone()).unwrap();
-        let mut cache: CacheMut<i32, String, FolderCache<i32>> = CacheMut::new(folder, 10);
+    #[test]
+    fn bus_invalidates_copies_in_other_caches() {
+        use std::sync::{Arc, Mutex};
+        use crate::{CacheCompatible, CacheMutCompatible, EvictReason, Retrieval};
+        use crate::bus::LocalBus;
+
+        /// One store shared by several caches, as a database or network file system would be.
+        #[derive(Clone, Default)]
+        struct Store(Arc<Mutex<HashMap<i32, String>>>);
+        impl CacheCompatible<i32, String> for Store {
+            type Error = &'static str;
+            const RETRIEVAL: Retrieval = Retrieval::Copy;
+            fn contains(&self, k: i32) -> bool { self.0.lock().unwrap().contains_key(&k) }
+            fn get(&mut self, k: i32) -> Result<String, Self::Error> { self.0.lock().unwrap().get(&k).cloned().ok_or("missing") }
+            fn replace(&mut self, _: i32, _: String) -> Result<(), Self::Error> { Ok(()) }
+        }
+        impl CacheMutCompatible<i32, String> for Store {
+            fn insert(&mut self, k: i32, v: String) -> Result<(), Self::Error> { self.0.lock().unwrap().insert(k, v); Ok(()) }
+            fn remove(&mut self, k: i32) -> Result<(), Self::Error> { self.0.lock().unwrap().remove(&k); Ok(()) }
+            fn commit(&mut self) -> Result<(), Self::Error> { Ok(()) }
+        }
 
-        assert_eq!(*cache.get(&0).unwrap(), "zero");
-        assert_eq!(*cache.get(&1).unwrap(), "one");
-        assert_eq!(*cache.get(&2).unwrap(), "two");
-        assert_eq!(*cache.get(&3).unwrap(), "value_3");
-        assert_eq!(*cache.get(&4).unwrap(), "value_4");
+        let store = Store::default();
+        let mut bus = LocalBus::new();
+        let mut a = CacheMut::new(store.clone(), 4).with_bus(bus.handle());
+        let mut b = CacheMut::new(store.clone(), 4).with_bus(bus.handle());
+        let events = b.subscribe(4);
+        a.insert(1, "old".to_string()).unwrap();
+        a.commit().unwrap();
+        assert_eq!(*b.get(&1).unwrap(), "old");
+        a.insert(1, "new".to_string()).unwrap();
+        a.commit().unwrap();
+        let timeout = std::time::Duration::from_secs(10);
+        assert_eq!(events.recv_timeout(timeout), Ok((1, EvictReason::Invalidated)));
+        assert_eq!(*b.get(&1).unwrap(), "new");
+        // A copy borrowed when the change arrives is dropped once released.
+        let held = b.get(&1).unwrap();
+        a.remove(&1).unwrap();
+        assert_eq!(events.recv_timeout(timeout), Ok((1, EvictReason::Invalidated)));
+        drop(held);
+        assert!(!b.contains(&1));
+        b.commit().unwrap();
+        assert!(store.0.lock().unwrap().is_empty());
     }
 
     #[test]
-    fn many_insert() {
-        let tempdir = TempDir::new("test").unwrap();
-        let folder_path = tempdir.path().to_path_buf();
-        let folder = FolderCache::continued(folder_path.clone()).unwrap();
-        let mut cache = CacheMut::new(folder, 2);
-        for i in 0..200 {
+    fn sharded_rebalance() {
+        use crate::sharded::{ShardError, ShardedBackend};
+        let shards = ShardedBackend::new().with_shard("a", HashMap::new()).with_shard("b", HashMap::new());
+        let mut cache = CacheMut::new(shards, 8);
+        for i in 0..100u32 {
             cache.insert(i, i.to_string()).unwrap();
         }
-        drop(cache);
-        let folder = FolderCache::continued(folder_path.clone()).unwrap();
-        let mut cache: CacheMut<i32, String, FolderCache<i32>> = CacheMut::new(folder, 2);
-        for i in 0..200 {
+        let moved = cache.with_backend(|shards| {
+            let held: usize = shards.shards().map(|(_, shard)| shard.len()).sum();
+            assert_eq!(held, 100);
+            shards.add_shard("c", HashMap::new()).unwrap()
+        }).unwrap();
+        assert!(0 < moved && moved < 100);
+        assert_eq!(cache.with_backend(|shards| shards.shard("c").unwrap().len()).unwrap(), moved);
+        let duplicate = cache.with_backend(|shards| shards.add_shard("c", HashMap::new())).unwrap();
+        assert!(matches!(duplicate, Err(ShardError::Duplicate(name)) if name == "c"));
+        assert_eq!(cache.with_backend(|shards| shards.shards().count()).unwrap(), 3);
+        for i in 0..100u32 {
+            assert_eq!(*cache.get(&i).unwrap(), i.to_string());
+        }
+        let removed = cache.with_backend(|shards| shards.remove_shard("a").unwrap().unwrap()).unwrap();
+        assert!(removed.is_empty());
+        for i in 0..100u32 {
             assert_eq!(*cache.get(&i).unwrap(), i.to_string());
         }
     }
 
     #[test]
-    fn variable_size_insert() {
-        let tempdir = TempDir::new("test_many_insert").unwrap();
-        let folder_path = tempdir.path().to_path_buf();
+    fn failed_write_back_keeps_the_modified_value() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use crate::{CacheCompatible, CacheMutCompatible, Retrieval};
+
+        /// Refuses writes while `failing` is set.
+        struct Flaky(HashMap<i32, String>, Arc<AtomicBool>);
+        impl CacheCompatible<i32, String> for Flaky {
+            type Error = &'static str;
+            const RETRIEVAL: Retrieval = Retrieval::Copy;
+            fn contains(&self, k: i32) -> bool { self.0.contains_key(&k) }
+            fn get(&mut self, k: i32) -> Result<String, Self::Error> { self.0.get(&k).cloned().ok_or("missing") }
+            fn replace(&mut self, _: i32, _: String) -> Result<(), Self::Error> { Ok(()) }
+        }
+        impl CacheMutCompatible<i32, String> for Flaky {
+            fn insert(&mut self, k: i32, v: String) -> Result<(), Self::Error> { self.insert_shared(k, &v).map(|_| ()) }
+            fn insert_shared(&mut self, k: i32, v: &String) -> Result<bool, Self::Error> {
+                if self.1.load(Ordering::Relaxed) {
+                    return Err("write refused");
+                }
+                self.0.insert(k, v.clone());
+                Ok(true)
+            }
+            fn remove(&mut self, k: i32) -> Result<(), Self::Error> { self.0.remove(&k); Ok(()) }
+            fn commit(&mut self) -> Result<(), Self::Error> { Ok(()) }
+        }
 
-        // Create a folder-backed cache with small capacity to trigger evictions
-        let folder = FolderCache::continued(folder_path.clone()).unwrap();
-        let mut cache = CacheMut::new(folder, 2);
+        let failing = Arc::new(AtomicBool::new(false));
+        let map = HashMap::from([(1, "one".to_string()), (2, "two".to_string())]);
+        let a = CacheMut::new(Flaky(map, failing.clone()), 1);
+        let mut b = a.clone();
+        a.get_mut(&1).unwrap().push_str(" changed");
+        failing.store(true, Ordering::Relaxed);
+        // Releasing 2 evicts the modified 1, which the backend refuses.
+        drop(b.get(&2).unwrap());
+        assert_eq!(*b.get(&1).unwrap(), "one changed");
+        drop(b.get(&2).unwrap());
+        assert_eq!(b.flush(), Err("write refused"));
+        assert_eq!(*b.get(&1).unwrap(), "one changed");
+        failing.store(false, Ordering::Relaxed);
+        b.commit().unwrap();
+        assert_eq!(b.with_backend(|flaky| flaky.0[&1].clone()).unwrap(), "one changed");
+    }
 
-        // Insert 200 entries, each containing "memphis" repeated i times
-        for i in 0..200 {
-            let value = "memphis".repeat(i);
-            cache.insert(i, value).unwrap();
-        }
+    #[test]
+    fn contains_does_not_promote() {
+        let map: HashMap<i32, String> = (0..10).map(|i| (i, i.to_string())).collect();
+        let cache = CacheMut::new(map, 2);
+        drop(cache.get(&1).unwrap());
+        drop(cache.get(&2).unwrap());
+        assert!(cache.contains_cached(&1) && !cache.contains_cached(&3));
+        assert!(cache.contains(&1) && cache.contains(&3));
+        // 1 is still the least recently used, so loading 3 pushes it out.
+        drop(cache.get(&3).unwrap());
+        assert!(!cache.contains_cached(&1) && cache.contains_cached(&2));
+        assert!(cache.contains(&1));
+    }
 
-        // Drop cache to force write-back to folder
-        drop(cache);
+    #[test]
+    fn zero_capacity_passes_through() {
+        let map: HashMap<i32, String> = (0..4).map(|i| (i, i.to_string())).collect();
+        let mut cache = CacheMut::new(map, 0);
+        assert_eq!(cache.cap(), 0);
+        cache.get_mut(&1).unwrap().push('!');
+        assert_eq!(cache.stats().cached, 0);
+        cache.insert(5, "5".to_string()).unwrap();
+        assert_eq!(cache.stats().cached, 0);
+        assert!(!cache.contains_cached(&1) && !cache.contains_cached(&5));
+        assert_eq!(cache.maintain_backend(|map| (map[&1].clone(), map[&5].clone())), ("1!".to_string(), "5".to_string()));
+        assert_eq!(*cache.get(&1).unwrap(), "1!");
+    }
 
-        // Recreate from the same folder and verify persistence
-        let folder = FolderCache::continued(folder_path.clone()).unwrap();
-        let mut cache: CacheMut<usize, String, FolderCache<usize>> = CacheMut::new(folder, 2);
+    #[test]
+    fn idle_entries_expire() {
+        use std::time::Duration;
+        use crate::EvictReason;
+        use crate::clock::MockClock;
+
+        let clock = MockClock::new();
+        let map: HashMap<i32, String> = (0..4).map(|i| (i, i.to_string())).collect();
+        let mut cache = CacheMut::new(map, 4).with_tti(Duration::from_secs(60)).with_clock(clock.clone());
+        let events = cache.subscribe(4);
+        drop(cache.get(&0).unwrap());
+        cache.insert_with_tti(5, "5".to_string(), Duration::from_secs(10)).unwrap();
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(cache.expire_idle().unwrap(), 1);
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), [(5, EvictReason::Idle)]);
+        // Using an entry restarts its time to idle, and borrowed entries do not expire.
+        drop(cache.get(&0).unwrap());
+        let held = cache.get(&1).unwrap();
+        clock.advance(Duration::from_secs(55));
+        assert_eq!(cache.expire_idle().unwrap(), 0);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(cache.expire_idle().unwrap(), 1);
+        assert!(!cache.contains_cached(&0) && cache.contains_cached(&1));
+        drop(held);
+        assert_eq!(cache.maintain_backend(|map| (map[&0].clone(), map[&5].clone())), ("0".to_string(), "5".to_string()));
+    }
 
-        for i in 0..200 {
-            let expected = "memphis".repeat(i);
-            assert_eq!(*cache.get(&i).unwrap(), expected);
-        }
+    #[test]
+    fn debug_shows_counts() {
+        #[derive(Debug)]
+        struct Service {cache: CacheMut<i32, String, HashMap<i32, String>>}
+
+        let map: HashMap<i32, String> = (0..4).map(|i| (i, i.to_string())).collect();
+        let service = Service {cache: CacheMut::new(map, 4)};
+        drop(service.cache.get(&0).unwrap());
+        let mut held = service.cache.get_mut(&1).unwrap();
+        held.push('!');
+        assert_eq!(format!("{service:?}"), "Service { cache: CacheMut { capacity: 4, cached: 1, active: 1, dirty: 1, unwritten: 0, .. } }");
+        assert_eq!(format!("{held:?}"), "CMRefMut { key: 1, .. }");
+        assert_eq!(format!("{:?}", service.cache.get(&0).unwrap()), "CMRef { key: 0, stale: false, .. }");
     }
 
     #[test]
-    fn mutate_short_to_long_persists() {
-        // Create a temporary folder for persistence
-        let tempdir = TempDir::new("test_mutate_short_to_long").unwrap();
-        let folder_path = tempdir.path().to_path_buf();
+    fn collection_traits() {
+        let mut cache: CacheMut<i32, String, HashMap<i32, String>> = (0..3).map(|i| (i, i.to_string())).collect();
+        cache.extend([(3, "3".to_string()), (0, "zero".to_string())]);
+        assert_eq!(*cache.get(&0).unwrap(), "zero");
+        let other = cache.clone();
+        let mut drained: Vec<(i32, String)> = cache.into_iter().collect();
+        drained.sort();
+        assert_eq!(drained, [(0, "zero".to_string()), (1, "1".to_string()), (2, "2".to_string()), (3, "3".to_string())]);
+        assert!(!other.contains(&0) && !other.contains(&3));
+    }
 
-        // Initialize the cache
-        let folder = FolderCache::continued(folder_path.clone()).unwrap();
-        let mut cache = CacheMut::new(folder, 2);
+    #[test]
+    fn expect_getters_name_the_key() {
+        let map: HashMap<i32, String> = HashMap::from([(1, "one".to_string())]);
+        let cache = CacheMut::new(map, 4);
+        cache.get_mut_expect(&1).push('!');
+        assert_eq!(*cache.get_expect(&1), "one!");
+        let panic = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| drop(cache.get_expect(&7)))).unwrap_err();
+        assert_eq!(panic.downcast_ref::<String>().map(String::as_str), Some("failed to get 7: NotInMap"));
+    }
 
-        // Insert a short string
-        cache.insert(1, "hi".to_string()).unwrap();
-        assert_eq!(*cache.get(&1).unwrap(), "hi");
+    #[test]
+    fn bloom_filter_answers_misses() {
+        use crate::bloom::BloomFilter;
+        let map: HashMap<u32, String> = (0..100).map(|i| (i, i.to_string())).collect();
+        let filter = BloomFilter::from_keys(map.keys(), 0.01);
+        assert!(map.keys().all(|k| filter.might_contain(k)));
+        assert!((1000..2000u32).filter(|k| filter.might_contain(k)).count() < 50);
+        let mut cache = CacheMut::new(map, 4).with_bloom_filter(filter);
+        assert!(cache.contains(&7));
+        assert_eq!(*cache.get_if_present(&7).unwrap().unwrap(), "7");
+        assert!(cache.get_if_present(&1000).unwrap().is_none());
+        // Keys written behind the cache's back are not in the filter.
+        cache.with_backend(|map| map.insert(5000, "hidden".to_string())).unwrap();
+        assert!(!cache.contains(&5000));
+        cache.insert(5000, "seen".to_string()).unwrap();
+        cache.commit().unwrap();
+        assert!(cache.contains(&5000));
+    }
 
-        // Mutate it to a long string
-        {
-            let mut v = cache.get_mut(&1).unwrap();
-            *v = "memphis".repeat(500); // very long string
+    #[test]
+    fn clear_where_both_layers() {
+        let mut cache: CacheMut<i32, String, HashMap<i32, String>> = CacheMut::new(HashMap::new(), 4);
+        for i in 0..20 {
+            cache.insert(i, i.to_string()).unwrap();
         }
+        *cache.get_mut(&3).unwrap() = "changed".to_string();
+        cache.clear_where(|k| k%2 == 1).unwrap();
+        assert!((0..20).all(|i| cache.contains(&i) == (i%2 == 0)));
+        assert!(cache.get(&3).is_err());
+        cache.flush().unwrap();
+        let backend = cache.with_backend(|cc| cc.clone()).unwrap();
+        assert_eq!(backend.len(), 10);
+        assert!(backend.keys().all(|k| k%2 == 0));
+    }
 
-        // Drop to force persistence to disk
+    #[test]
+    fn adaptive_capacity() {
+        use crate::adaptive::AdaptiveCapacity;
+        let map: HashMap<u32, String> = (0..12).map(|i| (i, i.to_string())).collect();
+        let cache = CacheMut::new(map, 4).with_adaptive_capacity(AdaptiveCapacity::new(2, 16).with_window(10));
+        // Cycling over more keys than fit misses every time, until the capacity grows to hold them all.
+        for _ in 0..20 {
+            for i in 0..12 {
+                cache.get(&i).unwrap();
+            }
+        }
+        assert!(cache.cap() >= 12);
+        let grown = cache.stats();
+        for _ in 0..12 {
+            cache.get(&0).unwrap();
+        }
+        assert_eq!(cache.stats().misses, grown.misses);
+        // A working set of one key hits every time, so the capacity shrinks to the minimum.
+        for _ in 0..200 {
+            cache.get(&0).unwrap();
+        }
+        assert_eq!(cache.cap(), 2);
+
+        let map: HashMap<u32, String> = (0..12).map(|i| (i, i.to_string())).collect();
+        let capped = AdaptiveCapacity::new(2, 16).with_window(10).with_max_bytes(500, |_: &String| 100);
+        let cache = CacheMut::new(map, 4).with_adaptive_capacity(capped);
+        for _ in 0..20 {
+            for i in 0..12 {
+                cache.get(&i).unwrap();
+            }
+        }
+        assert!(cache.cap() <= 5 && cache.stats().cached <= 5);
+    }
+
+    #[cfg(feature = "pressure")]
+    #[test]
+    fn memory_pressure() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use crate::pressure::{AvailableMemory, CgroupPressure, MemoryPressure};
+        let map: HashMap<u32, String> = (0..10).map(|i| (i, i.to_string())).collect();
+        let mut cache = CacheMut::new(map, 8);
+        for i in 0..8 {
+            cache.get_mut(&i).unwrap().push('!');
+        }
+        let pressed = Arc::new(AtomicBool::new(false));
+        let signal = pressed.clone();
+        let mut pressure = MemoryPressure::new(move || Ok(signal.load(Ordering::Relaxed)), 0.25);
+        assert!(!pressure.run_due(&mut cache).unwrap());
+        assert_eq!(cache.cap(), 8);
+        pressed.store(true, Ordering::Relaxed);
+        assert!(pressure.run_due(&mut cache).unwrap());
+        assert_eq!((cache.cap(), cache.stats().cached), (2, 2));
+        // The evicted entries were written back.
+        cache.set_capacity(8).unwrap();
+        assert_eq!(cache.cap(), 2);
+        assert_eq!(*cache.get(&0).unwrap(), "0!");
+        pressed.store(false, Ordering::Relaxed);
+        assert!(!pressure.run_due(&mut cache).unwrap());
+        assert_eq!(cache.cap(), 8);
+
+        let dir = tempdir::TempDir::new("pressure").unwrap();
+        std::fs::write(dir.path().join("memory.pressure"), "some avg10=12.50 avg60=3.00 avg300=1.00 total=100\nfull avg10=0.00 avg60=0.00 avg300=0.00 total=0\n").unwrap();
+        assert_eq!(CgroupPressure::at(dir.path().join("memory.pressure"), 10.0).avg10().unwrap(), 12.5);
+        std::fs::write(dir.path().join("meminfo"), "MemTotal:       16000000 kB\nMemAvailable:    2000000 kB\n").unwrap();
+        let meminfo = AvailableMemory::at(dir.path().join("meminfo"), 0);
+        assert_eq!(meminfo.available().unwrap(), 2_048_000_000);
+    }
+
+    #[test]
+    fn weight_stats() {
+        let map: HashMap<u32, String> = (0..6).map(|i| (i, "x".repeat(if i == 5 {1000} else {10}))).collect();
+        let cache = CacheMut::new(map, 4).with_weigher(|v: &String| v.len());
+        assert_eq!(cache.stats().weights.unwrap(), crate::WeightStats::default());
+        for i in 0..4 {
+            cache.get(&i).unwrap();
+        }
+        let weights = cache.stats().weights.unwrap();
+        assert_eq!((weights.total, weights.heaviest, weights.evicted), (40, 10, 0));
+        assert_eq!(weights.classes, vec![crate::WeightClass {up_to: 16, values: 4, weight: 40}]);
+        cache.get(&5).unwrap();
+        let weights = cache.stats().weights.unwrap();
+        assert_eq!((weights.total, weights.heaviest, weights.evicted), (1030, 1000, 10));
+        assert_eq!(weights.classes.iter().map(|class| (class.up_to, class.values)).collect::<Vec<_>>(), vec![(16, 3), (1024, 1)]);
+        assert_eq!(CacheMut::new(HashMap::<u32, String>::new(), 4).stats().weights, None);
+    }
+
+    #[test]
+    fn write_debounce() {
+        use std::time::Duration;
+        use crate::clock::MockClock;
+        let clock = MockClock::new();
+        let map: HashMap<u32, String> = (0..2).map(|i| (i, i.to_string())).collect();
+        let mut cache = CacheMut::new(map, 0).with_clock(clock.clone()).with_write_debounce(Duration::from_secs(10));
+        for _ in 0..5 {
+            cache.get_mut(&0).unwrap().push('!');
+        }
+        // Released with a capacity of 0, but held back rather than handed to the backend.
+        assert_eq!(cache.stats().unwritten, 1);
+        assert_eq!(cache.maintain_backend(|map| map.get(&0).cloned()), None);
+        assert_eq!(*cache.get(&0).unwrap(), "0!!!!!");
+        cache.flush().unwrap();
+        assert_eq!(cache.write_due().unwrap(), 0);
+        clock.advance(Duration::from_secs(11));
+        assert_eq!(cache.write_due().unwrap(), 1);
+        assert_eq!(cache.maintain_backend(|map| map.get(&0).cloned()).as_deref(), Some("0!!!!!"));
+        cache.get_mut(&1).unwrap().push('!');
+        cache.commit().unwrap();
+        assert_eq!(cache.maintain_backend(|map| map.get(&1).cloned()).as_deref(), Some("1!"));
+    }
+
+    #[test]
+    fn trace_and_simulate() {
+        use crate::trace::{self, Policy};
+        let map: HashMap<u32, String> = (0..8).map(|i| (i, i.to_string())).collect();
+        let cache = CacheMut::new(map, 4).with_trace(100);
+        for _ in 0..3 {
+            for i in 0..6 {
+                cache.get(&i).unwrap();
+            }
+        }
+        let events = cache.trace();
+        assert_eq!(events.len(), 18);
+        assert_eq!(events.iter().filter(|event| event.hit).count() as u64, cache.stats().hits);
+        // Six keys in a loop never hit in four slots, and always do in six.
+        assert_eq!(trace::simulate(&events, 4).hits, 0);
+        assert_eq!(trace::simulate(&events, 6), trace::Simulation {hits: 12, misses: 6});
+        assert_eq!(trace::simulate_with(&events, 6, Policy::Fifo).hits, 12);
+        let mut bytes = Vec::new();
+        trace::write(&events, &mut bytes).unwrap();
+        assert_eq!(trace::read(&bytes[..]).unwrap(), events);
+        // The ring buffer keeps the most recent events.
+        let cache = CacheMut::new(HashMap::from([(0u32, 0u32)]), 4).with_trace(2);
+        for _ in 0..5 {
+            cache.get(&0).unwrap();
+        }
+        assert_eq!(cache.trace().len(), 2);
+    }
+
+    #[test]
+    fn priorities() {
+        use crate::Priority;
+        let map: HashMap<u32, String> = (0..10).map(|i| (i, i.to_string())).collect();
+        let mut cache = CacheMut::new(map, 4);
+        cache.get_with_priority(&0, Priority::High).unwrap();
+        cache.get_with_priority(&1, Priority::Low).unwrap();
+        cache.get(&2).unwrap();
+        cache.get(&3).unwrap();
+        // The low priority entry goes first, then the normal ones, and the high priority one last.
+        cache.get(&4).unwrap();
+        assert!(!cache.contains_cached(&1));
+        cache.get(&5).unwrap();
+        cache.get(&6).unwrap();
+        assert!(cache.contains_cached(&0) && !cache.contains_cached(&2) && !cache.contains_cached(&3));
+        cache.insert_with_priority(7, "seven".to_string(), Priority::Low).unwrap();
+        cache.get(&8).unwrap();
+        assert!(!cache.contains_cached(&7) && cache.contains_cached(&0));
+        // Outside the window, recency wins.
+        let map: HashMap<u32, String> = (0..10).map(|i| (i, i.to_string())).collect();
+        let cache = CacheMut::new(map, 4).with_priority_window(2);
+        cache.get_with_priority(&0, Priority::Low).unwrap();
+        for i in 1..4 {
+            cache.get_with_priority(&i, Priority::High).unwrap();
+        }
+        cache.get(&4).unwrap();
+        assert!(!cache.contains_cached(&0));
+        cache.get(&5).unwrap();
+        assert!(!cache.contains_cached(&1));
+    }
+
+    #[test]
+    fn group_eviction() {
+        let map: HashMap<u32, String> = (0..10).map(|i| (i, i.to_string())).collect();
+        let mut cache = CacheMut::new(map, 6);
+        for k in 0..3 {
+            cache.set_group(k, 1);
+        }
+        for k in 0..6 {
+            cache.get(&k).unwrap();
+        }
+        // Evicting 0 to make room takes the rest of its group out too.
+        cache.get(&6).unwrap();
+        assert!((0..3).all(|k| !cache.contains_cached(&k)));
+        assert_eq!(cache.stats().cached, 4);
+        let mut group = cache.group(1);
+        group.sort();
+        assert_eq!(group, vec![0, 1, 2]);
+
+        let map: HashMap<u32, String> = (0..10).map(|i| (i, i.to_string())).collect();
+        let mut cache = CacheMut::new(map, 4);
+        cache.set_group(0, 1);
+        cache.set_group(1, 1);
+        cache.get(&1).unwrap();
+        cache.get(&3).unwrap();
+        // Using 0 keeps 1 as recently used, so 3 is evicted first.
+        cache.get(&0).unwrap();
+        cache.get(&4).unwrap();
+        cache.get(&5).unwrap();
+        assert!(cache.contains_cached(&0) && cache.contains_cached(&1) && !cache.contains_cached(&3));
+        assert_eq!(cache.evict_group(1).unwrap(), 2);
+        assert!(!cache.contains_cached(&0) && !cache.contains_cached(&1));
+        cache.ungroup(&0);
+        assert_eq!(cache.group(1), vec![1]);
+    }
+
+    #[test]
+    fn dependency_invalidation() {
+        let map: HashMap<u32, String> = (0..6).map(|i| (i, i.to_string())).collect();
+        let mut cache = CacheMut::new(map, 8);
+        // 1 and 2 come from 0, and 3 from 2.
+        cache.add_dependency(1, 0);
+        cache.add_dependency(2, 0);
+        cache.add_dependency(3, 2);
+        for k in 0..4 {
+            cache.get(&k).unwrap();
+        }
+        cache.get_mut(&0).unwrap().push('!');
+        assert!((1..4).all(|k| !cache.contains(&k)));
+        assert!(cache.dependents(&0).is_empty());
+        // Removing a source reaches the backend too, and leaves unrelated entries alone.
+        cache.add_dependency(5, 4);
+        cache.add_dependency(4, 5);
+        cache.remove(&4).unwrap();
+        assert!(!cache.contains(&5) && cache.contains(&0));
+        cache.insert(1, "one".to_string()).unwrap();
+        cache.add_dependency(1, 0);
+        cache.insert(0, "zero".to_string()).unwrap();
+        assert!(!cache.contains(&1) && cache.contains(&0));
+    }
+
+    #[test]
+    fn conditional_refetch() {
+        use std::sync::{Arc, Mutex};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Duration;
+        use crate::{CacheCompatible, CacheMutCompatible, IfChanged, Retrieval, Token};
+        use crate::clock::MockClock;
+
+        /// Versions every value and counts the values it sends, as an HTTP server with ETags would.
+        #[derive(Clone, Default)]
+        struct Versioned(Arc<Mutex<HashMap<i32, (String, u8)>>>, Arc<AtomicUsize>);
+        impl CacheCompatible<i32, String> for Versioned {
+            type Error = &'static str;
+            const RETRIEVAL: Retrieval = Retrieval::Copy;
+            fn contains(&self, k: i32) -> bool { self.0.lock().unwrap().contains_key(&k) }
+            fn get(&mut self, k: i32) -> Result<String, Self::Error> { self.get_versioned(k).map(|(v, _)| v) }
+            fn replace(&mut self, _: i32, _: String) -> Result<(), Self::Error> { Ok(()) }
+            fn get_versioned(&mut self, k: i32) -> Result<(String, Option<Token>), Self::Error> {
+                self.1.fetch_add(1, Ordering::Relaxed);
+                self.0.lock().unwrap().get(&k).map(|(v, version)| (v.clone(), Some(vec![*version]))).ok_or("missing")
+            }
+            fn get_if_changed(&mut self, k: i32, token: &Token) -> Result<IfChanged<String>, Self::Error> {
+                if self.0.lock().unwrap().get(&k).is_some_and(|(_, version)| token == &[*version]) {
+                    return Ok(IfChanged::NotModified);
+                }
+                self.get_versioned(k).map(|(v, token)| IfChanged::Changed(v, token))
+            }
+        }
+        impl CacheMutCompatible<i32, String> for Versioned {
+            fn insert(&mut self, k: i32, v: String) -> Result<(), Self::Error> {
+                let mut store = self.0.lock().unwrap();
+                let version = store.get(&k).map_or(0, |(_, version)| version+1);
+                store.insert(k, (v, version));
+                Ok(())
+            }
+            fn remove(&mut self, k: i32) -> Result<(), Self::Error> { self.0.lock().unwrap().remove(&k); Ok(()) }
+            fn commit(&mut self) -> Result<(), Self::Error> { Ok(()) }
+        }
+
+        let store = Versioned::default();
+        store.clone().insert(0, "old".to_string()).unwrap();
+        let clock = MockClock::new();
+        let mut cache = CacheMut::new(store.clone(), 4).with_ttl(Duration::from_secs(60)).with_clock(clock.clone());
+        assert_eq!(*cache.get(&0).unwrap(), "old");
+        clock.advance(Duration::from_secs(60));
+        let current = cache.get(&0).unwrap();
+        assert!(!current.is_stale());
+        assert_eq!(*current, "old");
+        drop(current);
+        assert_eq!(store.1.load(Ordering::Relaxed), 1);
+
+        store.clone().insert(0, "new".to_string()).unwrap();
+        assert_eq!(cache.refetch(&0), Ok(Some(true)));
+        assert_eq!(*cache.get(&0).unwrap(), "new");
+        assert_eq!(cache.refetch(&0), Ok(Some(false)));
+        assert_eq!(store.1.load(Ordering::Relaxed), 2);
+        *cache.get_mut(&0).unwrap() = "mine".to_string();
+        assert_eq!(cache.refetch(&0), Ok(None));
+    }
+
+    #[test]
+    fn circuit_breaker() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+        use std::time::Duration;
+        use crate::{CacheCompatible, CacheMutCompatible, Retrieval};
+        use crate::breaker::{BreakerError, CircuitBreaker};
+        use crate::clock::MockClock;
+
+        /// Fails every call while `down` is set, and counts the calls.
+        struct Remote(HashMap<i32, String>, Arc<AtomicBool>, Arc<AtomicUsize>);
+        impl Remote {
+            fn reach(&self) -> Result<(), &'static str> {
+                self.2.fetch_add(1, Ordering::Relaxed);
+                if self.1.load(Ordering::Relaxed) {Err("unreachable")} else {Ok(())}
+            }
+        }
+        impl CacheCompatible<i32, String> for Remote {
+            type Error = &'static str;
+            const RETRIEVAL: Retrieval = Retrieval::Copy;
+            fn contains(&self, k: i32) -> bool { self.0.contains_key(&k) }
+            fn get(&mut self, k: i32) -> Result<String, Self::Error> { self.reach()?; self.0.get(&k).cloned().ok_or("missing") }
+            fn replace(&mut self, _: i32, _: String) -> Result<(), Self::Error> { Ok(()) }
+            fn health(&mut self) -> Result<(), Self::Error> { self.reach() }
+        }
+        impl CacheMutCompatible<i32, String> for Remote {
+            fn insert(&mut self, k: i32, v: String) -> Result<(), Self::Error> { self.reach()?; self.0.insert(k, v); Ok(()) }
+            fn remove(&mut self, k: i32) -> Result<(), Self::Error> { self.reach()?; self.0.remove(&k); Ok(()) }
+            fn commit(&mut self) -> Result<(), Self::Error> { self.reach() }
+        }
+
+        let (down, calls) = (Arc::new(AtomicBool::new(false)), Arc::new(AtomicUsize::new(0)));
+        let remote = Remote((0..4).map(|i| (i, i.to_string())).collect(), down.clone(), calls.clone());
+        let clock = MockClock::new();
+        let breaker = CircuitBreaker::new(remote, 2, Duration::from_secs(30)).with_clock(clock.clone())
+            .counting(|e| *e != "missing");
+        let cache = CacheMut::new(breaker, 4).with_ttl(Duration::from_secs(60)).with_clock(clock.clone());
+        assert_eq!(*cache.get(&0).unwrap(), "0");
+        assert_eq!(cache.get(&9).err(), Some(BreakerError::Backend("missing")));
+        assert_eq!(cache.get(&9).err(), Some(BreakerError::Backend("missing")));
+
+        down.store(true, Ordering::Relaxed);
+        assert_eq!(cache.get(&1).err(), Some(BreakerError::Backend("unreachable")));
+        assert_eq!(cache.get(&2).err(), Some(BreakerError::Backend("unreachable")));
+        let before = calls.load(Ordering::Relaxed);
+        assert_eq!(cache.get(&3).err(), Some(BreakerError::Open));
+        assert!(!cache.contains(&3));
+        clock.advance(Duration::from_secs(60));
+        let stale = cache.get(&0).unwrap();
+        assert!(stale.is_stale());
+        assert_eq!(*stale, "0");
+        drop(stale);
+        assert_eq!(calls.load(Ordering::Relaxed), before);
+        assert_eq!(cache.get(&3).err(), Some(BreakerError::Open));
+        assert_eq!(calls.load(Ordering::Relaxed), before+1, "only the health probe after the cooldown");
+
+        down.store(false, Ordering::Relaxed);
+        assert_eq!(cache.get(&3).err(), Some(BreakerError::Open));
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(*cache.get(&3).unwrap(), "3");
+    }
+
+    #[test]
+    fn hedged_reads() {
+        use std::time::Duration;
+        use crate::{CacheCompatible, CacheMutCompatible, Retrieval};
+        use crate::hedged::Hedged;
+
+        /// Answers after `delay`, as a store across a network would.
+        struct Replica(HashMap<i32, String>, Duration);
+        impl CacheCompatible<i32, String> for Replica {
+            type Error = &'static str;
+            const RETRIEVAL: Retrieval = Retrieval::Copy;
+            fn contains(&self, k: i32) -> bool { self.0.contains_key(&k) }
+            fn get(&mut self, k: i32) -> Result<String, Self::Error> {
+                std::thread::sleep(self.1);
+                self.0.get(&k).cloned().ok_or("missing")
+            }
+            fn replace(&mut self, _: i32, _: String) -> Result<(), Self::Error> { Ok(()) }
+        }
+        impl CacheMutCompatible<i32, String> for Replica {
+            fn insert(&mut self, k: i32, v: String) -> Result<(), Self::Error> { self.0.insert(k, v); Ok(()) }
+            fn remove(&mut self, k: i32) -> Result<(), Self::Error> { self.0.remove(&k); Ok(()) }
+            fn commit(&mut self) -> Result<(), Self::Error> { Ok(()) }
+        }
+
+        let values: HashMap<i32, String> = (0..4).map(|i| (i, i.to_string())).collect();
+        let (primary, fallback) = (Replica(values.clone(), Duration::ZERO), Replica(values.clone(), Duration::ZERO));
+        let mut quick = Hedged::new(primary, fallback, Duration::from_secs(5));
+        assert_eq!(quick.get(0), Ok("0".to_string()));
+        assert_eq!(quick.get(9), Err("missing"));
+        assert_eq!((quick.reads(), quick.hedged()), (2, 0));
+
+        let slow = Replica(values.clone(), Duration::from_millis(300));
+        let mut hedged = Hedged::new(slow, Replica(values, Duration::ZERO), Duration::from_millis(20));
+        assert_eq!(hedged.get(1), Ok("1".to_string()));
+        assert_eq!(hedged.get(9), Err("missing"), "the primary's answer once the fallback fails");
+        assert_eq!((hedged.hedged(), hedged.fallback_won()), (2, 1));
+        hedged.insert(5, "5".to_string()).unwrap();
+        assert!(hedged.contains(5));
+
+        let cache = CacheMut::new(hedged, 4);
+        assert_eq!(*cache.get(&2).unwrap(), "2");
+    }
+
+    #[test]
+    fn modify_with_skips_unchanged_write_backs() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use crate::{CacheCompatible, CacheMutCompatible, Retrieval};
+
+        /// Counts the values written to it.
+        struct Counted(HashMap<i32, Vec<i32>>, Arc<AtomicUsize>);
+        impl CacheCompatible<i32, Vec<i32>> for Counted {
+            type Error = &'static str;
+            const RETRIEVAL: Retrieval = Retrieval::Copy;
+            fn contains(&self, k: i32) -> bool { self.0.contains_key(&k) }
+            fn get(&mut self, k: i32) -> Result<Vec<i32>, Self::Error> { self.0.get(&k).cloned().ok_or("missing") }
+            fn replace(&mut self, _: i32, _: Vec<i32>) -> Result<(), Self::Error> { Ok(()) }
+        }
+        impl CacheMutCompatible<i32, Vec<i32>> for Counted {
+            fn insert(&mut self, k: i32, v: Vec<i32>) -> Result<(), Self::Error> {
+                self.1.fetch_add(1, Ordering::Relaxed);
+                self.0.insert(k, v);
+                Ok(())
+            }
+            fn remove(&mut self, k: i32) -> Result<(), Self::Error> { self.0.remove(&k); Ok(()) }
+            fn commit(&mut self) -> Result<(), Self::Error> { Ok(()) }
+        }
+
+        let writes = Arc::new(AtomicUsize::new(0));
+        let mut cache = CacheMut::new(Counted(HashMap::from([(0, vec![1, 2])]), writes.clone()), 4);
+        let add_if_missing = |v: &mut Vec<i32>, x| !v.contains(&x) && { v.push(x); true };
+        assert!(!cache.get_mut(&0).unwrap().modify_with(|v| add_if_missing(v, 2)));
+        cache.flush().unwrap();
+        assert_eq!(writes.load(Ordering::Relaxed), 0);
+
+        let mut guard = cache.get_mut(&0).unwrap();
+        assert!(guard.modify_with(|v| add_if_missing(v, 3)));
+        assert!(!guard.modify_with(|v| add_if_missing(v, 3)));
+        drop(guard);
+        assert!(!cache.get_mut(&0).unwrap().modify_with(|v| add_if_missing(v, 1)), "still modified from before");
+        cache.flush().unwrap();
+        assert_eq!(writes.load(Ordering::Relaxed), 1);
+        assert_eq!(*cache.get(&0).unwrap(), vec![1, 2, 3]);
+
+        cache.get_mut(&0).unwrap().push(4);
+        cache.flush().unwrap();
+        assert_eq!(writes.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn validator_skips_take_backends() {
+        let map: HashMap<i32, String> = HashMap::from([(0, "stale".to_string())]);
+        let mut cache = CacheMut::new(map, 4).with_validator(|_, v: &String| !v.starts_with("stale"));
+        assert_eq!(*cache.get(&0).unwrap(), "stale");
+        // The map handed its value over, so rejecting the cached copy would lose it.
+        assert_eq!(*cache.get(&0).unwrap(), "stale");
+        cache.insert(1, "stale too".to_string()).unwrap();
+        assert_eq!(*cache.get(&1).unwrap(), "stale too");
+    }
+
+    #[test]
+    fn failed_commits_keep_unwritten_values() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use crate::{CacheCompatible, CacheMutCompatible, Retrieval};
+
+        /// Refuses every write while `down` is set.
+        struct Flaky {map: HashMap<i32, String>, down: Arc<AtomicBool>}
+        impl Flaky {
+            fn check(&self) -> Result<(), &'static str> {
+                if self.down.load(Ordering::SeqCst) {Err("backend down")} else {Ok(())}
+            }
+        }
+        impl CacheCompatible<i32, String> for Flaky {
+            type Error = &'static str;
+            const RETRIEVAL: Retrieval = Retrieval::Copy;
+            fn contains(&self, k: i32) -> bool { self.map.contains_key(&k) }
+            fn get(&mut self, k: i32) -> Result<String, Self::Error> { self.map.get(&k).cloned().ok_or("missing") }
+            fn replace(&mut self, _: i32, _: String) -> Result<(), Self::Error> { Ok(()) }
+        }
+        impl CacheMutCompatible<i32, String> for Flaky {
+            fn insert(&mut self, k: i32, v: String) -> Result<(), Self::Error> { self.insert_shared(k, &v).map(|_| ()) }
+            fn insert_shared(&mut self, k: i32, v: &String) -> Result<bool, Self::Error> {
+                self.check()?;
+                self.map.insert(k, v.clone());
+                Ok(true)
+            }
+            fn remove(&mut self, k: i32) -> Result<(), Self::Error> {
+                self.check()?;
+                self.map.remove(&k);
+                Ok(())
+            }
+            fn commit(&mut self) -> Result<(), Self::Error> { self.check() }
+        }
+
+        let down = Arc::new(AtomicBool::new(false));
+        let map = HashMap::from([(0, "zero".to_string()), (1, "one".to_string())]);
+        let mut cache = CacheMut::new(Flaky {map, down: down.clone()}, 4);
+        cache.get_mut(&0).unwrap().push_str(" changed");
+        cache.insert(2, "two".to_string()).unwrap();
+        down.store(true, Ordering::SeqCst);
+        assert_eq!(cache.commit(), Err("backend down"));
+        // Nothing reached the backend, and nothing was lost: the values are still read from memory.
+        assert_eq!(cache.maintain_backend(|flaky| flaky.map.get(&0).cloned()), Some("zero".to_string()));
+        assert_eq!(*cache.get(&0).unwrap(), "zero changed");
+        assert_eq!(*cache.get(&2).unwrap(), "two");
+        assert_eq!(cache.commit(), Err("backend down"));
+
+        down.store(false, Ordering::SeqCst);
+        cache.commit().unwrap();
+        assert_eq!(cache.stats().unwritten, 0);
+        let stored = cache.maintain_backend(|flaky| flaky.map.clone());
+        assert_eq!(stored, HashMap::from([(0, "zero changed".to_string()), (1, "one".to_string()), (2, "two".to_string())]));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn failed_parallel_commits_keep_unwritten_values() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use crate::{CacheCompatible, CacheMutCompatible, Retrieval, Serialized};
+
+        /// Stores values serialized, and refuses every write while `down` is set.
+        struct Flaky {map: HashMap<i32, Vec<u8>>, down: Arc<AtomicBool>}
+        impl Flaky {
+            fn check(&self) -> Result<(), &'static str> {
+                if self.down.load(Ordering::SeqCst) {Err("backend down")} else {Ok(())}
+            }
+        }
+        impl CacheCompatible<i32, String> for Flaky {
+            type Error = &'static str;
+            const RETRIEVAL: Retrieval = Retrieval::Copy;
+            fn contains(&self, k: i32) -> bool { self.map.contains_key(&k) }
+            fn get(&mut self, k: i32) -> Result<String, Self::Error> {
+                self.map.get(&k).map(|vser| String::from_utf8(vser.clone()).unwrap()).ok_or("missing")
+            }
+            fn replace(&mut self, _: i32, _: String) -> Result<(), Self::Error> { Ok(()) }
+        }
+        impl CacheMutCompatible<i32, String> for Flaky {
+            const SERIALIZED: Option<Serialized<i32, String, Self>> = Some(Serialized {
+                serialize: |v| Ok(v.clone().into_bytes()),
+                insert: |flaky, k, vser| {
+                    flaky.check()?;
+                    flaky.map.insert(k, vser);
+                    Ok(())
+                },
+            });
+            fn insert(&mut self, k: i32, v: String) -> Result<(), Self::Error> {
+                self.check()?;
+                self.map.insert(k, v.into_bytes());
+                Ok(())
+            }
+            fn remove(&mut self, k: i32) -> Result<(), Self::Error> {
+                self.check()?;
+                self.map.remove(&k);
+                Ok(())
+            }
+            fn commit(&mut self) -> Result<(), Self::Error> { self.check() }
+        }
+
+        let down = Arc::new(AtomicBool::new(false));
+        let mut cache = CacheMut::new(Flaky {map: HashMap::new(), down: down.clone()}, 8);
+        for i in 0..4 {
+            cache.insert(i, i.to_string()).unwrap();
+        }
+        down.store(true, Ordering::SeqCst);
+        assert_eq!(cache.commit_parallel(), Err("backend down"));
+        // Every value taken out for writing is still served from memory, not just the one that failed.
+        assert_eq!(cache.stats().unwritten, 4);
+        for i in 0..4 {
+            assert_eq!(*cache.get(&i).unwrap(), i.to_string());
+        }
+        assert_eq!(cache.commit_parallel(), Err("backend down"));
+        assert_eq!(cache.commit_parallel(), Err("backend down"));
+        assert_eq!(cache.stats().unwritten, 4);
+
+        down.store(false, Ordering::SeqCst);
+        cache.commit_parallel().unwrap();
+        assert_eq!(cache.stats().unwritten, 0);
+        assert_eq!(cache.maintain_backend(|flaky| flaky.map.len()), 4);
+    }
+
+    #[test]
+    fn removing_a_range_clears_poison() {
+        use std::collections::BTreeMap;
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let map: BTreeMap<u32, Vec<u32>> = (0..4).map(|i| (i, vec![i])).collect();
+        let mut cache = CacheMut::new(map, 4);
+        let _ = catch_unwind(AssertUnwindSafe(|| {
+            let _v = cache.get_mut(&1).unwrap();
+            panic!();
+        }));
+        assert!(cache.is_poisoned(&1));
+        cache.remove_range(1..3).unwrap();
+        assert!(!cache.is_poisoned(&1));
+        // Written back by something other than this cache, the key reads as usual.
+        cache.maintain_backend(|map| map.insert(1, vec![5]));
+        assert_eq!(*cache.get(&1).unwrap(), [5]);
+    }
+
+    #[test]
+    fn bulk_removals_forget_priorities() {
+        use std::collections::BTreeMap;
+        use crate::Priority;
+
+        let map: BTreeMap<u32, String> = (1..10).map(|i| (i, i.to_string())).collect();
+        let mut cache = CacheMut::new(map, 2);
+        for removal in 0..3 {
+            cache.insert_with_priority(0, "kept".to_string(), Priority::High).unwrap();
+            match removal {
+                0 => cache.remove_many(&[0]).unwrap(),
+                1 => cache.remove_range(0..1).unwrap(),
+                _ => cache.clear_where(|k| *k == 0).unwrap(),
+            }
+            // Inserted again, the entry has the normal priority, so it is the first to go as the least recently used.
+            cache.insert(0, "zero".to_string()).unwrap();
+            cache.get(&1).unwrap();
+            cache.get(&2).unwrap();
+            assert!(!cache.contains_cached(&0) && cache.contains_cached(&1));
+            cache.commit().unwrap();
+        }
+    }
+
+
+    #[test]
+    fn bulk_removals_invalidate_dependents() {
+        use std::collections::BTreeMap;
+
+        let map: BTreeMap<u32, String> = (0..8).map(|i| (i, i.to_string())).collect();
+        let mut cache = CacheMut::new(map, 8);
+        // 5 comes from 1, which is removed by range, and 6 from 3, which is removed by predicate without being cached.
+        cache.add_dependency(5, 1);
+        cache.add_dependency(6, 3);
+        cache.get(&1).unwrap();
+        cache.get(&5).unwrap();
+        cache.remove_range(0..2).unwrap();
+        assert!(!cache.contains(&5) && cache.contains(&2));
+        cache.clear_where(|k| *k == 3).unwrap();
+        assert!(!cache.contains(&6) && cache.contains(&4));
+    }
+
+
+    #[test]
+    fn failed_rebalancing_keeps_taken_values() {
+        use crate::{CacheCompatible, CacheMutCompatible, Retrieval};
+        use crate::sharded::{ListKeys, ShardError, ShardedBackend};
+
+        /// Takes values out when read, and refuses every write if `refuses` is set.
+        struct Taken {map: HashMap<u32, String>, refuses: bool}
+        impl CacheCompatible<u32, String> for Taken {
+            type Error = &'static str;
+            const RETRIEVAL: Retrieval = Retrieval::Take;
+            fn contains(&self, k: u32) -> bool { self.map.contains_key(&k) }
+            fn get(&mut self, k: u32) -> Result<String, Self::Error> { self.map.remove(&k).ok_or("missing") }
+            fn replace(&mut self, k: u32, v: String) -> Result<(), Self::Error> { self.map.insert(k, v); Ok(()) }
+        }
+        impl CacheMutCompatible<u32, String> for Taken {
+            fn insert(&mut self, k: u32, v: String) -> Result<(), Self::Error> {
+                if self.refuses {
+                    return Err("write refused");
+                }
+                self.map.insert(k, v);
+                Ok(())
+            }
+            fn remove(&mut self, k: u32) -> Result<(), Self::Error> { self.map.remove(&k); Ok(()) }
+            fn commit(&mut self) -> Result<(), Self::Error> { Ok(()) }
+        }
+        impl ListKeys<u32, String> for Taken {
+            fn keys(&self) -> Vec<u32> { self.map.keys().copied().collect() }
+        }
+
+        let map = (0..20).map(|i| (i, i.to_string())).collect();
+        let mut shards = ShardedBackend::new().with_shard("a", Taken {map, refuses: false});
+        assert_eq!(shards.add_shard("b", Taken {map: HashMap::new(), refuses: true}), Err(ShardError::Backend("write refused")));
+        assert_eq!(shards.shard("a").unwrap().map.len(), 20);
+    }
+}
+
+#[cfg(all(test, feature = "folder"))]
+mod folder_tests {
+    use crate::{CacheMut, ShutdownError};
+    use crate::folder_compatible::{FolderCache, Problem, FILE_HEADER_BYTES, SLOT_HEADER_BYTES};
+    use crate::paged_compatible::PagedCache;
+    use tempdir::TempDir;
+
+    /// The default size class of a record whose value and key take `len` bytes.
+    fn class_of(len: u64) -> u64 {
+        1 << (u64::BITS-(SLOT_HEADER_BYTES+len).leading_zeros()+1)
+    }
+
+    #[test]
+    fn insert() {
+        let tempdir = TempDir::new("test").unwrap();
+        let folder = FolderCache::continued(tempdir.into_path()).unwrap();
+        let mut cache = CacheMut::new(folder, 2);
+        for i in 0..10 {
+            cache.insert(i, i.to_string()).unwrap();
+        }
+        assert_eq!(*cache.get(&1).unwrap(), "1");
+        assert_eq!(*cache.get(&2).unwrap(), "2");
+        assert_eq!(*cache.get(&3).unwrap(), "3");
+        assert_eq!(*cache.get(&4).unwrap(), "4");
+        assert_eq!(*cache.get(&9).unwrap(), "9");
+        assert_eq!(*cache.get(&1).unwrap(), "1");
+    }
+
+    #[test]
+    fn persist_across_drop() {
+        // Create a temporary folder for persistence
+        let tempdir = TempDir::new("test_persist").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+
+        {
+            // Create a cache and insert a few values
+            let folder = FolderCache::continued(folder_path.clone()).unwrap();
+            let mut cache = CacheMut::new(folder, 2);
+            cache.insert(42, "meaning".to_string()).unwrap();
+            cache.insert(99, "bottles".to_string()).unwrap();
+
+            // Ensure value is get()table before drop
+            assert_eq!(*cache.get(&42).unwrap(), "meaning");
+        } // <- cache and folder dropped here
+
+        // Continue the cache from the same folder path
+        {
+            let folder = FolderCache::continued(folder_path.clone()).unwrap();
+            let cache: CacheMut<i32, String, FolderCache<i32>> = CacheMut::new(folder, 2);
+
+            // Ensure persisted value is still available
+            assert_eq!(*cache.get(&42).unwrap(), "meaning");
+            assert_eq!(*cache.get(&99).unwrap(), "bottles");
+        }
+    }
+
+
+    #[test]
+    fn cleared_removes_old_data() {
+        // Create a temporary directory and persist some data
+        let tempdir = TempDir::new("test_cleared").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+
+        {
+            let folder = FolderCache::continued(folder_path.clone()).unwrap();
+            let mut cache = CacheMut::new(folder, 2);
+            cache.insert(1, "one".to_string()).unwrap();
+            cache.insert(2, "two".to_string()).unwrap();
+            assert_eq!(*cache.get(&1).unwrap(), "one");
+        }
+
+        // Check that files exist before clearing
+        let entries_before: Vec<_> = std::fs::read_dir(&folder_path)
+            .unwrap()
+            .collect();
+        assert!(!entries_before.is_empty(), "Folder should not be empty before clearing");
+
+        // Recreate using cleared() — should delete old data
+        {
+            let folder = FolderCache::cleared(folder_path.clone()).unwrap();
+
+            // Folder should now be empty
+            let entries_after: Vec<_> = std::fs::read_dir(&folder_path)
+                .unwrap()
+                .collect();
+            assert!(entries_after.is_empty(), "Folder should be empty after FolderCache::cleared");
+
+            let cache: CacheMut<i32, String, FolderCache<i32>> = CacheMut::new(folder, 2);
+
+            // Old values should not exist
+            assert!(cache.get(&1).is_err(), "Old value 1 should not persist after clear");
+            assert!(cache.get(&2).is_err(), "Old value 2 should not persist after clear");
+        }
+    }
+
+    #[test]
+    fn mutation_persists_after_eviction() {
+        // Create persistent folder
+        let tempdir = TempDir::new("test_mut_persist").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+
+        // Create a cache with small capacity so eviction happens quickly
+        let folder = FolderCache::continued(folder_path.clone()).unwrap();
+        let mut cache = CacheMut::new(folder, 2);
+
+        // Insert several items
+        for i in 0..5 {
+            cache.insert(i, format!("value_{i}")).unwrap();
+        }
+
+        // Mutate a value via get_mut
+        {
+            let mut v = cache.get_mut(&1).unwrap();
+            *v = "modified".to_string();
+        }
+
+        // Cause eviction: hold more than capacity (2) active entries
+        let _a = cache.get(&2).unwrap();
+        let _b = cache.get(&3).unwrap();
+        let _c = cache.get(&4).unwrap();
+        // The mutated value (key 1) should be evicted here
+
+        // Drop active references to allow reloading
+        drop(_a);
+        drop(_b);
+        drop(_c);
+
+        // Fetch the value again (forcing reload from folder)
+        let v = cache.get(&1).unwrap();
+
+        // The mutation should have persisted through eviction
+        assert_eq!(*v, "modified");
+    }
+
+    #[test]
+    fn commit_persists_non_evicted_mutations() {
+        // Create a temporary persistent folder
+        let tempdir = TempDir::new("test_commit_persist").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+
+        // Create a cache with capacity large enough to hold all entries
+        let folder = FolderCache::continued(folder_path.clone()).unwrap();
+        let mut cache = CacheMut::new(folder, 10);
+
+        // Insert a few values
+        for i in 0..5 {
+            cache.insert(i, format!("value_{i}")).unwrap();
+        }
+
+        // Mutate several values through get_mut()
+        {
+            *cache.get_mut(&0).unwrap() = "zero".to_string();
+            *cache.get_mut(&1).unwrap() = "one".to_string();
+            *cache.get_mut(&2).unwrap() = "two".to_string();
+        }
+
+        // No eviction occurs because capacity is large enough
+        // Force all in-memory changes to persist
+        cache.commit().unwrap();
+
+        // Drop the cache entirely to ensure reload from disk
+        drop(cache);
+
+        // Reload from the same folder and verify persistence
+        let folder = FolderCache::continued(folder_path.clone()).unwrap();
+        let cache: CacheMut<i32, String, FolderCache<i32>> = CacheMut::new(folder, 10);
+
+        assert_eq!(*cache.get(&0).unwrap(), "zero");
+        assert_eq!(*cache.get(&1).unwrap(), "one");
+        assert_eq!(*cache.get(&2).unwrap(), "two");
+        assert_eq!(*cache.get(&3).unwrap(), "value_3");
+        assert_eq!(*cache.get(&4).unwrap(), "value_4");
+    }
+
+    #[test]
+    fn many_insert() {
+        let tempdir = TempDir::new("test").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+        let folder = FolderCache::continued(folder_path.clone()).unwrap();
+        let mut cache = CacheMut::new(folder, 2);
+        for i in 0..200 {
+            cache.insert(i, i.to_string()).unwrap();
+        }
+        drop(cache);
+        let folder = FolderCache::continued(folder_path.clone()).unwrap();
+        let cache: CacheMut<i32, String, FolderCache<i32>> = CacheMut::new(folder, 2);
+        for i in 0..200 {
+            assert_eq!(*cache.get(&i).unwrap(), i.to_string());
+        }
+    }
+
+    #[test]
+    fn variable_size_insert() {
+        let tempdir = TempDir::new("test_many_insert").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+
+        // Create a folder-backed cache with small capacity to trigger evictions
+        let folder = FolderCache::continued(folder_path.clone()).unwrap();
+        let mut cache = CacheMut::new(folder, 2);
+
+        // Insert 200 entries, each containing "memphis" repeated i times
+        for i in 0..200 {
+            let value = "memphis".repeat(i);
+            cache.insert(i, value).unwrap();
+        }
+
+        // Drop cache to force write-back to folder
+        drop(cache);
+
+        // Recreate from the same folder and verify persistence
+        let folder = FolderCache::continued(folder_path.clone()).unwrap();
+        let cache: CacheMut<usize, String, FolderCache<usize>> = CacheMut::new(folder, 2);
+
+        for i in 0..200 {
+            let expected = "memphis".repeat(i);
+            assert_eq!(*cache.get(&i).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn mutate_short_to_long_persists() {
+        // Create a temporary folder for persistence
+        let tempdir = TempDir::new("test_mutate_short_to_long").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+
+        // Initialize the cache
+        let folder = FolderCache::continued(folder_path.clone()).unwrap();
+        let mut cache = CacheMut::new(folder, 2);
+
+        // Insert a short string
+        cache.insert(1, "hi".to_string()).unwrap();
+        assert_eq!(*cache.get(&1).unwrap(), "hi");
+
+        // Mutate it to a long string
+        {
+            let mut v = cache.get_mut(&1).unwrap();
+            *v = "memphis".repeat(500); // very long string
+        }
+
+        // Drop to force persistence to disk
+        drop(cache);
+
+        // Reload the cache from the same folder
+        let folder = FolderCache::continued(folder_path.clone()).unwrap();
+        let cache: CacheMut<i32, String, FolderCache<i32>> = CacheMut::new(folder, 2);
+
+        // Ensure the long string persisted correctly
+        let value = cache.get(&1).unwrap();
+        assert_eq!(value.len(), "memphis".len() * 500);
+        assert!(value.starts_with("memphis"));
+        assert!(value.ends_with("memphis"));
+    }
+
+    #[test]
+    fn file_reuse_and_size_stability() {
+        // Create a persistent folder
+        let tempdir = TempDir::new("test_file_reuse").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+
+        // Create a cache and insert 0..7
+        let folder = FolderCache::continued(folder_path.clone()).unwrap();
+        let mut cache = CacheMut::new(folder, 4);
+
+        for i in 0..7 {
+            cache.insert(i, i.to_string()).unwrap();
+        }
+
+        // Drop cache to flush all data
+        drop(cache);
+
+        // Ensure there is only one .cache file
+        let mut cache_files: Vec<_> = std::fs::read_dir(&folder_path)
+            .unwrap()
+            .filter_map(|e| {
+                let entry = e.unwrap();
+                let path = entry.path();
+                if path.extension().is_some_and(|ext| ext == "cache") {
+                    Some(path)
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        assert_eq!(
+            cache_files.len(),
+            1,
+            "Expected exactly one .cache file in folder"
+        );
+
+        let cache_file = cache_files.pop().unwrap();
+        let initial_size = std::fs::metadata(&cache_file).unwrap().len();
+
+        // Reopen cache and remove all inserted values
+        {
+            let folder = FolderCache::continued(folder_path.clone()).unwrap();
+            let mut cache: CacheMut<i32, String, FolderCache<i32>> = CacheMut::new(folder, 4);
+
+            for i in 0..7 {
+                cache.remove(&i).unwrap();
+            }
+        }
+
+        // Drop and restore — ensure all values are gone
+        {
+            let folder = FolderCache::continued(folder_path.clone()).unwrap();
+            let cache: CacheMut<i32, String, FolderCache<i32>> = CacheMut::new(folder, 4);
+
+            for i in 0..7 {
+                assert!(cache.get(&i).is_err(), "Expected key {i} to be absent");
+            }
+        }
+
+        // Insert 0..7 again, but values are reversed (7 - i).to_string()
+        {
+            let folder = FolderCache::continued(folder_path.clone()).unwrap();
+            let mut cache = CacheMut::new(folder, 4);
+
+            for i in 0..7 {
+                cache.insert(i, (7 - i).to_string()).unwrap();
+            }
+
+            drop(cache);
+        }
+        
+        let v: Vec<_> = std::fs::read_dir(&folder_path)
+            .unwrap()
+            .filter_map(|e| {
+                let entry = e.unwrap();
+                let path = entry.path();
+                if path.extension().is_some_and(|ext| ext == "cache") {
+                    Some(path)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        assert_eq!(v.len(), 1);
+
+        // Check .cache file size again — should not have grown
+        let final_size = std::fs::metadata(&cache_file).unwrap().len();
+
+        assert_eq!(
+            initial_size, final_size,
+            ".cache file size should remain the same after reinsertion"
+        );
+    }
+
+    #[test]
+    fn compact_reclaims_space() {
+        let tempdir = TempDir::new("test_compact").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+        let folder_size = |path: &std::path::Path| -> u64 {
+            // Bucket files only: the manifest also shrinks as compaction drops files.
+            std::fs::read_dir(path).unwrap().map(|e| e.unwrap()).filter(|e| e.file_name() != "manifest")
+                .map(|e| e.metadata().unwrap().len()).sum()
+        };
+
+        let mut folder = FolderCache::continued(folder_path.clone()).unwrap();
+        for i in 0..200usize {
+            folder.insert(i, &"memphis".repeat(i % 20)).unwrap();
+        }
+        for i in 0..190usize {
+            folder.remove(&i).unwrap();
+        }
+        let before = folder_size(&folder_path);
+        let freed = folder.compact().unwrap();
+        let after = folder_size(&folder_path);
+        assert!(freed > 0);
+        assert_eq!(before - after, freed);
+
+        for i in 190..200usize {
+            assert_eq!(folder.get::<String>(&i).unwrap(), "memphis".repeat(i % 20));
+        }
+        drop(folder);
+
+        // Compacted files must still be readable after reopening.
+        let folder = FolderCache::continued(folder_path.clone()).unwrap();
+        let cache: CacheMut<usize, String, FolderCache<usize>> = CacheMut::new(folder, 2);
+        for i in 190..200usize {
+            assert_eq!(*cache.get(&i).unwrap(), "memphis".repeat(i % 20));
+        }
+        assert!(cache.get(&0).is_err());
+    }
+
+    #[test]
+    fn insert_with_hint_groups_and_reads_back() {
+        let tempdir = TempDir::new("test_hint").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+
+        let mut folder = FolderCache::continued(folder_path.clone()).unwrap();
+        // Interleave two groups so every hinted insert has to displace a record of the other group.
+        for i in 0..20u32 {
+            folder.insert_with_hint(i, &format!("value_{i}"), (i % 2) as u64).unwrap();
+        }
+        folder.insert(100, &"plain".to_string()).unwrap();
+        folder.insert_with_hint(4, &"four".to_string(), 0).unwrap();
+
+        let keys: Vec<u32> = (0..20).step_by(2).chain([100, 999]).collect();
+        let values: Vec<Option<String>> = folder.get_many(&keys).unwrap();
+        assert_eq!(values[2].as_deref(), Some("four"));
+        assert_eq!(values[10].as_deref(), Some("plain"));
+        assert_eq!(values[11], None);
+        for (k, v) in keys.iter().zip(&values).take(10) {
+            if *k != 4 {
+                assert_eq!(v.as_deref(), Some(format!("value_{k}").as_str()));
+            }
+        }
+        drop(folder);
+
+        let folder = FolderCache::continued(folder_path).unwrap();
+        for i in (1..20u32).step_by(2) {
+            assert_eq!(folder.get::<String>(&i).unwrap(), format!("value_{i}"));
+        }
+    }
+
+    #[test]
+    fn paged_aligned_pages() {
+        let tempdir = TempDir::new("test_paged").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+
+        {
+            let pages = PagedCache::<[f32; 3]>::continued(folder_path.clone(), 16).unwrap();
+            assert_eq!(pages.stride(), 16);
+            let mut cache = CacheMut::new(pages, 2);
+            for i in 0..10usize {
+                cache.insert(i*3, [i as f32, 0.5, -1.0]).unwrap();
+            }
+            cache.remove(&6).unwrap();
+        }
+
+        let data_len = std::fs::metadata(folder_path.join("data.pages")).unwrap().len();
+        assert_eq!(data_len, 27*16 + 12);
+
+        let pages = PagedCache::<[f32; 3]>::continued(folder_path.clone(), 16).unwrap();
+        let cache = CacheMut::new(pages, 2);
+        assert_eq!(*cache.get(&9).unwrap(), [3.0, 0.5, -1.0]);
+        assert!(cache.get(&6).is_err());
+        assert!(cache.get(&1).is_err());
+        assert!(PagedCache::<[f32; 3]>::continued(folder_path, 64).is_err());
+    }
+
+    #[test]
+    fn torn_journal_is_discarded() {
+        let tempdir = TempDir::new("test_journal").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+
+        {
+            let folder = FolderCache::continued(folder_path.clone()).unwrap();
+            let mut cache = CacheMut::new(folder, 2);
+            for i in 0..6 {
+                cache.insert(i, format!("value_{i}")).unwrap();
+            }
+            // Same size class, so this overwrites the record in place through the journal.
+            cache.insert(2, "value_x".to_string()).unwrap();
+            cache.remove(&0).unwrap();
+        }
+        let journal = folder_path.join("journal.wal");
+        assert_eq!(std::fs::metadata(&journal).unwrap().len(), 0, "journal should be empty once writes are applied");
+
+        // A batch that never finished being logged must not be applied.
+        std::fs::write(&journal, [7u8; 45]).unwrap();
+        let folder = FolderCache::continued(folder_path.clone()).unwrap();
+        assert!(!journal.exists());
+        let cache: CacheMut<i32, String, FolderCache<i32>> = CacheMut::new(folder, 2);
+        assert_eq!(*cache.get(&2).unwrap(), "value_x");
+        assert!(cache.get(&0).is_err());
+        for i in [1, 3, 4, 5] {
+            assert_eq!(*cache.get(&i).unwrap(), format!("value_{i}"));
+        }
+    }
+
+    #[test]
+    fn durability_modes_persist() {
+        use crate::folder_compatible::Durability;
+
+        for durability in [Durability::Never, Durability::OnCommit, Durability::EveryWrite] {
+            let tempdir = TempDir::new("test_durability").unwrap();
+            let folder_path = tempdir.path().to_path_buf();
+            {
+                let folder = FolderCache::continued(folder_path.clone()).unwrap().with_durability(durability);
+                let mut cache = CacheMut::new(folder, 2);
+                for i in 0..10 {
+                    cache.insert(i, "memphis".repeat(i as usize)).unwrap();
+                }
+                cache.insert(3, "short".to_string()).unwrap();
+                cache.remove(&4).unwrap();
+                cache.commit().unwrap();
+            }
+            let folder = FolderCache::continued(folder_path).unwrap();
+            let cache: CacheMut<i32, String, FolderCache<i32>> = CacheMut::new(folder, 2);
+            assert_eq!(*cache.get(&3).unwrap(), "short");
+            assert_eq!(*cache.get(&9).unwrap(), "memphis".repeat(9));
+            assert!(cache.get(&4).is_err());
+        }
+    }
+
+    #[test]
+    fn maintenance_policy_flushes_and_compacts() {
+        use crate::maintenance::{Maintenance, MaintenancePolicy, PolicyError};
+
+        assert_eq!("flush_interval = 5".parse::<MaintenancePolicy>(), Err(PolicyError::BadValue(1, "flush_interval".to_string())));
+        assert_eq!("\nttl = 5s".parse::<MaintenancePolicy>(), Err(PolicyError::UnknownKey(2, "ttl".to_string())));
+        let policy: MaintenancePolicy = "
+            # write back every tick
+            flush_interval = 0ms
+            compact_above_waste = 0.25
+            tick = 10ms
+        ".parse().unwrap();
+        assert_eq!(policy.tick, std::time::Duration::from_millis(10));
+
+        let tempdir = TempDir::new("test_maintenance").unwrap();
+        let folder = FolderCache::continued(tempdir.path().to_path_buf()).unwrap();
+        let mut cache = CacheMut::new(folder, 8);
+        for i in 0..64 {
+            cache.insert(i, i.to_string()).unwrap();
+        }
+        for i in 0..60 {
+            cache.remove(&i).unwrap();
+        }
+        *cache.get_mut(&61).unwrap() = "changed".to_string();
+        let held = cache.get(&62).unwrap();
+
+        let report = Maintenance::new(policy).run_due(&mut cache).unwrap();
+        assert!(report.flushed);
+        assert!(report.compacted_bytes.unwrap() > 0);
+        assert_eq!(*held, "62");
+        drop(held);
+
+        // The flushed value reached the backend while the cache stayed open.
+        assert_eq!(cache.maintain_backend(|cc| cc.get::<String>(&61)).unwrap(), "changed");
+    }
+
+    #[test]
+    fn legacy_files_are_migrated() {
+        let tempdir = TempDir::new("test_migrate").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+
+        // Hand-write a bucket file in the original layout: an 8-byte item count with no magic, followed by
+        // 32-byte slots of (key size, value size, value, key). The third record fills its slot, so it no longer fits
+        // once entry headers carry a timestamp.
+        let mut legacy = 3u64.to_le_bytes().to_vec();
+        for (k, v) in [(1u32, "one"), (2, "two"), (4, "fourteen chars")] {
+            let kser = rmp_serde::to_vec(&k).unwrap();
+            let vser = rmp_serde::to_vec(v).unwrap();
+            let mut slot = Vec::new();
+            slot.extend_from_slice(&(kser.len() as u64).to_le_bytes());
+            slot.extend_from_slice(&(vser.len() as u64).to_le_bytes());
+            slot.extend_from_slice(&vser);
+            slot.extend_from_slice(&kser);
+            slot.resize(32, 0);
+            legacy.extend_from_slice(&slot);
+        }
+        let path = folder_path.join("32.cache");
+        std::fs::write(&path, &legacy).unwrap();
+
+        let mut folder = FolderCache::continued(folder_path.clone()).unwrap();
+        assert_eq!(folder.get::<String>(&1).unwrap(), "one");
+        assert_eq!(folder.get::<String>(&2).unwrap(), "two");
+        assert_eq!(folder.get::<String>(&4).unwrap(), "fourteen chars");
+        folder.insert(3, &"three".to_string()).unwrap();
+        drop(folder);
+        assert_eq!(&std::fs::read(&path).unwrap()[0..4], b"FCch");
+
+        let folder = FolderCache::<u32>::continued(folder_path.clone()).unwrap();
+        assert_eq!(folder.get::<String>(&3).unwrap(), "three");
+
+        // Files from a newer build are refused rather than misread.
+        let mut future = std::fs::read(&path).unwrap();
+        future[4..8].copy_from_slice(&99u32.to_le_bytes());
+        std::fs::write(&path, &future).unwrap();
+        assert!(FolderCache::<u32>::continued(folder_path).is_err());
+    }
+
+    #[test]
+    fn verify_and_repair_damaged_folder() {
+        let tempdir = TempDir::new("test_verify").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+
+        {
+            let mut folder = FolderCache::continued(folder_path.clone()).unwrap();
+            for i in 0..6u32 {
+                folder.insert(i, &format!("value_{i}")).unwrap();
+            }
+        }
+        let report = FolderCache::<u32>::verify(&folder_path).unwrap();
+        assert!(report.is_ok(), "{report:?}");
+        assert_eq!(report.records, 6);
+
+        // Every record lands in the same size class. Claim an absurd value size for slot 2 and smash the header magic.
+        let class = class_of(9);
+        let path = folder_path.join(format!("{class}.cache"));
+        let mut bytes = std::fs::read(&path).unwrap();
+        let slot2 = (FILE_HEADER_BYTES + 2*class) as usize;
+        bytes[slot2 + 8..slot2 + 16].copy_from_slice(&1000u64.to_le_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+        let report = FolderCache::<u32>::verify(&folder_path).unwrap();
+        assert_eq!(report.problems, vec![(path.clone(), Problem::RecordOutOfBounds(2))]);
+
+        bytes[0] = b'X';
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(FolderCache::<u32>::continued(folder_path.clone()).is_err());
+        assert_eq!(FolderCache::<u32>::verify(&folder_path).unwrap().problems, vec![(path.clone(), Problem::BadHeader)]);
+
+        let repaired = FolderCache::<u32>::repair(&folder_path).unwrap();
+        assert_eq!(repaired.salvaged, 5);
+        assert!(FolderCache::<u32>::verify(&folder_path).unwrap().is_ok());
+
+        let folder = FolderCache::<u32>::continued(folder_path).unwrap();
+        assert!(folder.get::<String>(&2).is_err());
+        for i in [0, 1, 3, 4, 5] {
+            assert_eq!(folder.get::<String>(&i).unwrap(), format!("value_{i}"));
+        }
+    }
+
+    #[test]
+    fn second_open_is_locked_out() {
+        use crate::folder_compatible::FolderCacheError;
+
+        let tempdir = TempDir::new("test_lock").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+
+        let folder = FolderCache::<u32>::continued(folder_path.clone()).unwrap();
+        assert!(matches!(FolderCache::<u32>::continued(folder_path.clone()), Err(FolderCacheError::AlreadyLocked)));
+        assert!(matches!(FolderCache::<u32>::cleared(folder_path.clone()), Err(FolderCacheError::AlreadyLocked)));
+        assert!(matches!(FolderCache::<u32>::repair(&folder_path), Err(FolderCacheError::AlreadyLocked)));
+        drop(folder);
+
+        assert!(FolderCache::<u32>::continued(folder_path).is_ok());
+    }
+
+    #[test]
+    fn read_only_rejects_writes() {
+        use crate::folder_compatible::FolderCacheError;
+
+        let tempdir = TempDir::new("test_read_only").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+        {
+            let mut folder = FolderCache::continued(folder_path.clone()).unwrap();
+            folder.insert(1u32, &"one".to_string()).unwrap();
+        }
+        let snapshot = |path: &std::path::Path| -> Vec<(std::path::PathBuf, Vec<u8>)> {
+            let mut files: Vec<_> = std::fs::read_dir(path).unwrap()
+                .map(|e| e.unwrap().path()).map(|p| (p.clone(), std::fs::read(p).unwrap())).collect();
+            files.sort();
+            files
+        };
+        let before = snapshot(&folder_path);
+
+        let folder = FolderCache::<u32>::read_only(folder_path.clone()).unwrap();
+        let mut second = FolderCache::<u32>::read_only(folder_path.clone()).unwrap();
+        assert!(matches!(FolderCache::<u32>::continued(folder_path.clone()), Err(FolderCacheError::AlreadyLocked)));
+        assert!(matches!(second.insert(2, &"two".to_string()), Err(FolderCacheError::ReadOnly)));
+        assert!(matches!(second.remove(&1), Err(FolderCacheError::ReadOnly)));
+        assert_eq!(second.get::<String>(&1).unwrap(), "one");
+
+        let cache: CacheMut<u32, String, FolderCache<u32>> = CacheMut::new(folder, 2);
+        assert_eq!(*cache.get(&1).unwrap(), "one");
         drop(cache);
+        drop(second);
+        assert_eq!(before, snapshot(&folder_path));
+    }
+
+    #[test]
+    fn prefixed_and_sharded_layouts_share_a_folder() {
+        use crate::folder_compatible::Layout;
+
+        let tempdir = TempDir::new("test_layout").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+        let thumbs = Layout::default().with_prefix("thumbs_").with_extension("bin");
+        let blobs = Layout::default().with_prefix("blobs_").with_shards(4);
+        {
+            let mut a = FolderCache::continued_with(folder_path.clone(), thumbs.clone()).unwrap();
+            let mut b = FolderCache::continued_with(folder_path.clone(), blobs.clone()).unwrap();
+            for i in 0..32u32 {
+                a.insert(i, &format!("thumb_{i}")).unwrap();
+                b.insert(i, &"blob".repeat(i as usize)).unwrap();
+            }
+        }
+        assert!(folder_path.join(format!("thumbs_{}.bin", class_of(10))).exists());
+        for shard in ["blobs_00", "blobs_01", "blobs_02", "blobs_03"] {
+            let files = std::fs::read_dir(folder_path.join(shard)).unwrap().count();
+            assert!(files > 0, "{shard} should hold part of the entries");
+        }
+        assert_eq!(FolderCache::<u32>::verify_with(&folder_path, &blobs).unwrap().records, 32);
+
+        // Reopening with another shard count still finds everything.
+        let a = FolderCache::<u32>::continued_with(folder_path.clone(), thumbs).unwrap();
+        let b = FolderCache::<u32>::continued_with(folder_path.clone(), blobs.with_shards(2)).unwrap();
+        for i in 0..32u32 {
+            assert_eq!(a.get::<String>(&i).unwrap(), format!("thumb_{i}"));
+            assert_eq!(b.get::<String>(&i).unwrap(), "blob".repeat(i as usize));
+        }
+        assert!(FolderCache::<u32>::continued(folder_path).unwrap().get::<String>(&0).is_err());
+    }
+
+    #[test]
+    fn named_tables_share_one_folder() {
+        use crate::folder_compatible::{CacheFolder, FolderCacheError};
+
+        let tempdir = TempDir::new("test_tables").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+        {
+            let folder = CacheFolder::open(folder_path.clone()).unwrap();
+            assert!(matches!(CacheFolder::open(folder_path.clone()), Err(FolderCacheError::AlreadyLocked)));
+            assert!(matches!(FolderCache::<u32>::continued(folder_path.clone()), Err(FolderCacheError::AlreadyLocked)));
+
+            let mut thumbnails = folder.table::<u32>("thumbnails").unwrap();
+            let mut users = folder.table::<u32>("users").unwrap();
+            assert!(matches!(folder.table::<u32>("users"), Err(FolderCacheError::AlreadyLocked)));
+            thumbnails.insert(1, &"small.png".to_string()).unwrap();
+            users.insert(1, &"alice".to_string()).unwrap();
+            drop(users);
+            let users = folder.table::<u32>("users").unwrap();
+            assert_eq!(users.get::<String>(&1).unwrap(), "alice");
+            assert_eq!(folder.table_names().unwrap(), ["thumbnails", "users"]);
+        }
+
+        let folder = CacheFolder::open(folder_path).unwrap();
+        let cache: CacheMut<u32, String, FolderCache<u32>> = CacheMut::new(folder.table("thumbnails").unwrap(), 2);
+        assert_eq!(*cache.get(&1).unwrap(), "small.png");
+    }
+
+    #[test]
+    fn interleaved_size_classes_with_small_handle_pool() {
+        let tempdir = TempDir::new("test_open_files").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+        let mut folder = FolderCache::continued(folder_path.clone()).unwrap()
+            .with_open_files(std::num::NonZeroUsize::new(2).unwrap());
+        // Four size classes written round-robin, so a pool of two keeps evicting handles.
+        for i in 0..40u32 {
+            folder.insert(i, &"x".repeat(1 << (i % 4 * 2))).unwrap();
+        }
+        for i in (0..40u32).rev() {
+            assert_eq!(folder.get::<String>(&i).unwrap(), "x".repeat(1 << (i % 4 * 2)));
+        }
+        drop(folder);
+        let folder = FolderCache::<u32>::continued(folder_path).unwrap();
+        assert_eq!(folder.get::<String>(&39).unwrap(), "x".repeat(64));
+    }
+
+    #[test]
+    fn concurrent_reads_share_the_cache() {
+        let tempdir = TempDir::new("test_concurrent_reads").unwrap();
+        let mut folder = FolderCache::continued(tempdir.path().to_path_buf()).unwrap();
+        for i in 0..100u32 {
+            folder.insert(i, &"memphis".repeat(i as usize % 10)).unwrap();
+        }
+        let folder = &folder;
+        std::thread::scope(|scope| {
+            for t in 0..4u32 {
+                scope.spawn(move || {
+                    for i in (t..100).step_by(4) {
+                        assert_eq!(folder.get::<String>(&i).unwrap(), "memphis".repeat(i as usize % 10));
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn appends_are_buffered_until_commit() {
+        let tempdir = TempDir::new("test_buffered").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+        let header_count = || {
+            let bytes = std::fs::read(folder_path.join(format!("{}.cache", class_of(3)))).unwrap();
+            u64::from_le_bytes(bytes[8..16].try_into().unwrap())
+        };
+
+        let mut folder = FolderCache::continued(folder_path.clone()).unwrap();
+        for i in 0..5u32 {
+            folder.insert(i, &i.to_string()).unwrap();
+        }
+        assert_eq!(header_count(), 0, "the header should not be rewritten per insert");
+        assert_eq!(folder.get::<String>(&4).unwrap(), "4");
+        // In-place writes go through the journal, so pending appends are written first.
+        folder.remove(&1).unwrap();
+        assert_eq!(header_count(), 4);
+        folder.insert(7, &"7".to_string()).unwrap();
+        folder.commit().unwrap();
+        assert_eq!(header_count(), 5);
+        folder.insert(8, &"8".to_string()).unwrap();
+        drop(folder);
+
+        let folder = FolderCache::<u32>::continued(folder_path.clone()).unwrap();
+        for i in [0, 2, 3, 4, 7, 8] {
+            assert_eq!(folder.get::<String>(&i).unwrap(), i.to_string());
+        }
+    }
+
+    #[test]
+    fn insert_many_bulk_loads() {
+        let tempdir = TempDir::new("test_insert_many").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+
+        let mut folder = FolderCache::continued(folder_path.clone()).unwrap();
+        folder.insert(3u32, &"old".to_string()).unwrap();
+        folder.insert_many((0..500u32).map(|i| (i, "memphis".repeat(i as usize % 7)))).unwrap();
+        folder.insert_many([(600, "a".to_string()), (600, "b".to_string())]).unwrap();
+        drop(folder);
+
+        let folder = FolderCache::<u32>::continued(folder_path).unwrap();
+        for i in 0..500u32 {
+            assert_eq!(folder.get::<String>(&i).unwrap(), "memphis".repeat(i as usize % 7));
+        }
+        assert_eq!(folder.get::<String>(&600).unwrap(), "b");
+    }
+
+    #[test]
+    fn reads_follow_growth_and_compaction() {
+        // Exercises remapping when built with the `mmap` feature; plain reads otherwise.
+        let tempdir = TempDir::new("test_mapped_reads").unwrap();
+        let mut folder = FolderCache::continued(tempdir.path().to_path_buf()).unwrap();
+        for round in 0..3u32 {
+            for i in 0..50u32 {
+                folder.insert(i, &format!("{round}_{}", "x".repeat(i as usize))).unwrap();
+            }
+            folder.commit().unwrap();
+            for i in 0..50u32 {
+                assert_eq!(folder.get::<String>(&i).unwrap(), format!("{round}_{}", "x".repeat(i as usize)));
+            }
+        }
+        for i in 10..50u32 {
+            folder.remove(&i).unwrap();
+        }
+        folder.compact().unwrap();
+        for i in 0..10u32 {
+            assert_eq!(folder.get::<String>(&i).unwrap(), format!("2_{}", "x".repeat(i as usize)));
+        }
+    }
+
+    #[test]
+    fn custom_size_classes() {
+        use crate::folder_compatible::SizeClasses;
+
+        let tempdir = TempDir::new("test_size_classes").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+        let bucket_sizes = |path: &std::path::Path| -> Vec<u64> {
+            let mut sizes: Vec<u64> = std::fs::read_dir(path).unwrap()
+                .filter_map(|e| e.unwrap().file_name().to_str()?.strip_suffix(".cache")?.parse().ok()).collect();
+            sizes.sort();
+            sizes
+        };
+        {
+            let mut folder = FolderCache::continued(folder_path.clone()).unwrap()
+                .with_size_classes(SizeClasses::Ladder(vec![SLOT_HEADER_BYTES+8, SLOT_HEADER_BYTES+24, 100]));
+            folder.insert(1u32, &"a".to_string()).unwrap();
+            folder.insert(2, &"x".repeat(20)).unwrap();
+            folder.insert(3, &"x".repeat(200)).unwrap();
+        }
+        // Past the last class, records take up a multiple of it.
+        let last = (SLOT_HEADER_BYTES+203).next_multiple_of(100);
+        assert_eq!(bucket_sizes(&folder_path), [SLOT_HEADER_BYTES+8, SLOT_HEADER_BYTES+24, last]);
+
+        let mut folder = FolderCache::<u32>::continued(folder_path.clone()).unwrap()
+            .with_size_classes(SizeClasses::Growth(1.25));
+        folder.insert(4, &"x".repeat(30)).unwrap();
+        for (k, len) in [(1, 1), (2, 20), (3, 200), (4, 30)] {
+            assert_eq!(folder.get::<String>(&k).unwrap().len(), len);
+        }
+        // 30 bytes of value plus framing: 32 -> 40 -> 56 -> 72 -> ...
+        let mut classes = std::iter::successors(Some(32), |c: &u64| Some(((*c as f64*1.25).ceil() as u64).next_multiple_of(8)));
+        let class = classes.find(|c| *c >= SLOT_HEADER_BYTES+32).unwrap();
+        assert!(bucket_sizes(&folder_path).contains(&class));
+    }
+
+    #[test]
+    fn large_values_overflow_to_blobs() {
+        let tempdir = TempDir::new("test_overflow").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+        let big = "x".repeat(10_000);
+        {
+            let mut folder = FolderCache::continued(folder_path.clone()).unwrap().with_overflow_threshold(1024);
+            folder.insert(1u32, &big).unwrap();
+            folder.insert(2, &"small".to_string()).unwrap();
+            folder.insert_many([(3, big.clone()), (4, "tiny".to_string())]).unwrap();
+            assert_eq!(folder.get::<String>(&1).unwrap(), big);
+            // Overwriting a blob with a small value deletes the blob.
+            folder.insert(3, &"shrunk".to_string()).unwrap();
+        }
+        let blobs = folder_path.join("blobs");
+        assert_eq!(std::fs::read_dir(&blobs).unwrap().count(), 1);
+        assert!(!folder_path.join(format!("{}.cache", class_of(10_004))).exists());
+        assert!(FolderCache::<u32>::verify(&folder_path).unwrap().is_ok());
+
+        let mut folder = FolderCache::<u32>::continued(folder_path.clone()).unwrap().with_overflow_threshold(1024);
+        assert_eq!(folder.get::<String>(&1).unwrap(), big);
+        assert_eq!(folder.get::<String>(&3).unwrap(), "shrunk");
+        folder.insert(5, &big).unwrap();
+        folder.remove(&1).unwrap();
+        assert_eq!(folder.get::<String>(&5).unwrap(), big);
+        assert_eq!(std::fs::read_dir(&blobs).unwrap().count(), 1);
+        folder.compact().unwrap();
+        assert_eq!(folder.get::<String>(&5).unwrap(), big);
+    }
+
+    #[test]
+    fn streamed_values() {
+        use crate::folder_compatible::FolderCacheError;
+
+        let tempdir = TempDir::new("test_streaming").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i*7) as u8).collect();
+        {
+            let mut folder = FolderCache::continued(folder_path.clone()).unwrap();
+            assert_eq!(folder.insert_from(1u32, &b"first"[..]).unwrap(), 5);
+            assert_eq!(folder.insert_from(1, std::io::Cursor::new(&data)).unwrap(), data.len() as u64);
+            folder.insert(2, &"not bytes".to_string()).unwrap();
+        }
+        let folder = FolderCache::<u32>::continued(folder_path.clone()).unwrap();
+        let mut out = Vec::new();
+        assert_eq!(folder.read_to(&1, &mut out).unwrap(), data.len() as u64);
+        assert_eq!(out, data);
+        // The overwritten stream's blob is gone.
+        assert_eq!(std::fs::read_dir(folder_path.join("blobs")).unwrap().count(), 1);
+        assert!(matches!(folder.read_to(&2, std::io::sink()), Err(FolderCacheError::Decode(_))));
+        assert!(matches!(folder.read_to(&3, std::io::sink()), Err(FolderCacheError::Nothing)));
+    }
+
+    #[test]
+    fn partial_reads_of_binary_values() {
+        let tempdir = TempDir::new("test_read_range").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+        let data: Vec<u8> = (0..1_000_000u32).map(|i| (i%251) as u8).collect();
+        let mut folder = FolderCache::continued(folder_path).unwrap();
+        folder.insert_from(1u32, std::io::Cursor::new(&data)).unwrap();
+        // Binary values inserted normally stay inline in their bucket.
+        struct Bytes<'a>(&'a [u8]);
+        impl serde::Serialize for Bytes<'_> {
+            fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {s.serialize_bytes(self.0)}
+        }
+        folder.insert(2, &Bytes(&data[..100])).unwrap();
+        folder.insert(3, &"text".to_string()).unwrap();
+        assert_eq!(folder.read_range(&1, 500_000, 1000).unwrap(), &data[500_000..501_000]);
+        assert_eq!(folder.read_range(&1, 999_990, 1000).unwrap(), &data[999_990..]);
+        assert!(folder.read_range(&1, 2_000_000, 10).unwrap().is_empty());
+        assert_eq!(folder.read_range(&2, 10, 20).unwrap(), &data[10..30]);
+        assert!(folder.read_range(&3, 0, 1).is_err());
+    }
+
+    #[test]
+    fn quota_evicts_least_recently_written() {
+        let tempdir = TempDir::new("test_quota").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+        let mut folder = FolderCache::continued(folder_path).unwrap().with_quota(8*1024);
+        let value = "v".repeat(100);
+        for i in 0..200u32 {
+            folder.insert(i, &value).unwrap();
+            assert!(folder.disk_bytes() <= 8*1024, "{} bytes after insert {i}", folder.disk_bytes());
+        }
+        let mut evicted = folder.take_evicted();
+        evicted.sort();
+        assert!(!evicted.is_empty());
+        // The oldest entries went first, and everything not evicted is still there.
+        assert_eq!(evicted, (0..evicted.len() as u32).collect::<Vec<_>>());
+        for i in evicted.len() as u32..200 {
+            assert!(folder.contains(&i));
+        }
+        assert!(folder.take_evicted().is_empty());
+    }
+
+    #[test]
+    fn entry_metadata() {
+        let tempdir = TempDir::new("test_metadata").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+        let start = std::time::SystemTime::now();
+        let created = {
+            let mut folder = FolderCache::continued(folder_path.clone()).unwrap();
+            folder.insert(1u32, &"short".to_string()).unwrap();
+            let meta = folder.metadata(&1).unwrap();
+            assert!(meta.created >= start);
+            assert_eq!(meta.created, meta.modified);
+            meta.created
+        };
+        let mut folder = FolderCache::<u32>::continued(folder_path).unwrap();
+        // Overwrites keep the creation time, in place and when the value moves to another size class.
+        folder.insert(1, &"same".to_string()).unwrap();
+        let meta = folder.metadata(&1).unwrap();
+        assert_eq!(meta.created, created);
+        assert!(meta.modified > created);
+        folder.insert(1, &"much longer value".repeat(20)).unwrap();
+        folder.insert_with_hint(1, &"hinted".to_string(), 7).unwrap();
+        let moved = folder.metadata(&1).unwrap();
+        assert_eq!(moved.created, created);
+        assert!(moved.modified > meta.modified);
+        assert!(folder.metadata(&2).is_err());
+    }
+
+    #[test]
+    fn iterate_entries() {
+        let tempdir = TempDir::new("test_iter").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+        {
+            let mut folder = FolderCache::continued(folder_path.clone()).unwrap().with_overflow_threshold(500);
+            for i in 0..50u32 {
+                folder.insert(i, &"z".repeat(i as usize*20)).unwrap();
+            }
+            folder.remove(&10).unwrap();
+        }
+        let folder = FolderCache::<u32>::continued(folder_path).unwrap();
+        let mut keys: Vec<u32> = folder.keys().copied().collect();
+        keys.sort();
+        let expected: Vec<u32> = (0..50).filter(|i| *i != 10).collect();
+        assert_eq!(keys, expected);
+
+        let mut entries: Vec<(u32, String)> = folder.iter().collect::<Result<_, _>>().unwrap();
+        entries.sort();
+        assert_eq!(entries, expected.iter().map(|i| (*i, "z".repeat(*i as usize*20))).collect::<Vec<_>>());
+        let total: usize = folder.values::<String>().map(|v| v.unwrap().len()).sum();
+        assert_eq!(total, expected.iter().map(|i| *i as usize*20).sum::<usize>());
+    }
+
+    #[test]
+    fn occupancy_stats() {
+        let tempdir = TempDir::new("test_stats").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+        let mut folder = FolderCache::continued(folder_path).unwrap();
+        for i in 0..5u32 {
+            folder.insert(i, &"a".repeat(10)).unwrap();
+        }
+        folder.insert(5, &"b".repeat(100)).unwrap();
+        let stats = folder.stats().unwrap();
+        // A 10-character string takes 11 bytes and its key 1, behind the slot header.
+        let (small, large) = (class_of(12), class_of(103));
+        assert_eq!(stats.classes.iter().map(|c| (c.size_per_item, c.items, c.reserved)).collect::<Vec<_>>(), [(small, 5, 8), (large, 1, 4)]);
+        assert_eq!(stats.classes[0].wasted_bytes, 3*small + 5*(small-SLOT_HEADER_BYTES-12));
+        assert_eq!(stats.classes[0].file_bytes, FILE_HEADER_BYTES + 8*small);
+        assert_eq!(stats.total.items, 6);
+        assert_eq!(stats.total.file_bytes, stats.classes.iter().map(|c| c.file_bytes).sum::<u64>());
+        assert_eq!(stats.blob_bytes, 0);
+        folder.compact().unwrap();
+        assert_eq!(folder.stats().unwrap().total.reserved, 6);
+    }
+
+    #[test]
+    fn identical_values_are_stored_once() {
+        let tempdir = TempDir::new("test_dedup").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+        let blobs = folder_path.join("blobs");
+        let artifact = "artifact".repeat(100);
+        {
+            let mut folder = FolderCache::continued(folder_path.clone()).unwrap().with_dedup(64);
+            for i in 0..10u32 {
+                folder.insert(i, &artifact).unwrap();
+            }
+            folder.insert(10, &"other".repeat(100)).unwrap();
+            folder.insert(11, &"small".to_string()).unwrap();
+            assert_eq!(std::fs::read_dir(&blobs).unwrap().count(), 2);
+            assert_eq!(folder.stats().unwrap().blob_bytes, 3 + 800 + 3 + 500);
+        }
+        // Reference counts are rebuilt on open: the shared blob lives until its last record is gone.
+        let mut folder = FolderCache::<u32>::continued(folder_path.clone()).unwrap().with_dedup(64);
+        for i in 0..9 {
+            folder.remove(&i).unwrap();
+        }
+        folder.insert(10, &"replaced".to_string()).unwrap();
+        assert_eq!(std::fs::read_dir(&blobs).unwrap().count(), 1);
+        assert_eq!(folder.get::<String>(&9).unwrap(), artifact);
+        folder.remove(&9).unwrap();
+        assert_eq!(std::fs::read_dir(&blobs).unwrap().count(), 0);
+        assert!(FolderCache::<u32>::verify(&folder_path).unwrap().is_ok());
+    }
+
+    #[test]
+    fn hashed_keys() {
+        use crate::folder_compatible::HashedFolderCache;
+
+        let tempdir = TempDir::new("test_hashed").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+        let path = |i: u32| format!("/very/long/path/to/some/deeply/nested/artifact/directory/file_{i}.bin");
+        for verify in [false, true] {
+            let stats = {
+                let mut folder = HashedFolderCache::new(FolderCache::cleared(folder_path.clone()).unwrap(), verify);
+                for i in 0..20 {
+                    folder.insert(&path(i), &i).unwrap();
+                }
+                folder.remove(&path(3)).unwrap();
+                folder.inner().stats().unwrap()
+            };
+            let folder = HashedFolderCache::<String>::new(FolderCache::continued(folder_path.clone()).unwrap(), verify);
+            assert!(!folder.contains(&path(3)));
+            for i in (0..20).filter(|i| *i != 3) {
+                assert_eq!(folder.get::<u32>(&path(i)).unwrap(), i);
+            }
+            assert!(folder.get::<u32>(&"unknown".to_string()).is_err());
+            // Without verification a record is a hash and a small number, much less than the key alone.
+            if !verify {
+                assert_eq!(stats.classes.iter().map(|c| c.size_per_item).collect::<Vec<_>>(), [128]);
+            }
+        }
+    }
+
+    #[test]
+    fn snapshot_and_restore() {
+        use crate::folder_compatible::Layout;
+
+        let tempdir = TempDir::new("test_snapshot").unwrap();
+        let (folder_path, backup, restored) = (tempdir.path().join("live"), tempdir.path().join("backup"), tempdir.path().join("restored"));
+        std::fs::create_dir(&folder_path).unwrap();
+        let layout = Layout::default().with_shards(2);
+        let mut folder = FolderCache::cleared_with(folder_path.clone(), layout.clone()).unwrap().with_overflow_threshold(100);
+        for i in 0..10 {
+            folder.insert(i, &format!("value {i}")).unwrap();
+        }
+        folder.insert(10, &"x".repeat(500)).unwrap();
+        folder.snapshot(&backup).unwrap();
+        // Changes after the snapshot do not reach it.
+        folder.insert(0, &"changed".to_string()).unwrap();
+        folder.remove(&10).unwrap();
+        drop(folder);
+
+        let folder = FolderCache::<i32>::from_snapshot_with(&backup, restored.clone(), layout.clone()).unwrap();
+        assert_eq!(folder.get::<String>(&0).unwrap(), "value 0");
+        assert_eq!(folder.get::<String>(&9).unwrap(), "value 9");
+        assert_eq!(folder.get::<String>(&10).unwrap(), "x".repeat(500));
+        drop(folder);
+        let folder = FolderCache::<i32>::continued_with(folder_path, layout).unwrap();
+        assert_eq!(folder.get::<String>(&0).unwrap(), "changed");
+        assert!(!folder.contains(&10));
+    }
+
+    #[test]
+    fn background_writes() {
+        use crate::folder_compatible::FolderCacheError;
+
+        let tempdir = TempDir::new("test_background").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+        {
+            let folder = FolderCache::<i32>::cleared(folder_path.clone()).unwrap().in_background();
+            let mut cache: CacheMut<i32, String, _> = CacheMut::new(folder, 2);
+            for i in 0..50 {
+                cache.insert(i, format!("value {i}")).unwrap();
+            }
+            // Evicted entries are readable while their writes are still queued.
+            assert_eq!(*cache.get(&0).unwrap(), "value 0");
+            cache.remove(&1).unwrap();
+            cache.commit().unwrap();
+        }
+        let folder = FolderCache::<i32>::continued(folder_path.clone()).unwrap();
+        assert_eq!(folder.get::<String>(&49).unwrap(), "value 49");
+        assert!(!folder.contains(&1));
+        drop(folder);
+
+        // Failed writes surface at the next commit.
+        let mut folder = FolderCache::<i32>::read_only(folder_path).unwrap().in_background();
+        folder.insert(100, &"new".to_string()).unwrap();
+        assert!(matches!(folder.commit(), Err(FolderCacheError::ReadOnly)));
+        assert!(folder.commit().is_ok());
+    }
+
+    #[test]
+    fn versioned_writes() {
+        use crate::folder_compatible::FolderCacheError;
+
+        let tempdir = TempDir::new("test_versions").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+        {
+            let mut folder = FolderCache::continued(folder_path.clone()).unwrap();
+            assert_eq!(folder.insert_if_version(1u32, &"first".to_string(), 0).unwrap(), 1);
+            assert!(matches!(folder.insert_if_version(1, &"again".to_string(), 0), Err(FolderCacheError::VersionMismatch {expected: 0, found: 1})));
+            folder.insert(1, &"second".to_string()).unwrap();
+            // Moving to a larger size class still counts as the next write.
+            folder.insert(1, &"third".repeat(50)).unwrap();
+            assert_eq!(folder.get_versioned::<String>(&1).unwrap(), ("third".repeat(50), 3));
+        }
+        let mut folder = FolderCache::<u32>::continued(folder_path).unwrap();
+        assert_eq!(folder.metadata(&1).unwrap().version, 3);
+        assert!(folder.insert_if_version(1, &"stale".to_string(), 2).is_err());
+        assert_eq!(folder.insert_if_version(1, &"fourth".to_string(), 3).unwrap(), 4);
+        assert_eq!(folder.get_versioned::<String>(&1).unwrap(), ("fourth".to_string(), 4));
+        folder.remove(&1).unwrap();
+        assert!(folder.get_versioned::<String>(&1).is_err());
+        assert_eq!(folder.insert_if_version(1, &"fifth".to_string(), 0).unwrap(), 1);
+    }
+
+    #[test]
+    fn append_to_values() {
+        use crate::folder_compatible::FolderCacheError;
+
+        let tempdir = TempDir::new("test_append").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+        {
+            let mut folder = FolderCache::continued(folder_path.clone()).unwrap().with_overflow_threshold(1000);
+            folder.insert(1u32, &"a".repeat(30)).unwrap();
+            // Crossing 31 characters widens the string header; the record still fits its 256-byte slot.
+            folder.append(1, &"bc".to_string()).unwrap();
+            assert_eq!(folder.stats().unwrap().classes.iter().map(|c| (c.size_per_item, c.items)).collect::<Vec<_>>(), [(256, 1)]);
+            folder.append(1, &"d".repeat(100)).unwrap();
+            folder.insert(2, &vec![1u32, 2]).unwrap();
+            folder.append(2, &vec![3u32, 70000]).unwrap();
+            folder.insert(3, &"x".repeat(2000)).unwrap();
+            folder.append(3, &"y".to_string()).unwrap();
+            assert!(folder.append(2, &"text".to_string()).is_err());
+            assert!(matches!(folder.append(4, &"new".to_string()), Err(FolderCacheError::Nothing)));
+        }
+        let folder = FolderCache::<u32>::continued(folder_path.clone()).unwrap();
+        assert_eq!(folder.get::<String>(&1).unwrap(), "a".repeat(30)+"bc"+&"d".repeat(100));
+        assert_eq!(folder.get::<Vec<u32>>(&2).unwrap(), [1, 2, 3, 70000]);
+        assert_eq!(folder.get::<String>(&3).unwrap(), "x".repeat(2000)+"y");
+
+        // Through a cache, cached values are extended in memory and the rest by the backend.
+        let mut cache: CacheMut<u32, String, _> = CacheMut::new(folder, 1);
+        cache.append(&1, "e").unwrap();
+        drop(cache.get(&3).unwrap());
+        cache.append(&3, "z").unwrap();
+        cache.commit().unwrap();
+        assert!(cache.get(&1).unwrap().ends_with("de"));
+        assert!(cache.get(&3).unwrap().ends_with("yz"));
+
+        let mut map = CacheMut::new(std::collections::HashMap::from([(1, vec![1u8])]), 1);
+        map.append(&1, &[2, 3]).unwrap();
+        assert_eq!(*map.get(&1).unwrap(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn secondary_indexes() {
+        use serde::{Serialize, Deserialize};
+
+        #[derive(Serialize, Deserialize)]
+        struct Doc {owner: u32, body: String}
+        let tempdir = TempDir::new("test_indexes").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+        let sorted = |mut keys: Vec<u32>| {keys.sort(); keys};
+        {
+            let mut folder = FolderCache::continued(folder_path.clone()).unwrap();
+            folder.add_index("owner", |doc: &Doc| doc.owner).unwrap();
+            for i in 0..10u32 {
+                folder.insert(i, &Doc {owner: i%3, body: format!("doc {i}")}).unwrap();
+            }
+            folder.insert_many((10..12).map(|i| (i, Doc {owner: 7, body: String::new()}))).unwrap();
+            folder.insert(0, &Doc {owner: 7, body: "moved".to_string()}).unwrap();
+            assert_eq!(sorted(folder.keys_by("owner", &0u32).unwrap()), [3, 6, 9]);
+            assert_eq!(sorted(folder.keys_by("owner", &7u32).unwrap()), [0, 10, 11]);
+            assert!(folder.keys_by("owner", &5u32).unwrap().is_empty());
+            assert!(folder.keys_by("missing", &0u32).is_err());
+            folder.commit().unwrap();
+            // Not saved before closing, so the next open has to extract this one again.
+            folder.insert(1, &Doc {owner: 0, body: "changed".to_string()}).unwrap();
+        }
+        let mut folder = FolderCache::<u32>::continued(folder_path).unwrap();
+        folder.add_index("owner", |doc: &Doc| doc.owner).unwrap();
+        assert_eq!(sorted(folder.keys_by("owner", &0u32).unwrap()), [1, 3, 6, 9]);
+        assert_eq!(folder.remove_by("owner", &7u32).unwrap(), 3);
+        assert!(!folder.contains(&10));
+        assert!(folder.keys_by("owner", &7u32).unwrap().is_empty());
+        assert_eq!(folder.keys().count(), 9);
+    }
+
+    #[test]
+    fn remove_by_prefix() {
+        let tempdir = TempDir::new("test_prefix").unwrap();
+        let mut folder = FolderCache::cleared(tempdir.path().to_path_buf()).unwrap();
+        for tenant in ["acme", "acme-labs", "globex"] {
+            for i in 0..20 {
+                folder.insert(format!("{tenant}/{i}"), &i).unwrap();
+            }
+        }
+        assert_eq!(folder.remove_prefix("acme/").unwrap(), 20);
+        assert_eq!(folder.remove_range("globex/10".to_string().."globex/2".to_string()).unwrap(), 10);
+        assert!(folder.contains(&"acme-labs/3".to_string()));
+        assert!(!folder.contains(&"acme/3".to_string()));
+        assert!(folder.contains(&"globex/2".to_string()) && !folder.contains(&"globex/15".to_string()));
+        assert_eq!(folder.keys().count(), 30);
+    }
+
+    #[test]
+    #[cfg(feature = "conformance")]
+    fn backends_pass_the_conformance_suite() {
+        use std::collections::{BTreeMap, HashMap};
+        use crate::conformance::Conformance;
+
+        Conformance::new(HashMap::new, |i| i as u32, |i| format!("value {i}")).run();
+        Conformance::new(BTreeMap::new, |i| i as u32, |i| vec![i as u8; i % 7]).run();
+        let dir = TempDir::new("conformance").unwrap();
+        let mut n = 0;
+        Conformance::new(move || {
+            n += 1;
+            let path = dir.path().join(n.to_string());
+            std::fs::create_dir(&path).unwrap();
+            FolderCache::cleared(path).unwrap()
+        }, |i| i as u32, |i| format!("value {i}")).run();
+    }
 
-        // Reload the cache from the same folder
-        let folder = FolderCache::continued(folder_path.clone()).unwrap();
+    #[test]
+    #[cfg(feature = "testing")]
+    fn model_checker_finds_lost_writes() {
+        use std::collections::HashMap;
+        use crate::{CacheCompatible, CacheMutCompatible, Retrieval};
+        use crate::hashmap_compatible::NotInMap;
+        use crate::testing::ModelChecker;
+
+        ModelChecker::new(HashMap::new, |i| i as u8, |i| i.to_string()).check_seeds(0..50);
+        ModelChecker::new(HashMap::new, |i| i as u8, |i| i.to_string()).with_capacity(1).with_keys(3).check_seeds(0..50);
+        let dir = TempDir::new("model").unwrap();
+        let mut n = 0;
+        ModelChecker::new(move || {
+            n += 1;
+            let path = dir.path().join(n.to_string());
+            std::fs::create_dir(&path).unwrap();
+            FolderCache::cleared(path).unwrap()
+        }, |i| i as u8, |i| i.to_string()).check_seeds(0..10);
+
+        // Keeps the first value inserted for each key.
+        #[derive(Default)]
+        struct FirstWins(HashMap<u8, String>);
+        impl CacheCompatible<u8, String> for FirstWins {
+            type Error = NotInMap;
+            const RETRIEVAL: Retrieval = Retrieval::Copy;
+            fn contains(&self, k: u8) -> bool { self.0.contains_key(&k) }
+            fn get(&mut self, k: u8) -> Result<String, NotInMap> { self.0.get(&k).cloned().ok_or(NotInMap) }
+            fn replace(&mut self, _: u8, _: String) -> Result<(), NotInMap> { Ok(()) }
+        }
+        impl CacheMutCompatible<u8, String> for FirstWins {
+            fn insert(&mut self, k: u8, v: String) -> Result<(), NotInMap> {
+                self.0.entry(k).or_insert(v);
+                Ok(())
+            }
+            fn remove(&mut self, k: u8) -> Result<(), NotInMap> {
+                self.0.remove(&k);
+                Ok(())
+            }
+            fn commit(&mut self) -> Result<(), NotInMap> { Ok(()) }
+        }
+        let mut checker = ModelChecker::new(FirstWins::default, |i| i as u8, |i| i.to_string());
+        let failure = (0..10).find_map(|seed| checker.check(seed).err()).unwrap();
+        assert_eq!(checker.check(failure.seed).unwrap_err().ops, failure.ops);
+        assert!(failure.to_string().starts_with(&format!("seed {}: ", failure.seed)));
+    }
+
+    #[test]
+    fn leaked_guards_can_be_deactivated() {
+        use crate::LockError;
+
+        let tempdir = TempDir::new("leaked").unwrap();
+        let mut folder = FolderCache::cleared(tempdir.path().to_path_buf()).unwrap();
+        folder.insert(0, &"stored".to_string()).unwrap();
         let mut cache: CacheMut<i32, String, FolderCache<i32>> = CacheMut::new(folder, 2);
+        std::mem::forget(cache.get_mut(&0).unwrap());
+        assert!(matches!(cache.try_get(&0), Err(LockError::WouldDeadlock(0))));
+        // The lock is still held by the leaked guard, so this is not a missed drop.
+        assert_eq!(cache.purge_inactive_locks().unwrap(), []);
+        assert!(cache.force_deactivate(&0).unwrap());
+        assert!(!cache.force_deactivate(&0).unwrap());
+        assert_eq!(cache.num_active(), 0);
+        *cache.try_get_mut(&0).unwrap() = "replaced".to_string();
+        cache.commit().unwrap();
+        assert_eq!(*cache.get(&0).unwrap(), "replaced");
+    }
 
-        // Ensure the long string persisted correctly
-        let value = cache.get(&1).unwrap();
-        assert_eq!(value.len(), "memphis".len() * 500);
-        assert!(value.starts_with("memphis"));
-        assert!(value.ends_with("memphis"));
+    #[test]
+    #[cfg(feature = "parallel")]
+    fn commit_parallel_serializes_dirty_values() {
+        let tempdir = TempDir::new("parallel").unwrap();
+        let folder = FolderCache::cleared(tempdir.path().to_path_buf()).unwrap();
+        let mut cache: CacheMut<u32, String, FolderCache<u32>> = CacheMut::new(folder, 1000);
+        for i in 0..1000 {
+            cache.insert(i, format!("value {i}")).unwrap();
+        }
+        cache.commit().unwrap();
+        for i in (0..1000).step_by(3) {
+            cache.get_mut(&i).unwrap().push('!');
+        }
+        drop(cache.get(&1).unwrap());
+        cache.commit_parallel().unwrap();
+        drop(cache);
+        let folder = FolderCache::continued(tempdir.path().to_path_buf()).unwrap();
+        for i in 0..1000 {
+            let expected = if i % 3 == 0 {format!("value {i}!")} else {format!("value {i}")};
+            assert_eq!(folder.get::<String>(&i).unwrap(), expected);
+        }
     }
 
     #[test]
-    fn file_reuse_and_size_stability() {
-        // Create a persistent folder
-        let tempdir = TempDir::new("test_file_reuse").unwrap();
-        let folder_path = tempdir.path().to_path_buf();
+    fn commits_can_run_in_the_background() {
+        let tempdir = TempDir::new("spawn_commit").unwrap();
+        let folder = FolderCache::cleared(tempdir.path().to_path_buf()).unwrap();
+        let mut cache: CacheMut<u32, String, FolderCache<u32>> = CacheMut::new(folder, 100);
+        for i in 0..100 {
+            cache.insert(i, i.to_string()).unwrap();
+        }
+        *cache.get_mut(&0).unwrap() = "changed".to_string();
+        let held = cache.get_mut(&1).unwrap();
+        cache.spawn_flush().join().unwrap().unwrap();
+        assert!(cache.active(&1));
+        drop(held);
+        cache.spawn_commit().join().unwrap().unwrap();
+        assert_eq!(cache.stats().cached, 0);
+        drop(cache);
+        let folder = FolderCache::continued(tempdir.path().to_path_buf()).unwrap();
+        assert_eq!(folder.get::<String>(&0).unwrap(), "changed");
+        assert_eq!(folder.get::<String>(&99).unwrap(), "99");
+    }
 
-        // Create a cache and insert 0..7
-        let folder = FolderCache::continued(folder_path.clone()).unwrap();
-        let mut cache = CacheMut::new(folder, 4);
+    #[test]
+    fn warm_tier_holds_evicted_values_compressed() {
+        use crate::tiered::WarmTier;
 
-        for i in 0..7 {
+        let tempdir = TempDir::new("warm").unwrap();
+        let folder = FolderCache::cleared(tempdir.path().to_path_buf()).unwrap();
+        let value = |i: u32| format!("{i}: {}", "abcd".repeat(50));
+        let mut cache: CacheMut<u32, String, _> = CacheMut::new(WarmTier::new(folder, 400), 2);
+        for i in 0..10 {
+            cache.insert(i, value(i)).unwrap();
+        }
+        for i in 0..10 {
+            assert_eq!(*cache.get(&i).unwrap(), value(i));
+        }
+        // The two hot entries are in the cache, the next few in the warm tier, compressed well below their size,
+        // and the rest have gone to the folder.
+        cache.maintain_backend(|tier| {
+            assert!(tier.len() >= 4);
+            assert!(tier.bytes() <= 400);
+            assert!(tier.inner().keys().count() < 10);
+            assert!(!tier.inner().contains(&9));
+        });
+        *cache.get_mut(&9).unwrap() = "changed".to_string();
+        cache.commit().unwrap();
+        drop(cache);
+        let folder = FolderCache::continued(tempdir.path().to_path_buf()).unwrap();
+        assert_eq!(folder.get::<String>(&9).unwrap(), "changed");
+        assert_eq!(folder.get::<String>(&5).unwrap(), value(5));
+
+        #[cfg(feature = "conformance")]
+        crate::conformance::Conformance::new(|| WarmTier::new(std::collections::HashMap::new(), 40), |i| i as u32, |i| format!("value {i}")).run();
+    }
+
+    #[test]
+    fn inserts_are_not_overwritten_by_older_copies() {
+        let tempdir = TempDir::new("insert_race").unwrap();
+        let folder = FolderCache::cleared(tempdir.path().to_path_buf()).unwrap();
+        let mut cache: CacheMut<u32, String, FolderCache<u32>> = CacheMut::new(folder, 2);
+        for i in 0..4 {
+            cache.insert(i, format!("old {i}")).unwrap();
+        }
+        // A modified copy in memory is replaced, not written back after the insert.
+        *cache.get_mut(&3).unwrap() = "modified".to_string();
+        cache.insert(3, "new".to_string()).unwrap();
+        // A copy waiting to be written back because a leaked guard still shares it is dropped as well.
+        *cache.get_mut(&2).unwrap() = "modified".to_string();
+        std::mem::forget(cache.get(&2).unwrap());
+        cache.force_deactivate(&2).unwrap();
+        drop(cache.get(&0).unwrap());
+        drop(cache.get(&1).unwrap());
+        assert_eq!(cache.stats().unwritten, 0);
+        cache.insert(2, "new".to_string()).unwrap();
+        for i in 10..20 {
             cache.insert(i, i.to_string()).unwrap();
         }
+        cache.commit().unwrap();
+        // The inserted value is read back from memory until then, and from the backend afterwards.
+        assert_eq!(*cache.get(&3).unwrap(), "new");
+        assert_eq!(*cache.get(&2).unwrap(), "new");
+        cache.force_deactivate(&2).unwrap();
+        cache.commit().unwrap();
+        // The leaked guard keeps the folder open, so it is read through the cache.
+        assert_eq!(cache.maintain_backend(|folder| folder.get::<String>(&3)).unwrap(), "new");
+        assert_eq!(cache.maintain_backend(|folder| folder.get::<String>(&2)).unwrap(), "new");
+    }
 
-        // Drop cache to flush all data
+    #[test]
+    fn weak_handles_do_not_keep_the_cache_open() {
+        let tempdir = TempDir::new("weak_handle").unwrap();
+        let folder = FolderCache::cleared(tempdir.path().to_path_buf()).unwrap();
+        let mut cache: CacheMut<u32, String, FolderCache<u32>> = CacheMut::new(folder, 2);
+        cache.insert(1, "one".to_string()).unwrap();
+        let weak = cache.downgrade();
+        let clone = weak.upgrade().unwrap();
+        let guard = cache.get(&1).unwrap();
+        assert_eq!(cache.handle_count(), 3);
+        drop((clone, guard));
+        assert_eq!(cache.handle_count(), 1);
         drop(cache);
+        assert!(weak.upgrade().is_none());
+        // The folder was committed and released when the last handle went.
+        let folder = FolderCache::continued(tempdir.path().to_path_buf()).unwrap();
+        assert_eq!(folder.get::<String>(&1).unwrap(), "one");
+    }
 
-        // Ensure there is only one .cache file
-        let mut cache_files: Vec<_> = std::fs::read_dir(&folder_path)
-            .unwrap()
-            .filter_map(|e| {
-                let entry = e.unwrap();
-                let path = entry.path();
-                if path.extension().map_or(false, |ext| ext == "cache") {
-                    Some(path)
-                } else {
-                    None
-                }
-            })
-            .collect();
+    #[test]
+    fn shutdown_waits_for_guards_and_returns_the_backend() {
+        let tempdir = TempDir::new("shutdown").unwrap();
+        let folder = FolderCache::cleared(tempdir.path().to_path_buf()).unwrap();
+        let mut cache: CacheMut<u32, String, FolderCache<u32>> = CacheMut::new(folder, 2);
+        cache.insert(1, "one".to_string()).unwrap();
+        let mut guard = cache.get_mut(&1).unwrap();
+        let cache = match cache.shutdown(std::time::Duration::from_millis(10)) {
+            Err(ShutdownError::Held(cache, held)) => {
+                assert_eq!(held, [1]);
+                cache
+            },
+            _ => panic!("shutdown should have timed out"),
+        };
+        let release = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            *guard = "changed".to_string();
+        });
+        let folder = cache.shutdown(std::time::Duration::from_secs(10)).unwrap();
+        release.join().unwrap();
+        assert_eq!(folder.get::<String>(&1).unwrap(), "changed");
+    }
 
-        assert_eq!(
-            cache_files.len(),
-            1,
-            "Expected exactly one .cache file in folder"
-        );
+    #[test]
+    fn with_backend_sees_modified_entries() {
+        let tempdir = TempDir::new("with_backend").unwrap();
+        let folder = FolderCache::cleared(tempdir.path().to_path_buf()).unwrap();
+        let mut cache: CacheMut<u32, String, FolderCache<u32>> = CacheMut::new(folder, 4);
+        cache.insert_bypass(1, "one".to_string()).unwrap();
+        cache.insert_bypass(2, "two".to_string()).unwrap();
+        drop(cache.get(&1).unwrap());
+        *cache.get_mut(&2).unwrap() = "changed".to_string();
+        assert_eq!(cache.with_backend(|folder| folder.get::<String>(&2)).unwrap().unwrap(), "changed");
+        // The unmodified entry stays in memory.
+        assert_eq!(cache.stats().cached, 1);
+    }
 
-        let cache_file = cache_files.pop().unwrap();
-        let initial_size = std::fs::metadata(&cache_file).unwrap().len();
+    #[cfg(feature = "ipc")]
+    #[test]
+    fn ipc_clients_share_the_cache() {
+        use crate::ipc::{self, IpcClient};
+        let tempdir = TempDir::new("ipc").unwrap();
+        std::fs::create_dir(tempdir.path().join("cache")).unwrap();
+        let folder = FolderCache::cleared(tempdir.path().join("cache")).unwrap();
+        let cache: CacheMut<u32, String, FolderCache<u32>> = CacheMut::new(folder, 2);
+        let server = ipc::serve(cache, tempdir.path().join("socket")).unwrap();
+        let (mut a, mut b) = (IpcClient::connect(server.path()).unwrap(), IpcClient::connect(server.path()).unwrap());
+        a.insert(1, "one".to_string()).unwrap();
+        assert_eq!(b.get::<_, String>(1).unwrap().as_deref(), Some("one"));
+        b.remove::<_, String>(1).unwrap();
+        assert_eq!(a.get::<_, String>(1).unwrap(), None);
+        drop(server);
+        assert!(!tempdir.path().join("socket").exists());
+    }
 
-        // Reopen cache and remove all inserted values
-        {
-            let folder = FolderCache::continued(folder_path.clone()).unwrap();
-            let mut cache: CacheMut<i32, String, FolderCache<i32>> = CacheMut::new(folder, 4);
+    #[cfg(feature = "ipc")]
+    #[test]
+    fn ipc_refuses_entries_borrowed_by_the_server() {
+        use crate::ipc::{self, IpcClient};
+        let tempdir = TempDir::new("ipc_borrowed").unwrap();
+        std::fs::create_dir(tempdir.path().join("cache")).unwrap();
+        let folder = FolderCache::cleared(tempdir.path().join("cache")).unwrap();
+        let cache: CacheMut<u32, String, FolderCache<u32>> = CacheMut::new(folder, 2);
+        let local = cache.clone();
+        let server = ipc::serve(cache, tempdir.path().join("socket")).unwrap();
+        let mut client = IpcClient::connect(server.path()).unwrap();
+        client.insert(1, "one".to_string()).unwrap();
+        let mut guard = local.get_mut(&1).unwrap();
+        assert!(client.insert(1, "uno".to_string()).is_err());
+        assert!(client.remove::<_, String>(1).is_err());
+        assert!(client.get::<_, String>(1).is_err());
+        guard.push_str(" changed");
+        drop(guard);
+        assert_eq!(client.get::<_, String>(1).unwrap().as_deref(), Some("one changed"));
+        client.remove::<_, String>(1).unwrap();
+        assert!(!local.contains(&1));
+    }
 
-            for i in 0..7 {
-                cache.remove(&i).unwrap();
+    #[test]
+    fn max_value_size() {
+        use crate::folder_compatible::FolderCacheError;
+        let dir = TempDir::new("max_value_size").unwrap();
+        let folder = FolderCache::<u32>::cleared(dir.path().to_path_buf()).unwrap().with_max_value_size(100);
+        let mut cache = CacheMut::new(folder, 4).with_max_value_size(50);
+        cache.insert(1, "small".to_string()).unwrap();
+        // Over the cache's limit: written through instead of held in memory.
+        cache.insert(2, "x".repeat(60)).unwrap();
+        assert!(cache.contains_cached(&1) && !cache.contains_cached(&2));
+        assert!(cache.maintain_backend(|folder| folder.contains(&2)));
+        // Over the folder's limit too: refused.
+        assert!(matches!(cache.insert(3, "x".repeat(200)), Err(FolderCacheError::TooLarge(100))));
+        assert!(!cache.contains(&3));
+        cache.maintain_backend(|folder| {
+            assert!(matches!(folder.insert_from(4, &[0u8; 200][..]), Err(FolderCacheError::TooLarge(100))));
+            assert!(!folder.contains(&4));
+            assert_eq!(folder.insert_from(4, &[0u8; 50][..]).unwrap(), 50);
+        });
+        assert_eq!(*cache.get(&2).unwrap(), "x".repeat(60));
+    }
+
+    #[test]
+    fn cache_from_config() {
+        use std::time::Duration;
+        use crate::config::{CacheConfig, OpenMode};
+        use crate::folder_compatible::{Durability, SizeClasses};
+
+        let dir = TempDir::new("cache_from_config").unwrap();
+        let config = CacheConfig {
+            folder: dir.path().to_path_buf(), capacity: 2, open: OpenMode::Clear, durability: Durability::EveryWrite,
+            size_classes: SizeClasses::Ladder(vec![64, 256]), poison_policy: Default::default(),
+            ttl: Some(Duration::from_secs(600)), tti: Some(Duration::from_millis(1500)), max_value_size: None,
+        };
+        let bytes = rmp_serde::to_vec_named(&config).unwrap();
+        assert_eq!(rmp_serde::from_slice::<CacheConfig>(&bytes).unwrap(), config);
+        let mut cache: CacheMut<u32, String, FolderCache<u32>> = CacheMut::from_config(&config).unwrap();
+        cache.insert(1, "one".to_string()).unwrap();
+        cache.commit().unwrap();
+        drop(cache);
+        let reopened = CacheConfig {open: OpenMode::Continue, ..config.clone()};
+        let cache: CacheMut<u32, String, FolderCache<u32>> = CacheMut::from_config(&reopened).unwrap();
+        assert_eq!(*cache.get(&1).unwrap(), "one");
+        drop(cache);
+        let invalid = CacheConfig {size_classes: SizeClasses::Growth(0.5), ..config};
+        assert!(CacheMut::<u32, String, FolderCache<u32>>::from_config(&invalid).is_err());
+    }
+
+    #[test]
+    fn keys_paged() {
+        let dir = TempDir::new("keys_paged").unwrap();
+        let mut cache = CacheMut::new(FolderCache::<u32>::cleared(dir.path().to_path_buf()).unwrap(), 8);
+        for i in 0..25 {
+            cache.insert(i, i.to_string()).unwrap();
+        }
+        let (mut keys, mut cursor, mut pages) = (Vec::new(), Some(0), 0);
+        while let Some(at) = cursor {
+            let (page, next) = cache.keys_paged(at, 10).unwrap();
+            assert!(page.len() <= 10);
+            keys.extend(page);
+            cursor = next;
+            pages += 1;
+        }
+        keys.sort();
+        assert_eq!(keys, (0..25).collect::<Vec<u32>>());
+        assert_eq!(pages, 3);
+    }
+
+    #[test]
+    fn scan_prefix_and_range() {
+        let dir = TempDir::new("scan_prefix_and_range").unwrap();
+        for ordered in [false, true] {
+            let mut folder = FolderCache::<String>::cleared(dir.path().to_path_buf()).unwrap();
+            for (k, v) in [("b/1", 3u32), ("a/2", 2), ("a/1", 1), ("ab", 4)] {
+                folder.insert(k.to_string(), &v).unwrap();
+            }
+            if ordered {
+                folder = folder.with_ordered_keys();
             }
+            assert_eq!(folder.scan_prefix("a/"), vec!["a/1".to_string(), "a/2".to_string()]);
+            assert_eq!(folder.scan_range("a/2".to_string().."b/1".to_string()), vec!["a/2".to_string(), "ab".to_string()]);
+            assert_eq!(folder.scan_prefix_entries::<u32>("a").unwrap(), vec![("a/1".to_string(), 1), ("a/2".to_string(), 2), ("ab".to_string(), 4)]);
+            folder.insert("a/0".to_string(), &0u32).unwrap();
+            folder.remove(&"a/2".to_string()).unwrap();
+            assert_eq!(folder.scan_range_entries::<u32>(.."a/9".to_string()).unwrap(), vec![("a/0".to_string(), 0), ("a/1".to_string(), 1)]);
+            assert_eq!(folder.remove_range("a/1".to_string().."b".to_string()).unwrap(), 2);
+            assert_eq!(folder.scan_prefix("a"), vec!["a/0".to_string()]);
         }
+    }
 
-        // Drop and restore — ensure all values are gone
+    #[test]
+    fn continued_lazy_loads_keys_in_background() {
+        let dir = TempDir::new("continued_lazy_loads_keys_in_background").unwrap();
         {
-            let folder = FolderCache::continued(folder_path.clone()).unwrap();
-            let mut cache: CacheMut<i32, String, FolderCache<i32>> = CacheMut::new(folder, 4);
+            let mut folder = FolderCache::<u32>::cleared(dir.path().to_path_buf()).unwrap().with_dedup(16);
+            for i in 0..200u32 {
+                folder.insert(i, &i.to_string()).unwrap();
+            }
+            folder.insert(1000, &"x".repeat(64)).unwrap();
+            folder.insert(1001, &"x".repeat(64)).unwrap();
+            folder.commit().unwrap();
+        }
+        let mut folder = FolderCache::<u32>::continued_lazy(dir.path().to_path_buf()).unwrap().with_dedup(16);
+        folder.wait_loaded().unwrap();
+        assert_eq!(folder.keys().count(), 202);
+        assert_eq!(folder.get::<String>(&150).unwrap(), "150");
+        // The shared blob is still counted twice, so removing one entry keeps it for the other.
+        folder.remove(&1000).unwrap();
+        assert_eq!(folder.get::<String>(&1001).unwrap(), "x".repeat(64));
+        folder.insert(200, &"200".to_string()).unwrap();
+        assert!(folder.contains(&200) && !folder.contains(&1000));
+    }
 
-            for i in 0..7 {
-                assert!(cache.get(&i).is_err(), "Expected key {i} to be absent");
+    #[test]
+    fn key_index_written_on_close_and_ignored_when_stale() {
+        let dir = TempDir::new("key_index_written_on_close_and_ignored_when_stale").unwrap();
+        let indexes = || -> Vec<std::path::PathBuf> {
+            std::fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().path())
+                .filter(|p| p.extension().is_some_and(|e| e == "keys")).collect()
+        };
+        {
+            let mut folder = FolderCache::<u32>::cleared(dir.path().to_path_buf()).unwrap();
+            for i in 0..50u32 {
+                folder.insert(i, &i).unwrap();
             }
         }
+        let index = indexes();
+        assert_eq!(index.len(), 1);
+        let old = std::fs::read(&index[0]).unwrap();
+        {
+            let mut folder = FolderCache::<u32>::continued(dir.path().to_path_buf()).unwrap();
+            assert_eq!(folder.keys().count(), 50);
+            folder.remove(&3).unwrap();
+            // The first write deletes the index, so a crash can not leave it behind.
+            assert!(indexes().is_empty());
+            folder.insert(100, &100u32).unwrap();
+        }
+        assert_ne!(std::fs::read(&index[0]).unwrap(), old);
+        std::fs::write(&index[0], old).unwrap();
+        let folder = FolderCache::<u32>::continued(dir.path().to_path_buf()).unwrap();
+        let mut keys: Vec<u32> = folder.keys().copied().collect();
+        keys.sort();
+        assert_eq!(keys, (0..50).filter(|i| *i != 3).chain([100]).collect::<Vec<_>>());
+        assert_eq!(folder.get::<u32>(&49).unwrap(), 49);
+    }
 
-        // Insert 0..7 again, but values are reversed (7 - i).to_string()
+    #[cfg(feature = "dictionary")]
+    #[test]
+    fn dictionary_compression() {
+        let dir = TempDir::new("dictionary_compression").unwrap();
+        let doc = |i: u32| format!(r#"{{"id":{i},"kind":"thumbnail","width":640,"height":480,"format":"image/webp","owner":"user-{}"}}"#, i%7);
+        let mut folder = FolderCache::<u32>::cleared(dir.path().to_path_buf()).unwrap();
+        for i in 0..200 {
+            folder.insert(i, &doc(i)).unwrap();
+        }
+        folder.compact().unwrap();
+        let before = folder.disk_bytes();
+        assert!(folder.train_dictionary(50, 4096).unwrap() > 0);
+        for i in 0..200 {
+            folder.insert(i, &doc(i)).unwrap();
+        }
+        folder.compact().unwrap();
+        assert!(folder.disk_bytes() < before*2/3, "{} >= 2/3 of {}", folder.disk_bytes(), before);
+        assert_eq!(folder.get::<String>(&17).unwrap(), doc(17));
+        drop(folder);
+        let folder = FolderCache::<u32>::continued(dir.path().to_path_buf()).unwrap();
+        assert!((0..200).all(|i| folder.get::<String>(&i).unwrap() == doc(i)));
+    }
+
+    #[test]
+    fn compact_step_one_bucket_at_a_time() {
+        use crate::maintenance::{Maintenance, MaintenancePolicy};
+
+        let dir = TempDir::new("compact_step").unwrap();
+        let mut folder = FolderCache::<u32>::cleared(dir.path().to_path_buf()).unwrap();
+        for i in 0..64 {
+            folder.insert(i, &"x".repeat(if i%2 == 0 {4} else {100})).unwrap();
+        }
+        for i in 0..60 {
+            folder.remove(&i).unwrap();
+        }
+        let files = || std::fs::read_dir(dir.path()).unwrap().count();
+        let (before, count) = (folder.disk_bytes(), files());
+        assert_eq!(folder.compact_step(1.0).unwrap(), None);
+        // The sparse small class folds into the larger one first, then the larger one is truncated.
+        assert!(folder.compact_step(0.5).unwrap().unwrap() > 0);
+        assert_eq!(files(), count-1);
+        assert!(folder.compact_step(0.5).unwrap().unwrap() > 0);
+        assert_eq!(folder.compact_step(0.5).unwrap(), None);
+        assert!(folder.disk_bytes() < before);
+        assert!((60..64).all(|i| folder.get::<String>(&i).unwrap().len() == if i%2 == 0 {4} else {100}));
+
+        let policy: MaintenancePolicy = "compact_step_above_waste = 0.5\ncompact_step_interval = 1h".parse().unwrap();
+        let mut maintenance = Maintenance::new(policy);
+        let mut cache = CacheMut::new(folder, 4);
+        for i in 100..164 {
+            cache.insert(i, "y".repeat(if i%2 == 0 {100} else {1000})).unwrap();
+        }
+        cache.flush().unwrap();
+        for i in 108..164 {
+            cache.remove(&i).unwrap();
+        }
+        cache.flush().unwrap();
+        assert!(maintenance.run_due(&mut cache).unwrap().compacted_step_bytes.is_some());
+        // One step per interval.
+        assert_eq!(maintenance.run_due(&mut cache).unwrap().compacted_step_bytes, None);
+        assert!(cache.maintain_backend(|cc| cc.compact_step(0.5)).unwrap().is_some());
+        assert_eq!(*cache.get(&61).unwrap(), "x".repeat(100));
+        assert_eq!(*cache.get(&107).unwrap(), "y".repeat(1000));
+    }
+
+    #[test]
+    fn manifest_discards_interrupted_changes() {
+        use crate::folder_compatible::FolderCacheError;
+
+        let dir = TempDir::new("manifest").unwrap();
+        let bucket_files = || {
+            let mut names: Vec<String> = std::fs::read_dir(dir.path()).unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap()).filter(|n| n.ends_with(".cache")).collect();
+            names.sort();
+            names
+        };
+        let mut folder = FolderCache::<u32>::cleared(dir.path().to_path_buf()).unwrap();
+        for i in 0..8 {
+            folder.insert(i, &"x".repeat(if i < 2 {4} else {100})).unwrap();
+        }
+        folder.remove(&7).unwrap();
+        let small = bucket_files()[0].clone();
+        // Folding the two small records into the larger class writes a new generation of its file.
+        folder.compact().unwrap();
+        drop(folder);
+        let files = bucket_files();
+        assert_eq!(files.len(), 1);
+        assert!(files[0].contains('-'), "{files:?}");
+
+        // As if a crash came before the old files were deleted, or before a new file made it into the manifest.
+        let large = files[0].split_once('-').unwrap().0.to_string()+".cache";
+        std::fs::copy(dir.path().join(&files[0]), dir.path().join(&large)).unwrap();
+        std::fs::copy(dir.path().join(&files[0]), dir.path().join(&small)).unwrap();
+        let mut folder = FolderCache::<u32>::continued(dir.path().to_path_buf()).unwrap();
+        assert_eq!(bucket_files(), files);
+        assert_eq!(folder.keys().count(), 7);
+        folder.remove(&0).unwrap();
+        drop(folder);
+        let folder = FolderCache::<u32>::continued(dir.path().to_path_buf()).unwrap();
+        assert!(!folder.contains(&0));
+        assert_eq!(folder.get::<String>(&6).unwrap(), "x".repeat(100));
+        drop(folder);
+
+        // A clear that stopped after emptying the manifest leaves nothing behind on the next open.
+        std::fs::write(dir.path().join("manifest"), "FolderCache manifest 1\n").unwrap();
+        let folder = FolderCache::<u32>::continued(dir.path().to_path_buf()).unwrap();
+        assert_eq!(folder.keys().count(), 0);
+        assert!(bucket_files().is_empty());
+        drop(folder);
+        std::fs::write(dir.path().join("manifest"), "not a manifest").unwrap();
+        assert!(matches!(FolderCache::<u32>::continued(dir.path().to_path_buf()), Err(FolderCacheError::BadManifest)));
+    }
+
+    #[test]
+    fn checkpoint_and_rollback() {
+        use crate::folder_compatible::FolderCacheError;
+
+        let dir = TempDir::new("checkpoint").unwrap();
+        let mut folder = FolderCache::<u32>::cleared(dir.path().to_path_buf()).unwrap().with_ordered_keys();
+        for i in 0..20 {
+            folder.insert(i, &i.to_string()).unwrap();
+        }
+        folder.checkpoint("before").unwrap();
+        for i in 0..10 {
+            folder.insert(i, &"changed".to_string()).unwrap();
+        }
+        for i in 10..20 {
+            folder.remove(&i).unwrap();
+        }
+        folder.insert(100, &"x".repeat(300)).unwrap();
+        folder.compact().unwrap();
+        folder.checkpoint("after").unwrap();
+        assert_eq!(folder.checkpoints().unwrap(), ["after", "before"]);
+
+        folder.rollback_to("before").unwrap();
+        assert!((0..20).all(|i| folder.get::<String>(&i).unwrap() == i.to_string()));
+        assert!(!folder.contains(&100));
+        assert_eq!(folder.scan_range(5..8), [5, 6, 7]);
+        // Writing after a rollback leaves the checkpoint as it was.
+        folder.insert(0, &"again".to_string()).unwrap();
+        folder.rollback_to("before").unwrap();
+        assert_eq!(folder.get::<String>(&0).unwrap(), "0");
+
+        folder.rollback_to("after").unwrap();
+        assert_eq!(folder.get::<String>(&3).unwrap(), "changed");
+        assert_eq!(folder.get::<String>(&100).unwrap(), "x".repeat(300));
+        assert!(matches!(folder.rollback_to("never"), Err(FolderCacheError::NoCheckpoint(_))));
+        assert!(folder.remove_checkpoint("after").unwrap());
+        assert!(!folder.remove_checkpoint("after").unwrap());
+        drop(folder);
+
+        let folder = FolderCache::<u32>::continued(dir.path().to_path_buf()).unwrap();
+        assert_eq!(folder.keys().count(), 11);
+        assert_eq!(folder.get::<String>(&3).unwrap(), "changed");
+        assert_eq!(folder.checkpoints().unwrap(), ["before"]);
+    }
+
+    #[test]
+    fn pinned_entries_survive_eviction_and_clear() {
+        let dir = TempDir::new("pinned").unwrap();
+        let mut folder = FolderCache::<u32>::cleared(dir.path().to_path_buf()).unwrap();
+        for i in 0..10 {
+            folder.insert(i, &"x".repeat(100)).unwrap();
+        }
+        assert!(folder.pin(&0).unwrap());
+        assert!(folder.pin(&5).unwrap());
+        assert!(!folder.pin(&99).unwrap());
+        assert_eq!(folder.clear_unpinned().unwrap(), 8);
+        let mut pinned = folder.pinned().unwrap();
+        pinned.sort();
+        assert_eq!(pinned, [0, 5]);
+        drop(folder);
+
+        // Pins are kept on disk, and quota eviction passes over them even though they are the oldest entries.
+        let mut folder = FolderCache::<u32>::continued(dir.path().to_path_buf()).unwrap().with_quota(4096);
+        assert!(folder.is_pinned(&0) && folder.is_pinned(&5));
+        for i in 10..100 {
+            folder.insert(i, &"y".repeat(100)).unwrap();
+        }
+        assert!(!folder.take_evicted().is_empty());
+        assert!(folder.contains(&0) && folder.contains(&5));
+
+        assert!(folder.unpin(&0).unwrap());
+        assert!(!folder.unpin(&0).unwrap());
+        folder.remove(&5).unwrap();
+        assert!(!folder.is_pinned(&5));
+        drop(folder);
+        let folder = FolderCache::<u32>::continued(dir.path().to_path_buf()).unwrap();
+        assert!(folder.pinned().unwrap().is_empty());
+    }
+
+    #[test]
+    fn folder_clear_where() {
+        let dir = TempDir::new("clear_where").unwrap();
+        let mut folder = FolderCache::<String>::cleared(dir.path().to_path_buf()).unwrap();
+        for i in 0..30 {
+            folder.insert(format!("{}/{i}", if i < 10 {"tmp"} else {"keep"}), &i).unwrap();
+        }
+        assert_eq!(folder.clear_where(|k| k.starts_with("tmp/")).unwrap(), 10);
+        assert_eq!(folder.keys().count(), 20);
+        assert!(folder.keys().all(|k| k.starts_with("keep/")));
+        drop(folder);
+        assert_eq!(FolderCache::<String>::continued(dir.path().to_path_buf()).unwrap().keys().count(), 20);
+    }
+
+    #[test]
+    fn folder_usage() {
+        let dir = TempDir::new("usage").unwrap();
+        let mut cache = CacheMut::new(FolderCache::<u32>::cleared(dir.path().to_path_buf()).unwrap(), 8);
+        let empty = cache.usage().unwrap();
+        assert_eq!(empty.entries, 0);
+        for i in 0..20 {
+            cache.insert(i, vec![i as u8; 100]).unwrap();
+        }
+        cache.commit().unwrap();
+        let full = cache.usage().unwrap();
+        assert_eq!(full.entries, 20);
+        assert!(full.disk_bytes > empty.disk_bytes);
+        assert!(full.live_bytes >= 20*100 && full.live_bytes <= full.disk_bytes);
+        for i in 0..10 {
+            cache.remove(&i).unwrap();
+        }
+        cache.commit().unwrap();
+        let half = cache.usage().unwrap();
+        assert_eq!(half.entries, 10);
+        assert!(half.live_bytes < full.live_bytes);
+        let plain: CacheMut<u32, u32, std::collections::HashMap<u32, u32>> = CacheMut::new(std::collections::HashMap::new(), 8);
+        assert_eq!(plain.usage(), None);
+    }
+
+    #[test]
+    fn folder_validator() {
+        let dir = TempDir::new("validator").unwrap();
+        let folder = FolderCache::<u32>::cleared(dir.path().to_path_buf()).unwrap();
+        let mut cache = CacheMut::new(folder, 8).with_validator(|_, v: &String| !v.starts_with("stale"));
+        let events = cache.subscribe(8);
+        cache.insert(0, "stale".to_string()).unwrap();
+        cache.insert(1, "kept".to_string()).unwrap();
+        cache.flush().unwrap();
+        cache.get(&0).unwrap();
+        cache.get(&1).unwrap();
+        // The backend is brought up to date behind the cache's copy, which the validator then rejects.
+        cache.with_backend(|folder| folder.insert(0, &"fresh".to_string())).unwrap().unwrap();
+        let misses = cache.stats().misses;
+        assert_eq!(*cache.get(&0).unwrap(), "fresh");
+        assert_eq!(*cache.get(&1).unwrap(), "kept");
+        assert_eq!(cache.stats().misses, misses+1);
+        assert_eq!(events.try_iter().filter(|(_, reason)| *reason == crate::EvictReason::Rejected).collect::<Vec<_>>(), [(0, crate::EvictReason::Rejected)]);
+    }
+
+    #[test]
+    fn validator_keeps_modified_values() {
+        let dir = TempDir::new("validator_dirty").unwrap();
+        let folder = FolderCache::<u32>::cleared(dir.path().to_path_buf()).unwrap();
+        let mut cache = CacheMut::new(folder, 8).with_validator(|_, v: &String| !v.starts_with("stale"));
+        cache.insert(1, "fresh".to_string()).unwrap();
+        cache.flush().unwrap();
+        cache.insert(0, "stale".to_string()).unwrap();
+        *cache.get_mut(&1).unwrap() = "stale too".to_string();
+        // Both are modified and not yet written back, so the backend has nothing to replace them with.
+        assert_eq!(*cache.get(&0).unwrap(), "stale");
+        assert_eq!(*cache.get(&1).unwrap(), "stale too");
+        cache.flush().unwrap();
+        assert_eq!(cache.with_backend(|folder| folder.get::<String>(&0)).unwrap().unwrap(), "stale");
+    }
+
+    #[test]
+    fn corrupt_compressed_lengths_are_refused() {
+        use crate::compression::{compress, decompress};
+
+        let packed = compress(b"abcdabcdabcdabcd");
+        assert_eq!(decompress(&packed, 16).as_deref(), Some(&b"abcdabcdabcdabcd"[..]));
+        assert_eq!(decompress(&packed, 15), None);
+        // Four literals, then a back-reference of usize::MAX bytes.
+        let mut corrupt = vec![4, b'a', b'b', b'c', b'd'];
+        corrupt.extend([0xff; 9]);
+        corrupt.extend([0x01, 4]);
+        assert_eq!(decompress(&corrupt, 1 << 20), None);
+    }
+
+
+    #[test]
+    fn hints_survive_reopening() {
+        let tempdir = TempDir::new("test_hint_reopen").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+
+        let mut folder = FolderCache::continued(folder_path.clone()).unwrap();
+        for i in 0..10u32 {
+            folder.insert_with_hint(i, &format!("value_{i}"), (i % 2) as u64).unwrap();
+            // Every move is written in one step, so the bucket never holds a record twice.
+            let report = FolderCache::<u32>::verify(&folder_path).unwrap();
+            assert!(report.is_ok(), "{:?}", report.problems);
+        }
+        drop(folder);
+
+        let mut folder = FolderCache::continued(folder_path.clone()).unwrap();
+        assert_eq!(folder.hint(&3), Some(1));
+        folder.insert_with_hint(10, &"value_10".to_string(), 0).unwrap();
+        // The records placed before the cache was closed keep their groups next to the new ones.
+        let groups: Vec<Option<u64>> = (0..11u32).map(|i| folder.hint(&i)).collect();
+        assert_eq!(groups, (0..11).map(|i| Some(i % 2)).collect::<Vec<_>>());
+        drop(folder);
+
+        let folder = FolderCache::continued(folder_path).unwrap();
+        for i in 0..11u32 {
+            assert_eq!(folder.get::<String>(&i).unwrap(), format!("value_{i}"));
+        }
+    }
+
+
+    #[test]
+    fn quota_smaller_than_an_entry() {
+        let tempdir = TempDir::new("test_quota_overflow").unwrap();
+        let mut folder = FolderCache::continued(tempdir.path().to_path_buf()).unwrap().with_quota(4096);
+        for i in 0..4u32 {
+            folder.insert(i, &"v".repeat(100)).unwrap();
+        }
+        assert!(folder.take_evicted().is_empty());
+        // Nothing else can make room for an entry bigger than the whole quota, so it is evicted along with the rest.
+        folder.insert(4, &"x".repeat(10_000)).unwrap();
+        let mut evicted = folder.take_evicted();
+        evicted.sort();
+        assert_eq!(evicted, [0, 1, 2, 3, 4]);
+        assert!(folder.disk_bytes() <= 4096);
+
+        // Pinned entries stay even if that leaves the cache over its quota.
+        folder.insert(5, &"x".repeat(10_000)).unwrap();
+        assert_eq!(folder.take_evicted(), [5]);
+        folder.insert(6, &"v".repeat(100)).unwrap();
+        assert!(folder.pin(&6).unwrap());
+        folder.insert(7, &"x".repeat(10_000)).unwrap();
+        assert_eq!(folder.take_evicted(), [7]);
+        assert_eq!(folder.get::<String>(&6).unwrap(), "v".repeat(100));
+    }
+
+    #[test]
+    fn corrupt_journals_are_discarded() {
+        let tempdir = TempDir::new("test_corrupt_journal").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
         {
-            let folder = FolderCache::continued(folder_path.clone()).unwrap();
-            let mut cache = CacheMut::new(folder, 4);
+            let mut folder = FolderCache::continued(folder_path.clone()).unwrap();
+            for i in 0..4u32 {
+                folder.insert(i, &format!("value_{i}")).unwrap();
+            }
+        }
+        let journal = folder_path.join("journal.wal");
+        let class = class_of(9);
+        // A torn batch: a plausible start, cut off before its writes and checksum.
+        let mut torn = class.to_le_bytes().to_vec();
+        torn.extend(1u64.to_le_bytes());
+        torn.extend(FILE_HEADER_BYTES.to_le_bytes());
+        for log in [vec![0xff; 3], vec![0xff; 64], torn] {
+            std::fs::write(&journal, &log).unwrap();
+            assert!(FolderCache::<u32>::verify(&folder_path).unwrap().pending_journal);
+            let folder = FolderCache::<u32>::continued(folder_path.clone()).unwrap();
+            assert!(!journal.exists());
+            for i in 0..4u32 {
+                assert_eq!(folder.get::<String>(&i).unwrap(), format!("value_{i}"));
+            }
+        }
+        assert!(FolderCache::<u32>::verify(&folder_path).unwrap().is_ok());
+    }
 
-            for i in 0..7 {
-                cache.insert(i, (7 - i).to_string()).unwrap();
+
+    #[cfg(feature = "dictionary")]
+    #[test]
+    fn corrupt_compressed_records_fail_to_read() {
+        use crate::folder_compatible::FolderCacheError;
+
+        let dir = TempDir::new("corrupt_compressed").unwrap();
+        let doc = |i: u32| format!(r#"{{"id":{i},"kind":"thumbnail","width":640,"height":480,"format":"image/webp"}}"#);
+        {
+            let mut folder = FolderCache::<u32>::cleared(dir.path().to_path_buf()).unwrap();
+            for i in 0..100 {
+                folder.insert(i, &doc(i)).unwrap();
+            }
+            assert!(folder.train_dictionary(50, 4096).unwrap() > 0);
+            for i in 0..100 {
+                folder.insert(i, &doc(i)).unwrap();
+            }
+        }
+        // Compressed values start with an `ext 32` header of type 0x44, then the dictionary id. Point the first
+        // record at a dictionary that does not exist, and fill the second one's compressed bytes with garbage.
+        let mut corrupted = 0;
+        for entry in std::fs::read_dir(dir.path()).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|ext| ext != "cache") {
+                continue;
             }
+            let mut bytes = std::fs::read(&path).unwrap();
+            let starts: Vec<usize> = bytes.windows(6).enumerate()
+                .filter(|(_, w)| w[0] == 0xc9 && w[5] == 0x44).map(|(at, _)| at+6).take(2).collect();
+            if let [first, second] = starts[..] {
+                bytes[first..first+4].copy_from_slice(&u32::MAX.to_le_bytes());
+                bytes[second+4..second+12].fill(0xff);
+                std::fs::write(&path, &bytes).unwrap();
+                corrupted = 2;
+                break;
+            }
+        }
+        assert_eq!(corrupted, 2);
 
-            drop(cache);
+        let folder = FolderCache::<u32>::continued(dir.path().to_path_buf()).unwrap();
+        let failed: Vec<FolderCacheError> = (0..100).filter_map(|i| folder.get::<String>(&i).err()).collect();
+        assert_eq!(failed.len(), 2);
+        assert!(matches!(failed[..], [FolderCacheError::BadCompression, FolderCacheError::BadCompression]), "{failed:?}");
+    }
+
+
+    #[test]
+    fn failed_writes_leave_indexes_alone() {
+        use serde::{Serialize, Deserialize};
+
+        #[derive(Serialize, Deserialize)]
+        struct Doc {owner: u32, body: String}
+        let tempdir = TempDir::new("test_index_failures").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+        let mut folder = FolderCache::continued(folder_path.clone()).unwrap().with_overflow_threshold(64);
+        folder.add_index("owner", |doc: &Doc| doc.owner).unwrap();
+        folder.insert(1u32, &Doc {owner: 1, body: String::new()}).unwrap();
+        // Values over the threshold go to blob files, which can not be created with a file in the way.
+        std::fs::write(folder_path.join("blobs"), b"").unwrap();
+        let big = Doc {owner: 2, body: "x".repeat(100)};
+        assert!(folder.insert(2, &big).is_err());
+        assert!(folder.insert(1, &big).is_err());
+        assert!(folder.insert_with_hint(3, &big, 0).is_err());
+        assert!(folder.keys_by("owner", &2u32).unwrap().is_empty());
+        assert_eq!(folder.keys_by("owner", &1u32).unwrap(), [1]);
+        folder.commit().unwrap();
+        drop(folder);
+
+        let mut folder = FolderCache::<u32>::continued(folder_path).unwrap();
+        folder.add_index("owner", |doc: &Doc| doc.owner).unwrap();
+        assert_eq!(folder.keys_by("owner", &1u32).unwrap(), [1]);
+    }
+
+
+    #[test]
+    fn hinted_rewrites_keep_the_old_value_until_written() {
+        let tempdir = TempDir::new("test_hint_rewrite").unwrap();
+        let folder_path = tempdir.path().to_path_buf();
+        let mut folder = FolderCache::continued(folder_path.clone()).unwrap().with_overflow_threshold(64);
+        for i in 0..6u32 {
+            folder.insert_with_hint(i, &format!("value_{i}"), (i % 2) as u64).unwrap();
+        }
+        assert!(folder.pin(&2).unwrap());
+        // Rewriting a record, including one other records were moved around, keeps every other key in place.
+        for i in [2u32, 5, 0] {
+            folder.insert_with_hint(i, &format!("again_{i}"), 1).unwrap();
+        }
+        assert!(folder.is_pinned(&2));
+        assert_eq!(folder.hint(&0), Some(1));
+        for i in 0..6u32 {
+            let expected = if [0, 2, 5].contains(&i) {format!("again_{i}")} else {format!("value_{i}")};
+            assert_eq!(folder.get::<String>(&i).unwrap(), expected);
         }
-        
-        let v: Vec<_> = std::fs::read_dir(&folder_path)
-            .unwrap()
-            .filter_map(|e| {
-                let entry = e.unwrap();
-                let path = entry.path();
-                if path.extension().map_or(false, |ext| ext == "cache") {
-                    Some(path)
-                } else {
-                    None
-                }
-            })
-            .collect();
-        assert_eq!(v.len(), 1);
 
-        // Check .cache file size again — should not have grown
-        let final_size = std::fs::metadata(&cache_file).unwrap().len();
+        // A value that can not be written leaves the old one.
+        std::fs::write(folder_path.join("blobs"), b"").unwrap();
+        assert!(folder.insert_with_hint(3, &"x".repeat(100), 1).is_err());
+        assert_eq!(folder.get::<String>(&3).unwrap(), "value_3");
+        drop(folder);
+        let report = FolderCache::<u32>::verify(&folder_path).unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.records, 6);
+    }
 
-        assert_eq!(
-            initial_size, final_size,
-            ".cache file size should remain the same after reinsertion"
-        );
+
+    #[test]
+    fn max_value_size_covers_appends_and_hints() {
+        use crate::folder_compatible::FolderCacheError;
+        let dir = TempDir::new("max_value_size_paths").unwrap();
+        let mut folder = FolderCache::<u32>::cleared(dir.path().to_path_buf()).unwrap().with_max_value_size(100);
+        folder.insert(1, &"x".repeat(90)).unwrap();
+        // The appended value as a whole is over the limit, though the tail is not.
+        assert!(matches!(folder.append(1, &"y".repeat(20)), Err(FolderCacheError::TooLarge(100))));
+        assert_eq!(folder.get::<String>(&1).unwrap(), "x".repeat(90));
+        folder.append(1, &"y".repeat(5)).unwrap();
+        assert!(matches!(folder.insert_with_hint(2, &"x".repeat(200), 0), Err(FolderCacheError::TooLarge(100))));
+        assert!(!folder.contains(&2));
+        folder.insert_with_hint(2, &"x".repeat(50), 0).unwrap();
+    }
+
+    #[test]
+    fn warm_tier_keeps_values_the_backend_refuses() {
+        use std::collections::HashMap;
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use crate::{CacheCompatible, CacheMutCompatible, Retrieval};
+        use crate::tiered::WarmTier;
+
+        /// Takes values out when read, and refuses every write while `down` is set.
+        struct Flaky {map: HashMap<u32, String>, down: Arc<AtomicBool>}
+        impl Flaky {
+            fn check(&self) -> Result<(), &'static str> {
+                if self.down.load(Ordering::SeqCst) {Err("backend down")} else {Ok(())}
+            }
+        }
+        impl CacheCompatible<u32, String> for Flaky {
+            type Error = &'static str;
+            const RETRIEVAL: Retrieval = Retrieval::Take;
+            fn contains(&self, k: u32) -> bool { self.map.contains_key(&k) }
+            fn get(&mut self, k: u32) -> Result<String, Self::Error> { self.map.remove(&k).ok_or("missing") }
+            fn replace(&mut self, k: u32, v: String) -> Result<(), Self::Error> { self.insert(k, v) }
+        }
+        impl CacheMutCompatible<u32, String> for Flaky {
+            fn insert(&mut self, k: u32, v: String) -> Result<(), Self::Error> {
+                self.check()?;
+                self.map.insert(k, v);
+                Ok(())
+            }
+            fn remove(&mut self, k: u32) -> Result<(), Self::Error> {
+                self.check()?;
+                self.map.remove(&k);
+                Ok(())
+            }
+            fn commit(&mut self) -> Result<(), Self::Error> { self.check() }
+        }
+
+        let down = Arc::new(AtomicBool::new(true));
+        // Nothing fits in the warm tier, so every value is moved on to the backend at once.
+        let mut tier = WarmTier::new(Flaky {map: HashMap::new(), down: down.clone()}, 0);
+        assert!(tier.insert(0, "zero".to_string()).is_err());
+        assert!(CacheMutCompatible::<u32, String>::commit(&mut tier).is_err());
+        assert_eq!(tier.len(), 1);
+        down.store(false, Ordering::SeqCst);
+        CacheMutCompatible::<u32, String>::commit(&mut tier).unwrap();
+        assert!(tier.is_empty());
+        assert_eq!(tier.inner().map[&0], "zero");
     }
 }
@@ -0,0 +1,71 @@
+use std::cell::Cell;
+use std::sync::{Condvar, MutexGuard, PoisonError};
+pub use parking_lot::lock_api::{GuardNoSend, GuardSend, RawRwLock};
+
+/// A read-write lock built on the standard library's `Mutex` and `Condvar` instead of `parking_lot`'s own, for
+/// `CacheMut::new_with_lock`. Writers wait for readers to finish and new readers wait for a writer, in no fixed order.
+pub struct StdRwLock {readers: std::sync::Mutex<isize>, released: Condvar}
+impl StdRwLock {
+    /// The number of readers, or -1 while written. The count is only changed in full, so it is kept if a thread
+    /// panicked while holding the mutex.
+    fn readers(&self) -> MutexGuard<'_, isize> { self.readers.lock().unwrap_or_else(PoisonError::into_inner) }
+}
+unsafe impl RawRwLock for StdRwLock {
+    const INIT: Self = Self {readers: std::sync::Mutex::new(0), released: Condvar::new()};
+    type GuardMarker = GuardSend;
+
+    fn lock_shared(&self) {
+        let mut readers = self.released.wait_while(self.readers(), |readers| *readers < 0).unwrap_or_else(PoisonError::into_inner);
+        *readers += 1;
+    }
+    fn try_lock_shared(&self) -> bool {
+        let mut readers = self.readers();
+        *readers >= 0 && {*readers += 1; true}
+    }
+    unsafe fn unlock_shared(&self) {
+        let mut readers = self.readers();
+        *readers -= 1;
+        if *readers == 0 {
+            self.released.notify_all();
+        }
+    }
+    fn lock_exclusive(&self) {
+        let mut readers = self.released.wait_while(self.readers(), |readers| *readers != 0).unwrap_or_else(PoisonError::into_inner);
+        *readers = -1;
+    }
+    fn try_lock_exclusive(&self) -> bool {
+        let mut readers = self.readers();
+        *readers == 0 && {*readers = -1; true}
+    }
+    unsafe fn unlock_exclusive(&self) {
+        *self.readers() = 0;
+        self.released.notify_all();
+    }
+    fn is_locked(&self) -> bool { *self.readers() != 0 }
+    fn is_locked_exclusive(&self) -> bool { *self.readers() < 0 }
+}
+
+/// No locking, for single-threaded programs: a `CacheMut` using it is neither `Send` nor `Sync`. Borrowing a value
+/// that is borrowed mutably, or mutably while it is borrowed, panics, as waiting could only deadlock.
+pub struct NoLock(Cell<isize>);
+unsafe impl RawRwLock for NoLock {
+    const INIT: Self = Self(Cell::new(0));
+    type GuardMarker = GuardNoSend;
+
+    fn lock_shared(&self) {
+        assert!(self.try_lock_shared(), "value is already borrowed mutably");
+    }
+    fn try_lock_shared(&self) -> bool {
+        self.0.get() >= 0 && {self.0.set(self.0.get()+1); true}
+    }
+    unsafe fn unlock_shared(&self) { self.0.set(self.0.get()-1) }
+    fn lock_exclusive(&self) {
+        assert!(self.try_lock_exclusive(), "value is already borrowed");
+    }
+    fn try_lock_exclusive(&self) -> bool {
+        self.0.get() == 0 && {self.0.set(-1); true}
+    }
+    unsafe fn unlock_exclusive(&self) { self.0.set(0) }
+    fn is_locked(&self) -> bool { self.0.get() != 0 }
+    fn is_locked_exclusive(&self) -> bool { self.0.get() < 0 }
+}
@@ -0,0 +1,209 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::{CacheMut, CacheMutCompatible};
+use crate::lock::RawRwLock;
+use crate::clock::{Clock, SystemClock};
+
+/// Backend operations that the maintenance subsystem can trigger.
+pub trait Maintain<K, V>: CacheMutCompatible<K, V> {
+    /// Fraction (0 to 1) of the backend's storage that holds no live data.
+    fn wasted_fraction(&self) -> f64;
+    /// Reclaims wasted storage, returning the number of bytes freed.
+    fn compact(&mut self) -> Result<u64, Self::Error>;
+    /// Reclaims the wasted storage of one part of the backend whose dead space is over `above_waste` of it, returning
+    /// the number of bytes freed, or `None` if no part was over. Backends that can compact in parts (`FolderCache`, one
+    /// bucket file at a time) override this; by default the whole backend is compacted once `wasted_fraction` is over.
+    fn compact_step(&mut self, above_waste: f64) -> Result<Option<u64>, Self::Error> {
+        if self.wasted_fraction() > above_waste {self.compact().map(Some)} else {Ok(None)}
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PolicyError {
+    Syntax(usize),
+    UnknownKey(usize, String),
+    BadValue(usize, String),
+}
+impl std::fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Syntax(n) => write!(f, "line {n}: expected `key = value`"),
+            Self::UnknownKey(n, key) => write!(f, "line {n}: unknown key `{key}`"),
+            Self::BadValue(n, key) => write!(f, "line {n}: invalid value for `{key}`"),
+        }
+    }
+}
+impl std::error::Error for PolicyError {}
+
+/// Declarative description of periodic upkeep, parsed from lines of `key = value` (`#` starts a comment):
+/// ```text
+/// tick = 1s                  # how often due work is checked for
+/// flush_interval = 30s       # write back inactive entries and commit the backend
+/// compact_above_waste = 0.5  # compact once half the backend's storage is dead space
+/// compact_step_above_waste = 0.5  # or compact one part (bucket file) at a time, once half of it is dead space
+/// compact_step_interval = 10s     # but no more than one part every 10 seconds
+/// ```
+/// Durations take an `ms`, `s`, `m` or `h` suffix.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaintenancePolicy {
+    pub tick: Duration,
+    pub flush_interval: Option<Duration>,
+    pub compact_above_waste: Option<f64>,
+    /// Compacts at most one part of the backend per tick, holding the cache only for that part, so that readers and
+    /// writers wait less than for `compact_above_waste`.
+    pub compact_step_above_waste: Option<f64>,
+    /// The least time between two `compact_step_above_waste` steps.
+    pub compact_step_interval: Duration,
+}
+impl Default for MaintenancePolicy {
+    fn default() -> Self {
+        Self {
+            tick: Duration::from_secs(1), flush_interval: None, compact_above_waste: None,
+            compact_step_above_waste: None, compact_step_interval: Duration::ZERO,
+        }
+    }
+}
+pub(crate) fn parse_duration(s: &str) -> Option<Duration> {
+    let split = s.find(|c: char| !c.is_ascii_digit())?;
+    let (n, unit) = s.split_at(split);
+    let n: u64 = n.parse().ok()?;
+    match unit {
+        "ms" => Some(Duration::from_millis(n)),
+        "s" => Some(Duration::from_secs(n)),
+        "m" => Some(Duration::from_secs(n*60)),
+        "h" => Some(Duration::from_secs(n*3600)),
+        _ => None,
+    }
+}
+fn parse_fraction(s: &str) -> Option<f64> {
+    s.parse().ok().filter(|x| (0.0..=1.0).contains(x))
+}
+impl FromStr for MaintenancePolicy {
+    type Err = PolicyError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut policy = Self::default();
+        for (n, line) in s.lines().enumerate().map(|(n, l)| (n+1, l.split('#').next().unwrap().trim())) {
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or(PolicyError::Syntax(n))?;
+            let (key, value) = (key.trim(), value.trim());
+            let bad = || PolicyError::BadValue(n, key.to_string());
+            match key {
+                "tick" => policy.tick = parse_duration(value).filter(|d| !d.is_zero()).ok_or_else(bad)?,
+                "flush_interval" => policy.flush_interval = Some(parse_duration(value).ok_or_else(bad)?),
+                "compact_above_waste" => policy.compact_above_waste = Some(parse_fraction(value).ok_or_else(bad)?),
+                "compact_step_above_waste" => policy.compact_step_above_waste = Some(parse_fraction(value).ok_or_else(bad)?),
+                "compact_step_interval" => policy.compact_step_interval = parse_duration(value).ok_or_else(bad)?,
+                _ => return Err(PolicyError::UnknownKey(n, key.to_string())),
+            }
+        }
+        Ok(policy)
+    }
+}
+
+/// What a maintenance pass did.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MaintenanceReport {
+    pub flushed: bool,
+    pub compacted_bytes: Option<u64>,
+    /// Bytes freed by a `compact_step_above_waste` step, if one ran.
+    pub compacted_step_bytes: Option<u64>,
+    /// How many entries `CacheMut::expire_idle` dropped.
+    pub expired_idle: usize,
+    /// How many entries held by `CacheMut::with_write_debounce` `CacheMut::write_due` wrote back.
+    pub written_due: usize,
+}
+
+/// Executes a `MaintenancePolicy`, keeping track of when each task last ran.
+pub struct Maintenance {policy: MaintenancePolicy, last_flush: Instant, last_step: Option<Instant>, clock: Arc<dyn Clock>}
+impl Maintenance {
+    pub fn new(policy: MaintenancePolicy) -> Self {
+        Self {policy, last_flush: Instant::now(), last_step: None, clock: Arc::new(SystemClock)}
+    }
+    /// Takes the time for `flush_interval` and `compact_step_interval` from `clock` rather than the wall clock.
+    pub fn with_clock(mut self, clock: impl Clock+'static) -> Self {
+        self.last_flush = clock.now();
+        self.last_step = None;
+        self.clock = Arc::new(clock);
+        self
+    }
+    pub fn policy(&self) -> &MaintenancePolicy {&self.policy}
+    /// Runs whatever tasks are due.
+    pub fn run_due<K, V, CC, L>(&mut self, cache: &mut CacheMut<K, V, CC, L>) -> Result<MaintenanceReport, CC::Error> where
+    K: Copy+Eq+std::hash::Hash, CC: Maintain<K, V>, L: RawRwLock {
+        let mut report = MaintenanceReport {expired_idle: cache.expire_idle()?, written_due: cache.write_due()?, ..MaintenanceReport::default()};
+        if self.policy.flush_interval.is_some_and(|interval| self.clock.now().duration_since(self.last_flush) >= interval) {
+            cache.flush()?;
+            self.last_flush = self.clock.now();
+            report.flushed = true;
+        }
+        if let Some(threshold) = self.policy.compact_above_waste {
+            report.compacted_bytes = cache.maintain_backend(|cc| {
+                if cc.wasted_fraction() > threshold {cc.compact().map(Some)} else {Ok(None)}
+            })?;
+        }
+        if let Some(threshold) = self.policy.compact_step_above_waste
+            && self.last_step.is_none_or(|last| self.clock.now().duration_since(last) >= self.policy.compact_step_interval) {
+            report.compacted_step_bytes = cache.maintain_backend(|cc| cc.compact_step(threshold))?;
+            if report.compacted_step_bytes.is_some() {
+                self.last_step = Some(self.clock.now());
+            }
+        }
+        Ok(report)
+    }
+    /// Runs the policy on a background thread every `tick` until the returned handle is stopped or dropped.
+    /// Errors are passed to `on_error` and do not stop the thread.
+    pub fn spawn<K, V, CC, L>(mut self, mut cache: CacheMut<K, V, CC, L>, on_error: impl Fn(CC::Error)+Send+'static) -> MaintenanceHandle where
+    K: Copy+Eq+std::hash::Hash+Send+'static, V: Send+Sync+'static,
+    CC: Maintain<K, V>+Send+'static, L: RawRwLock+Send+Sync+'static {
+        let tick = self.policy.tick;
+        MaintenanceHandle::spawn(tick, move || {
+            if let Err(e) = self.run_due(&mut cache) {
+                on_error(e);
+            }
+        })
+    }
+}
+
+/// Stops the maintenance thread when dropped.
+pub struct MaintenanceHandle {stop: Arc<AtomicBool>, thread: Option<JoinHandle<()>>}
+impl MaintenanceHandle {
+    /// Calls `task` on a new thread every `tick`.
+    pub(crate) fn spawn(tick: Duration, mut task: impl FnMut()+Send+'static) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = std::thread::spawn(move || {
+            loop {
+                std::thread::park_timeout(tick);
+                if thread_stop.load(Ordering::Relaxed) {
+                    break;
+                }
+                task();
+            }
+        });
+        Self {stop, thread: Some(thread)}
+    }
+    pub(crate) fn thread(&self) -> Option<std::thread::Thread> {
+        self.thread.as_ref().map(|thread| thread.thread().clone())
+    }
+    pub fn stop(mut self) {
+        self.join();
+    }
+    fn join(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+impl Drop for MaintenanceHandle {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
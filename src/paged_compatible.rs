@@ -0,0 +1,126 @@
+use std::path::PathBuf;
+use std::fs::{OpenOptions,File};
+use std::io::{Read,Write,Seek,SeekFrom};
+use std::marker::PhantomData;
+
+use crate::folder_compatible::FolderCacheError;
+use crate::{CacheCompatible, CacheMutCompatible, Retrieval};
+
+const DATA_FILE: &str = "data.pages";
+const MAP_FILE: &str = "present.pages";
+
+/// A value with a fixed encoded size, stored by `PagedCache` without any per-entry framing.
+pub trait FixedSize: Sized {
+    const SIZE: usize;
+    fn write_bytes(&self, out: &mut [u8]);
+    fn read_bytes(bytes: &[u8]) -> Self;
+}
+macro_rules! fixed_size_array {
+    ($($t:ty),*) => {$(
+        impl<const N: usize> FixedSize for [$t; N] {
+            const SIZE: usize = N*size_of::<$t>();
+            fn write_bytes(&self, out: &mut [u8]) {
+                for (x, chunk) in self.iter().zip(out.chunks_exact_mut(size_of::<$t>())) {
+                    chunk.copy_from_slice(&x.to_le_bytes());
+                }
+            }
+            fn read_bytes(bytes: &[u8]) -> Self {
+                std::array::from_fn(|i| {
+                    let mut a = [0; size_of::<$t>()];
+                    a.copy_from_slice(&bytes[i*size_of::<$t>()..(i+1)*size_of::<$t>()]);
+                    <$t>::from_le_bytes(a)
+                })
+            }
+        }
+    )*};
+}
+fixed_size_array!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+/// A paged file keyed by `usize`. Page `i` lives at `i*stride` in the data file, where `stride` is the value size
+/// rounded up to the requested alignment. Which pages are present is tracked in a separate bitmap file.
+pub struct PagedCache<V: FixedSize> {data: File, map: File, present: Vec<u8>, stride: u64, _v: PhantomData<V>}
+impl<V: FixedSize> PagedCache<V> {
+    /// Opens (or creates) the paged file in `folder`. `align` must be a power of two; pass 1 for tightly packed pages.
+    pub fn continued(folder: PathBuf, align: u64) -> Result<Self, FolderCacheError> {
+        assert!(align.is_power_of_two());
+        let stride = (V::SIZE as u64).max(1).next_multiple_of(align);
+        let data = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(folder.join(DATA_FILE))?;
+        let mut map = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(folder.join(MAP_FILE))?;
+        let mut present = Vec::new();
+        if map.metadata()?.len() == 0 {
+            map.write_all(&stride.to_le_bytes())?;
+        } else {
+            let mut header = [0; size_of::<u64>()];
+            map.read_exact(&mut header)?;
+            let found = u64::from_le_bytes(header);
+            if found != stride {
+                return Err(FolderCacheError::PageLayout {found, expected: stride});
+            }
+            map.read_to_end(&mut present)?;
+        }
+        Ok(Self {data, map, present, stride, _v: PhantomData})
+    }
+    pub fn cleared(folder: PathBuf, align: u64) -> Result<Self, FolderCacheError> {
+        for name in [DATA_FILE, MAP_FILE] {
+            match std::fs::remove_file(folder.join(name)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Self::continued(folder, align)
+    }
+    pub fn stride(&self) -> u64 {self.stride}
+    fn set_present(&mut self, i: usize, present: bool) -> Result<(), FolderCacheError> {
+        if i/8 >= self.present.len() {
+            self.present.resize(i/8+1, 0);
+        }
+        if present {self.present[i/8] |= 1<<(i%8)} else {self.present[i/8] &= !(1<<(i%8))}
+        self.map.seek(SeekFrom::Start(size_of::<u64>() as u64+(i/8) as u64))?;
+        self.map.write_all(&self.present[i/8..i/8+1])?;
+        Ok(())
+    }
+    pub fn contains(&self, i: usize) -> bool {
+        self.present.get(i/8).is_some_and(|byte| byte&(1<<(i%8)) != 0)
+    }
+    pub fn insert(&mut self, i: usize, v: &V) -> Result<(), FolderCacheError> {
+        let mut bytes = vec![0; V::SIZE];
+        v.write_bytes(&mut bytes);
+        self.data.seek(SeekFrom::Start(i as u64*self.stride))?;
+        self.data.write_all(&bytes)?;
+        self.set_present(i, true)
+    }
+    pub fn get(&mut self, i: usize) -> Result<V, FolderCacheError> {
+        if !self.contains(i) {
+            return Err(FolderCacheError::Nothing);
+        }
+        let mut bytes = vec![0; V::SIZE];
+        self.data.seek(SeekFrom::Start(i as u64*self.stride))?;
+        self.data.read_exact(&mut bytes)?;
+        Ok(V::read_bytes(&bytes))
+    }
+    pub fn remove(&mut self, i: usize) -> Result<(), FolderCacheError> {
+        if self.contains(i) {
+            self.set_present(i, false)?;
+        }
+        Ok(())
+    }
+}
+
+impl<V: FixedSize> CacheCompatible<usize, V> for PagedCache<V> {
+    type Error = FolderCacheError;
+    const RETRIEVAL: Retrieval = Retrieval::Copy;
+
+    fn contains(&self, k: usize) -> bool { PagedCache::contains(self, k) }
+    fn get(&mut self, k: usize) -> Result<V, Self::Error> { PagedCache::get(self, k) }
+
+    fn replace(&mut self, _: usize, _: V) -> Result<(), Self::Error> { Ok(()) }
+}
+impl<V: FixedSize> CacheMutCompatible<usize, V> for PagedCache<V> {
+    fn insert(&mut self, k: usize, v: V) -> Result<(), Self::Error> { PagedCache::insert(self, k, &v) }
+
+    fn insert_shared(&mut self, k: usize, v: &V) -> Result<bool, Self::Error> { PagedCache::insert(self, k, v).map(|_| true) }
+
+    fn remove(&mut self, k: usize) -> Result<(), Self::Error> { PagedCache::remove(self, k) }
+
+    fn commit(&mut self) -> Result<(), Self::Error> { Ok(()) }
+}
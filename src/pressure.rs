@@ -0,0 +1,130 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::{CacheMut, CacheMutCompatible};
+use crate::lock::RawRwLock;
+use crate::maintenance::MaintenanceHandle;
+
+/// Tells whether the host is short of memory. Closures returning `io::Result<bool>` are signals too.
+pub trait PressureSignal: Send {
+    fn under_pressure(&mut self) -> io::Result<bool>;
+}
+impl<F> PressureSignal for F where F: FnMut() -> io::Result<bool>+Send {
+    fn under_pressure(&mut self) -> io::Result<bool> { self() }
+}
+
+fn invalid(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("unexpected contents in {}", path.display()))
+}
+
+/// Linux's pressure stall information for a cgroup (v2): under pressure while some task waited on memory for at
+/// least `threshold` percent of the last 10 seconds (the `some avg10` of `memory.pressure`).
+#[derive(Clone, Debug)]
+pub struct CgroupPressure {path: PathBuf, threshold: f64}
+impl CgroupPressure {
+    /// The cgroup this process runs in, as `/proc/self/cgroup` names it, or the root of `/sys/fs/cgroup` (which is
+    /// the container's own cgroup inside most containers).
+    pub fn new(threshold: f64) -> Self {
+        let own = std::fs::read_to_string("/proc/self/cgroup").ok().and_then(|groups| {
+            groups.lines().find_map(|line| line.strip_prefix("0::").map(|path| path.trim_start_matches('/').to_string()))
+        });
+        let path = Path::new("/sys/fs/cgroup").join(own.unwrap_or_default()).join("memory.pressure");
+        Self::at(if path.exists() {path} else {PathBuf::from("/sys/fs/cgroup/memory.pressure")}, threshold)
+    }
+    /// Reads the pressure file at `path`.
+    pub fn at(path: impl Into<PathBuf>, threshold: f64) -> Self { Self {path: path.into(), threshold} }
+    /// The `some avg10` percentage.
+    pub fn avg10(&self) -> io::Result<f64> {
+        let pressure = std::fs::read_to_string(&self.path)?;
+        let some = pressure.lines().find_map(|line| line.strip_prefix("some ")).ok_or_else(|| invalid(&self.path))?;
+        some.split_whitespace().find_map(|field| field.strip_prefix("avg10="))
+            .and_then(|avg| avg.parse().ok()).ok_or_else(|| invalid(&self.path))
+    }
+}
+impl PressureSignal for CgroupPressure {
+    fn under_pressure(&mut self) -> io::Result<bool> { Ok(self.avg10()? >= self.threshold) }
+}
+
+/// Polls `MemAvailable` in Linux's `/proc/meminfo`: under pressure while less than `min_bytes` is available.
+#[derive(Clone, Debug)]
+pub struct AvailableMemory {path: PathBuf, min_bytes: u64}
+impl AvailableMemory {
+    pub fn new(min_bytes: u64) -> Self { Self::at("/proc/meminfo", min_bytes) }
+    /// Reads a file in the format of `/proc/meminfo` at `path`.
+    pub fn at(path: impl Into<PathBuf>, min_bytes: u64) -> Self { Self {path: path.into(), min_bytes} }
+    pub fn available(&self) -> io::Result<u64> {
+        let info = std::fs::read_to_string(&self.path)?;
+        let kb = info.lines().find_map(|line| line.strip_prefix("MemAvailable:"))
+            .and_then(|kb| kb.trim().trim_end_matches("kB").trim().parse::<u64>().ok()).ok_or_else(|| invalid(&self.path))?;
+        Ok(kb*1024)
+    }
+}
+impl PressureSignal for AvailableMemory {
+    fn under_pressure(&mut self) -> io::Result<bool> { Ok(self.available()? < self.min_bytes) }
+}
+
+#[derive(Debug)]
+pub enum PressureError<E> {
+    Signal(io::Error),
+    Cache(E),
+}
+impl<E: std::fmt::Display> std::fmt::Display for PressureError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Signal(e) => write!(f, "could not read memory pressure: {e}"),
+            Self::Cache(e) => write!(f, "could not shrink the cache: {e}"),
+        }
+    }
+}
+impl<E: std::fmt::Debug+std::fmt::Display> std::error::Error for PressureError<E> {}
+
+/// Shrinks a cache while the host is short of memory, as told by a `PressureSignal`, writing the entries over the
+/// reduced capacity back to the backend, and restores the capacity once the pressure is gone. While shrunk, an
+/// adaptive capacity (see `CacheMut::with_adaptive_capacity`) does not grow past the reduced capacity.
+pub struct MemoryPressure<S> {signal: S, keep: f64, tick: Duration, restore: Option<usize>}
+impl<S: PressureSignal> MemoryPressure<S> {
+    /// Keeps `keep` (0 to 1) of the capacity under pressure; 0 writes back every released entry.
+    pub fn new(signal: S, keep: f64) -> Self {
+        assert!((0.0..=1.0).contains(&keep), "keep must be between 0 and 1");
+        Self {signal, keep, tick: Duration::from_secs(1), restore: None}
+    }
+    /// How often `spawn` polls the signal. Defaults to one second.
+    pub fn with_tick(mut self, tick: Duration) -> Self {
+        assert!(!tick.is_zero());
+        self.tick = tick;
+        self
+    }
+    /// Whether the cache is currently shrunk.
+    pub fn shrunk(&self) -> bool { self.restore.is_some() }
+    /// Polls the signal once, shrinking or restoring the cache as needed, and returns whether it is under pressure.
+    pub fn run_due<K, V, CC, L>(&mut self, cache: &mut CacheMut<K, V, CC, L>) -> Result<bool, PressureError<CC::Error>> where
+    K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V>, L: RawRwLock {
+        let pressure = self.signal.under_pressure().map_err(PressureError::Signal)?;
+        match (pressure, self.restore) {
+            (true, None) => {
+                let capacity = cache.cap();
+                cache.limit_capacity(Some((capacity as f64*self.keep) as usize)).map_err(PressureError::Cache)?;
+                self.restore = Some(capacity);
+            },
+            (false, Some(capacity)) => {
+                cache.limit_capacity(None).map_err(PressureError::Cache)?;
+                cache.set_capacity(capacity).map_err(PressureError::Cache)?;
+                self.restore = None;
+            },
+            _ => {},
+        }
+        Ok(pressure)
+    }
+    /// Runs `run_due` on a background thread every tick until the returned handle is stopped or dropped.
+    /// Errors are passed to `on_error` and do not stop the thread.
+    pub fn spawn<K, V, CC, L>(mut self, mut cache: CacheMut<K, V, CC, L>, on_error: impl Fn(PressureError<CC::Error>)+Send+'static) -> MaintenanceHandle where
+    S: 'static, K: Copy+Eq+std::hash::Hash+Send+'static, V: Send+Sync+'static, CC: CacheMutCompatible<K, V>+Send+'static,
+    L: RawRwLock+Send+Sync+'static {
+        MaintenanceHandle::spawn(self.tick, move || {
+            if let Err(e) = self.run_due(&mut cache) {
+                on_error(e);
+            }
+        })
+    }
+}
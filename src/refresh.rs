@@ -0,0 +1,53 @@
+use std::time::Duration;
+
+use crate::{CacheMut, CacheMutCompatible};
+use crate::lock::RawRwLock;
+use crate::maintenance::MaintenanceHandle;
+
+/// Loads the current value of a key from wherever the cache's values come from, or `None` if it cannot right now.
+type Loader<K, V> = Box<dyn Fn(&K) -> Option<V>+Send>;
+
+/// Reloads values shortly before their TTL (see `CacheMut::with_ttl`) runs out, so that readers of hot keys keep
+/// getting the old value instead of waiting for a reload. Only values held in memory are refreshed; values that are
+/// borrowed at the time are retried on the next pass.
+pub struct RefreshAhead<K, V> {ahead: Duration, tick: Duration, loader: Loader<K, V>}
+impl<K, V> RefreshAhead<K, V> {
+    /// Refreshes values once they are within `ahead` of their TTL, using `loader`. When it returns `None`, the old
+    /// value is kept and the load is tried again on the next pass.
+    pub fn new(ahead: Duration, loader: impl Fn(&K) -> Option<V>+Send+'static) -> Self {
+        Self {ahead, tick: Duration::from_secs(1), loader: Box::new(loader)}
+    }
+    /// How often `spawn` looks for aging values. Defaults to one second.
+    pub fn with_tick(mut self, tick: Duration) -> Self {
+        assert!(!tick.is_zero());
+        self.tick = tick;
+        self
+    }
+    /// Refreshes every value that is due and returns how many were replaced. The loader runs without holding the
+    /// cache's lock.
+    pub fn run_due<CC, L>(&self, cache: &mut CacheMut<K, V, CC, L>) -> Result<usize, CC::Error> where
+    K: Copy+Eq+std::hash::Hash, CC: CacheMutCompatible<K, V>, L: RawRwLock {
+        let mut refreshed = 0;
+        for k in cache.aging(self.ahead) {
+            if let Some(v) = (self.loader)(&k)
+                && cache.refresh(k, v)? {
+                    refreshed += 1;
+                }
+        }
+        Ok(refreshed)
+    }
+    /// Runs `run_due` on a background thread every tick until the returned handle is stopped or dropped.
+    /// Errors are passed to `on_error` and do not stop the thread.
+    /// `CacheMut::get_stale_ok` wakes the thread early.
+    pub fn spawn<CC, L>(self, cache: CacheMut<K, V, CC, L>, on_error: impl Fn(CC::Error)+Send+'static) -> MaintenanceHandle where
+    K: Copy+Eq+std::hash::Hash+Send+'static, V: Send+Sync+'static, CC: CacheMutCompatible<K, V>+Send+'static, L: RawRwLock+Send+Sync+'static {
+        let mut thread_cache = cache.clone();
+        let handle = MaintenanceHandle::spawn(self.tick, move || {
+            if let Err(e) = self.run_due(&mut thread_cache) {
+                on_error(e);
+            }
+        });
+        cache.set_refresher(handle.thread());
+        handle
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::ops::RangeBounds;
+
+use crate::{Appendable, CacheCompatible, CacheMutCompatible, CacheRangeCompatible, IfChanged, Retrieval, Serialized, Token, Usage};
+
+/// Backends that can list the keys they hold, so that `ShardedBackend` can move entries between shards.
+pub trait ListKeys<K, V>: CacheMutCompatible<K, V> {
+    fn keys(&self) -> Vec<K>;
+    /// Up to `limit` keys from position `cursor` on (0 for the first page), and the cursor of the next page, or
+    /// `None` after the last one. Keys added or removed between pages may be missed or listed twice. By default this
+    /// lists every key for each page; backends that can skip ahead cheaply should override it.
+    fn keys_paged(&self, cursor: usize, limit: usize) -> (Vec<K>, Option<usize>) {
+        page(self.keys().into_iter(), cursor, limit)
+    }
+    /// Removes every entry whose key `matches`, returning how many. By default this lists every key and removes the
+    /// matching ones with one `remove_many`; backends that can filter their own index should override it.
+    fn remove_where(&mut self, matches: &dyn Fn(&K) -> bool) -> Result<usize, Self::Error> {
+        let keys: Vec<K> = self.keys().into_iter().filter(|k| matches(k)).collect();
+        let removed = keys.len();
+        self.remove_many(keys)?;
+        Ok(removed)
+    }
+}
+/// The page of `keys` that `ListKeys::keys_paged` returns.
+pub(crate) fn page<K>(keys: impl Iterator<Item = K>, cursor: usize, limit: usize) -> (Vec<K>, Option<usize>) {
+    let mut keys = keys.skip(cursor).peekable();
+    let page: Vec<K> = keys.by_ref().take(limit).collect();
+    let next = keys.peek().is_some().then_some(cursor+page.len());
+    (page, next)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ShardError<E> {
+    /// `add_shard` was given the name of a shard that is already there.
+    Duplicate(String),
+    Backend(E),
+}
+impl<E: std::fmt::Display> std::fmt::Display for ShardError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Duplicate(name) => write!(f, "there is already a shard called {name:?}"),
+            Self::Backend(e) => write!(f, "backend error: {e}"),
+        }
+    }
+}
+impl<E: std::fmt::Debug+std::fmt::Display> std::error::Error for ShardError<E> {}
+
+/// How many points each shard has on the ring. More points spread keys more evenly.
+const POINTS_PER_SHARD: u32 = 64;
+
+/// 64-bit FNV-1a, which unlike the standard library's hasher is the same in every build, so that keys stay on their
+/// shard across restarts.
+struct Fnv(u64);
+impl Hasher for Fnv {
+    fn finish(&self) -> u64 { self.0 }
+    fn write(&mut self, bytes: &[u8]) {
+        self.0 = bytes.iter().fold(self.0, |hash, b| (hash^*b as u64).wrapping_mul(0x100000001b3));
+    }
+}
+fn hash(value: &impl Hash) -> u64 {
+    let mut hasher = Fnv(0xcbf29ce484222325);
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Spreads keys over several backends (`FolderCache`s on different disks, for example) by consistent hashing on
+/// the shards' names, so that adding or removing a shard only moves the keys that belong to it. A key's shard
+/// depends only on the names of the shards, so they must be opened under the same names each time.
+/// Panics if used with no shards.
+pub struct ShardedBackend<CC> {shards: Vec<(String, CC)>, ring: BTreeMap<u64, usize>}
+impl<CC> Default for ShardedBackend<CC> {
+    fn default() -> Self { Self {shards: Vec::new(), ring: BTreeMap::new()} }
+}
+impl<CC> ShardedBackend<CC> {
+    pub fn new() -> Self { Self::default() }
+    /// Adds a shard without moving any entries to it, for opening shards that already hold their keys.
+    pub fn with_shard(mut self, name: impl Into<String>, shard: CC) -> Self {
+        let name = name.into();
+        assert!(self.shards.iter().all(|(other, _)| *other != name), "shard names must be unique");
+        self.shards.push((name, shard));
+        self.rebuild(self.shards.len());
+        self
+    }
+    /// Places the first `n` shards on the ring.
+    fn rebuild(&mut self, n: usize) {
+        self.ring = self.shards[..n].iter().enumerate()
+            .flat_map(|(i, (name, _))| (0..POINTS_PER_SHARD).map(move |point| (hash(&(name, point)), i)))
+            .collect();
+    }
+    fn shard_of<K: Hash>(&self, k: &K) -> usize {
+        let at = hash(k);
+        let (_, i) = self.ring.range(at..).next().or_else(|| self.ring.iter().next()).expect("ShardedBackend has no shards");
+        *i
+    }
+    pub fn shards(&self) -> impl Iterator<Item = (&str, &CC)> {
+        self.shards.iter().map(|(name, shard)| (name.as_str(), shard))
+    }
+    pub fn shard(&self, name: &str) -> Option<&CC> {
+        self.shards.iter().find(|(other, _)| other == name).map(|(_, shard)| shard)
+    }
+}
+impl<CC> ShardedBackend<CC> {
+    /// Moves the entries of `from` that no longer belong there to their shard, returning how many moved. An entry
+    /// the other shard refuses stays where it was.
+    fn rebalance<K, V>(&mut self, from: usize) -> Result<usize, CC::Error> where
+    K: Hash+Clone, V: Clone, CC: ListKeys<K, V> {
+        let mut moved = 0;
+        for k in self.shards[from].1.keys() {
+            let to = self.shard_of(&k);
+            if to != from {
+                let v = self.shards[from].1.get(k.clone())?;
+                // A `Take` shard no longer has the value, so it is handed back if the move fails.
+                let kept = (CC::RETRIEVAL == Retrieval::Take).then(|| v.clone());
+                if let Err(e) = self.shards[to].1.insert(k.clone(), v) {
+                    if let Some(v) = kept {
+                        self.shards[from].1.replace(k, v)?;
+                    }
+                    return Err(e);
+                }
+                self.shards[from].1.remove(k)?;
+                moved += 1;
+            }
+        }
+        Ok(moved)
+    }
+    /// Adds a shard and moves to it the entries of the other shards that now belong to it, returning how many moved.
+    /// Roughly one in every (number of shards) entries moves. Commit afterwards to make the move durable. Fails
+    /// with `ShardError::Duplicate`, leaving the shards as they were, if there is already a shard called `name`.
+    pub fn add_shard<K, V>(&mut self, name: impl Into<String>, shard: CC) -> Result<usize, ShardError<CC::Error>> where
+    K: Hash+Clone, V: Clone, CC: ListKeys<K, V> {
+        let name = name.into();
+        if self.shard(&name).is_some() {
+            return Err(ShardError::Duplicate(name));
+        }
+        let old = self.shards.len();
+        *self = std::mem::take(self).with_shard(name, shard);
+        let mut moved = 0;
+        for from in 0..old {
+            moved += self.rebalance(from).map_err(ShardError::Backend)?;
+        }
+        Ok(moved)
+    }
+    /// Removes the shard called `name` after moving all of its entries to the others, and returns it. Commit
+    /// afterwards to make the move durable. Returns `None` if there is no such shard; on an error, the shard stays
+    /// with whatever entries were not yet moved.
+    pub fn remove_shard<K, V>(&mut self, name: &str) -> Result<Option<CC>, CC::Error> where
+    K: Hash+Clone, V: Clone, CC: ListKeys<K, V> {
+        let Some(i) = self.shards.iter().position(|(other, _)| other == name) else {return Ok(None)};
+        // Moves the shard to the end and off the ring, so that its keys map to the others.
+        let removed = self.shards.remove(i);
+        self.shards.push(removed);
+        let last = self.shards.len()-1;
+        self.rebuild(last);
+        if let Err(e) = self.rebalance(last) {
+            self.rebuild(last+1);
+            return Err(e);
+        }
+        let (_, shard) = self.shards.pop().unwrap();
+        Ok(Some(shard))
+    }
+}
+
+impl<K, V, CC> CacheCompatible<K, V> for ShardedBackend<CC> where K: Hash, CC: CacheMutCompatible<K, V> {
+    type Error = CC::Error;
+    const RETRIEVAL: Retrieval = CC::RETRIEVAL;
+
+    fn contains(&self, k: K) -> bool { self.shards[self.shard_of(&k)].1.contains(k) }
+    fn get(&mut self, k: K) -> Result<V, Self::Error> {
+        let i = self.shard_of(&k);
+        self.shards[i].1.get(k)
+    }
+    fn replace(&mut self, k: K, v: V) -> Result<(), Self::Error> {
+        let i = self.shard_of(&k);
+        self.shards[i].1.replace(k, v)
+    }
+    fn get_versioned(&mut self, k: K) -> Result<(V, Option<Token>), Self::Error> {
+        let i = self.shard_of(&k);
+        self.shards[i].1.get_versioned(k)
+    }
+    fn get_if_changed(&mut self, k: K, token: &Token) -> Result<IfChanged<V>, Self::Error> {
+        let i = self.shard_of(&k);
+        self.shards[i].1.get_if_changed(k, token)
+    }
+    /// Healthy only if every shard is.
+    fn health(&mut self) -> Result<(), Self::Error> {
+        self.shards.iter_mut().try_for_each(|(_, shard)| shard.health())
+    }
+}
+impl<K, V, CC> CacheMutCompatible<K, V> for ShardedBackend<CC> where K: Hash, CC: CacheMutCompatible<K, V> {
+    fn insert(&mut self, k: K, v: V) -> Result<(), Self::Error> {
+        let i = self.shard_of(&k);
+        self.shards[i].1.insert(k, v)
+    }
+    fn insert_shared(&mut self, k: K, v: &V) -> Result<bool, Self::Error> {
+        let i = self.shard_of(&k);
+        self.shards[i].1.insert_shared(k, v)
+    }
+    const SERIALIZED: Option<Serialized<K, V, Self>> = match CC::SERIALIZED {
+        Some(serialized) => Some(Serialized {
+            serialize: serialized.serialize,
+            insert: |sharded, k, vser| {
+                let i = sharded.shard_of(&k);
+                (CC::SERIALIZED.unwrap().insert)(&mut sharded.shards[i].1, k, vser)
+            },
+        }),
+        None => None,
+    };
+    fn append(&mut self, k: K, suffix: &V::Suffix) -> Result<(), Self::Error> where V: Appendable, K: Clone {
+        let i = self.shard_of(&k);
+        self.shards[i].1.append(k, suffix)
+    }
+    fn remove(&mut self, k: K) -> Result<(), Self::Error> {
+        let i = self.shard_of(&k);
+        self.shards[i].1.remove(k)
+    }
+    /// One `remove_many` per shard.
+    fn remove_many(&mut self, keys: Vec<K>) -> Result<(), Self::Error> {
+        let mut by_shard: HashMap<usize, Vec<K>> = HashMap::new();
+        for k in keys {
+            by_shard.entry(self.shard_of(&k)).or_default().push(k);
+        }
+        by_shard.into_iter().try_for_each(|(i, keys)| self.shards[i].1.remove_many(keys))
+    }
+    fn commit(&mut self) -> Result<(), Self::Error> {
+        self.shards.iter_mut().try_for_each(|(_, shard)| shard.commit())
+    }
+    /// The sum over the shards, or `None` if any of them does not report its usage.
+    fn resource_usage(&self) -> Option<Usage> {
+        self.shards.iter().try_fold(Usage::default(), |total, (_, shard)| {
+            let usage = shard.resource_usage()?;
+            Some(Usage {
+                disk_bytes: total.disk_bytes+usage.disk_bytes, live_bytes: total.live_bytes+usage.live_bytes,
+                entries: total.entries+usage.entries,
+            })
+        })
+    }
+}
+/// Hashing does not keep keys in order, so the range is removed from every shard.
+impl<K, V, CC> CacheRangeCompatible<K, V> for ShardedBackend<CC> where K: Hash, CC: CacheRangeCompatible<K, V> {
+    fn remove_range(&mut self, range: impl RangeBounds<K>) -> Result<(), Self::Error> {
+        let range = (range.start_bound(), range.end_bound());
+        self.shards.iter_mut().try_for_each(|(_, shard)| shard.remove_range(range))
+    }
+}
+impl<K, V, CC> ListKeys<K, V> for ShardedBackend<CC> where K: Hash, CC: ListKeys<K, V> {
+    fn keys(&self) -> Vec<K> { self.shards.iter().flat_map(|(_, shard)| shard.keys()).collect() }
+    fn remove_where(&mut self, matches: &dyn Fn(&K) -> bool) -> Result<usize, Self::Error> {
+        self.shards.iter_mut().map(|(_, shard)| shard.remove_where(matches)).sum()
+    }
+}
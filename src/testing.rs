@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Display};
+
+use crate::{CMRef, CacheMut, CacheMutCompatible, Retrieval};
+
+/// One step of a `ModelChecker` run. Keys and values are given by number, as passed to the checker's `key` and
+/// `value` functions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    Insert(usize, usize),
+    Get(usize),
+    /// Reads a key and keeps the guard until the next `Release`, so later steps run with the entry borrowed.
+    Hold(usize),
+    Release,
+    /// Replaces the value through `CacheMut::get_mut`.
+    Write(usize, usize),
+    Remove(usize),
+    RemoveMany(Vec<usize>),
+    Commit,
+    Flush,
+}
+
+/// A run of `ModelChecker` that went wrong: its seed, the steps up to and including the one that failed, and what
+/// was wrong.
+#[derive(Clone, Debug)]
+pub struct Failure {
+    pub seed: u64,
+    pub ops: Vec<Op>,
+    pub message: String,
+}
+impl Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "seed {}: {}", self.seed, self.message)?;
+        for (i, op) in self.ops.iter().enumerate() {
+            writeln!(f, "  {i}: {op:?}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs random sequences of operations against a `CacheMut` over a backend and against a `HashMap`, checking every
+/// read and `contains` along the way and, after a final commit, that the backend holds exactly what the map does.
+/// Runs are reproducible from their seed. Small capacities and key counts make evictions and write-backs while
+/// entries are borrowed frequent.
+pub struct ModelChecker<K, V, CC> {
+    pub(crate) backend: Box<dyn FnMut() -> CC>,
+    pub(crate) key: Box<dyn Fn(usize) -> K>,
+    pub(crate) value: Box<dyn Fn(usize) -> V>,
+    capacity: usize, keys: usize, pub(crate) steps: usize,
+}
+impl<K, V, CC> ModelChecker<K, V, CC> where
+K: Copy+Eq+std::hash::Hash+Debug, V: Clone+PartialEq+Debug, CC: CacheMutCompatible<K, V>, CC::Error: Debug {
+    /// `backend` must make a fresh, empty backend each time. `key` and `value` must give distinct keys and values
+    /// for distinct numbers.
+    pub fn new(backend: impl FnMut() -> CC+'static, key: impl Fn(usize) -> K+'static, value: impl Fn(usize) -> V+'static) -> Self {
+        Self {backend: Box::new(backend), key: Box::new(key), value: Box::new(value), capacity: 2, keys: 6, steps: 200}
+    }
+    /// The capacity of the `CacheMut`. Defaults to 2.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+    /// How many distinct keys are used. Defaults to 6.
+    pub fn with_keys(mut self, keys: usize) -> Self {
+        self.keys = keys;
+        self
+    }
+    /// How many operations each run performs. Defaults to 200.
+    pub fn with_steps(mut self, steps: usize) -> Self {
+        self.steps = steps;
+        self
+    }
+    /// The operations of the run with `seed`.
+    pub fn ops(&self, seed: u64) -> Vec<Op> {
+        // xorshift never leaves zero.
+        let mut state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+        let mut next = |n: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % n as u64) as usize
+        };
+        (0..self.steps).map(|step| {
+            let k = next(self.keys);
+            match next(20) {
+                0..=4 => Op::Insert(k, step),
+                5..=8 => Op::Get(k),
+                9 | 10 => Op::Hold(k),
+                11 | 12 => Op::Release,
+                13 | 14 => Op::Write(k, step),
+                15 | 16 => Op::Remove(k),
+                17 => Op::RemoveMany(vec![k, next(self.keys)]),
+                18 => Op::Commit,
+                _ => Op::Flush,
+            }
+        }).collect()
+    }
+    /// Runs `ops` on a fresh backend. Steps that `CacheMut` does not allow at that point, such as writing to a
+    /// borrowed entry, are skipped.
+    pub fn check_ops(&mut self, ops: &[Op]) -> Result<(), (usize, String)> {
+        let mut cache = CacheMut::new((self.backend)(), self.capacity);
+        let mut model: HashMap<K, V> = HashMap::new();
+        let mut held: Vec<(K, CMRef<K, V, CC>)> = Vec::new();
+        for (i, op) in ops.iter().enumerate() {
+            let is_held = |k: &K, held: &[(K, CMRef<K, V, CC>)]| held.iter().any(|(h, _)| h == k);
+            let touched = match op {
+                Op::Insert(k, v) => {
+                    let (k, v) = ((self.key)(*k), (self.value)(*v));
+                    if !is_held(&k, &held) {
+                        cache.insert(k, v.clone()).map_err(|e| (i, format!("insert failed: {e:?}")))?;
+                        model.insert(k, v);
+                    }
+                    vec![k]
+                },
+                Op::Get(k) | Op::Hold(k) => {
+                    let k = (self.key)(*k);
+                    match (cache.get(&k), model.get(&k)) {
+                        (Ok(got), Some(v)) => {
+                            if *got != *v {
+                                return Err((i, format!("{k:?} is {:?}, expected {v:?}", *got)));
+                            }
+                            if matches!(op, Op::Hold(_)) {
+                                held.push((k, got));
+                            }
+                        },
+                        (Err(_), None) => {},
+                        (Ok(got), None) => return Err((i, format!("{k:?} is {:?}, expected nothing", *got))),
+                        (Err(e), Some(v)) => return Err((i, format!("get {k:?} failed with {e:?}, expected {v:?}"))),
+                    }
+                    vec![k]
+                },
+                Op::Release => {
+                    held.clear();
+                    vec![]
+                },
+                Op::Write(k, v) => {
+                    let (k, v) = ((self.key)(*k), (self.value)(*v));
+                    if !is_held(&k, &held) && let Some(old) = model.get_mut(&k) {
+                        let mut guard = cache.get_mut(&k).map_err(|e| (i, format!("get_mut {k:?} failed: {e:?}")))?;
+                        if *guard != *old {
+                            return Err((i, format!("{k:?} is {:?}, expected {old:?}", *guard)));
+                        }
+                        *guard = v.clone();
+                        *old = v;
+                    }
+                    vec![k]
+                },
+                Op::Remove(k) => {
+                    let k = (self.key)(*k);
+                    if !is_held(&k, &held) {
+                        cache.remove(&k).map_err(|e| (i, format!("remove failed: {e:?}")))?;
+                        model.remove(&k);
+                    }
+                    vec![k]
+                },
+                Op::RemoveMany(ks) => {
+                    let ks: Vec<K> = ks.iter().map(|k| (self.key)(*k)).collect();
+                    if !ks.iter().any(|k| is_held(k, &held)) {
+                        cache.remove_many(&ks).map_err(|e| (i, format!("remove_many failed: {e:?}")))?;
+                        ks.iter().for_each(|k| {model.remove(k);});
+                    }
+                    ks
+                },
+                Op::Commit => {
+                    if held.is_empty() {
+                        cache.commit().map_err(|e| (i, format!("commit failed: {e:?}")))?;
+                    }
+                    vec![]
+                },
+                Op::Flush => {
+                    cache.flush().map_err(|e| (i, format!("flush failed: {e:?}")))?;
+                    vec![]
+                },
+            };
+            for k in touched {
+                if cache.contains(&k) != model.contains_key(&k) {
+                    return Err((i, format!("contains {k:?} is {}", cache.contains(&k))));
+                }
+            }
+        }
+        let end = ops.len();
+        held.clear();
+        cache.commit().map_err(|e| (end, format!("final commit failed: {e:?}")))?;
+        for n in 0..self.keys {
+            let k = (self.key)(n);
+            let stored = cache.maintain_backend(|cc| {
+                if !cc.contains(k) {
+                    return Ok(None);
+                }
+                let v = cc.get(k)?;
+                if CC::RETRIEVAL == Retrieval::Take {
+                    cc.replace(k, v.clone())?;
+                }
+                Ok(Some(v))
+            }).map_err(|e: CC::Error| (end, format!("reading {k:?} from the backend failed: {e:?}")))?;
+            if stored.as_ref() != model.get(&k) {
+                return Err((end, format!("the backend has {stored:?} for {k:?} after the final commit, expected {:?}", model.get(&k))));
+            }
+        }
+        Ok(())
+    }
+    /// Runs the operations of `seed`.
+    pub fn check(&mut self, seed: u64) -> Result<(), Failure> {
+        let ops = self.ops(seed);
+        self.check_ops(&ops).map_err(|(i, message)| Failure {seed, ops: ops[..ops.len().min(i+1)].to_vec(), message})
+    }
+    /// Runs every seed in `seeds`, panicking with the steps of the first run that fails.
+    pub fn check_seeds(&mut self, seeds: std::ops::Range<u64>) {
+        for seed in seeds {
+            if let Err(failure) = self.check(seed) {
+                panic!("{failure}");
+            }
+        }
+    }
+}
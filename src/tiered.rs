@@ -0,0 +1,145 @@
+use std::collections::HashSet;
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{CacheCompatible, CacheMutCompatible, Retrieval, Usage};
+use crate::compression::{compress, decompress};
+
+#[derive(Error, Debug)]
+pub enum WarmTierError<E: std::fmt::Debug> {
+    #[error("backend error: {0:?}")]
+    Backend(E),
+    #[error(transparent)]
+    Encode(#[from] rmp_serde::encode::Error),
+    #[error(transparent)]
+    Decode(#[from] rmp_serde::decode::Error),
+    #[error("compressed value is corrupt")]
+    Corrupt,
+}
+
+/// A warm entry: the compressed serialized value and its size before compression, and whether the backend is missing
+/// this version of it.
+struct Warm {bytes: Vec<u8>, len: usize, unwritten: bool}
+
+/// A second in-memory tier between a `CacheMut` and its backend. Values the cache lets go of are kept here,
+/// serialized and compressed, up to `max_bytes` in all; only the least recently used ones beyond that go to the
+/// backend. Use it as the backend of a `CacheMut`: `CacheMut::new(WarmTier::new(folder, 64 << 20), 1000)`.
+pub struct WarmTier<K: std::hash::Hash+Eq, CC> {
+    inner: CC, warm: LruCache<K, Warm>,
+    bytes: usize, max_bytes: usize,
+    /// Keys handed to the cache while the backend was missing their value, so that it is written if they come back
+    /// unchanged.
+    lent_unwritten: HashSet<K>,
+}
+impl<K: std::hash::Hash+Eq+Clone, CC> WarmTier<K, CC> {
+    pub fn new(inner: CC, max_bytes: usize) -> Self {
+        Self {inner, warm: LruCache::unbounded(), bytes: 0, max_bytes, lent_unwritten: HashSet::new()}
+    }
+    pub fn inner(&self) -> &CC {&self.inner}
+    /// Entries held in the warm tier.
+    pub fn len(&self) -> usize {self.warm.len()}
+    pub fn is_empty(&self) -> bool {self.warm.is_empty()}
+    /// Compressed bytes held in the warm tier.
+    pub fn bytes(&self) -> usize {self.bytes}
+    fn take(&mut self, k: &K) -> Option<Warm> {
+        let warm = self.warm.pop(k)?;
+        self.bytes -= warm.bytes.len();
+        Some(warm)
+    }
+    fn decode<V: for <'a> Deserialize<'a>, E: std::fmt::Debug>(warm: &Warm) -> Result<V, WarmTierError<E>> {
+        Ok(rmp_serde::from_slice(&decompress(&warm.bytes, warm.len).ok_or(WarmTierError::Corrupt)?)?)
+    }
+}
+impl<K, CC> WarmTier<K, CC> where K: std::hash::Hash+Eq+Clone {
+    /// Stores `v` in the warm tier and moves what no longer fits to the backend.
+    fn keep<V>(&mut self, k: K, v: &V, unwritten: bool) -> Result<(), WarmTierError<CC::Error>> where
+    V: Serialize+for <'a> Deserialize<'a>, CC: CacheMutCompatible<K, V>, CC::Error: std::fmt::Debug {
+        let vser = rmp_serde::to_vec(v)?;
+        let bytes = compress(&vser);
+        self.take(&k);
+        self.bytes += bytes.len();
+        self.warm.push(k, Warm {bytes, len: vser.len(), unwritten});
+        while self.bytes > self.max_bytes && self.demote_lru::<V>()? {}
+        Ok(())
+    }
+    /// Hands the least recently used warm entry to the backend, if it does not have it already, and lets go of it
+    /// once it does. Returns whether there was one.
+    fn demote_lru<V>(&mut self) -> Result<bool, WarmTierError<CC::Error>> where
+    V: Serialize+for <'a> Deserialize<'a>, CC: CacheMutCompatible<K, V>, CC::Error: std::fmt::Debug {
+        let Some((k, warm)) = self.warm.peek_lru() else {return Ok(false)};
+        if warm.unwritten {
+            self.inner.insert(k.clone(), Self::decode(warm)?).map_err(WarmTierError::Backend)?;
+        } else if CC::RETRIEVAL == Retrieval::Take {
+            self.inner.replace(k.clone(), Self::decode(warm)?).map_err(WarmTierError::Backend)?;
+        }
+        if let Some((_, warm)) = self.warm.pop_lru() {
+            self.bytes -= warm.bytes.len();
+        }
+        Ok(true)
+    }
+}
+
+impl<K, V, CC> CacheCompatible<K, V> for WarmTier<K, CC> where
+K: std::hash::Hash+Eq+Clone, V: Serialize+for <'a> Deserialize<'a>, CC: CacheMutCompatible<K, V>, CC::Error: std::fmt::Debug {
+    type Error = WarmTierError<CC::Error>;
+    /// Values move between the cache and the warm tier.
+    const RETRIEVAL: Retrieval = Retrieval::Take;
+
+    fn contains(&self, k: K) -> bool { self.warm.contains(&k) || self.inner.contains(k) }
+
+    /// The cache hands the value back through `insert` if it changes it, and `replace` otherwise, so either way it
+    /// returns to the warm tier.
+    fn get(&mut self, k: K) -> Result<V, Self::Error> {
+        match self.take(&k) {
+            Some(warm) => {
+                if warm.unwritten {
+                    self.lent_unwritten.insert(k);
+                }
+                Self::decode(&warm)
+            },
+            None => self.inner.get(k).map_err(WarmTierError::Backend),
+        }
+    }
+
+    fn replace(&mut self, k: K, v: V) -> Result<(), Self::Error> {
+        let unwritten = self.lent_unwritten.remove(&k);
+        self.keep(k, &v, unwritten)
+    }
+
+    fn health(&mut self) -> Result<(), Self::Error> { self.inner.health().map_err(WarmTierError::Backend) }
+}
+
+impl<K, V, CC> CacheMutCompatible<K, V> for WarmTier<K, CC> where
+K: std::hash::Hash+Eq+Clone, V: Serialize+for <'a> Deserialize<'a>, CC: CacheMutCompatible<K, V>, CC::Error: std::fmt::Debug {
+    fn insert(&mut self, k: K, v: V) -> Result<(), Self::Error> {
+        self.lent_unwritten.remove(&k);
+        self.keep(k, &v, true)
+    }
+
+    fn remove(&mut self, k: K) -> Result<(), Self::Error> {
+        self.lent_unwritten.remove(&k);
+        self.take(&k);
+        self.inner.remove(k).map_err(WarmTierError::Backend)
+    }
+
+    /// Writes every warm entry the backend is missing to it, and commits it. A `Retrieval::Take` backend is given
+    /// the warm entries outright, since it would otherwise not have them.
+    fn commit(&mut self) -> Result<(), Self::Error> {
+        if CC::RETRIEVAL == Retrieval::Take {
+            while self.demote_lru::<V>()? {}
+            return self.inner.commit().map_err(WarmTierError::Backend);
+        }
+        let unwritten: Vec<K> = self.warm.iter().filter(|(_, warm)| warm.unwritten).map(|(k, _)| k.clone()).collect();
+        for k in unwritten {
+            if let Some(warm) = self.warm.peek_mut(&k) {
+                let v = Self::decode(warm)?;
+                warm.unwritten = false;
+                self.inner.insert(k, v).map_err(WarmTierError::Backend)?;
+            }
+        }
+        self.inner.commit().map_err(WarmTierError::Backend)
+    }
+    /// The backend's usage; warm entries are held in memory, not on disk.
+    fn resource_usage(&self) -> Option<Usage> { self.inner.resource_usage() }
+}
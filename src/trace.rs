@@ -0,0 +1,124 @@
+use std::collections::{HashSet, VecDeque};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use lru::LruCache;
+
+/// One lookup by `get` or `get_mut`, as recorded by `CacheMut::with_trace`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceEvent {
+    /// A hash of the key, so that traces can be kept and shared without the keys themselves.
+    pub key: u64,
+    pub hit: bool,
+    /// Since tracing began, by the cache's clock.
+    pub at: Duration,
+}
+impl TraceEvent {
+    const BYTES: usize = 8+1+8;
+}
+
+pub(crate) fn key_hash(k: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    k.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The most recent lookups of a cache, up to a fixed number.
+#[derive(Clone, Debug)]
+pub(crate) struct Trace {pub(crate) events: VecDeque<TraceEvent>, capacity: usize}
+impl Trace {
+    pub(crate) fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "the trace must hold at least one event");
+        Self {events: VecDeque::with_capacity(capacity), capacity}
+    }
+    pub(crate) fn push(&mut self, event: TraceEvent) {
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+}
+
+/// Writes `events` in a compact binary form that `read` takes back, for replaying elsewhere.
+pub fn write(events: &[TraceEvent], mut to: impl Write) -> io::Result<()> {
+    for event in events {
+        to.write_all(&event.key.to_be_bytes())?;
+        to.write_all(&[event.hit as u8])?;
+        to.write_all(&(event.at.as_nanos() as u64).to_be_bytes())?;
+    }
+    to.flush()
+}
+/// Reads the events written by `write`.
+pub fn read(mut from: impl Read) -> io::Result<Vec<TraceEvent>> {
+    let mut bytes = Vec::new();
+    from.read_to_end(&mut bytes)?;
+    if bytes.len()%TraceEvent::BYTES != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated trace"));
+    }
+    Ok(bytes.chunks_exact(TraceEvent::BYTES).map(|event| TraceEvent {
+        key: u64::from_be_bytes(event[..8].try_into().unwrap()),
+        hit: event[8] != 0,
+        at: Duration::from_nanos(u64::from_be_bytes(event[9..].try_into().unwrap())),
+    }).collect())
+}
+
+/// How the simulated cache of `simulate_with` chooses what to evict.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Policy {
+    /// The least recently used entry, as `CacheMut` does.
+    #[default]
+    Lru,
+    /// The entry that came in first, however often it was used since.
+    Fifo,
+}
+
+/// What a replayed trace would have hit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Simulation {pub hits: u64, pub misses: u64}
+impl Simulation {
+    pub fn hit_rate(&self) -> f64 {
+        if self.hits+self.misses == 0 {0.0} else {self.hits as f64/(self.hits+self.misses) as f64}
+    }
+}
+
+/// Replays `trace` against an LRU cache of `capacity` entries, to predict the hit rate `CacheMut` would get with it.
+/// Only lookups are traced, so keys written without being read first count as misses when next read.
+pub fn simulate(trace: &[TraceEvent], capacity: usize) -> Simulation { simulate_with(trace, capacity, Policy::Lru) }
+/// Like `simulate`, evicting by `policy`.
+pub fn simulate_with(trace: &[TraceEvent], capacity: usize, policy: Policy) -> Simulation {
+    let mut simulation = Simulation::default();
+    let Some(capacity) = std::num::NonZero::new(capacity) else {
+        simulation.misses = trace.len() as u64;
+        return simulation;
+    };
+    match policy {
+        Policy::Lru => {
+            let mut cache = LruCache::new(capacity);
+            for event in trace {
+                if cache.get(&event.key).is_some() {
+                    simulation.hits += 1;
+                } else {
+                    simulation.misses += 1;
+                    cache.put(event.key, ());
+                }
+            }
+        },
+        Policy::Fifo => {
+            let (mut order, mut held) = (VecDeque::new(), HashSet::new());
+            for event in trace {
+                if held.contains(&event.key) {
+                    simulation.hits += 1;
+                    continue;
+                }
+                simulation.misses += 1;
+                if order.len() == capacity.get() && let Some(oldest) = order.pop_front() {
+                    held.remove(&oldest);
+                }
+                order.push_back(event.key);
+                held.insert(event.key);
+            }
+        },
+    }
+    simulation
+}
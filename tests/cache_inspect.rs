@@ -0,0 +1,57 @@
+//! Runs the `cache-inspect` binary on a folder written by `FolderCache`.
+#![cfg(feature = "cli")]
+
+use std::path::Path;
+use std::process::{Command, Output};
+use cache::folder_compatible::FolderCache;
+use tempdir::TempDir;
+
+fn inspect(folder: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_cache-inspect")).arg(folder).args(args).output().unwrap()
+}
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+#[test]
+fn inspects_a_folder() {
+    let dir = TempDir::new("cache_inspect").unwrap();
+    let mut folder = FolderCache::<u32>::cleared(dir.path().to_path_buf()).unwrap();
+    folder.insert(1, &"one".to_string()).unwrap();
+    folder.insert(2, &vec![1, 2, 3]).unwrap();
+    drop(folder);
+
+    let keys = inspect(dir.path(), &["keys"]);
+    assert!(keys.status.success());
+    let mut listed: Vec<String> = stdout(&keys).lines().map(str::to_string).collect();
+    listed.sort();
+    assert_eq!(listed, ["1", "2"]);
+
+    let one = inspect(dir.path(), &["get", "1"]);
+    assert!(one.status.success());
+    assert_eq!(stdout(&one), "\"one\"\n");
+    assert_eq!(stdout(&inspect(dir.path(), &["get", "2"])), "[1,2,3]\n");
+
+    let verify = inspect(dir.path(), &["verify"]);
+    assert!(verify.status.success());
+    assert!(stdout(&verify).contains("2 records"), "{}", stdout(&verify));
+
+    let stats = inspect(dir.path(), &["stats"]);
+    assert!(stats.status.success());
+    assert!(stdout(&stats).lines().any(|line| line.trim_start().starts_with("total")));
+}
+
+#[test]
+fn fails_with_an_exit_code() {
+    let dir = TempDir::new("cache_inspect_errors").unwrap();
+    let mut folder = FolderCache::<u32>::cleared(dir.path().to_path_buf()).unwrap();
+    folder.insert(1, &"one".to_string()).unwrap();
+    drop(folder);
+
+    let missing = inspect(dir.path(), &["get", "7"]);
+    assert_eq!(missing.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&missing.stderr).starts_with("error: "));
+    assert_eq!(inspect(dir.path(), &["frobnicate"]).status.code(), Some(2));
+    assert_eq!(inspect(dir.path(), &["get"]).status.code(), Some(2));
+    assert_eq!(inspect(&dir.path().join("absent"), &["keys"]).status.code(), Some(1));
+}
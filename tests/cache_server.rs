@@ -0,0 +1,92 @@
+//! Runs the `cache-server` binary on a free port and talks HTTP to it.
+#![cfg(all(unix, feature = "server"))]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+use tempdir::TempDir;
+
+/// A running server, killed when dropped so that a failed test does not leave it behind.
+struct Server(Child);
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Starts the server on `folder` and returns it with the address it listens on.
+fn start(folder: &std::path::Path, args: &[&str]) -> (Server, String) {
+    let mut server = Server(Command::new(env!("CARGO_BIN_EXE_cache-server"))
+        .arg(folder).args(["--listen", "127.0.0.1:0", "--flush", "3600"]).args(args)
+        .stdout(Stdio::piped()).spawn().unwrap());
+    let mut line = String::new();
+    BufReader::new(server.0.stdout.take().unwrap()).read_line(&mut line).unwrap();
+    let address = line.trim().strip_prefix("listening on ").unwrap_or_else(|| panic!("unexpected output {line:?}"));
+    (server, address.to_string())
+}
+
+/// Stops the server as a service manager would, and checks that it exits cleanly.
+fn stop(mut server: Server) {
+    assert_eq!(unsafe {libc::kill(server.0.id() as libc::pid_t, libc::SIGTERM)}, 0);
+    assert!(server.0.wait().unwrap().success());
+}
+
+/// Whether the server turns a new connection away. Nothing is sent, as the server does not read the request of a
+/// connection it refuses.
+fn refused(address: &str) -> bool {
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+    response.starts_with(b"HTTP/1.1 503")
+}
+
+/// Sends one request and returns the status and body of the response.
+fn request(address: &str, method: &str, path: &str, body: &[u8]) -> (u16, Vec<u8>) {
+    let mut stream = TcpStream::connect(address).unwrap();
+    write!(stream, "{method} {path} HTTP/1.1\r\nHost: test\r\nContent-Length: {}\r\n\r\n", body.len()).unwrap();
+    stream.write_all(body).unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    let end = response.windows(4).position(|w| w == b"\r\n\r\n").expect("no end of headers");
+    let head = String::from_utf8_lossy(&response[..end]).into_owned();
+    let status = head.split_whitespace().nth(1).and_then(|status| status.parse().ok()).expect("no status");
+    (status, response[end+4..].to_vec())
+}
+
+#[test]
+fn serves_and_keeps_values_across_restarts() {
+    let dir = TempDir::new("cache_server").unwrap();
+    let (server, address) = start(dir.path(), &[]);
+    assert_eq!(request(&address, "GET", "/1", b""), (404, Vec::new()));
+    assert_eq!(request(&address, "PUT", "/1", b"one").0, 204);
+    assert_eq!(request(&address, "PUT", "/2", b"two").0, 204);
+    assert_eq!(request(&address, "GET", "/1", b""), (200, b"one".to_vec()));
+    assert_eq!(request(&address, "DELETE", "/2", b"").0, 204);
+    assert_eq!(request(&address, "GET", "/2", b"").0, 404);
+    assert_eq!(request(&address, "GET", "/not-a-key", b"").0, 400);
+    let (status, stats) = request(&address, "GET", "/stats", b"");
+    assert_eq!(status, 200);
+    assert!(String::from_utf8(stats).unwrap().lines().any(|line| line.starts_with("cached ")));
+    // Nothing was flushed yet, so the value only survives if shutting down commits it.
+    stop(server);
+
+    let (server, address) = start(dir.path(), &[]);
+    assert_eq!(request(&address, "GET", "/1", b""), (200, b"one".to_vec()));
+    assert_eq!(request(&address, "GET", "/2", b"").0, 404);
+    stop(server);
+}
+
+#[test]
+fn refuses_connections_beyond_the_limit() {
+    let dir = TempDir::new("cache_server_limit").unwrap();
+    let (server, address) = start(dir.path(), &["--connections", "1"]);
+    let idle = TcpStream::connect(&address).unwrap();
+    assert!(refused(&address));
+    drop(idle);
+    // The slot is given back once the idle connection is gone.
+    assert!((0..50).any(|_| !refused(&address)));
+    stop(server);
+}